@@ -4,11 +4,16 @@ use std::sync::Arc;
 use anyhow::Result;
 
 pub mod core {
-    #![allow(unused)]
-    use anyhow::bail;
+    use std::{
+        sync::Mutex,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use anyhow::Context as _;
     use async_trait::async_trait;
+    use futures::stream::{FuturesUnordered, StreamExt};
     use pallas_network::miniprotocols::Point;
-    use tokio::task;
+    use tokio::{sync::watch, task};
 
     use super::*;
 
@@ -23,59 +28,190 @@ pub mod core {
         pub fn register<T: Module>(&mut self, module: T) {
             self.modules.push(Box::new(module));
         }
+        /// Runs until every module's tasks have finished, or until SIGINT/SIGTERM.
         pub async fn run(self) -> Result<()> {
-            let context = Arc::new(Context {});
+            let token = CancellationToken::new();
+            let on_signal = token.clone();
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                on_signal.cancel();
+            });
+            self.run_until(token).await
+        }
+        /// Runs until every module's tasks have finished.
+        /// Once `token` is cancelled, modules are told to shut down, and we wait for them to wrap up.
+        pub async fn run_until(self, token: CancellationToken) -> Result<()> {
+            let context = Arc::new(Context {
+                shutdown: token,
+                tasks: Mutex::new(vec![]),
+            });
             for mut module in self.modules {
-                module.init(context.clone()).await?;
+                module
+                    .init(context.clone())
+                    .await
+                    .with_context(|| format!("could not start {}", module.name()))?;
+            }
+            context.wait_for_tasks().await
+        }
+    }
+
+    async fn shutdown_signal() {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+            let mut terminate =
+                signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+
+    /// A minimal stand-in for tokio-util's `CancellationToken`.
+    #[derive(Clone)]
+    pub struct CancellationToken {
+        cancelled: Arc<watch::Sender<bool>>,
+    }
+
+    impl CancellationToken {
+        pub fn new() -> Self {
+            Self {
+                cancelled: Arc::new(watch::Sender::new(false)),
             }
-            Ok(())
+        }
+        pub fn cancel(&self) {
+            self.cancelled.send_replace(true);
+        }
+        /// Resolves once the token has been cancelled.
+        pub async fn cancelled(&self) {
+            let mut receiver = self.cancelled.subscribe();
+            let _ = receiver.wait_for(|c| *c).await;
         }
     }
 
     pub enum AcropolisMessage {
         SyncFrom(Point),
-        NewTx(BlockInfo, Vec<u8>),
+        NewBlock(BlockInfo, Vec<u8>),
         Rollback(BlockInfo),
+        /// We've caught up with the chain, and are waiting for new blocks.
+        AwaitingBlocks,
     }
 
-    pub struct Subscription {}
+    pub struct Subscription {
+        read: usize,
+    }
     impl Subscription {
         pub async fn read(&mut self) -> Result<AcropolisMessage> {
-            let tx = "84a300d90102818258203e28562ebb6f9a777b28f154877c960f9ad5f850e05cf1d761f481c0390badc7000182a300581d6003c0b0797dd49a8549986c3c21c910b75cbe3a6d4e1318872a96763a011a002625a0028201d8185822d87a9f581cc279a3fb3b4e62bbc78e288783b58045d4ae82a18867d8352d02775aff82583900c279a3fb3b4e62bbc78e288783b58045d4ae82a18867d8352d02775a121fd22e0b57ac206fefc763f8bfa0771919f5218b40691eea4514d0821b0000003625048409a1581c45df5f274b8950b512b08d10656864958659c4ecf3ffad092ef63024a144555344720c021a00029ac5a0f5f6";
-            let tx = hex::decode(tx).unwrap();
-            Ok(AcropolisMessage::NewTx(
-                BlockInfo {
-                    slot: 0,
-                    hash: BlockHash([0; 32]),
-                },
-                tx,
+            self.read += 1;
+            // We only have the one block; after that we're at the tip, waiting for more.
+            match self.read {
+                1 => {}
+                2 => return Ok(AcropolisMessage::AwaitingBlocks),
+                _ => std::future::pending::<()>().await,
+            }
+            let block = "820785828a0000f64400000000440000000082440000000044000000000058200e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a884440000000000004400000000820a00440000000081a300d90102818258203e28562ebb6f9a777b28f154877c960f9ad5f850e05cf1d761f481c0390badc7000182a300581d6003c0b0797dd49a8549986c3c21c910b75cbe3a6d4e1318872a96763a011a002625a0028201d8185822d87a9f581cc279a3fb3b4e62bbc78e288783b58045d4ae82a18867d8352d02775aff82583900c279a3fb3b4e62bbc78e288783b58045d4ae82a18867d8352d02775a121fd22e0b57ac206fefc763f8bfa0771919f5218b40691eea4514d0821b0000003625048409a1581c45df5f274b8950b512b08d10656864958659c4ecf3ffad092ef63024a144555344720c021a00029ac581a0a080";
+            let block = hex::decode(block).unwrap();
+            Ok(AcropolisMessage::NewBlock(
+                BlockInfo::new(0, BlockHash([0; 32]), 0),
+                block,
             ))
         }
     }
 
-    pub struct Context {}
+    pub struct Context {
+        shutdown: CancellationToken,
+        tasks: Mutex<Vec<task::JoinHandle<Result<()>>>>,
+    }
 
     impl Context {
-        pub fn run<T, F>(&self, func: F) -> task::JoinHandle<T>
+        /// Runs `func` as one of the process's tasks. If it fails (or panics), the rest are
+        /// told to shut down, and the process returns its error.
+        pub fn run<F>(&self, func: F)
         where
-            T: Send + 'static,
-            F: Future<Output = T> + Send + 'static,
+            F: Future<Output = Result<()>> + Send + 'static,
         {
-            tokio::spawn(func)
+            let shutdown = self.shutdown.clone();
+            let task = tokio::spawn(async move {
+                let result = func.await;
+                if result.is_err() {
+                    shutdown.cancel();
+                }
+                result
+            });
+            self.tasks.lock().unwrap().push(task);
+        }
+
+        /// Cancelled when the process is shutting down.
+        /// Long-running tasks should stop at the next convenient point once it is.
+        pub fn shutdown_token(&self) -> CancellationToken {
+            self.shutdown.clone()
+        }
+
+        // Waits for every task, and returns the first failure, if any.
+        async fn wait_for_tasks(&self) -> Result<()> {
+            let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+            let mut tasks = tasks.into_iter().collect::<FuturesUnordered<_>>();
+            let mut first_error = None;
+            while let Some(result) = tasks.next().await {
+                let result = result.unwrap_or_else(|e| {
+                    // A panic skips the cancel in `run`, so it's up to us.
+                    self.shutdown.cancel();
+                    Err(anyhow::anyhow!("a task panicked: {e}"))
+                });
+                if let Err(e) = result {
+                    first_error.get_or_insert(e);
+                }
+            }
+            first_error.map_or(Ok(()), Err)
         }
 
+        // There's no bus to put it on, so it's only logged.
         pub async fn publish(&self, topic: &str, message: AcropolisMessage) -> Result<()> {
+            if let AcropolisMessage::SyncFrom(point) = message {
+                tracing::debug!(topic, slot = point.slot_or_default(), "asked to sync from");
+            }
             Ok(())
         }
 
-        pub async fn subscribe(&self, topic: &str) -> Result<Subscription> {
-            Ok(Subscription {})
+        // Every subscription gets the same one block, whatever it's for.
+        pub async fn subscribe(&self, _topic: &str) -> Result<Subscription> {
+            Ok(Subscription { read: 0 })
         }
     }
 
+    #[derive(Clone, Debug)]
     pub struct BlockInfo {
         pub slot: u64,
         pub hash: BlockHash,
+        /// How many blocks came before this one.
+        pub height: u64,
+        pub epoch: u64,
+        /// When the block's slot started.
+        pub timestamp: SystemTime,
+    }
+
+    impl BlockInfo {
+        /// The epoch and timestamp depend on which network we're following,
+        /// so the indexer fills them in (with `locate`) when the block reaches it.
+        pub fn new(slot: u64, hash: BlockHash, height: u64) -> Self {
+            Self {
+                slot,
+                hash,
+                height,
+                epoch: 0,
+                timestamp: UNIX_EPOCH,
+            }
+        }
+
+        pub fn locate(&mut self, chain_params: &ChainParams) {
+            self.epoch = chain_params.slot_to_epoch(self.slot);
+            self.timestamp = chain_params.slot_to_time(self.slot);
+        }
     }
 
     #[derive(Clone, Copy, Debug)]
@@ -85,6 +221,23 @@ pub mod core {
         pub fn to_vec(self) -> Vec<u8> {
             self.0.to_vec()
         }
+
+        /// Parses a hash from hex, which has to come to exactly `BYTES` bytes.
+        pub fn from_hex(hex: &str) -> Result<Self> {
+            let bytes = hex::decode(hex)?;
+            bytes.try_into().map_err(|b: Vec<u8>| {
+                anyhow::anyhow!(
+                    "Expected length {} for block hash, but got {}",
+                    Self::BYTES,
+                    b.len()
+                )
+            })
+        }
+
+        /// The point on the chain of the block with this hash, at `slot`.
+        pub fn to_point(self, slot: u64) -> Point {
+            Point::Specific(slot, self.to_vec())
+        }
     }
 
     impl TryFrom<Vec<u8>> for BlockHash {
@@ -95,22 +248,206 @@ pub mod core {
         }
     }
 
+    /// The genesis parameters needed to turn a slot into an epoch, or a time.
+    #[derive(Clone, Debug)]
+    pub struct ChainParams {
+        /// When slot 0 started, in seconds since the Unix epoch.
+        pub system_start: u64,
+        pub byron_epoch_length: u64,
+        /// In seconds.
+        pub byron_slot_length: u64,
+        pub shelley_start_slot: u64,
+        pub shelley_start_epoch: u64,
+        pub shelley_epoch_length: u64,
+        /// In seconds.
+        pub shelley_slot_length: u64,
+    }
+
+    impl ChainParams {
+        pub fn mainnet() -> Self {
+            Self {
+                system_start: 1_506_203_091,
+                byron_epoch_length: 21_600,
+                byron_slot_length: 20,
+                shelley_start_slot: 4_492_800,
+                shelley_start_epoch: 208,
+                shelley_epoch_length: 432_000,
+                shelley_slot_length: 1,
+            }
+        }
+
+        pub fn preprod() -> Self {
+            Self {
+                system_start: 1_654_041_600,
+                byron_epoch_length: 21_600,
+                byron_slot_length: 20,
+                shelley_start_slot: 86_400,
+                shelley_start_epoch: 4,
+                shelley_epoch_length: 432_000,
+                shelley_slot_length: 1,
+            }
+        }
+
+        /// Preview never had a Byron era; it started in Shelley at slot 0.
+        pub fn preview() -> Self {
+            Self {
+                system_start: 1_666_656_000,
+                byron_epoch_length: 4_320,
+                byron_slot_length: 20,
+                shelley_start_slot: 0,
+                shelley_start_epoch: 0,
+                shelley_epoch_length: 86_400,
+                shelley_slot_length: 1,
+            }
+        }
+
+        /// When `slot` starts. The slots before `shelley_start_slot` last `byron_slot_length`
+        /// each, and the ones from it on `shelley_slot_length`, so on mainnet slot 4,492,800,
+        /// the first in Shelley, starts at 1,596,059,091 (2020-07-29 21:44:51 UTC).
+        pub fn slot_to_time(&self, slot: u64) -> SystemTime {
+            let byron_slots = slot.min(self.shelley_start_slot);
+            let shelley_slots = slot - byron_slots;
+            let seconds = self.system_start
+                + byron_slots * self.byron_slot_length
+                + shelley_slots * self.shelley_slot_length;
+            UNIX_EPOCH + Duration::from_secs(seconds)
+        }
+
+        /// Which epoch `slot` is in. On mainnet, slot 4,492,799 is the end of Byron's epoch 207,
+        /// and slot 4,492,800 starts Shelley's epoch 208.
+        pub fn slot_to_epoch(&self, slot: u64) -> u64 {
+            if slot < self.shelley_start_slot {
+                return slot / self.byron_epoch_length;
+            }
+            self.shelley_start_epoch + (slot - self.shelley_start_slot) / self.shelley_epoch_length
+        }
+    }
+
     #[async_trait]
     pub trait Module: Send + Sync + 'static {
         fn name(&self) -> String;
         async fn init(&mut self, context: Arc<Context>) -> Result<()>;
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn unix_time(seconds: u64) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(seconds)
+        }
+
+        #[test]
+        fn a_block_hash_is_parsed_from_exactly_32_bytes_of_hex() {
+            let hex = "89d9b5a5b8ddc8d7e5a6795e9774d97faf1efea59b2caf7eaf9f8c5b32059df4";
+            let hash = BlockHash::from_hex(hex).unwrap();
+            assert_eq!(hash.to_vec(), hex::decode(hex).unwrap());
+            assert_eq!(
+                hash.to_point(4_492_800),
+                Point::Specific(4_492_800, hash.to_vec())
+            );
+
+            let error = |hex: &str| BlockHash::from_hex(hex).unwrap_err().to_string();
+            assert_eq!(error(&hex[1..]), "Odd number of digits");
+            assert_eq!(
+                error(&hex[..62]),
+                "Expected length 32 for block hash, but got 31"
+            );
+            assert_eq!(
+                error(&format!("{hex}00")),
+                "Expected length 32 for block hash, but got 33"
+            );
+            assert_eq!(
+                error(&hex.replace('8', "g")),
+                "Invalid character 'g' at position 0"
+            );
+        }
+
+        #[test]
+        fn blocks_are_placed_in_preprods_epochs() {
+            let preprod = ChainParams::preprod();
+            let located = |slot| {
+                let mut info = BlockInfo::new(slot, BlockHash([0; 32]), 0);
+                info.locate(&preprod);
+                (info.epoch, info.timestamp)
+            };
+            // Preprod had four 21,600-slot Byron epochs, of 20 second slots.
+            assert_eq!(located(0), (0, unix_time(1_654_041_600)));
+            assert_eq!(located(21_599), (0, unix_time(1_654_473_580)));
+            assert_eq!(located(21_600), (1, unix_time(1_654_473_600)));
+            assert_eq!(located(86_399), (3, unix_time(1_655_769_580)));
+            // Shelley began at slot 86,400, on 2022-06-21, with epoch 4.
+            assert_eq!(located(86_400), (4, unix_time(1_655_769_600)));
+            assert_eq!(located(518_399), (4, unix_time(1_656_201_599)));
+            assert_eq!(located(518_400), (5, unix_time(1_656_201_600)));
+        }
+
+        #[test]
+        fn mainnets_byron_slots_last_twenty_seconds_and_shelleys_one() {
+            let mainnet = ChainParams::mainnet();
+            let at = |slot| (mainnet.slot_to_epoch(slot), mainnet.slot_to_time(slot));
+            assert_eq!(at(0), (0, unix_time(1_506_203_091)));
+            assert_eq!(at(21_599), (0, unix_time(1_506_635_071)));
+            assert_eq!(at(21_600), (1, unix_time(1_506_635_091)));
+            // The last Byron slot, then the first of Shelley twenty seconds later.
+            assert_eq!(at(4_492_799), (207, unix_time(1_596_059_071)));
+            assert_eq!(at(4_492_800), (208, unix_time(1_596_059_091)));
+            assert_eq!(at(4_924_799), (208, unix_time(1_596_491_090)));
+            assert_eq!(at(4_924_800), (209, unix_time(1_596_491_091)));
+            // Well into Babbage, on 2023-08-09.
+            assert_eq!(at(100_000_000), (429, unix_time(1_691_566_291)));
+        }
+
+        #[test]
+        fn preview_starts_in_shelley() {
+            let preview = ChainParams::preview();
+            let at = |slot| (preview.slot_to_epoch(slot), preview.slot_to_time(slot));
+            assert_eq!(at(0), (0, unix_time(1_666_656_000)));
+            assert_eq!(at(1), (0, unix_time(1_666_656_001)));
+            assert_eq!(at(86_399), (0, unix_time(1_666_742_399)));
+            assert_eq!(at(86_400), (1, unix_time(1_666_742_400)));
+        }
+    }
 }
 
 pub mod indexer {
-    use std::{cmp::Ordering, collections::HashMap};
+    use std::{
+        cmp::Ordering,
+        collections::{BTreeMap, HashMap, HashSet, VecDeque},
+        fs::File,
+        io::{BufWriter, Write},
+        path::{Path, PathBuf},
+        sync::{
+            Mutex,
+            atomic::{self, AtomicU64},
+        },
+        time::{Duration, Instant},
+    };
 
+    use anyhow::Context as _;
     use async_trait::async_trait;
     use futures::{StreamExt, stream::FuturesUnordered};
+    use pallas_addresses::{Address, ShelleyPaymentPart};
+    use pallas_crypto::hash::{Hash, Hasher};
     use pallas_network::miniprotocols::Point;
-    use pallas_traverse::MultiEraTx;
+    use pallas_primitives::{
+        alonzo::Metadata,
+        conway::{MintedDatumOption, PseudoScript, RedeemerTag},
+    };
+    use pallas_traverse::{
+        Era, MultiEraAsset, MultiEraBlock, MultiEraCert, MultiEraOutput, MultiEraRedeemer,
+        MultiEraTx, OutputRef,
+    };
+    #[cfg(feature = "http")]
+    use tokio::sync::broadcast;
+    #[cfg(any(test, feature = "metrics"))]
+    use tokio::sync::oneshot;
+    use tokio::sync::{RwLock, RwLockWriteGuard, mpsc, watch};
+    use tracing::Instrument as _;
 
-    use crate::acropolis::core::{AcropolisMessage, BlockInfo, Module};
+    use crate::acropolis::core::{AcropolisMessage, BlockInfo, ChainParams, Module};
+    #[cfg(feature = "http")]
+    use crate::http::{IndexRoutes, Response, Router};
 
     use super::*;
 
@@ -120,185 +457,5336 @@ pub mod indexer {
         pub point: Point,
     }
 
+    /// Something an index did, for whoever's downstream of it.
+    #[derive(Debug, Clone, serde::Serialize)]
+    pub struct IndexEvent {
+        /// What happened, like `pool_updated`. Each index picks its own kinds.
+        pub kind: String,
+        pub slot: u64,
+        pub data: serde_json::Value,
+    }
+
+    impl IndexEvent {
+        pub fn new(kind: impl Into<String>, slot: u64, data: serde_json::Value) -> Self {
+            Self {
+                kind: kind.into(),
+                slot,
+                data,
+            }
+        }
+
+        /// The event as the sinks send it on, tagged with the index it came from.
+        pub fn to_json(&self, index: &str) -> serde_json::Value {
+            serde_json::json!({
+                "index": index,
+                "kind": self.kind,
+                "slot": self.slot,
+                "data": self.data,
+            })
+        }
+    }
+
+    /// Somewhere to publish index events. Events only get here once the blocks they came from
+    /// are committed, so a sink never sees anything from a block which is later rolled back
+    /// without also seeing the rollback.
+    #[async_trait]
+    pub trait EventSink: Send + Sync + 'static {
+        async fn publish(&mut self, index: &str, events: &[IndexEvent]) -> Result<()>;
+    }
+
+    /// Writes each event as a line of JSON.
+    pub struct JsonlSink {
+        out: Box<dyn Write + Send + Sync>,
+    }
+
+    impl JsonlSink {
+        pub fn new(out: impl Write + Send + Sync + 'static) -> Self {
+            Self { out: Box::new(out) }
+        }
+
+        pub fn stdout() -> Self {
+            Self::new(std::io::stdout())
+        }
+
+        /// Writes to `path`, replacing whatever was there.
+        pub fn create(path: &Path) -> Result<Self> {
+            let file = File::create(path)
+                .with_context(|| format!("could not create {}", path.display()))?;
+            Ok(Self::new(BufWriter::new(file)))
+        }
+    }
+
+    #[async_trait]
+    impl EventSink for JsonlSink {
+        async fn publish(&mut self, index: &str, events: &[IndexEvent]) -> Result<()> {
+            for event in events {
+                writeln!(self.out, "{}", event.to_json(index))?;
+            }
+            self.out.flush()?;
+            Ok(())
+        }
+    }
+
+    /// Hands every event to whoever has subscribed, for pushing on to live clients.
+    /// Clones share the same subscribers. A subscriber which falls more than `capacity` events
+    /// behind misses some, rather than holding the indexer up.
+    #[cfg(feature = "http")]
+    #[derive(Clone)]
+    pub struct BroadcastSink {
+        events: broadcast::Sender<(String, IndexEvent)>,
+    }
+
+    #[cfg(feature = "http")]
+    impl BroadcastSink {
+        pub fn new(capacity: usize) -> Self {
+            Self {
+                events: broadcast::Sender::new(capacity),
+            }
+        }
+
+        /// Every event published from now on, along with the index it came from.
+        pub fn subscribe(&self) -> broadcast::Receiver<(String, IndexEvent)> {
+            self.events.subscribe()
+        }
+    }
+
+    #[cfg(feature = "http")]
+    #[async_trait]
+    impl EventSink for BroadcastSink {
+        async fn publish(&mut self, index: &str, events: &[IndexEvent]) -> Result<()> {
+            for event in events {
+                // It's fine for nobody to be listening.
+                let _ = self.events.send((index.to_string(), event.clone()));
+            }
+            Ok(())
+        }
+    }
+
+    /// An index's state as rows of columns, for pulling into something like a dataframe.
+    pub struct Table {
+        pub columns: Vec<String>,
+        pub rows: Vec<Vec<String>>,
+    }
+
+    impl Table {
+        pub fn new(columns: &[&str]) -> Self {
+            Self {
+                columns: columns.iter().map(|c| c.to_string()).collect(),
+                rows: vec![],
+            }
+        }
+
+        pub fn push(&mut self, row: Vec<String>) {
+            self.rows.push(row);
+        }
+
+        fn write_csv(&self, out: &mut impl Write) -> std::io::Result<()> {
+            writeln!(out, "{}", csv_row(&self.columns))?;
+            for row in &self.rows {
+                writeln!(out, "{}", csv_row(row))?;
+            }
+            Ok(())
+        }
+
+        // Every column is a string, as in the CSV, so the two always agree.
+        #[cfg(feature = "parquet")]
+        fn write_parquet<W: Write + Send>(&self, out: W) -> Result<W> {
+            use arrow_array::{ArrayRef, RecordBatch, StringArray};
+            use arrow_schema::{DataType, Field, Schema};
+            use parquet::{
+                arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties,
+            };
+
+            let fields = self
+                .columns
+                .iter()
+                .map(|c| Field::new(c, DataType::Utf8, true))
+                .collect::<Vec<_>>();
+            let schema = Arc::new(Schema::new(fields));
+            let columns = (0..self.columns.len())
+                .map(|i| {
+                    let column = self.rows.iter().map(|row| row.get(i).map(String::as_str));
+                    Arc::new(column.collect::<StringArray>()) as ArrayRef
+                })
+                .collect();
+            let batch = RecordBatch::try_new(schema.clone(), columns)?;
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let mut writer = ArrowWriter::try_new(out, schema, Some(properties))?;
+            writer.write(&batch)?;
+            Ok(writer.into_inner()?)
+        }
+    }
+
+    fn csv_row(fields: &[String]) -> String {
+        let fields = fields.iter().map(|f| {
+            if f.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", f.replace('"', "\"\""))
+            } else {
+                f.clone()
+            }
+        });
+        fields.collect::<Vec<_>>().join(",")
+    }
+
+    /// How `export_index` lays out its output. Parquet needs the `parquet` feature.
+    #[derive(Debug, Clone, Copy)]
+    pub enum ExportFormat {
+        Csv,
+        #[cfg(feature = "parquet")]
+        Parquet,
+    }
+
+    impl ExportFormat {
+        /// The file extension for an export in this format.
+        pub fn extension(&self) -> &'static str {
+            match self {
+                Self::Csv => "csv",
+                #[cfg(feature = "parquet")]
+                Self::Parquet => "parquet",
+            }
+        }
+    }
+
+    impl std::str::FromStr for ExportFormat {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> Result<Self> {
+            match s {
+                "csv" => Ok(Self::Csv),
+                #[cfg(feature = "parquet")]
+                "parquet" => Ok(Self::Parquet),
+                #[cfg(not(feature = "parquet"))]
+                "parquet" => anyhow::bail!("Parquet needs the parquet feature"),
+                _ => anyhow::bail!("expected csv or parquet"),
+            }
+        }
+    }
+
+    /// What an index would have made of the txs it was shown, in a dry run.
+    #[derive(Default, Clone, Copy, Debug)]
+    pub struct Inspection {
+        /// Things the index would have recorded, like the datums it parsed.
+        pub parsed: u64,
+        /// Things the index should have been able to read, but couldn't.
+        pub failed: u64,
+    }
+
+    // Enough of an index for someone outside the indexer to read it.
+    #[derive(Clone)]
+    struct IndexReader {
+        index: Arc<RwLock<dyn ManagedIndex>>,
+        applied: Arc<AtomicU64>,
+    }
+
+    /// Persists how far along the chain each index has gotten, keyed by index name.
     pub trait CursorStore: Send + Sync + 'static {
-        fn load(&self) -> impl Future<Output = Result<Vec<Cursor>>> + Send;
-        fn save(&mut self, cursors: &[Cursor]) -> impl Future<Output = Result<()>> + Send;
+        fn get(&self, name: &str) -> impl Future<Output = Result<Option<Point>>> + Send;
+        /// Apply every update or none of them.
+        fn commit_batch(
+            &mut self,
+            updates: &[(String, Point)],
+        ) -> impl Future<Output = Result<()>> + Send;
+        /// Every index's latest committed point. This only reads, so a store shared with
+        /// a query handler can answer it while the indexer commits, and it never sees half a batch.
+        fn latest(&self) -> impl Future<Output = Result<BTreeMap<String, Point>>> + Send;
+        /// The points most recently committed for an index, newest first.
+        /// Stores which don't keep a history only know the latest one.
+        fn history(&self, name: &str) -> impl Future<Output = Result<Vec<Point>>> + Send {
+            async move { Ok(self.get(name).await?.into_iter().collect()) }
+        }
+        /// The latest snapshot of an index's state, along with the point it was taken at.
+        /// Stores which don't keep snapshots don't need to implement this.
+        fn load_snapshot(
+            &self,
+            name: &str,
+        ) -> impl Future<Output = Result<Option<(Point, Vec<u8>)>>> + Send {
+            let _ = name;
+            async { Ok(None) }
+        }
+        /// Replace an index's snapshot.
+        fn save_snapshot(
+            &mut self,
+            name: &str,
+            point: &Point,
+            snapshot: Vec<u8>,
+        ) -> impl Future<Output = Result<()>> + Send {
+            let _ = (name, point, snapshot);
+            async { Ok(()) }
+        }
     }
 
+    /// How many committed points we keep for each index, to offer the node when resuming.
+    pub const CURSOR_HISTORY: usize = 10;
+
     pub struct InMemoryCursorStore {
-        cursors: Vec<Cursor>,
+        // Newest first.
+        cursors: HashMap<String, VecDeque<Point>>,
+        snapshots: HashMap<String, (Point, Vec<u8>)>,
+        // How many points to keep for each index.
+        history_len: usize,
     }
     impl InMemoryCursorStore {
         pub fn new(cursors: Vec<Cursor>) -> Self {
-            Self { cursors }
+            Self {
+                cursors: cursors
+                    .into_iter()
+                    .map(|c| (c.name, VecDeque::from([c.point])))
+                    .collect(),
+                snapshots: HashMap::new(),
+                history_len: CURSOR_HISTORY,
+            }
+        }
+
+        /// Keep only the last `points` committed for each index, rather than `CURSOR_HISTORY`.
+        /// The indexer never offers the node more than `CURSOR_HISTORY`, so more is wasted.
+        pub fn with_history(mut self, points: usize) -> Self {
+            self.history_len = points.max(1);
+            self
         }
     }
     impl CursorStore for InMemoryCursorStore {
-        async fn load(&self) -> Result<Vec<Cursor>> {
-            Ok(self.cursors.clone())
+        async fn get(&self, name: &str) -> Result<Option<Point>> {
+            Ok(self.cursors.get(name).and_then(|h| h.front()).cloned())
+        }
+
+        async fn latest(&self) -> Result<BTreeMap<String, Point>> {
+            Ok(self
+                .cursors
+                .iter()
+                .filter_map(|(name, h)| Some((name.clone(), h.front()?.clone())))
+                .collect())
+        }
+
+        async fn history(&self, name: &str) -> Result<Vec<Point>> {
+            Ok(self
+                .cursors
+                .get(name)
+                .map(|h| h.iter().cloned().collect())
+                .unwrap_or_default())
+        }
+
+        async fn commit_batch(&mut self, updates: &[(String, Point)]) -> Result<()> {
+            for (name, point) in updates {
+                let history = self.cursors.entry(name.clone()).or_default();
+                history.push_front(point.clone());
+                history.truncate(self.history_len);
+            }
+            Ok(())
+        }
+
+        async fn load_snapshot(&self, name: &str) -> Result<Option<(Point, Vec<u8>)>> {
+            Ok(self.snapshots.get(name).cloned())
         }
 
-        async fn save(&mut self, cursors: &[Cursor]) -> Result<()> {
-            self.cursors = cursors.to_vec();
+        async fn save_snapshot(
+            &mut self,
+            name: &str,
+            point: &Point,
+            snapshot: Vec<u8>,
+        ) -> Result<()> {
+            self.snapshots
+                .insert(name.to_string(), (point.clone(), snapshot));
             Ok(())
         }
     }
 
+    /// One of the blocks handed to `handle_block_batch`.
+    pub struct BatchedBlock<'b> {
+        pub info: &'b BlockInfo,
+        pub block: &'b MultiEraBlock<'b>,
+        /// Whether each tx, in block order, got through the index's `tx_filter`.
+        pub admitted: Vec<bool>,
+        /// What each tx's inputs resolved to, if `needs_resolved_inputs` says to resolve them.
+        pub resolved: &'b [ResolvedInputs],
+    }
+
     #[async_trait]
     pub trait ManagedIndex: Send + Sync + 'static {
         fn name(&self) -> String;
+        /// Called once, before the index sees anything else, for setup which might be slow or fail
+        /// (opening connections, running migrations). If it fails, the index is faulted.
+        async fn on_start(&mut self) -> Result<()> {
+            Ok(())
+        }
 
+        /// Transactions which don't pass this filter are never handed to the per-tx hooks.
+        fn tx_filter(&self) -> TxFilter {
+            TxFilter::All
+        }
+
+        /// Return true to have `handle_raw_block` called with each block's CBOR.
+        fn wants_raw_cbor(&self) -> bool {
+            false
+        }
+        /// Called before `handle_block`, with the block's CBOR exactly as it came from the chain,
+        /// for decoding whatever pallas doesn't surface. Only if `wants_raw_cbor` says to.
+        /// For a batch, it's called for every block in the batch before `handle_block_batch`.
+        async fn handle_raw_block(
+            &mut self,
+            info: &BlockInfo,
+            block: &MultiEraBlock,
+            cbor: &[u8],
+        ) -> Result<()> {
+            let _ = (info, block, cbor);
+            Ok(())
+        }
+        /// Called for every block, Byron's epoch boundary blocks (which have no txs) included.
+        async fn handle_block(&mut self, info: &BlockInfo, block: &MultiEraBlock) -> Result<()> {
+            let _ = (info, block);
+            Ok(())
+        }
+        /// Byron txs come through here too. They only have inputs and outputs, paying to
+        /// `Address::Byron` addresses, so the other per-tx hooks never fire for them.
+        /// Txs come in block order. Go through a tx's outputs with [`tx_outputs`], rather than
+        /// numbering whatever's left after skipping some, so each output's index is the
+        /// ledger's.
+        /// A tx whose scripts failed comes through here too, since it still took its collateral.
+        /// `tx.consumes()` and [`tx_outputs`] give what it really spent and created, and the
+        /// mint, metadata, certificate and withdrawal hooks never see it.
         async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> Result<()> {
             let _ = (info, tx);
             Ok(())
         }
+        /// What `handle_onchain_tx` would have made of `tx`, without touching the index.
+        /// A dry run calls this instead of any of the handlers, and logs what comes back.
+        fn inspect_tx(&self, info: &BlockInfo, tx: &MultiEraTx) -> Inspection {
+            let _ = (info, tx);
+            Inspection::default()
+        }
+        /// Return true to be handed blocks in batches with `handle_block_batch` while we're catching up
+        /// with the chain, rather than one at a time. Near the tip, blocks go through the usual hooks.
+        /// A batch is always handled before the index's cursor is committed, so batches only get
+        /// bigger than a block with a checkpoint interval set.
+        fn prefers_batches(&self) -> bool {
+            false
+        }
+        /// Handles several blocks at once, during catch-up, if `prefers_batches` says to.
+        /// Each block says which of its txs got through `tx_filter`, and comes with their resolved
+        /// inputs if the index needs them. By default, it runs each block through the usual
+        /// per-block and per-tx hooks, skipping the txs the filter didn't admit.
+        async fn handle_block_batch(&mut self, blocks: &[BatchedBlock<'_>]) -> Result<()> {
+            for batched in blocks {
+                // The raw hook has already been called for the whole batch.
+                let BatchedBlock {
+                    info,
+                    block,
+                    admitted,
+                    resolved,
+                } = batched;
+                feed_txs(self, info, block, admitted, resolved).await?;
+            }
+            Ok(())
+        }
+        /// Return true to have `handle_onchain_tx_with_inputs` called instead of `handle_onchain_tx`.
+        /// Resolving inputs isn't free, so indexes have to opt in.
+        fn needs_resolved_inputs(&self) -> bool {
+            false
+        }
+        /// Which outputs `handle_onchain_tx_with_inputs` needs, when a tx spends or references
+        /// them. Only these are kept around for resolving, so narrowing it saves memory.
+        fn resolved_outputs(&self) -> ResolvedOutputs {
+            ResolvedOutputs::All
+        }
+        async fn handle_onchain_tx_with_inputs(
+            &mut self,
+            info: &BlockInfo,
+            tx: &MultiEraTx,
+            inputs: &ResolvedInputs,
+        ) -> Result<()> {
+            let _ = inputs;
+            self.handle_onchain_tx(info, tx).await
+        }
+        /// Called once per minting policy for every tx with a non-empty mint field.
+        /// Burns show up as negative quantities. The assets come as pallas' era-independent
+        /// `MultiEraAsset`s, rather than Conway's `Mint`, so it fires for Alonzo and Babbage
+        /// mints too.
+        async fn handle_mint(
+            &mut self,
+            info: &BlockInfo,
+            policy: &Hash<28>,
+            assets: &[MultiEraAsset],
+        ) -> Result<()> {
+            let _ = (info, policy, assets);
+            Ok(())
+        }
+        /// Called for every tx with non-empty metadata, after `handle_onchain_tx`.
+        /// The metadatum tree is passed through as-is.
+        async fn handle_metadata(
+            &mut self,
+            info: &BlockInfo,
+            tx: &MultiEraTx,
+            metadata: &Metadata,
+        ) -> Result<()> {
+            let _ = (info, tx, metadata);
+            Ok(())
+        }
+        /// Called for each of a tx's certificates, in order, after `handle_onchain_tx`.
+        async fn handle_certificate(
+            &mut self,
+            info: &BlockInfo,
+            cert: &MultiEraCert,
+        ) -> Result<()> {
+            let _ = (info, cert);
+            Ok(())
+        }
+        /// Called for each reward account a tx withdraws from, after `handle_onchain_tx`.
+        async fn handle_withdrawal(
+            &mut self,
+            info: &BlockInfo,
+            reward_account: &[u8],
+            amount: u64,
+        ) -> Result<()> {
+            let _ = (info, reward_account, amount);
+            Ok(())
+        }
         async fn handle_rollback(&mut self, info: &BlockInfo) -> Result<()> {
             let _ = info;
             Ok(())
         }
+        /// Like `handle_rollback`, but also told how far back the index had got, so it knows
+        /// exactly which slots it's undoing. By default, it's just `handle_rollback`.
+        async fn handle_rollback_range(&mut self, rollback: &RollbackInfo) -> Result<()> {
+            self.handle_rollback(&rollback.to).await
+        }
+        /// The oldest slot the index can roll back to, for indexes which forget older history.
+        /// A rollback past it resets the index instead.
+        fn retained_since(&self) -> Option<u64> {
+            None
+        }
+        /// Forget whatever history the index only needs to undo blocks before `before_slot`.
+        /// It must still be able to roll back to `before_slot` itself, or anywhere after it.
+        /// Only called when the indexer is given a volatile window.
+        async fn prune(&mut self, before_slot: u64) -> Result<()> {
+            let _ = before_slot;
+            Ok(())
+        }
+        /// How to retry `handle_onchain_tx` and `handle_rollback` when they fail, say on a
+        /// database that's briefly locked. The hook is called again with the same arguments,
+        /// so it mustn't have changed anything before failing. Never retries by default.
+        fn retry_policy(&self) -> RetryPolicy {
+            RetryPolicy::default()
+        }
+        /// Forget everything, so the index can start over from wherever a deep rollback left the chain,
+        /// or from wherever it asked to with [`ResyncFrom`]. An index which can't start over from
+        /// just anywhere, say one keeping running totals, can forget everything and return a
+        /// `ResyncFrom` itself, to be resynced from that point instead.
+        async fn reset(&mut self) -> Result<()> {
+            anyhow::bail!("{} can't be reset", self.name())
+        }
+        /// Called before the first block of a new epoch.
+        /// This doesn't fire when a rollback crosses back over an epoch boundary,
+        /// but it fires again once the chain rolls forward into the new epoch.
+        async fn on_epoch_boundary(&mut self, old_epoch: u64, new_epoch: u64) -> Result<()> {
+            let _ = (old_epoch, new_epoch);
+            Ok(())
+        }
+        /// Hands over the events the index has recorded since it was last asked.
+        /// The indexer publishes them to its event sinks once the blocks they came from are committed.
+        /// Events recorded in `handle_rollback` should name what the rollback undid; they're passed on
+        /// as part of the rollback event.
+        fn drain_events(&mut self) -> Vec<IndexEvent> {
+            vec![]
+        }
+        /// Called just before the index's cursor is committed. An index which buffers its writes
+        /// should flush them here, so its cursor never gets ahead of what it's saved.
+        async fn checkpoint(&mut self) -> Result<()> {
+            Ok(())
+        }
+        /// Called once we've caught up with the chain and are following new blocks as they arrive.
+        /// If we fall behind again (e.g. after a reconnect or a rollback), this fires again once we catch up.
+        async fn on_tip_reached(&mut self) -> Result<()> {
+            Ok(())
+        }
+        /// Serialize the index's state, so a restart can pick up from it rather than replaying the chain.
+        /// Returns None if the index doesn't support snapshots (the default).
+        fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+        /// Replace the index's state with a snapshot taken by `snapshot`.
+        fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+            let _ = snapshot;
+            anyhow::bail!("{} doesn't support snapshots", self.name())
+        }
+        /// The index's state laid out as a table, for `export_index`.
+        /// Returns None if the index can't be exported (the default).
+        fn export(&self) -> Option<Table> {
+            None
+        }
+        /// Routes for reading the index over HTTP, served under `/index/{name}`.
+        /// The indexer holds the index's write lock while it applies each block or rollback,
+        /// and the routes take the read lock, so a request only ever sees the index between blocks.
+        #[cfg(feature = "http")]
+        fn router(&self) -> Option<Router<Self>>
+        where
+            Self: Sized,
+        {
+            None
+        }
     }
 
-    struct IndexWrapper {
-        name: String,
-        index: Box<dyn ManagedIndex>,
-        tip: Point,
-        force_restart: bool,
+    /// What a rollback undoes, for one index: every slot from `to.slot` up to `from_slot`.
+    #[derive(Debug, Clone)]
+    pub struct RollbackInfo {
+        /// The same block `handle_rollback` is given.
+        pub to: BlockInfo,
+        /// The slot of the newest block the index had handled, which is the last one undone.
+        pub from_slot: u64,
     }
 
-    pub struct ChainIndexer<CS: CursorStore> {
-        indexes: HashMap<String, IndexWrapper>,
-        cursor_store: Option<CS>,
+    type TxFn<S> = Box<dyn FnMut(&BlockInfo, &MultiEraTx, &mut S) -> Result<()> + Send + Sync>;
+    type RollbackFn<S> = Box<dyn FnMut(&BlockInfo, &mut S) -> Result<()> + Send + Sync>;
+    #[cfg(feature = "http")]
+    type ViewFn<S> = Arc<dyn Fn(&S) -> serde_json::Value + Send + Sync>;
+
+    /// An index made of a couple of closures over some state, for when a whole `ManagedIndex`
+    /// impl is more than an index deserves. It sees every tx, and snapshots nothing.
+    pub struct FnIndex<S> {
+        name: String,
+        state: S,
+        on_tx: TxFn<S>,
+        on_rollback: RollbackFn<S>,
+        #[cfg(feature = "http")]
+        view: Option<ViewFn<S>>,
     }
 
-    impl<CS: CursorStore> ChainIndexer<CS> {
-        pub fn new(cursors: CS) -> Self {
+    impl<S: Default> FnIndex<S> {
+        pub fn new(
+            name: impl Into<String>,
+            on_tx: impl FnMut(&BlockInfo, &MultiEraTx, &mut S) -> Result<()> + Send + Sync + 'static,
+            on_rollback: impl FnMut(&BlockInfo, &mut S) -> Result<()> + Send + Sync + 'static,
+        ) -> Self {
             Self {
-                indexes: HashMap::new(),
-                cursor_store: Some(cursors),
+                name: name.into(),
+                state: S::default(),
+                on_tx: Box::new(on_tx),
+                on_rollback: Box::new(on_rollback),
+                #[cfg(feature = "http")]
+                view: None,
             }
         }
+    }
 
-        /// Begin managing an index.
-        /// If the index doesn't already exist, it will start indexing from `start`.
-        /// If it does, it will start from wherever it began before, unless `force_restart` is passed.
-        pub fn add_index<M: ManagedIndex>(&mut self, index: M, start: Point, force_restart: bool) {
-            let name = index.name();
-            if self
-                .indexes
-                .insert(
-                    name.clone(),
-                    IndexWrapper {
-                        name,
-                        index: Box::new(index),
-                        tip: start,
-                        force_restart,
-                    },
-                )
-                .is_some()
-            {
-                panic!("adding same index twice");
-            }
+    impl<S> FnIndex<S> {
+        #[cfg(any(test, feature = "http"))]
+        pub fn state(&self) -> &S {
+            &self.state
+        }
+
+        /// Serve the state as JSON, laid out by `view`, at the root of the index's routes.
+        #[cfg(feature = "http")]
+        pub fn with_view(
+            mut self,
+            view: impl Fn(&S) -> serde_json::Value + Send + Sync + 'static,
+        ) -> Self {
+            self.view = Some(Arc::new(view));
+            self
         }
     }
 
     #[async_trait]
-    impl<CS: CursorStore> Module for ChainIndexer<CS> {
+    impl<S: Send + Sync + 'static> ManagedIndex for FnIndex<S> {
         fn name(&self) -> String {
-            "chain-indexer".into()
+            self.name.clone()
         }
 
-        async fn init(&mut self, context: Arc<core::Context>) -> Result<()> {
-            let mut blocks = context.subscribe("blocks").await?;
-            let mut indexes = std::mem::take(&mut self.indexes);
-            let mut cursor_store = self.cursor_store.take().unwrap();
+        async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> Result<()> {
+            (self.on_tx)(info, tx, &mut self.state)
+        }
 
-            context.clone().run(async move {
-                let should_fetch_cursors = indexes.values().any(|i| !i.force_restart);
-                if should_fetch_cursors {
-                    let cursors = cursor_store.load().await.expect("could not fetch cursors");
-                    for cursor in cursors {
-                        let Some(index) = indexes.get_mut(&cursor.name) else {
-                            continue;
-                        };
-                        if !index.force_restart {
-                            index.tip = cursor.point;
-                        }
-                    }
+        async fn handle_rollback(&mut self, info: &BlockInfo) -> Result<()> {
+            (self.on_rollback)(info, &mut self.state)
+        }
+
+        #[cfg(feature = "http")]
+        fn router(&self) -> Option<Router<Self>> {
+            let view = self.view.clone()?;
+            Some(Router::new().get("/", move |index: &Self, _| {
+                Response::json(view(index.state()))
+            }))
+        }
+    }
+
+    /// The outputs which a transaction spends, references, or puts up as collateral.
+    /// Only outputs created since the indexer started syncing can be resolved.
+    #[derive(Default)]
+    pub struct ResolvedInputs {
+        outputs: HashMap<OutputRef, (Era, Vec<u8>)>,
+    }
+    impl ResolvedInputs {
+        pub fn get(&self, output_ref: &OutputRef) -> Option<MultiEraOutput<'_>> {
+            let (era, cbor) = self.outputs.get(output_ref)?;
+            MultiEraOutput::decode(*era, cbor).ok()
+        }
+
+        /// The fee `tx` works out to from what it moves: what it spends and withdraws, less
+        /// what it pays out. If its scripts failed, that's the collateral it forfeits.
+        /// None unless every input it consumes resolved, or if it has certificates,
+        /// proposals or a treasury donation, whose deposits this can't see.
+        pub fn implied_fee(&self, tx: &MultiEraTx) -> Option<u64> {
+            if !tx.certs().is_empty() {
+                return None;
+            }
+            if let Some(tx) = tx.as_conway() {
+                let body = &tx.transaction_body;
+                if body.proposal_procedures.is_some() || body.donation.is_some() {
+                    return None;
                 }
-                let first_point = indexes
-                    .values()
-                    .map(|i| &i.tip)
-                    .min_by(|l, r| compare_points(&l, &r))
-                    .cloned()
-                    .unwrap_or(Point::Origin);
-                context
-                    .publish("sync-from", AcropolisMessage::SyncFrom(first_point))
-                    .await
-                    .expect("could not start sync");
-                while let Ok(message) = blocks.read().await {
-                    match message {
-                        AcropolisMessage::NewTx(info, tx) => {
-                            let tx = MultiEraTx::decode(&tx).expect("invalid tx");
-                            let tx = &tx;
-                            let at = Point::Specific(info.slot, info.hash.to_vec());
-                            process_message(indexes.values_mut(), at, |x| {
-                                x.handle_onchain_tx(&info, tx)
-                            })
-                            .await;
-                        }
-                        AcropolisMessage::Rollback(info) => {
-                            let at = Point::Specific(info.slot, info.hash.to_vec());
-                            process_message(indexes.values_mut(), at, |x| x.handle_rollback(&info))
-                                .await;
-                        }
-                        _ => {}
+            }
+            let mut spent = 0u64;
+            for input in tx.consumes() {
+                spent += self.get(&input.output_ref())?.value().coin();
+            }
+            let withdrawn: u64 = if tx.is_valid() {
+                tx.withdrawals_sorted_set()
+                    .iter()
+                    .map(|(_, amount)| amount)
+                    .sum()
+            } else {
+                0
+            };
+            let paid: u64 = tx
+                .produces()
+                .iter()
+                .map(|(_, output)| output.value().coin())
+                .sum();
+            (spent + withdrawn).checked_sub(paid)
+        }
+
+        /// Whether the fee `tx` declares is what it actually pays, going by `implied_fee`.
+        /// None if either isn't known.
+        pub fn fee_matches(&self, tx: &MultiEraTx) -> Option<bool> {
+            Some(tx_fee(tx)? == self.implied_fee(tx)?)
+        }
+    }
+
+    // Spent outputs are kept around this long (the mainnet stability window, 3k/f),
+    // so that a rollback can make them resolvable again.
+    const SPENT_OUTPUT_RETENTION_SLOTS: u64 = 129_600;
+
+    /// Which outputs an index wants resolved, for `ManagedIndex::resolved_outputs`.
+    #[derive(Clone)]
+    pub enum ResolvedOutputs {
+        All,
+        /// Outputs carrying their datum inline.
+        InlineDatums,
+        /// Outputs created by Byron txs, which are all a Byron tx can spend.
+        Byron,
+    }
+
+    impl ResolvedOutputs {
+        fn matches(&self, output: &MultiEraOutput) -> bool {
+            match self {
+                ResolvedOutputs::All => true,
+                ResolvedOutputs::InlineDatums => {
+                    matches!(output.datum(), Some(MintedDatumOption::Data(_)))
+                }
+                ResolvedOutputs::Byron => matches!(output, MultiEraOutput::Byron(_)),
+            }
+        }
+    }
+
+    struct CachedOutput {
+        era: Era,
+        cbor: Vec<u8>,
+        created_at: u64,
+        spent_at: Option<u64>,
+    }
+
+    /// Outputs seen since we started syncing, used to resolve inputs.
+    #[derive(Default)]
+    struct UtxoCache {
+        outputs: HashMap<OutputRef, CachedOutput>,
+        // What's been spent, in the order it was, so pruning only has to look at the front.
+        spent: VecDeque<(u64, OutputRef)>,
+    }
+    impl UtxoCache {
+        /// Resolve the tx's inputs, then record what it spends, and what it produces that any
+        /// of `wanted` match.
+        fn apply(
+            &mut self,
+            slot: u64,
+            tx: &MultiEraTx,
+            wanted: &[ResolvedOutputs],
+        ) -> ResolvedInputs {
+            let resolved = self.resolve(tx);
+            self.spend(slot, tx);
+            self.produce(slot, tx, |o| wanted.iter().any(|w| w.matches(o)));
+            resolved
+        }
+
+        fn resolve(&self, tx: &MultiEraTx) -> ResolvedInputs {
+            let mut resolved = ResolvedInputs::default();
+            let inputs = tx.inputs().into_iter();
+            for input in inputs.chain(tx.reference_inputs()).chain(tx.collateral()) {
+                let output_ref = input.output_ref();
+                if let Some(output) = self.outputs.get(&output_ref) {
+                    let value = (output.era, output.cbor.clone());
+                    resolved.outputs.insert(output_ref, value);
+                }
+            }
+            resolved
+        }
+
+        // consumes/produces account for phase-2 failures, where only collateral moves.
+
+        /// Mark whatever the tx consumes as spent. Returns whether it spent anything we know of.
+        fn spend(&mut self, slot: u64, tx: &MultiEraTx) -> bool {
+            let mut spent = false;
+            for input in tx.consumes() {
+                let output_ref = input.output_ref();
+                if let Some(output) = self.outputs.get_mut(&output_ref) {
+                    if output.spent_at.is_none() {
+                        output.spent_at = Some(slot);
+                        self.spent.push_back((slot, output_ref));
                     }
-                    let cursors = indexes
-                        .values()
-                        .map(|i| Cursor {
-                            name: i.name.clone(),
-                            point: i.tip.clone(),
-                        })
-                        .collect::<Vec<_>>();
-                    cursor_store
-                        .save(&cursors)
-                        .await
-                        .expect("couldn't save cursors");
+                    spent = true;
                 }
-            });
+            }
+            spent
+        }
+
+        /// Remember the tx's outputs which pass `keep`. Returns whether any did.
+        fn produce(
+            &mut self,
+            slot: u64,
+            tx: &MultiEraTx,
+            keep: impl Fn(&MultiEraOutput) -> bool,
+        ) -> bool {
+            let mut produced = false;
+            for (idx, output) in tx.produces() {
+                if !keep(&output) {
+                    continue;
+                }
+                let cached = CachedOutput {
+                    era: output.era(),
+                    cbor: output.encode(),
+                    created_at: slot,
+                    spent_at: None,
+                };
+                self.outputs
+                    .insert(OutputRef::new(tx.hash(), idx as u64), cached);
+                produced = true;
+            }
+            produced
+        }
+
+        /// Forget outputs spent too long before `slot` to come back. Called once per block.
+        fn prune(&mut self, slot: u64) {
+            while let Some((spent_at, output_ref)) = self.spent.front() {
+                if spent_at + SPENT_OUTPUT_RETENTION_SLOTS >= slot {
+                    break;
+                }
+                // A rollback may have unspent it since, and something else spent it again.
+                if self
+                    .outputs
+                    .get(output_ref)
+                    .is_some_and(|o| o.spent_at == Some(*spent_at))
+                {
+                    self.outputs.remove(output_ref);
+                }
+                self.spent.pop_front();
+            }
+        }
+
+        fn rollback(&mut self, slot: u64) {
+            while let Some((spent_at, output_ref)) = self.spent.back() {
+                if *spent_at < slot {
+                    break;
+                }
+                if let Some(output) = self.outputs.get_mut(output_ref) {
+                    output.spent_at = None;
+                }
+                self.spent.pop_back();
+            }
+            self.outputs.retain(|_, o| o.created_at < slot);
+        }
+    }
+
+    /// A cheap check for which transactions an index cares about.
+    #[derive(Clone)]
+    pub enum TxFilter {
+        All,
+        /// Txs which pay to, or spend from, this address.
+        Address(Address),
+        /// Txs which pay to, or spend from, any of these addresses.
+        Addresses(HashSet<Address>),
+        /// Txs which pay to, or spend from, any address with this payment credential,
+        /// whatever its staking part.
+        PaymentCredential(ShelleyPaymentPart),
+        /// Txs which mint or burn this policy, or move one of its tokens.
+        Policy(Hash<28>),
+    }
+
+    impl TxFilter {
+        /// Whether `output` is one of the outputs that would let its tx through.
+        pub fn matches_output(&self, output: &MultiEraOutput) -> bool {
+            match self {
+                TxFilter::All => true,
+                TxFilter::Address(address) => output.address().is_ok_and(|a| a == *address),
+                TxFilter::Addresses(addresses) => {
+                    output.address().is_ok_and(|a| addresses.contains(&a))
+                }
+                TxFilter::PaymentCredential(credential) => output
+                    .address()
+                    .is_ok_and(|a| payment_credential(&a) == Some(credential)),
+                TxFilter::Policy(policy) => {
+                    output.value().assets().iter().any(|p| p.policy() == policy)
+                }
+            }
+        }
+    }
+
+    /// The payment credential of an address, if it has one.
+    /// Byron addresses and reward accounts don't.
+    pub fn payment_credential(address: &Address) -> Option<&ShelleyPaymentPart> {
+        match address {
+            Address::Shelley(address) => Some(address.payment()),
+            _ => None,
+        }
+    }
+
+    /// The script an output carries for other txs to reference.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ReferenceScript {
+        pub hash: Hash<28>,
+        pub language: ScriptLanguage,
+        /// In bytes, as it appears on chain.
+        pub size: usize,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ScriptLanguage {
+        Native,
+        PlutusV1,
+        PlutusV2,
+        PlutusV3,
+    }
+
+    impl std::fmt::Display for ScriptLanguage {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(match self {
+                ScriptLanguage::Native => "native",
+                ScriptLanguage::PlutusV1 => "plutus_v1",
+                ScriptLanguage::PlutusV2 => "plutus_v2",
+                ScriptLanguage::PlutusV3 => "plutus_v3",
+            })
+        }
+    }
+
+    /// The reference script attached to `output`, if it has one.
+    pub fn reference_script(output: &MultiEraOutput) -> Option<ReferenceScript> {
+        // A script's hash is over its bytes, prefixed with a tag for its language.
+        let (language, tag, bytes) = match output.script_ref()? {
+            PseudoScript::NativeScript(script) => {
+                (ScriptLanguage::Native, 0, script.raw_cbor().to_vec())
+            }
+            PseudoScript::PlutusV1Script(script) => {
+                (ScriptLanguage::PlutusV1, 1, script.0.to_vec())
+            }
+            PseudoScript::PlutusV2Script(script) => {
+                (ScriptLanguage::PlutusV2, 2, script.0.to_vec())
+            }
+            PseudoScript::PlutusV3Script(script) => {
+                (ScriptLanguage::PlutusV3, 3, script.0.to_vec())
+            }
+        };
+        Some(ReferenceScript {
+            hash: Hasher::<224>::hash_tagged(&bytes, tag),
+            language,
+            size: bytes.len(),
+        })
+    }
+
+    /// The outputs `tx` really creates, in ledger order, each with its `OutputRef`. The index
+    /// is the output's position in the tx body, so it stays right however many of them a
+    /// caller skips. If the tx's scripts failed, its outputs never happened, and all it creates
+    /// is its collateral return, if it has one, which comes after where the outputs would be.
+    pub fn tx_outputs<'a>(
+        tx: &'a MultiEraTx,
+    ) -> impl Iterator<Item = (OutputRef, MultiEraOutput<'a>)> {
+        let hash = tx.hash();
+        tx.produces()
+            .into_iter()
+            .map(move |(i, output)| (OutputRef::new(hash, i as u64), output))
+    }
+
+    /// The redeemer for each script input `tx` spends, by the input it's for. A spend
+    /// redeemer points at its input by position, but in the inputs sorted by tx hash and
+    /// index, not in the order the tx body happens to list them.
+    pub fn spend_redeemers<'a>(tx: &'a MultiEraTx) -> HashMap<OutputRef, MultiEraRedeemer<'a>> {
+        let inputs = tx.inputs_sorted_set();
+        tx.redeemers()
+            .into_iter()
+            .filter(|r| r.tag() == RedeemerTag::Spend)
+            .filter_map(|r| Some((inputs.get(r.index() as usize)?.output_ref(), r)))
+            .collect()
+    }
+
+    /// The fee `tx` pays, as its body declares it. If its scripts failed, that's the
+    /// collateral it forfeits instead, which is only declared from Babbage on.
+    /// Byron txs don't declare a fee at all.
+    pub fn tx_fee(tx: &MultiEraTx) -> Option<u64> {
+        if tx.is_valid() {
+            tx.fee()
+        } else {
+            tx.total_collateral()
+        }
+    }
+
+    /// The size of `tx` in bytes, as it appears on chain: its body, witnesses and metadata.
+    pub fn tx_size(tx: &MultiEraTx) -> usize {
+        tx.size()
+    }
+
+    struct IndexFilter {
+        filter: TxFilter,
+        // Outputs which matched the filter, so the txs spending them get through too.
+        matched: UtxoCache,
+        // How many txs have made it through the filter and been handled.
+        processed_txs: u64,
+        // What a dry run has seen so far.
+        dry_run: DryRunTally,
+        // How long the index has taken over each block.
+        timing: BlockTiming,
+    }
+
+    /// How many blocks an index has handled within each of `BLOCK_DURATION_BUCKETS`
+    /// (the last count being all of them), and how long they took altogether.
+    #[derive(Debug, Clone, Default)]
+    pub struct DurationHistogram {
+        pub buckets: [u64; BLOCK_DURATION_BUCKETS.len() + 1],
+        pub sum: Duration,
+    }
+
+    /// The upper bounds, in seconds, of the buckets block durations are counted in.
+    pub const BLOCK_DURATION_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+    impl DurationHistogram {
+        fn record(&mut self, elapsed: Duration) {
+            let secs = elapsed.as_secs_f64();
+            for (bucket, bound) in self.buckets.iter_mut().zip(BLOCK_DURATION_BUCKETS) {
+                if secs <= bound {
+                    *bucket += 1;
+                }
+            }
+            self.buckets[BLOCK_DURATION_BUCKETS.len()] += 1;
+            self.sum += elapsed;
+        }
+    }
+
+    // Slow blocks are only warned about once a minute per index, however many there are.
+    const SLOW_WARNING_EVERY: Duration = Duration::from_secs(60);
+
+    #[derive(Default)]
+    struct BlockTiming {
+        histogram: DurationHistogram,
+        // How long a block has to take to be warned about, if at all.
+        slow: Option<Duration>,
+        last_warning: Option<Instant>,
+        // Slow blocks since the last warning, which it didn't mention.
+        unreported: u64,
+    }
+
+    impl BlockTiming {
+        fn record(&mut self, name: &str, slot: u64, elapsed: Duration) {
+            self.histogram.record(elapsed);
+            if self.slow.is_none_or(|s| elapsed < s) {
+                return;
+            }
+            if self
+                .last_warning
+                .is_some_and(|w| w.elapsed() < SLOW_WARNING_EVERY)
+            {
+                self.unreported += 1;
+                return;
+            }
+            let others = std::mem::take(&mut self.unreported);
+            tracing::warn!(
+                index = name,
+                slot,
+                ?elapsed,
+                others,
+                "slow block ({others} others since the last warning)"
+            );
+            self.last_warning = Some(Instant::now());
+        }
+    }
+
+    #[derive(Default)]
+    struct DryRunTally {
+        txs: u64,
+        outputs: u64,
+        inspection: Inspection,
+    }
+
+    impl DryRunTally {
+        fn is_empty(&self) -> bool {
+            self.txs == 0
+        }
+
+        fn add(&mut self, other: &DryRunTally) {
+            self.txs += other.txs;
+            self.outputs += other.outputs;
+            self.inspection.parsed += other.inspection.parsed;
+            self.inspection.failed += other.inspection.failed;
+        }
+    }
+
+    impl std::fmt::Display for DryRunTally {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{} txs, {} matching outputs, {} parsed, {} failed to parse",
+                self.txs, self.outputs, self.inspection.parsed, self.inspection.failed
+            )
+        }
+    }
+
+    impl IndexFilter {
+        fn new(filter: TxFilter) -> Self {
+            Self {
+                filter,
+                matched: UtxoCache::default(),
+                processed_txs: 0,
+                dry_run: DryRunTally::default(),
+                timing: BlockTiming::default(),
+            }
+        }
+
+        fn admit(&mut self, slot: u64, tx: &MultiEraTx) -> bool {
+            if let TxFilter::All = self.filter {
+                return true;
+            }
+            let spends = self.matched.spend(slot, tx);
+            let produces = self
+                .matched
+                .produce(slot, tx, |o| self.filter.matches_output(o));
+            let mints = match &self.filter {
+                TxFilter::Policy(policy) => tx.mints().iter().any(|m| m.policy() == policy),
+                _ => false,
+            };
+            spends || produces || mints
+        }
+
+        fn rollback(&mut self, slot: u64) {
+            self.matched.rollback(slot);
+        }
+    }
+
+    // Blocks saved up for a batch, each with what its txs' inputs resolved to.
+    type Batch = Vec<(BlockInfo, Arc<DecodedBlock>, Arc<[ResolvedInputs]>)>;
+
+    struct IndexWrapper {
+        name: String,
+        // Shared with anything serving reads from the index.
+        index: Arc<RwLock<dyn ManagedIndex>>,
+        filter: IndexFilter,
+        // Which outputs the index wants resolved, if it wants inputs resolved at all.
+        resolved_outputs: Option<ResolvedOutputs>,
+        // Whether the index can snapshot its state.
+        snapshots: bool,
+        tip: Point,
+        // The slot of `tip`, shared with anything reading the index. It only moves while the
+        // index is write-locked, so a reader always knows how far along what it's reading is.
+        applied: Arc<AtomicU64>,
+        // Events from blocks whose cursors haven't been committed yet.
+        events: Vec<IndexEvent>,
+        // Blocks the index has been given, but is saving up to handle in one batch.
+        batch: Batch,
+        // The points most recently committed for this index, newest first.
+        history: Vec<Point>,
+        // The index has been told to forget how to undo anything before this slot.
+        pruned_before: Option<u64>,
+        force_restart: bool,
+        // How many blocks must be built on top of a block before the index sees it.
+        confirmations: u64,
+        // Once an index fails, we stop updating it.
+        faulted: bool,
+    }
+
+    impl IndexWrapper {
+        // Also hands back the index, shared, for the caller to read from.
+        fn new<M: ManagedIndex>(
+            name: String,
+            index: M,
+            start: Point,
+            force_restart: bool,
+            confirmations: u64,
+        ) -> (Self, Arc<RwLock<M>>) {
+            let filter = IndexFilter::new(index.tx_filter());
+            let resolved_outputs = index
+                .needs_resolved_inputs()
+                .then(|| index.resolved_outputs());
+            let snapshots = index.snapshot().is_ok_and(|s| s.is_some());
+            let index = Arc::new(RwLock::new(index));
+            let wrapper = Self {
+                name,
+                index: index.clone(),
+                filter,
+                resolved_outputs,
+                snapshots,
+                applied: Arc::new(AtomicU64::new(start.slot_or_default())),
+                tip: start,
+                events: vec![],
+                batch: vec![],
+                history: vec![],
+                pruned_before: None,
+                force_restart,
+                confirmations,
+                faulted: false,
+            };
+            (wrapper, index)
+        }
+
+        fn reader(&self) -> IndexReader {
+            IndexReader {
+                index: self.index.clone(),
+                applied: self.applied.clone(),
+            }
+        }
+    }
+
+    // Changes to which indexes are running, sent from a handle to the sync loop.
+    #[cfg(any(test, feature = "metrics"))]
+    enum IndexCommand {
+        Add(Box<IndexWrapper>, oneshot::Sender<Result<()>>),
+        Remove(String, oneshot::Sender<bool>),
+    }
+
+    // Without the metrics server, nothing adds or removes an index once it's running.
+    #[cfg(not(any(test, feature = "metrics")))]
+    type IndexCommand = std::convert::Infallible;
+
+    #[derive(Default)]
+    struct IndexerStatus {
+        faulted: BTreeMap<String, String>,
+        at_tip: bool,
+        // The slot each index has processed up to.
+        processed: BTreeMap<String, u64>,
+        // The slot of the newest block we've heard about.
+        tip_slot: u64,
+        // The slot of the node's tip, if the chain source can tell us.
+        node_tip_slot: u64,
+        counters: IndexerCounters,
+        // How many messages we've fetched from the chain but not handled yet.
+        buffered_blocks: usize,
+        // Why we stopped following the chain, if it was because of a broken chain.
+        continuity: Option<ContinuityError>,
+        // Whether we've stopped between messages because someone asked us to.
+        paused: bool,
+        // Whether the loop fetching blocks from the chain is still going.
+        running: bool,
+        // Why the loop stopped, if it was because something failed rather than being shut down.
+        error: Option<String>,
+        // The cursor each index last committed, for reading without going near the cursor store.
+        committed: BTreeMap<String, Point>,
+        readers: HashMap<String, IndexReader>,
+    }
+
+    // Marks the fetch loop as running for as long as it's alive, however it stops.
+    struct RunningGuard(Arc<Mutex<IndexerStatus>>);
+
+    impl RunningGuard {
+        fn new(status: &Arc<Mutex<IndexerStatus>>) -> Self {
+            status.lock().unwrap().running = true;
+            Self(status.clone())
+        }
+    }
+
+    impl Drop for RunningGuard {
+        fn drop(&mut self) {
+            // If we're dropped by a panic, the lock may be poisoned, but it's still worth saying.
+            let mut status = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            status.running = false;
+        }
+    }
+
+    /// A block which doesn't follow on from the last one we processed.
+    #[derive(Debug, Clone)]
+    pub struct ContinuityError {
+        /// The last block we processed.
+        pub expected: Point,
+        /// The block which should have come straight after it, but didn't.
+        pub got: Point,
+    }
+
+    impl std::fmt::Display for ContinuityError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "block {:?} doesn't follow {:?}", self.got, self.expected)
+        }
+    }
+
+    impl std::error::Error for ContinuityError {}
+
+    /// Checks that `block` comes straight after `last`, by slot and by its previous hash.
+    fn check_continuity(
+        last: &Point,
+        info: &BlockInfo,
+        block: &MultiEraBlock,
+    ) -> Result<(), ContinuityError> {
+        let got = Point::Specific(info.slot, info.hash.to_vec());
+        let previous = block.header().previous_hash().map(|h| h.to_vec());
+        let follows = match last {
+            Point::Origin => previous.is_none(),
+            // A Byron epoch boundary block shares its slot with the block after it.
+            Point::Specific(slot, hash) => info.slot >= *slot && previous.as_ref() == Some(hash),
+        };
+        if follows {
             Ok(())
+        } else {
+            Err(ContinuityError {
+                expected: last.clone(),
+                got,
+            })
         }
     }
 
-    async fn process_message<'a, F, Fut>(
-        indexes: impl Iterator<Item = &'a mut IndexWrapper>,
-        at: Point,
-        f: F,
-    ) where
-        F: Fn(&'a mut dyn ManagedIndex) -> Fut,
-        Fut: Future<Output = Result<()>>,
-    {
-        let mut fut = FuturesUnordered::new();
-        for index in indexes {
-            let this_is_next =
-                index.tip.slot_or_default() == at.slot_or_default().saturating_sub(1);
-            if this_is_next {
-                fut.push(async {
-                    match f(index.index.as_mut()).await {
-                        Ok(()) => {
-                            index.tip = at.clone();
-                        }
-                        Err(e) => {
-                            eprintln!("index {} failed at {:?}: {e:#}", index.name, index.tip);
-                        }
-                    }
-                });
+    /// Running totals of the work the indexer has done.
+    #[derive(Debug, Clone, Default)]
+    pub struct IndexerCounters {
+        pub blocks_processed: u64,
+        pub rollbacks: u64,
+        /// How many txs each index has handled.
+        pub processed_txs: BTreeMap<String, u64>,
+        /// How long each index has taken over its blocks.
+        pub block_durations: BTreeMap<String, DurationHistogram>,
+    }
+
+    /// How far along an index is.
+    #[derive(Debug, Clone)]
+    pub struct SyncStatus {
+        pub index: String,
+        pub processed_slot: u64,
+        pub tip_slot: u64,
+        pub lag_slots: u64,
+    }
+
+    /// A cloneable view into a `ChainIndexer`, which stays usable after it's been registered.
+    #[derive(Clone)]
+    pub struct ChainIndexerHandle {
+        status: Arc<Mutex<IndexerStatus>>,
+        #[cfg(feature = "metrics")]
+        pause: Arc<watch::Sender<bool>>,
+        #[cfg(any(test, feature = "metrics"))]
+        commands: mpsc::UnboundedSender<IndexCommand>,
+    }
+
+    impl ChainIndexerHandle {
+        /// The indexes which have stopped updating, along with the error which stopped them.
+        pub fn faulted_indexes(&self) -> Vec<(String, String)> {
+            let status = self.status.lock().unwrap();
+            status
+                .faulted
+                .iter()
+                .map(|(name, error)| (name.clone(), error.clone()))
+                .collect()
+        }
+
+        /// The cursor each index last committed, as of the last commit. This is only a copy, so
+        /// it's safe to read from anywhere while the indexer syncs, and it never holds it up.
+        #[cfg(any(test, feature = "http"))]
+        pub fn committed_cursors(&self) -> BTreeMap<String, Point> {
+            self.status.lock().unwrap().committed.clone()
+        }
+
+        /// Whether we're still fetching blocks from the chain. This goes false for good if
+        /// syncing stops, say because of a broken chain or a lost connection.
+        #[cfg(any(test, feature = "http", feature = "metrics"))]
+        pub fn is_running(&self) -> bool {
+            self.status.lock().unwrap().running
+        }
+
+        /// What stopped syncing, if it was a failure (say the cursor store couldn't be written)
+        /// rather than a shutdown.
+        #[cfg(any(test, feature = "http", feature = "metrics"))]
+        pub fn error(&self) -> Option<String> {
+            self.status.lock().unwrap().error.clone()
+        }
+
+        /// Whether we've caught up with the chain, rather than still syncing history.
+        #[cfg(any(test, feature = "http", feature = "metrics"))]
+        pub fn is_at_tip(&self) -> bool {
+            self.status.lock().unwrap().at_tip
+        }
+
+        /// Stop handling messages once the current one is done, and commit every cursor,
+        /// without dropping the connection to the node.
+        #[cfg(feature = "metrics")]
+        pub fn pause(&self) {
+            self.pause.send_replace(true);
+        }
+
+        /// Carry on from wherever `pause` stopped.
+        #[cfg(feature = "metrics")]
+        pub fn resume(&self) {
+            self.pause.send_replace(false);
+        }
+
+        /// Start running `index` alongside the others, the way `add_index` would have at startup.
+        /// If it starts behind the rest, every index waits while it catches up.
+        #[cfg(any(test, feature = "metrics"))]
+        pub async fn add_index_live<M: ManagedIndex>(
+            &self,
+            index: M,
+            start: Point,
+            force_restart: bool,
+            confirmations: u64,
+        ) -> Result<Arc<RwLock<M>>> {
+            self.add_index_live_as(index.name(), index, start, force_restart, confirmations)
+                .await
+        }
+
+        /// `add_index_live`, under `name` rather than the name the index gives itself.
+        #[cfg(any(test, feature = "metrics"))]
+        pub async fn add_index_live_as<M: ManagedIndex>(
+            &self,
+            name: String,
+            index: M,
+            start: Point,
+            force_restart: bool,
+            confirmations: u64,
+        ) -> Result<Arc<RwLock<M>>> {
+            let (wrapper, index) =
+                IndexWrapper::new(name, index, start, force_restart, confirmations);
+            let (reply, added) = oneshot::channel();
+            self.commands
+                .send(IndexCommand::Add(Box::new(wrapper), reply))
+                .map_err(|_| anyhow::anyhow!("the indexer has stopped"))?;
+            added
+                .await
+                .map_err(|_| anyhow::anyhow!("the indexer has stopped"))??;
+            Ok(index)
+        }
+
+        /// Stop running the named index, and drop the indexer's hold on it.
+        /// Its cursor stays committed, so adding it back later picks up where it left off.
+        #[cfg(any(test, feature = "metrics"))]
+        pub async fn remove_index(&self, name: &str) -> Result<()> {
+            let (reply, removed) = oneshot::channel();
+            self.commands
+                .send(IndexCommand::Remove(name.to_string(), reply))
+                .map_err(|_| anyhow::anyhow!("the indexer has stopped"))?;
+            if !removed
+                .await
+                .map_err(|_| anyhow::anyhow!("the indexer has stopped"))?
+            {
+                anyhow::bail!("no index named {name}");
+            }
+            Ok(())
+        }
+
+        /// Whether we've actually stopped since `pause` was called, rather than still finishing a message.
+        #[cfg(feature = "metrics")]
+        pub fn is_paused(&self) -> bool {
+            self.status.lock().unwrap().paused
+        }
+
+        #[cfg(any(test, feature = "metrics"))]
+        pub fn counters(&self) -> IndexerCounters {
+            self.status.lock().unwrap().counters.clone()
+        }
+
+        /// How many blocks have been fetched but not handled yet.
+        #[cfg(feature = "metrics")]
+        pub fn buffered_blocks(&self) -> usize {
+            self.status.lock().unwrap().buffered_blocks
+        }
+
+        /// The broken link in the chain which made us stop syncing, in strict mode.
+        pub fn continuity_error(&self) -> Option<ContinuityError> {
+            self.status.lock().unwrap().continuity.clone()
+        }
+
+        /// Write out the named index's current state to `path`, and returns the slot it was taken at.
+        /// The slot also goes in a sidecar file next to it, at `path` plus `.json`.
+        pub async fn export_index(
+            &self,
+            name: &str,
+            format: ExportFormat,
+            path: &Path,
+        ) -> Result<u64> {
+            let (table, slot) = self.export_table(name).await?;
+            let file = File::create(path)
+                .with_context(|| format!("could not create {}", path.display()))?;
+            let mut out = BufWriter::new(file);
+            match format {
+                ExportFormat::Csv => table.write_csv(&mut out)?,
+                #[cfg(feature = "parquet")]
+                ExportFormat::Parquet => out = table.write_parquet(out)?,
+            }
+            out.flush()?;
+            let mut sidecar = path.as_os_str().to_owned();
+            sidecar.push(".json");
+            let meta = serde_json::json!({ "index": name, "slot": slot, "rows": table.rows.len() });
+            std::fs::write(&sidecar, meta.to_string())
+                .with_context(|| format!("could not write {}", Path::new(&sidecar).display()))?;
+            Ok(slot)
+        }
+
+        /// Whether there's an index called `name`, whether or not it's faulted.
+        #[cfg(feature = "http")]
+        pub fn has_index(&self, name: &str) -> bool {
+            self.status.lock().unwrap().readers.contains_key(name)
+        }
+
+        /// The index's state as a table, along with the slot it's up to.
+        pub async fn export_table(&self, name: &str) -> Result<(Table, u64)> {
+            let reader = self.status.lock().unwrap().readers.get(name).cloned();
+            let Some(reader) = reader else {
+                anyhow::bail!("no index named {name}");
+            };
+            // The tip only moves while the indexer holds the write lock,
+            // so under the read lock it's exactly what the table reflects.
+            let index = reader.index.read().await;
+            let slot = reader.applied.load(atomic::Ordering::Relaxed);
+            let Some(table) = index.export() else {
+                let name = name.to_string();
+                return Err(NotExportable { name }.into());
+            };
+            Ok((table, slot))
+        }
+
+        /// How far behind the chain tip each index is.
+        pub fn sync_status(&self) -> Vec<SyncStatus> {
+            let status = self.status.lock().unwrap();
+            // Where the node says the tip is, if we've been told, and if it's ahead of our blocks.
+            let tip_slot = status.tip_slot.max(status.node_tip_slot);
+            status
+                .processed
+                .iter()
+                .map(|(index, processed_slot)| SyncStatus {
+                    index: index.clone(),
+                    processed_slot: *processed_slot,
+                    tip_slot,
+                    lag_slots: tip_slot.saturating_sub(*processed_slot),
+                })
+                .collect()
+        }
+
+        /// The index furthest behind the tip, and how many slots behind it is,
+        /// since that's how fresh the indexer is as a whole. None until an index has reported.
+        #[cfg(any(test, feature = "http", feature = "metrics"))]
+        pub fn worst_lag(&self) -> Option<(String, u64)> {
+            self.sync_status()
+                .into_iter()
+                .max_by_key(|s| s.lag_slots)
+                .map(|s| (s.index, s.lag_slots))
+        }
+    }
+
+    /// Where the indexer gets its blocks from.
+    #[async_trait]
+    pub trait ChainSource: Send + Sync + 'static {
+        /// Start following the chain from the newest of `points` which is still on it,
+        /// dropping whatever we were following before. Returns the point it picked.
+        async fn sync_from(&mut self, points: &[Point]) -> Result<Point>;
+
+        async fn next(&mut self) -> Result<AcropolisMessage>;
+
+        /// Up to `max` messages (and at least one), for sources which can ask for several at
+        /// once. By default it's one `next` after another, stopping early after anything but
+        /// a block, so a batch never sits at the tip waiting for blocks nobody's made yet.
+        async fn next_batch(&mut self, max: usize) -> Result<Vec<AcropolisMessage>> {
+            let mut batch = vec![self.next().await?];
+            while batch.len() < max && matches!(batch.last(), Some(AcropolisMessage::NewBlock(..)))
+            {
+                batch.push(self.next().await?);
             }
+            Ok(batch)
+        }
+
+        /// Whether `point` is on the chain. Sources which can't tell assume it is.
+        async fn find_intersect(&mut self, point: &Point) -> Result<Intersect> {
+            let _ = point;
+            Ok(Intersect::Found)
+        }
+
+        /// Where the node says the tip of the chain is, kept up to date as the source syncs,
+        /// for sources which can ask. Otherwise, the tip is the newest block we've been given.
+        fn tip(&self) -> Option<watch::Receiver<Point>> {
+            None
         }
-        while fut.next().await.is_some() {}
     }
 
-    fn compare_points<'a, 'b>(lhs: &'a Point, rhs: &'b Point) -> Ordering {
-        lhs.slot_or_default().cmp(&rhs.slot_or_default())
+    pub enum Intersect {
+        Found,
+        NotFound {
+            /// The nearest point before it which is on the chain, if there is one.
+            closest: Option<Point>,
+        },
+    }
+
+    /// An index's start point isn't on the chain we're following.
+    #[derive(Debug, Clone)]
+    pub struct StartPointNotFound {
+        pub index: String,
+        pub point: Point,
+        pub closest: Option<Point>,
+    }
+
+    impl std::fmt::Display for StartPointNotFound {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "index {} starts from {:?}, which isn't on the chain",
+                self.index, self.point
+            )?;
+            match &self.closest {
+                Some(closest) => write!(f, "; the closest point on it is {closest:?}"),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl std::error::Error for StartPointNotFound {}
+
+    /// Follows the chain over the message bus, which is where blocks come from outside of tests.
+    struct BusChainSource {
+        context: Arc<core::Context>,
+        blocks: Option<core::Subscription>,
+    }
+
+    #[async_trait]
+    impl ChainSource for BusChainSource {
+        async fn sync_from(&mut self, points: &[Point]) -> Result<Point> {
+            // The bus only takes the one point, so it had better still be on the chain.
+            let point = points.first().cloned().unwrap_or(Point::Origin);
+            self.blocks = Some(self.context.subscribe("blocks").await?);
+            self.context
+                .publish("sync-from", AcropolisMessage::SyncFrom(point.clone()))
+                .await?;
+            Ok(point)
+        }
+
+        async fn next(&mut self) -> Result<AcropolisMessage> {
+            match &mut self.blocks {
+                Some(blocks) => blocks.read().await,
+                None => anyhow::bail!("not syncing yet"),
+            }
+        }
+    }
+
+    self_cell::self_cell!(
+        /// A block's CBOR and what it decodes to, so it's decoded once, however many indexes
+        /// see it and however long it waits for confirmations.
+        pub struct DecodedBlock {
+            owner: Box<[u8]>,
+            #[covariant]
+            dependent: MultiEraBlock,
+        }
+    );
+
+    impl DecodedBlock {
+        pub fn decode(cbor: &[u8]) -> Result<Self, pallas_traverse::Error> {
+            Self::try_new(cbor.into(), |cbor| MultiEraBlock::decode(cbor))
+        }
+
+        pub fn block(&self) -> &MultiEraBlock<'_> {
+            self.borrow_dependent()
+        }
+
+        pub fn cbor(&self) -> &[u8] {
+            self.borrow_owner()
+        }
+    }
+
+    /// A message from the chain source, with its block (if it has one) already decoded.
+    enum Prefetched {
+        Block(BlockInfo, Arc<DecodedBlock>),
+        /// A block pallas can't decode, say from an era it doesn't know about yet.
+        Undecodable(BlockInfo, Vec<u8>, String),
+        Other(AcropolisMessage),
+    }
+
+    impl Prefetched {
+        fn new(message: AcropolisMessage) -> Self {
+            match message {
+                AcropolisMessage::NewBlock(info, cbor) => match DecodedBlock::decode(&cbor) {
+                    Ok(block) => Self::Block(info, Arc::new(block)),
+                    Err(error) => Self::Undecodable(info, cbor, error.to_string()),
+                },
+                message => Self::Other(message),
+            }
+        }
+    }
+
+    /// Reads from a chain source in the background, a bounded number of messages ahead,
+    /// and decodes their blocks on the way,
+    /// so fetching and decoding overlap with handling without running away from it.
+    struct BlockBuffer {
+        inner: Arc<tokio::sync::Mutex<Box<dyn ChainSource>>>,
+        // Taken up front, since the fetcher holds onto the source while it waits for blocks.
+        tip: Option<watch::Receiver<Point>>,
+        capacity: usize,
+        batch_size: usize,
+        messages: Option<mpsc::Receiver<Result<Prefetched>>>,
+        fetcher: Option<tokio::task::JoinHandle<()>>,
+    }
+
+    impl BlockBuffer {
+        fn new(inner: Box<dyn ChainSource>, capacity: usize, batch_size: usize) -> Self {
+            Self {
+                tip: inner.tip(),
+                inner: Arc::new(tokio::sync::Mutex::new(inner)),
+                capacity,
+                batch_size,
+                messages: None,
+                fetcher: None,
+            }
+        }
+
+        fn depth(&self) -> usize {
+            self.messages.as_ref().map_or(0, |m| m.len())
+        }
+
+        async fn stop(&mut self) {
+            self.messages = None;
+            if let Some(fetcher) = self.fetcher.take() {
+                fetcher.abort();
+                let _ = fetcher.await;
+            }
+        }
+
+        /// The next message, in chain order, with its block decoded.
+        async fn prefetched(&mut self) -> Result<Prefetched> {
+            let Some(messages) = &mut self.messages else {
+                anyhow::bail!("not syncing yet");
+            };
+            match messages.recv().await {
+                Some(message) => message,
+                None => anyhow::bail!("chain source stopped"),
+            }
+        }
+    }
+
+    impl Drop for BlockBuffer {
+        fn drop(&mut self) {
+            if let Some(fetcher) = &self.fetcher {
+                fetcher.abort();
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ChainSource for BlockBuffer {
+        async fn sync_from(&mut self, points: &[Point]) -> Result<Point> {
+            // Whatever's buffered came from wherever we were following before.
+            self.stop().await;
+            let at = self.inner.lock().await.sync_from(points).await?;
+            let (send, receive) = mpsc::channel(self.capacity);
+            let inner = self.inner.clone();
+            let batch_size = self.batch_size;
+            self.fetcher = Some(tokio::spawn(async move {
+                loop {
+                    let batch = inner.lock().await.next_batch(batch_size).await;
+                    let (messages, failed) = match batch {
+                        Ok(batch) => (
+                            batch.into_iter().map(|m| Ok(Prefetched::new(m))).collect(),
+                            false,
+                        ),
+                        Err(error) => (vec![Err(error)], true),
+                    };
+                    // Waits here while the buffer's full, so the next batch isn't asked for
+                    // until this one's all in.
+                    for message in messages {
+                        if send.send(message).await.is_err() {
+                            return;
+                        }
+                    }
+                    if failed {
+                        break;
+                    }
+                }
+            }));
+            self.messages = Some(receive);
+            Ok(at)
+        }
+
+        // The indexer reads with `prefetched`; this hands blocks back as CBOR, for anything
+        // which only knows about chain sources.
+        async fn next(&mut self) -> Result<AcropolisMessage> {
+            Ok(match self.prefetched().await? {
+                Prefetched::Block(info, block) => {
+                    AcropolisMessage::NewBlock(info, block.cbor().to_vec())
+                }
+                Prefetched::Undecodable(info, cbor, _) => AcropolisMessage::NewBlock(info, cbor),
+                Prefetched::Other(message) => message,
+            })
+        }
+
+        async fn find_intersect(&mut self, point: &Point) -> Result<Intersect> {
+            self.inner.lock().await.find_intersect(point).await
+        }
+
+        fn tip(&self) -> Option<watch::Receiver<Point>> {
+            self.tip.clone()
+        }
+    }
+
+    /// Something that happens on a scripted chain.
+    #[cfg(test)]
+    #[derive(Clone)]
+    pub enum ChainEvent {
+        /// A new block, as CBOR.
+        RollForward(BlockInfo, Vec<u8>),
+        /// Everything after this point is discarded.
+        RollBackward(Point),
+    }
+
+    /// Plays a fixed script of chain events, so tests can drive an indexer without a node.
+    /// Once the script runs out, it reports that it's at the tip and waits forever. Syncing
+    /// from a point it's already played past plays the blocks after it again, as a node would.
+    #[cfg(test)]
+    pub struct MockChainSource {
+        events: VecDeque<ChainEvent>,
+        // The chain as played so far, for working out what a rollback discards, and for
+        // playing again to whoever syncs from further back.
+        chain: Vec<(BlockInfo, Vec<u8>)>,
+        awaiting: bool,
+    }
+
+    #[cfg(test)]
+    impl MockChainSource {
+        pub fn new(events: Vec<ChainEvent>) -> Self {
+            Self {
+                events: events.into(),
+                chain: vec![],
+                awaiting: false,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    #[async_trait]
+    impl ChainSource for MockChainSource {
+        async fn sync_from(&mut self, points: &[Point]) -> Result<Point> {
+            let mut found = None;
+            for point in points {
+                if let Intersect::Found = self.find_intersect(point).await? {
+                    found = Some(point.clone());
+                    break;
+                }
+            }
+            let Some(point) = found else {
+                anyhow::bail!("none of {points:?} are on the chain");
+            };
+            let slot = match point {
+                Point::Origin => None,
+                Point::Specific(slot, _) => Some(slot),
+            };
+            // Whatever's been played since `point` is played again.
+            let keep = self
+                .chain
+                .partition_point(|(b, _)| slot.is_some_and(|slot| b.slot <= slot));
+            for (info, block) in self.chain.split_off(keep).into_iter().rev() {
+                self.events.push_front(ChainEvent::RollForward(info, block));
+            }
+            // Skip ahead past everything at or before `point`, as a real node would.
+            if let Some(slot) = slot {
+                while let Some(ChainEvent::RollForward(info, _)) = self.events.front()
+                    && info.slot <= slot
+                {
+                    let Some(ChainEvent::RollForward(info, block)) = self.events.pop_front() else {
+                        unreachable!();
+                    };
+                    self.chain.push((info, block));
+                }
+            }
+            Ok(point)
+        }
+
+        async fn find_intersect(&mut self, point: &Point) -> Result<Intersect> {
+            let Point::Specific(slot, hash) = point else {
+                return Ok(Intersect::Found);
+            };
+            let mut closest = None;
+            let upcoming = self.events.iter().filter_map(|e| match e {
+                ChainEvent::RollForward(info, _) => Some(info),
+                ChainEvent::RollBackward(_) => None,
+            });
+            for info in self.chain.iter().map(|(info, _)| info).chain(upcoming) {
+                if info.slot == *slot && info.hash.to_vec() == *hash {
+                    return Ok(Intersect::Found);
+                }
+                if info.slot < *slot {
+                    closest = Some(Point::Specific(info.slot, info.hash.to_vec()));
+                }
+            }
+            Ok(Intersect::NotFound { closest })
+        }
+
+        async fn next(&mut self) -> Result<AcropolisMessage> {
+            while let Some(event) = self.events.pop_front() {
+                match event {
+                    ChainEvent::RollForward(info, block) => {
+                        self.awaiting = false;
+                        self.chain.push((info.clone(), block.clone()));
+                        return Ok(AcropolisMessage::NewBlock(info, block));
+                    }
+                    ChainEvent::RollBackward(point) => {
+                        let keep = match point {
+                            Point::Origin => 0,
+                            Point::Specific(slot, _) => {
+                                self.chain.partition_point(|(b, _)| b.slot <= slot)
+                            }
+                        };
+                        // Rolling back to the tip discards nothing, so there's nothing to report.
+                        if keep < self.chain.len() {
+                            self.awaiting = false;
+                            let first_discarded = self.chain[keep].0.clone();
+                            self.chain.truncate(keep);
+                            return Ok(AcropolisMessage::Rollback(first_discarded));
+                        }
+                    }
+                }
+            }
+            if !std::mem::replace(&mut self.awaiting, true) {
+                return Ok(AcropolisMessage::AwaitingBlocks);
+            }
+            std::future::pending().await
+        }
+    }
+
+    /// How often the indexer commits cursors.
+    #[derive(Debug, Clone, Copy)]
+    pub enum CheckpointInterval {
+        Blocks(u64),
+        /// Checked whenever a message arrives, so this is a minimum, not a timer.
+        Every(Duration),
+    }
+
+    impl std::str::FromStr for CheckpointInterval {
+        type Err = anyhow::Error;
+
+        /// A number of blocks, or of seconds if it ends in `s`.
+        fn from_str(s: &str) -> Result<Self> {
+            match s.strip_suffix('s') {
+                Some(seconds) => Ok(Self::Every(Duration::from_secs(seconds.parse()?))),
+                None => Ok(Self::Blocks(s.parse::<u64>()?.max(1))),
+            }
+        }
+    }
+
+    /// What the indexer does with a block it can't decode, which is what a block from an era
+    /// newer than pallas knows about looks like.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum UnsupportedEra {
+        /// Stop syncing at the block, so nothing is indexed past it.
+        #[default]
+        Fail,
+        /// Warn about the block and carry on after it, as if it had no txs.
+        Skip,
+    }
+
+    impl std::str::FromStr for UnsupportedEra {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> Result<Self> {
+            match s {
+                "fail" => Ok(Self::Fail),
+                "skip" => Ok(Self::Skip),
+                _ => anyhow::bail!("expected fail or skip"),
+            }
+        }
+    }
+
+    // The era tag a block's CBOR starts with, if it starts the way every era's blocks do.
+    fn era_tag(block: &[u8]) -> Option<u8> {
+        match block {
+            [0x82, tag, ..] if *tag < 0x18 => Some(*tag),
+            _ => None,
+        }
+    }
+
+    /// Every index's committed cursors, for moving them to another store or machine.
+    #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+    pub struct CursorSnapshot {
+        /// Each index's committed points, newest first, as `[slot, hash]`, or null for the origin.
+        pub cursors: BTreeMap<String, Vec<Option<(u64, String)>>>,
+    }
+
+    /// A point in a `CursorSnapshot` that isn't a real block.
+    #[derive(Debug, Clone)]
+    pub struct InvalidCursor {
+        pub index: String,
+        pub slot: u64,
+        pub reason: String,
+    }
+
+    impl std::fmt::Display for InvalidCursor {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "index {}'s cursor at slot {} is invalid: {}",
+                self.index, self.slot, self.reason
+            )
+        }
+    }
+
+    impl std::error::Error for InvalidCursor {}
+
+    impl CursorSnapshot {
+        // Every index's points, newest first, checking each hash is a whole block hash.
+        fn points(&self) -> Result<Vec<(String, Vec<Point>)>> {
+            let point = |index: &str, point: &Option<(u64, String)>| {
+                let Some((slot, hash)) = point else {
+                    return Ok(Point::Origin);
+                };
+                let hash = core::BlockHash::from_hex(hash).map_err(|e| InvalidCursor {
+                    index: index.to_string(),
+                    slot: *slot,
+                    reason: format!("{e}"),
+                })?;
+                Ok(hash.to_point(*slot))
+            };
+            self.cursors
+                .iter()
+                .map(|(index, points)| {
+                    let points = points.iter().map(|p| point(index, p));
+                    Ok((index.clone(), points.collect::<Result<_, InvalidCursor>>()?))
+                })
+                .collect()
+        }
+    }
+
+    /// How the indexer reconnects when the chain-sync connection drops.
+    #[derive(Debug, Clone)]
+    pub struct ReconnectPolicy {
+        /// How many times in a row to try reconnecting before giving up.
+        pub max_retries: u32,
+        /// How long to wait before the first attempt. Each attempt after that waits twice as long.
+        pub base_delay: Duration,
+        /// The longest we'll ever wait between attempts.
+        pub max_delay: Duration,
+    }
+
+    impl Default for ReconnectPolicy {
+        fn default() -> Self {
+            Self {
+                max_retries: 10,
+                base_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(60),
+            }
+        }
+    }
+
+    impl ReconnectPolicy {
+        fn delay(&self, attempt: u32) -> Duration {
+            self.base_delay
+                .saturating_mul(1 << attempt.min(31))
+                .min(self.max_delay)
+        }
+    }
+
+    /// How often the indexer calls a failing tx or rollback hook again before it faults the index.
+    #[derive(Debug, Clone)]
+    pub struct RetryPolicy {
+        /// How many calls to make in all, the first one included. 1 never retries.
+        pub max_attempts: u32,
+        /// How long to wait before the first retry. Each retry after that waits twice as long.
+        pub backoff: Duration,
+    }
+
+    impl Default for RetryPolicy {
+        fn default() -> Self {
+            Self {
+                max_attempts: 1,
+                backoff: Duration::from_millis(100),
+            }
+        }
+    }
+
+    impl RetryPolicy {
+        /// Waits before a hook's next attempt, or hands back its error if it's had them all.
+        async fn wait(&self, attempt: &mut u32, error: anyhow::Error) -> Result<()> {
+            *attempt += 1;
+            if *attempt >= self.max_attempts {
+                return Err(error);
+            }
+            let delay = self.backoff.saturating_mul(1 << (*attempt - 1).min(31));
+            tracing::warn!(attempt, error = format!("{error:#}"), "retrying");
+            tokio::time::sleep(delay).await;
+            Ok(())
+        }
+    }
+
+    pub struct ChainIndexer<CS: CursorStore> {
+        indexes: HashMap<String, IndexWrapper>,
+        cursor_store: Option<CS>,
+        chain_params: ChainParams,
+        reconnect: ReconnectPolicy,
+        snapshot_every: Option<u64>,
+        checkpoint_every: Option<CheckpointInterval>,
+        block_buffer: usize,
+        fetch_batch_size: usize,
+        sinks: Vec<Box<dyn EventSink>>,
+        strict_continuity: bool,
+        on_unsupported_era: UnsupportedEra,
+        dry_run: bool,
+        volatile_window: Option<u64>,
+        persist_dir: Option<PathBuf>,
+        slow_block: Option<Duration>,
+        source: Option<Box<dyn ChainSource>>,
+        status: Arc<Mutex<IndexerStatus>>,
+        pause: Arc<watch::Sender<bool>>,
+        #[cfg(any(test, feature = "metrics"))]
+        commands: mpsc::UnboundedSender<IndexCommand>,
+        pending_commands: Option<mpsc::UnboundedReceiver<IndexCommand>>,
+        #[cfg(feature = "http")]
+        routes: Vec<(String, IndexRoutes)>,
+    }
+
+    impl<CS: CursorStore> ChainIndexer<CS> {
+        pub fn new(cursors: CS) -> Self {
+            let (commands, pending_commands) = mpsc::unbounded_channel();
+            // With nothing to send on it, the sync loop stops listening as soon as it starts.
+            #[cfg(not(any(test, feature = "metrics")))]
+            drop(commands);
+            Self {
+                indexes: HashMap::new(),
+                cursor_store: Some(cursors),
+                chain_params: ChainParams::mainnet(),
+                reconnect: ReconnectPolicy::default(),
+                snapshot_every: None,
+                checkpoint_every: None,
+                block_buffer: 4,
+                fetch_batch_size: 1,
+                sinks: vec![],
+                strict_continuity: false,
+                on_unsupported_era: UnsupportedEra::default(),
+                dry_run: false,
+                volatile_window: None,
+                persist_dir: None,
+                slow_block: Some(Duration::from_secs(5)),
+                source: None,
+                #[cfg(feature = "http")]
+                routes: vec![],
+                status: Arc::new(Mutex::new(IndexerStatus::default())),
+                pause: Arc::new(watch::Sender::new(false)),
+                #[cfg(any(test, feature = "metrics"))]
+                commands,
+                pending_commands: Some(pending_commands),
+            }
+        }
+
+        pub fn handle(&self) -> ChainIndexerHandle {
+            ChainIndexerHandle {
+                status: self.status.clone(),
+                #[cfg(feature = "metrics")]
+                pause: self.pause.clone(),
+                #[cfg(any(test, feature = "metrics"))]
+                commands: self.commands.clone(),
+            }
+        }
+
+        /// Set the genesis parameters used for epoch math. Defaults to mainnet.
+        pub fn set_chain_params(&mut self, chain_params: ChainParams) {
+            self.chain_params = chain_params;
+        }
+
+        /// The HTTP routes of every index added so far, by index name.
+        #[cfg(feature = "http")]
+        pub fn take_routes(&mut self) -> Vec<(String, IndexRoutes)> {
+            std::mem::take(&mut self.routes)
+        }
+
+        /// Snapshot every index which supports it once every `blocks` blocks (and on shutdown),
+        /// alongside its cursor. On startup, those indexes restore from their snapshot,
+        /// or replay from their start point if it's missing.
+        pub fn set_snapshot_interval(&mut self, blocks: u64) {
+            self.snapshot_every = Some(blocks.max(1));
+        }
+
+        /// Only commit cursors this often, rather than after every block. Indexes are asked to
+        /// `checkpoint` (flush whatever they've buffered) just before their cursors are committed.
+        pub fn set_checkpoint_interval(&mut self, interval: CheckpointInterval) {
+            self.checkpoint_every = Some(interval);
+        }
+
+        /// Publish every index's events to `sink`, as their blocks are committed.
+        pub fn add_event_sink(&mut self, sink: impl EventSink) {
+            self.sinks.push(Box::new(sink));
+        }
+
+        /// Fetch and decode up to this many blocks ahead of the indexes (4 by default). Once the
+        /// buffer's full, we stop reading from the chain until the indexes catch up. This hides
+        /// how slow the indexes are; the fetch batch size is for how far away the node is.
+        pub fn set_block_buffer(&mut self, capacity: usize) {
+            self.block_buffer = capacity.max(1);
+        }
+
+        /// Ask the source for up to this many messages at a time (1 by default), with
+        /// `ChainSource::next_batch`. A batch is read whole before any of it goes into the
+        /// block buffer, and the next isn't asked for until it's all in, so a buffer smaller
+        /// than the batch leaves the source idle while the indexes make room. Up to the buffer's
+        /// capacity plus a batch can be held at once.
+        pub fn set_fetch_batch_size(&mut self, messages: usize) {
+            self.fetch_batch_size = messages.max(1);
+        }
+
+        /// Check that every block follows on from the one before it, by slot and previous hash,
+        /// and stop syncing if one doesn't. Off by default, since it means decoding every block.
+        pub fn set_strict_continuity(&mut self, strict: bool) {
+            self.strict_continuity = strict;
+        }
+
+        /// Whether to stop syncing at a block we can't decode (the default), or to skip it.
+        /// Skipping keeps a deployment going through a hard fork to an era pallas doesn't know
+        /// yet, but whatever was in the skipped blocks never reaches the indexes.
+        pub fn set_on_unsupported_era(&mut self, policy: UnsupportedEra) {
+            self.on_unsupported_era = policy;
+        }
+
+        /// Only look at blocks, rather than index them: each index's filter still runs, and
+        /// `inspect_tx` is called on whatever gets through, but none of the handlers are,
+        /// and no cursors, snapshots, or events are committed. What each index would have
+        /// indexed is logged per block, and totalled up when the indexer stops.
+        pub fn set_dry_run(&mut self, dry_run: bool) {
+            self.dry_run = dry_run;
+        }
+
+        /// Have indexes `prune` anything more than `slots` behind their committed cursor, so their
+        /// history stops growing. The window has to reach back as far as the deepest rollback
+        /// the node can send, which is k blocks (2160 on mainnet), or 43200 slots at one block
+        /// every 20; a rollback any deeper resets the index instead. It's counted back from each
+        /// index's own cursor, and an index waiting on confirmations is already that many blocks
+        /// behind the tip, so confirmations only make the window safer.
+        pub fn set_volatile_window(&mut self, slots: u64) {
+            self.volatile_window = Some(slots);
+        }
+
+        /// Keep every index's committed cursor, and its snapshot if it takes them, in a file in
+        /// `dir`, rewritten whenever cursors are committed, and resume from those files on
+        /// startup. That's enough to survive a crash without any other store. Each file is
+        /// written in full alongside the last one before replacing it, and checksummed, so a
+        /// damaged file falls back to the one before it, or to replaying the index.
+        pub fn set_persist_dir(&mut self, dir: impl Into<PathBuf>) {
+            self.persist_dir = Some(dir.into());
+        }
+
+        /// The cursors every index has committed to the cursor store, to load into another with
+        /// `import_cursors`. Only works before the indexer starts, since the store moves into
+        /// the sync loop once it does.
+        pub async fn export_cursors(&self) -> Result<CursorSnapshot> {
+            let store = self
+                .cursor_store
+                .as_ref()
+                .context("the indexer has started, so its cursor store is in use")?;
+            let mut snapshot = CursorSnapshot::default();
+            for name in self.indexes.keys() {
+                let points = store
+                    .history(name)
+                    .await?
+                    .into_iter()
+                    .map(|p| match p {
+                        Point::Origin => None,
+                        Point::Specific(slot, hash) => Some((slot, hex::encode(hash))),
+                    })
+                    .collect();
+                snapshot.cursors.insert(name.clone(), points);
+            }
+            Ok(snapshot)
+        }
+
+        /// Commit the cursors in `snapshot` to the cursor store, oldest first, so each index's
+        /// history comes out in the same order. Nothing is written unless every point in it is
+        /// a real block, with a hash `BlockHash::BYTES` long. Has to happen before the indexer
+        /// starts, for the indexes to resume from them.
+        pub async fn import_cursors(&mut self, snapshot: &CursorSnapshot) -> Result<()> {
+            let points = snapshot.points()?;
+            let store = self
+                .cursor_store
+                .as_mut()
+                .context("the indexer has started, so its cursor store is in use")?;
+            let longest = points.iter().map(|(_, p)| p.len()).max().unwrap_or(0);
+            for age in (0..longest).rev() {
+                let updates = points
+                    .iter()
+                    .filter_map(|(name, p)| Some((name.clone(), p.get(age)?.clone())))
+                    .collect::<Vec<_>>();
+                store.commit_batch(&updates).await?;
+            }
+            Ok(())
+        }
+
+        /// Warn when an index takes longer than `threshold` over a single block (5 seconds by
+        /// default), naming the index and the slot. Each index is warned about at most once a
+        /// minute, and the warning says how many slow blocks went by since the last one.
+        /// `None` turns the warnings off; block times are still counted for the metrics.
+        pub fn set_slow_block_threshold(&mut self, threshold: Option<Duration>) {
+            self.slow_block = threshold;
+        }
+
+        /// Read blocks from `source`, rather than from the message bus.
+        pub fn set_chain_source(&mut self, source: impl ChainSource) {
+            self.source = Some(Box::new(source));
+        }
+
+        /// Set how to reconnect when the chain-sync connection drops.
+        pub fn set_reconnect_policy(&mut self, reconnect: ReconnectPolicy) {
+            self.reconnect = reconnect;
+        }
+
+        /// Begin managing an index.
+        /// If the index doesn't already exist, it will start indexing from `start`.
+        /// If it does, it will start from wherever it began before, unless `force_restart` is passed.
+        /// The index only sees blocks once `confirmations` more have been built on them,
+        /// so rollbacks shallower than that never reach it. 0 passes every block straight on.
+        /// The returned handle shares the index with the indexer, which only holds its write lock while applying a block.
+        /// Fails if another index is already registered under the same name, since they'd share a cursor.
+        pub fn add_index<M: ManagedIndex>(
+            &mut self,
+            index: M,
+            start: Point,
+            force_restart: bool,
+            confirmations: u64,
+        ) -> Result<Arc<RwLock<M>>> {
+            let name = index.name();
+            self.add_index_as(name, index, start, force_restart, confirmations)
+        }
+
+        /// Begin managing an index under `name` rather than the name it gives itself,
+        /// so that several indexes of the same type can have cursors of their own.
+        pub fn add_index_as<M: ManagedIndex>(
+            &mut self,
+            name: String,
+            index: M,
+            start: Point,
+            force_restart: bool,
+            confirmations: u64,
+        ) -> Result<Arc<RwLock<M>>> {
+            if self.indexes.contains_key(&name) {
+                return Err(DuplicateIndex { name }.into());
+            }
+            #[cfg(feature = "http")]
+            let router = index.router();
+            let (wrapper, index) =
+                IndexWrapper::new(name.clone(), index, start, force_restart, confirmations);
+            #[cfg(feature = "http")]
+            if let Some(router) = router {
+                self.routes
+                    .push((name.clone(), IndexRoutes::new(router, index.clone())));
+            }
+            self.status
+                .lock()
+                .unwrap()
+                .readers
+                .insert(name.clone(), wrapper.reader());
+            self.indexes.insert(name, wrapper);
+            Ok(index)
+        }
+    }
+
+    #[async_trait]
+    impl<CS: CursorStore> Module for ChainIndexer<CS> {
+        fn name(&self) -> String {
+            "chain-indexer".into()
+        }
+
+        async fn init(&mut self, context: Arc<core::Context>) -> Result<()> {
+            let source = self.source.take().unwrap_or_else(|| {
+                Box::new(BusChainSource {
+                    context: context.clone(),
+                    blocks: None,
+                })
+            });
+            let mut source = BlockBuffer::new(source, self.block_buffer, self.fetch_batch_size);
+            let mut indexes = std::mem::take(&mut self.indexes);
+            let cursor_store = self.cursor_store.take().unwrap();
+            if let Some(dir) = &self.persist_dir {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("could not create {}", dir.display()))?;
+            }
+            let status = self.status.clone();
+            // Most likely an index that's been renamed, which would start over under its new name.
+            for name in cursor_store.latest().await?.keys() {
+                if !indexes.contains_key(name) {
+                    tracing::warn!(index = %name, "the cursor store has a cursor for an index that isn't registered");
+                }
+            }
+
+            for index in indexes.values_mut() {
+                let resume = Resume {
+                    snapshots: self.snapshot_every.is_some(),
+                    persist_dir: self.persist_dir.as_deref(),
+                };
+                start_index(index, &cursor_store, &mut source, resume, &status).await?;
+                index.filter.timing.slow = self.slow_block;
+                // A snapshot could have been taken after pruning, so play it safe.
+                index.pruned_before = self
+                    .volatile_window
+                    .and_then(|w| index.tip.slot_or_default().checked_sub(w));
+            }
+
+            let first_point = sync_point(indexes.values().filter(|i| !i.faulted));
+            let syncing = Syncing {
+                node_tip: source.tip(),
+                source,
+                to_resolve: outputs_to_resolve(&indexes),
+                utxos: UtxoCache::default(),
+                depths: confirmation_depths(&indexes),
+                indexes,
+                cursor_store,
+                sinks: std::mem::take(&mut self.sinks),
+                status: status.clone(),
+                shutdown: context.shutdown_token(),
+                paused: self.pause.subscribe(),
+                commands: self.pending_commands.take().unwrap(),
+                epoch: epoch_of(&self.chain_params, &first_point),
+                chain_params: self.chain_params.clone(),
+                reconnect: self.reconnect.clone(),
+                snapshot_every: self.snapshot_every,
+                checkpoint_every: self.checkpoint_every,
+                strict_continuity: self.strict_continuity,
+                on_unsupported_era: self.on_unsupported_era,
+                dry_run: self.dry_run,
+                volatile_window: self.volatile_window,
+                persist_dir: self.persist_dir.clone(),
+                #[cfg(any(test, feature = "metrics"))]
+                slow_block: self.slow_block,
+                volatile: VecDeque::new(),
+                last_block: Some(first_point),
+                missed_rollback: None,
+                attempts: 0,
+                blocks_since_snapshot: 0,
+                pending: HashMap::new(),
+                blocks_since_checkpoint: 0,
+                last_checkpoint: Instant::now(),
+            };
+            context.clone().run(async move {
+                let _running = RunningGuard::new(&status);
+                let result = syncing.run().await;
+                if let Err(e) = &result {
+                    tracing::error!(error = format!("{e:#}"), "indexer stopped");
+                    status.lock().unwrap().error = Some(format!("{e:#}"));
+                }
+                result
+            });
+            Ok(())
+        }
+    }
+
+    /// The indexer once it's started, and everything it keeps track of while it follows the chain.
+    struct Syncing<CS: CursorStore> {
+        source: BlockBuffer,
+        node_tip: Option<watch::Receiver<Point>>,
+        indexes: HashMap<String, IndexWrapper>,
+        cursor_store: CS,
+        sinks: Vec<Box<dyn EventSink>>,
+        status: Arc<Mutex<IndexerStatus>>,
+        shutdown: core::CancellationToken,
+        paused: watch::Receiver<bool>,
+        commands: mpsc::UnboundedReceiver<IndexCommand>,
+        chain_params: ChainParams,
+        reconnect: ReconnectPolicy,
+        snapshot_every: Option<u64>,
+        checkpoint_every: Option<CheckpointInterval>,
+        strict_continuity: bool,
+        on_unsupported_era: UnsupportedEra,
+        dry_run: bool,
+        volatile_window: Option<u64>,
+        persist_dir: Option<PathBuf>,
+        // For the timing of indexes added once we're running.
+        #[cfg(any(test, feature = "metrics"))]
+        slow_block: Option<Duration>,
+        to_resolve: Vec<ResolvedOutputs>,
+        utxos: UtxoCache,
+        // How many confirmations each group of indexes waits for, fewest first.
+        depths: Vec<u64>,
+        // The most recent blocks, which indexes waiting on confirmations haven't seen yet.
+        volatile: VecDeque<PendingBlock>,
+        epoch: Option<u64>,
+        // The last block we've seen, if we know it, for checking the next one follows it.
+        last_block: Option<Point>,
+        // A rollback which happened while we weren't following the chain.
+        missed_rollback: Option<AcropolisMessage>,
+        // How many times in a row we've tried to reconnect.
+        attempts: u32,
+        blocks_since_snapshot: u64,
+        // Where indexes have got to since their cursors were last committed.
+        pending: HashMap<String, Point>,
+        blocks_since_checkpoint: u64,
+        last_checkpoint: Instant,
+    }
+
+    impl<CS: CursorStore> Syncing<CS> {
+        async fn run(mut self) -> Result<()> {
+            record_progress(&self.status, &self.indexes);
+            let live = self.indexes.values().filter(|i| !i.faulted);
+            self.missed_rollback = start_sync(&mut self.source, live)
+                .await
+                .context("could not start sync")?;
+            loop {
+                // Pausing also waits for the message we're on, and commits everything
+                // before it stops, so the cursors are settled for as long as we're paused.
+                if *self.paused.borrow_and_update() && !self.pause().await? {
+                    break;
+                }
+                // Only check for shutdown between messages, so whatever block we're on
+                // gets finished and its cursors committed before we stop.
+                let message = match self.missed_rollback.take() {
+                    Some(rollback) => Ok(Prefetched::Other(rollback)),
+                    None => tokio::select! {
+                        _ = self.shutdown.cancelled() => break,
+                        // The next block might be a while coming, if we're at the tip.
+                        Ok(()) = self.paused.changed() => continue,
+                        Some(command) = self.commands.recv() => {
+                            self.handle_command(command).await;
+                            continue;
+                        }
+                        message = self.source.prefetched() => message,
+                    },
+                };
+                let message = match message {
+                    Ok(message) => {
+                        self.attempts = 0;
+                        message
+                    }
+                    Err(error) => {
+                        if !self.reconnect(error).await {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                {
+                    let mut status = self.status.lock().unwrap();
+                    status.buffered_blocks = self.source.depth();
+                    if let Some(tip) = &self.node_tip {
+                        status.node_tip_slot = tip.borrow().slot_or_default();
+                    }
+                }
+                let is_block = matches!(message, Prefetched::Block(..));
+                let mut processed = match message {
+                    Prefetched::Undecodable(info, block, error) => {
+                        if !self.skip_undecodable(info, &block, &error) {
+                            break;
+                        }
+                        continue;
+                    }
+                    Prefetched::Block(info, block) => match self.handle_block(info, block).await {
+                        Some(processed) => processed,
+                        None => break,
+                    },
+                    Prefetched::Other(AcropolisMessage::Rollback(info)) => {
+                        self.handle_rollback(info).await
+                    }
+                    Prefetched::Other(AcropolisMessage::AwaitingBlocks) => self.reach_tip().await,
+                    _ => Processed::default(),
+                };
+                let resync = std::mem::take(&mut processed.resync);
+                if !resync.is_empty() {
+                    self.resync(resync, &mut processed).await;
+                }
+                record_progress(&self.status, &self.indexes);
+                self.checkpoint(processed, is_block).await?;
+            }
+            self.finish().await
+        }
+
+        /// Commits everything and waits to be resumed. Returns false if we're shut down first.
+        async fn pause(&mut self) -> Result<bool> {
+            self.commit().await?;
+            if self.snapshot_every.is_some() && !self.dry_run {
+                take_snapshots(&self.indexes, &mut self.cursor_store).await?;
+            }
+            self.status.lock().unwrap().paused = true;
+            tracing::info!("indexer paused");
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return Ok(false),
+                _ = self.paused.wait_for(|p| !p) => {}
+            }
+            self.status.lock().unwrap().paused = false;
+            tracing::info!("indexer resumed");
+            Ok(true)
+        }
+
+        #[cfg(not(any(test, feature = "metrics")))]
+        async fn handle_command(&mut self, command: IndexCommand) {
+            match command {}
+        }
+
+        #[cfg(any(test, feature = "metrics"))]
+        async fn handle_command(&mut self, mut command: IndexCommand) {
+            // A new index needs to know it's on the chain, which means getting
+            // at the source, so whatever it's fetching ahead has to go.
+            let adding = matches!(command, IndexCommand::Add(..));
+            if adding {
+                self.source.stop().await;
+            }
+            if let IndexCommand::Add(index, _) = &mut command {
+                index.filter.timing.slow = self.slow_block;
+            }
+            let resume = Resume {
+                snapshots: self.snapshot_every.is_some(),
+                persist_dir: self.persist_dir.as_deref(),
+            };
+            apply_command(
+                command,
+                &mut self.indexes,
+                &mut self.pending,
+                &self.cursor_store,
+                &mut self.source,
+                resume,
+                &self.status,
+            )
+            .await;
+            self.to_resolve = outputs_to_resolve(&self.indexes);
+            self.depths = confirmation_depths(&self.indexes);
+            record_progress(&self.status, &self.indexes);
+            if adding {
+                self.start_over().await;
+            }
+        }
+
+        /// Starts over from wherever the furthest-behind index is, say one that's just been
+        /// added. The rest skip what they've already seen, and it all gets replayed into the
+        /// volatile blocks and the UTXO cache on the way.
+        async fn start_over(&mut self) {
+            self.volatile.clear();
+            self.utxos = UtxoCache::default();
+            let first_point = sync_point(self.indexes.values().filter(|i| !i.faulted));
+            self.epoch = epoch_of(&self.chain_params, &first_point);
+            self.last_block = Some(first_point);
+            self.restart_sync("could not restart sync").await;
+        }
+
+        async fn restart_sync(&mut self, failure: &str) {
+            // Faulted indexes won't be updated again,
+            // so only the healthy ones decide where we pick back up.
+            let live = self.indexes.values().filter(|i| !i.faulted);
+            self.status.lock().unwrap().at_tip = false;
+            match start_sync(&mut self.source, live).await {
+                Ok(rollback) => self.missed_rollback = rollback,
+                Err(error) => tracing::error!(error = format!("{error:#}"), "{failure}"),
+            }
+        }
+
+        /// Waits to reconnect after the chain source failed. Returns false if we've given up,
+        /// or been shut down while waiting.
+        async fn reconnect(&mut self, error: anyhow::Error) -> bool {
+            if self.attempts >= self.reconnect.max_retries {
+                tracing::error!(error = format!("{error:#}"), "giving up on chain sync");
+                return false;
+            }
+            let delay = self.reconnect.delay(self.attempts);
+            self.attempts += 1;
+            tracing::warn!(
+                error = format!("{error:#}"),
+                ?delay,
+                "chain sync dropped, reconnecting"
+            );
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return false,
+                _ = tokio::time::sleep(delay) => {}
+            }
+            self.restart_sync("could not reconnect").await;
+            true
+        }
+
+        /// Returns whether we're skipping the block, rather than stopping at it.
+        fn skip_undecodable(&mut self, mut info: BlockInfo, block: &[u8], error: &str) -> bool {
+            info.locate(&self.chain_params);
+            let era = era_tag(block);
+            if self.on_unsupported_era == UnsupportedEra::Fail {
+                tracing::error!(slot = info.slot, era, %error, "stopping chain sync at a block we can't decode");
+                return false;
+            }
+            tracing::warn!(slot = info.slot, era, %error, "skipping a block we can't decode");
+            // Whatever comes next follows on from this block.
+            self.last_block = Some(Point::Specific(info.slot, info.hash.to_vec()));
+            true
+        }
+
+        /// Hands each group of indexes whichever block is now buried deep enough for it.
+        /// Returns nothing if the block doesn't follow on from the last one, in strict mode.
+        async fn handle_block(
+            &mut self,
+            mut info: BlockInfo,
+            block: Arc<DecodedBlock>,
+        ) -> Option<Processed> {
+            info.locate(&self.chain_params);
+            if self.strict_continuity {
+                if let Some(last) = &self.last_block
+                    && let Err(error) = check_continuity(last, &info, block.block())
+                {
+                    tracing::error!(%error, "stopping chain sync");
+                    self.status.lock().unwrap().continuity = Some(error);
+                    return None;
+                }
+                self.last_block = Some(Point::Specific(info.slot, info.hash.to_vec()));
+            }
+            {
+                let mut status = self.status.lock().unwrap();
+                status.tip_slot = info.slot;
+                status.counters.blocks_processed += 1;
+            }
+            // Resolve inputs up front and in order, since a tx can spend
+            // outputs created earlier in the same block.
+            let resolved = if !self.to_resolve.is_empty() {
+                let resolved = block
+                    .block()
+                    .txs()
+                    .iter()
+                    .map(|tx| self.utxos.apply(info.slot, tx, &self.to_resolve))
+                    .collect();
+                self.utxos.prune(info.slot);
+                resolved
+            } else {
+                Arc::default()
+            };
+            let new_epoch = info.epoch;
+            let old_epoch = self.epoch.replace(new_epoch).filter(|e| *e < new_epoch);
+            self.volatile.push_back(PendingBlock {
+                info,
+                block,
+                resolved,
+                old_epoch,
+                new_epoch,
+            });
+            let mut processed = Processed::default();
+            for depth in &self.depths {
+                let Some(pending) = self
+                    .volatile
+                    .len()
+                    .checked_sub(*depth as usize + 1)
+                    .and_then(|i| self.volatile.get(i))
+                else {
+                    continue;
+                };
+                let group = self
+                    .indexes
+                    .values_mut()
+                    .filter(|i| i.confirmations == *depth && is_after(&i.tip, &pending.info));
+                let applied = apply_block(group, pending, self.dry_run).await;
+                for name in applied.advanced.iter().filter(|_| !self.dry_run) {
+                    self.indexes
+                        .get_mut(name)
+                        .unwrap()
+                        .events
+                        .push(IndexEvent::new(
+                            "block_processed",
+                            pending.info.slot,
+                            serde_json::json!({
+                                "hash": hex::encode(pending.info.hash.to_vec()),
+                                "height": pending.info.height,
+                            }),
+                        ));
+                }
+                processed.extend(applied);
+            }
+            let max_depth = self.depths.last().copied().unwrap_or(0);
+            while self.volatile.len() > max_depth as usize + 1 {
+                self.volatile.pop_front();
+            }
+            Some(processed)
+        }
+
+        /// Undoes everything from `info` on, in every index which had got that far.
+        async fn handle_rollback(&mut self, mut info: BlockInfo) -> Processed {
+            info.locate(&self.chain_params);
+            {
+                let mut status = self.status.lock().unwrap();
+                // In slots, from the newest block we'd seen.
+                let depth = status.tip_slot.saturating_sub(info.slot) + 1;
+                tracing::info!(slot = info.slot, depth, "rollback");
+                status.at_tip = false;
+                status.tip_slot = info.slot.saturating_sub(1);
+                status.counters.rollbacks += 1;
+            }
+            self.utxos.rollback(info.slot);
+            self.volatile.retain(|b| b.info.slot < info.slot);
+            let previous = self
+                .volatile
+                .back()
+                .map(|b| Point::Specific(b.info.slot, b.info.hash.to_vec()));
+            // We can only check the next block if we still remember the one it should follow.
+            self.last_block = previous.clone();
+            // Everything before the rolled-back block survives,
+            // so we're back in whichever epoch the slot before it was in.
+            self.epoch = Some(self.chain_params.slot_to_epoch(info.slot.saturating_sub(1)));
+            let at = Point::Specific(info.slot, info.hash.to_vec());
+            // An index which committed a different block at this slot is on
+            // another fork, so rolling it back by slot alone would put it in the wrong place.
+            let mut early = Processed::default();
+            for index in self.indexes.values_mut().filter(|i| !i.faulted) {
+                if let Some(error) = check_rollback(index, &info) {
+                    tracing::error!(index = %index.name, %error, "index faulted");
+                    index.faulted = true;
+                    early.failed.push((index.name.clone(), error.to_string()));
+                }
+            }
+            // Only an index which has got as far as the rolled-back blocks has
+            // anything to undo. Indexes waiting on confirmations often haven't.
+            let is_affected = |i: &IndexWrapper| !is_after(&i.tip, &info);
+            let dry_run = self.dry_run;
+            // An index which has forgotten what came before the rollback
+            // can't undo it, so it starts over instead.
+            // A dry run never changed anything, so there's nothing to start over.
+            for index in self
+                .indexes
+                .values_mut()
+                .filter(|i| !dry_run && !i.faulted && is_affected(i))
+            {
+                let mut x = index.index.write().await;
+                let retained_since = x.retained_since().max(index.pruned_before);
+                if retained_since.is_none_or(|s| s <= info.slot) {
+                    continue;
+                }
+                let reorg = DeepReorg {
+                    index: index.name.clone(),
+                    to_slot: info.slot,
+                };
+                tracing::warn!(index = %reorg.index, to_slot = reorg.to_slot, "{reorg}");
+                index.pruned_before = None;
+                if let Err(e) = x.reset().await {
+                    match e.downcast::<ResyncFrom>() {
+                        // It can't start over from here, so it's resynced from where it asked.
+                        Ok(ResyncFrom { point }) => {
+                            tracing::warn!(index = %index.name, ?point, "starting over");
+                            early.resync.push((index.name.clone(), point));
+                        }
+                        Err(e) => {
+                            tracing::error!(index = %index.name, error = format!("{e:#}"), "index faulted");
+                            index.faulted = true;
+                            early.failed.push((index.name.clone(), format!("{e:#}")));
+                        }
+                    }
+                }
+            }
+            // Nothing's left of an index starting over to roll back.
+            let resyncing = early
+                .resync
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect::<HashSet<_>>();
+            let is_affected = |i: &IndexWrapper| is_affected(i) && !resyncing.contains(&i.name);
+            // Unpublished events from the discarded blocks can just be dropped,
+            // and the rollback event takes back any which were published.
+            let mut kept = HashMap::new();
+            for index in self.indexes.values_mut().filter(|i| is_affected(i)) {
+                index.events.retain(|e| e.slot < info.slot);
+                let from_slot = index.tip.slot_or_default();
+                kept.insert(index.name.clone(), (index.events.len(), from_slot));
+            }
+            let affected = self.indexes.values_mut().filter(|i| is_affected(i));
+            let info = &info;
+            let mut processed = process_message(affected, at, |x, filter, batch, tip| async move {
+                let mut x = x.write().await;
+                filter.rollback(info.slot);
+                batch.retain(|(b, ..)| b.slot < info.slot);
+                if !dry_run {
+                    let rollback = RollbackInfo {
+                        to: info.clone(),
+                        from_slot: tip.slot_or_default(),
+                    };
+                    tracing::debug!(from_slot = rollback.from_slot, "undoing");
+                    let retry = x.retry_policy();
+                    let mut attempt = 0;
+                    while let Err(e) = x.handle_rollback_range(&rollback).await {
+                        retry.wait(&mut attempt, e).await?;
+                    }
+                }
+                Ok(x)
+            })
+            .instrument(tracing::info_span!("rollback", slot = info.slot))
+            .await;
+            // Whatever the index reported while rolling back is what it reverted.
+            for name in &processed.advanced {
+                let index = self.indexes.get_mut(name).unwrap();
+                // Each index goes back to the last block it had before the
+                // rollback, if we know it. If not, it goes back to the origin, rather than
+                // staying on the discarded block, which a fork's next block can be before.
+                index.tip = previous
+                    .clone()
+                    .or_else(|| last_before(&index.history, info.slot))
+                    .unwrap_or(Point::Origin);
+                let events = &mut index.events;
+                let (kept, from_slot) = kept[name];
+                let reverted = events.split_off(kept);
+                events.push(IndexEvent::new(
+                    "rollback",
+                    info.slot,
+                    serde_json::json!({
+                        "hash": hex::encode(info.hash.to_vec()),
+                        "from_slot": from_slot,
+                        "reverted": reverted,
+                    }),
+                ));
+            }
+            processed.extend(early);
+            processed
+        }
+
+        async fn reach_tip(&mut self) -> Processed {
+            let reached = !std::mem::replace(&mut self.status.lock().unwrap().at_tip, true);
+            if !reached {
+                return Processed::default();
+            }
+            let slot = self.status.lock().unwrap().tip_slot;
+            tracing::info!(slot, "reached the tip");
+            if self.dry_run {
+                tracing::info!(slot, "dry run reached the tip");
+                Processed::default()
+            } else {
+                reach_tip(self.indexes.values_mut()).await
+            }
+        }
+
+        /// An index which asked to start over gets reset and its cursor moved right away, and
+        /// then, just like adding one, we go back to whichever index is furthest behind.
+        async fn resync(&mut self, resync: Vec<(String, Point)>, processed: &mut Processed) {
+            self.source.stop().await;
+            for (name, point) in resync {
+                self.pending.remove(&name);
+                let index = self.indexes.get_mut(&name).unwrap();
+                let resynced = resync_index(
+                    index,
+                    point,
+                    &mut self.cursor_store,
+                    &mut self.source,
+                    self.persist_dir.as_deref(),
+                    &self.status,
+                )
+                .await;
+                if let Err(e) = resynced {
+                    tracing::error!(index = %name, error = format!("{e:#}"), "index faulted");
+                    index.faulted = true;
+                    processed.failed.push((name, format!("{e:#}")));
+                }
+            }
+            self.start_over().await;
+        }
+
+        /// Commits the cursors of whatever advanced, once a checkpoint's due.
+        async fn checkpoint(&mut self, processed: Processed, is_block: bool) -> Result<()> {
+            // Cursors for a block are committed together, and only if every index
+            // which handled it succeeded, so a crash can't leave them half-advanced.
+            if !processed.failed.is_empty() {
+                self.status.lock().unwrap().faulted.extend(processed.failed);
+                return Ok(());
+            }
+            // The tips still move in a dry run, so each block goes to the right indexes,
+            // but nothing's committed.
+            if self.dry_run {
+                return Ok(());
+            }
+            for name in processed.advanced {
+                let tip = self.indexes[&name].tip.clone();
+                self.pending.insert(name, tip);
+            }
+            if is_block {
+                self.blocks_since_checkpoint += 1;
+            }
+            let due = match self.checkpoint_every {
+                None => true,
+                Some(CheckpointInterval::Blocks(blocks)) => self.blocks_since_checkpoint >= blocks,
+                Some(CheckpointInterval::Every(every)) => self.last_checkpoint.elapsed() >= every,
+            };
+            if !due {
+                return Ok(());
+            }
+            self.blocks_since_checkpoint = 0;
+            self.last_checkpoint = Instant::now();
+            if !self.commit().await? {
+                return Ok(());
+            }
+            if let Some(every) = self.snapshot_every
+                && is_block
+            {
+                self.blocks_since_snapshot += 1;
+                if self.blocks_since_snapshot >= every {
+                    self.blocks_since_snapshot = 0;
+                    take_snapshots(&self.indexes, &mut self.cursor_store).await?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Commits every pending cursor. Returns false if any index failed to checkpoint.
+        async fn commit(&mut self) -> Result<bool> {
+            let failed = commit_pending(
+                &mut self.indexes,
+                &mut self.pending,
+                &mut self.cursor_store,
+                &mut self.sinks,
+                self.volatile_window,
+                self.persist_dir.as_deref(),
+                &self.status,
+            )
+            .await?;
+            let committed = failed.is_empty();
+            self.status.lock().unwrap().faulted.extend(failed);
+            Ok(committed)
+        }
+
+        async fn finish(mut self) -> Result<()> {
+            if self.dry_run {
+                for index in self.indexes.values() {
+                    tracing::info!(index = %index.name, "dry run saw {}", index.filter.dry_run);
+                }
+                return Ok(());
+            }
+            self.commit().await?;
+            if self.snapshot_every.is_some() {
+                take_snapshots(&self.indexes, &mut self.cursor_store).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// An index asked to export which doesn't implement `export`.
+    #[derive(Debug, Clone)]
+    pub struct NotExportable {
+        pub name: String,
+    }
+
+    impl std::fmt::Display for NotExportable {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "index {} can't be exported", self.name)
+        }
+    }
+
+    impl std::error::Error for NotExportable {}
+
+    /// An index registered under a name another index already has.
+    /// Cursors, snapshots, and metrics are all keyed by name, so the two would overwrite each other.
+    #[derive(Debug, Clone)]
+    pub struct DuplicateIndex {
+        pub name: String,
+    }
+
+    impl std::fmt::Display for DuplicateIndex {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "an index named {} is already registered; give one of them another name",
+                self.name
+            )
+        }
+    }
+
+    impl std::error::Error for DuplicateIndex {}
+
+    /// A rollback deeper than an index keeps history for, which it starts over from.
+    #[derive(Debug, Clone)]
+    pub struct DeepReorg {
+        pub index: String,
+        pub to_slot: u64,
+    }
+
+    impl std::fmt::Display for DeepReorg {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "rolled back to slot {}, past what index {} remembers, so it's starting over from there",
+                self.to_slot, self.index
+            )
+        }
+    }
+
+    /// Returned from an index's hooks to have it start over from `point`, say when it finds its
+    /// own state is corrupt. The index is reset, and its cursor moved back to `point`, rather
+    /// than it being faulted.
+    #[derive(Debug, Clone)]
+    pub struct ResyncFrom {
+        pub point: Point,
+    }
+
+    impl std::fmt::Display for ResyncFrom {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "asked to start over from {:?}", self.point)
+        }
+    }
+
+    impl std::error::Error for ResyncFrom {}
+
+    /// A rollback of a block other than the one an index committed at that slot.
+    #[derive(Debug, Clone)]
+    pub struct RollbackHashMismatch {
+        pub index: String,
+        pub slot: u64,
+        /// The hash the index committed.
+        pub expected: Vec<u8>,
+        /// The hash the node is rolling back.
+        pub got: Vec<u8>,
+    }
+
+    impl std::fmt::Display for RollbackHashMismatch {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "rolling back block {} at slot {}, but index {} has block {} there",
+                hex::encode(&self.got),
+                self.slot,
+                self.index,
+                hex::encode(&self.expected)
+            )
+        }
+    }
+
+    impl std::error::Error for RollbackHashMismatch {}
+
+    /// Checks the block being rolled back against whatever `index` committed at the same slot.
+    fn check_rollback(index: &IndexWrapper, info: &BlockInfo) -> Option<RollbackHashMismatch> {
+        let got = info.hash.to_vec();
+        index
+            .history
+            .iter()
+            .chain([&index.tip])
+            .find_map(|point| match point {
+                Point::Specific(slot, hash) if *slot == info.slot && *hash != got => {
+                    Some(RollbackHashMismatch {
+                        index: index.name.clone(),
+                        slot: *slot,
+                        expected: hash.clone(),
+                        got: got.clone(),
+                    })
+                }
+                _ => None,
+            })
+    }
+
+    // Whether the block comes after `tip`, so an index there hasn't seen it yet. Byron's epoch
+    // boundary blocks share their slot with the block after them, so a block in the tip's own
+    // slot comes after it too, unless it's the tip itself.
+    fn is_after(tip: &Point, info: &BlockInfo) -> bool {
+        match tip {
+            Point::Origin => true,
+            Point::Specific(slot, hash) => {
+                *slot < info.slot || (*slot == info.slot && *hash != info.hash.to_vec())
+            }
+        }
+    }
+
+    // The newest of `points` (newest first) from before `slot`.
+    // The newest of `points` (newest first) before `slot`. The origin is before every slot.
+    fn last_before(points: &[Point], slot: u64) -> Option<Point> {
+        points
+            .iter()
+            .find(|p| match p {
+                Point::Origin => true,
+                Point::Specific(s, _) => *s < slot,
+            })
+            .cloned()
+    }
+
+    // The epoch `point`'s block is in, if it's a block.
+    fn epoch_of(chain_params: &ChainParams, point: &Point) -> Option<u64> {
+        match point {
+            Point::Origin => None,
+            Point::Specific(slot, _) => Some(chain_params.slot_to_epoch(*slot)),
+        }
+    }
+
+    fn confirmation_depths(indexes: &HashMap<String, IndexWrapper>) -> Vec<u64> {
+        let mut depths = indexes
+            .values()
+            .map(|i| i.confirmations)
+            .collect::<Vec<_>>();
+        depths.sort();
+        depths.dedup();
+        depths
+    }
+
+    /// Adds or removes an index while we're syncing.
+    #[cfg(any(test, feature = "metrics"))]
+    async fn apply_command<CS: CursorStore>(
+        command: IndexCommand,
+        indexes: &mut HashMap<String, IndexWrapper>,
+        pending: &mut HashMap<String, Point>,
+        cursor_store: &CS,
+        source: &mut dyn ChainSource,
+        resume: Resume<'_>,
+        status: &Mutex<IndexerStatus>,
+    ) {
+        match command {
+            IndexCommand::Add(mut index, reply) => {
+                let started = if indexes.contains_key(&index.name) {
+                    let name = index.name.clone();
+                    Err(DuplicateIndex { name }.into())
+                } else {
+                    start_index(&mut index, cursor_store, source, resume, status).await
+                };
+                if let Err(e) = started {
+                    let _ = reply.send(Err(e));
+                    return;
+                }
+                tracing::info!(index = %index.name, tip = ?index.tip, "index added");
+                status
+                    .lock()
+                    .unwrap()
+                    .readers
+                    .insert(index.name.clone(), index.reader());
+                indexes.insert(index.name.clone(), *index);
+                let _ = reply.send(Ok(()));
+            }
+            IndexCommand::Remove(name, reply) => {
+                let removed = indexes.remove(&name).is_some();
+                pending.remove(&name);
+                {
+                    let mut status = status.lock().unwrap();
+                    status.readers.remove(&name);
+                    status.faulted.remove(&name);
+                    status.committed.remove(&name);
+                }
+                if removed {
+                    tracing::info!(index = %name, "index removed");
+                }
+                let _ = reply.send(removed);
+            }
+        }
+    }
+
+    /// Starts `index` over from `point`, as it asked to, or from wherever its `reset` asks
+    /// instead. It forgets everything, and its cursor is committed at that point straight away,
+    /// so it picks up from there even if we stop before it next commits.
+    async fn resync_index<CS: CursorStore>(
+        index: &mut IndexWrapper,
+        point: Point,
+        cursor_store: &mut CS,
+        source: &mut dyn ChainSource,
+        persist_dir: Option<&Path>,
+        status: &Mutex<IndexerStatus>,
+    ) -> Result<()> {
+        // An index which can only start over from somewhere in particular says so from `reset`.
+        let point = match index.index.write().await.reset().await {
+            Ok(()) => point,
+            Err(e) => e.downcast::<ResyncFrom>()?.point,
+        };
+        if let Intersect::NotFound { closest } = source.find_intersect(&point).await? {
+            return Err(StartPointNotFound {
+                index: index.name.clone(),
+                point,
+                closest,
+            }
+            .into());
+        }
+        cursor_store
+            .commit_batch(&[(index.name.clone(), point.clone())])
+            .await?;
+        let name = index.name.clone();
+        status.lock().unwrap().committed.insert(name, point.clone());
+        index
+            .applied
+            .store(point.slot_or_default(), atomic::Ordering::Relaxed);
+        index.history = vec![point.clone()];
+        index.tip = point;
+        index.events.clear();
+        index.batch.clear();
+        index.filter.matched = UtxoCache::default();
+        index.pruned_before = None;
+        if let Some(dir) = persist_dir {
+            persist_index(dir, index).await;
+        }
+        Ok(())
+    }
+
+    /// Gets `index` ready to sync, by starting it and working out where it picks up from.
+    /// An index which fails to start is faulted, rather than stopping every index.
+    async fn start_index<CS: CursorStore>(
+        index: &mut IndexWrapper,
+        cursor_store: &CS,
+        source: &mut dyn ChainSource,
+        resume: Resume<'_>,
+        status: &Mutex<IndexerStatus>,
+    ) -> Result<()> {
+        if let Err(e) = index.index.write().await.on_start().await {
+            tracing::error!(index = %index.name, error = format!("{e:#}"), "index faulted");
+            index.faulted = true;
+            let mut status = status.lock().unwrap();
+            status.faulted.insert(index.name.clone(), format!("{e:#}"));
+            return Ok(());
+        }
+        let resumed = if index.force_restart {
+            None
+        } else {
+            resume_point(index, cursor_store, resume).await?
+        };
+        match resumed {
+            Some((point, history)) => {
+                index.history = history;
+                index
+                    .applied
+                    .store(point.slot_or_default(), atomic::Ordering::Relaxed);
+                let name = index.name.clone();
+                status.lock().unwrap().committed.insert(name, point.clone());
+                index.tip = point;
+            }
+            // Nothing's checked that the start point is really on the chain,
+            // and it's much easier to explain here than after a failed intersect.
+            None => {
+                if let Intersect::NotFound { closest } = source.find_intersect(&index.tip).await? {
+                    return Err(StartPointNotFound {
+                        index: index.name.clone(),
+                        point: index.tip.clone(),
+                        closest,
+                    }
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Where a starting index can pick its state back up from.
+    #[derive(Clone, Copy)]
+    struct Resume<'a> {
+        // Whether to restore snapshots from the cursor store.
+        snapshots: bool,
+        persist_dir: Option<&'a Path>,
+    }
+
+    /// Where `index` left off last time, and the points committed before that, newest first,
+    /// restoring its snapshot if it keeps one. Returns `None` if it has to start over from its
+    /// start point.
+    async fn resume_point<CS: CursorStore>(
+        index: &mut IndexWrapper,
+        cursor_store: &CS,
+        resume: Resume<'_>,
+    ) -> Result<Option<(Point, Vec<Point>)>> {
+        if let Some(dir) = resume.persist_dir {
+            let Some(persisted) = read_persisted(dir, &index.name) else {
+                return Ok(None);
+            };
+            // Just like with the cursor store, state in memory needs the snapshot to go with it.
+            if index.snapshots {
+                let Some(snapshot) = &persisted.snapshot else {
+                    return Ok(None);
+                };
+                if let Err(e) = index.index.write().await.restore(snapshot) {
+                    tracing::warn!(index = %index.name, error = format!("{e:#}"), "could not restore the index, so replaying it");
+                    return Ok(None);
+                }
+            }
+            return Ok(Some((persisted.points[0].clone(), persisted.points)));
+        }
+        let Some(point) = cursor_store.get(&index.name).await? else {
+            return Ok(None);
+        };
+        // An index which snapshots itself keeps its state in memory,
+        // so its cursor is no use without a snapshot from the same point.
+        if resume.snapshots && index.snapshots {
+            let snapshot = cursor_store.load_snapshot(&index.name).await?;
+            let Some((_, snapshot)) = snapshot.filter(|(at, _)| *at == point) else {
+                return Ok(None);
+            };
+            if let Err(e) = index.index.write().await.restore(&snapshot) {
+                tracing::warn!(index = %index.name, error = format!("{e:#}"), "could not restore the index, so replaying it");
+                return Ok(None);
+            }
+        }
+        let history = cursor_store.history(&index.name).await?;
+        Ok(Some((point, history)))
+    }
+
+    // What `set_persist_dir` keeps for an index: its committed cursor, then the points committed
+    // before it, and a snapshot from the cursor if the index takes them.
+    struct Persisted {
+        points: Vec<Point>,
+        snapshot: Option<Vec<u8>>,
+    }
+
+    impl Persisted {
+        // A checksum of everything after it, then the length of the points as JSON,
+        // the points, and the snapshot.
+        fn encode(&self) -> Vec<u8> {
+            let points = self
+                .points
+                .iter()
+                .map(|p| match p {
+                    Point::Origin => serde_json::Value::Null,
+                    Point::Specific(slot, hash) => serde_json::json!([slot, hex::encode(hash)]),
+                })
+                .collect::<Vec<_>>();
+            let meta = serde_json::json!({ "points": points, "snapshot": self.snapshot.is_some() });
+            let meta = meta.to_string().into_bytes();
+            let mut body = (meta.len() as u32).to_be_bytes().to_vec();
+            body.extend(meta);
+            body.extend(self.snapshot.iter().flatten());
+            let mut bytes = Hasher::<256>::hash(&body).to_vec();
+            bytes.extend(body);
+            bytes
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Self> {
+            let (checksum, body) = bytes.split_at_checked(32).context("it's too short")?;
+            if Hasher::<256>::hash(body).as_slice() != checksum {
+                anyhow::bail!("its checksum doesn't match");
+            }
+            let (len, rest) = body.split_at_checked(4).context("it's too short")?;
+            let len = u32::from_be_bytes(len.try_into()?) as usize;
+            let (meta, snapshot) = rest.split_at_checked(len).context("it's too short")?;
+            let meta: serde_json::Value = serde_json::from_slice(meta)?;
+            let points = meta["points"]
+                .as_array()
+                .context("it has no points")?
+                .iter()
+                .map(|p| match p {
+                    serde_json::Value::Null => Ok(Point::Origin),
+                    p => {
+                        let slot = p[0].as_u64().context("a point has no slot")?;
+                        let hash = hex::decode(p[1].as_str().context("a point has no hash")?)?;
+                        Ok(Point::Specific(slot, hash))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if points.is_empty() {
+                anyhow::bail!("it has no points");
+            }
+            let snapshot = meta["snapshot"]
+                .as_bool()
+                .unwrap_or(false)
+                .then(|| snapshot.to_vec());
+            Ok(Self { points, snapshot })
+        }
+    }
+
+    fn persisted_path(dir: &Path, name: &str, suffix: &str) -> PathBuf {
+        dir.join(format!("{name}.index{suffix}"))
+    }
+
+    // Writes the new file out in full before anything is renamed, and only then moves the old
+    // one aside, so whenever we stop there's a whole file at one of the two paths.
+    fn write_persisted(dir: &Path, name: &str, persisted: &Persisted) -> Result<()> {
+        let path = persisted_path(dir, name, "");
+        let tmp = persisted_path(dir, name, ".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&persisted.encode())?;
+        file.sync_all()?;
+        if path.exists() {
+            std::fs::rename(&path, persisted_path(dir, name, ".prev"))?;
+        }
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    // The newest whole file persisted for an index, if there is one.
+    fn read_persisted(dir: &Path, name: &str) -> Option<Persisted> {
+        for suffix in ["", ".prev"] {
+            let path = persisted_path(dir, name, suffix);
+            let bytes = match std::fs::read(&path) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "could not read a persisted index");
+                    continue;
+                }
+            };
+            match Persisted::decode(&bytes) {
+                Ok(persisted) => return Some(persisted),
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = format!("{e:#}"), "ignoring a persisted index")
+                }
+            }
+        }
+        None
+    }
+
+    async fn persist_index(dir: &Path, index: &IndexWrapper) {
+        let snapshot = if index.snapshots {
+            match index.index.read().await.snapshot() {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    tracing::error!(index = %index.name, error = format!("{e:#}"), "could not snapshot the index");
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let persisted = Persisted {
+            points: index.history.clone(),
+            snapshot,
+        };
+        if let Err(e) = write_persisted(dir, &index.name, &persisted) {
+            tracing::error!(index = %index.name, error = format!("{e:#}"), "could not persist the index");
+        }
+    }
+
+    /// Snapshots every index which supports it, and whose state matches its committed cursor.
+    async fn take_snapshots<CS: CursorStore>(
+        indexes: &HashMap<String, IndexWrapper>,
+        cursor_store: &mut CS,
+    ) -> Result<()> {
+        for index in indexes.values().filter(|i| i.snapshots && !i.faulted) {
+            let committed = cursor_store
+                .get(&index.name)
+                .await
+                .context("could not fetch cursors")?;
+            if committed.as_ref() != Some(&index.tip) {
+                continue;
+            }
+            let snapshot = index.index.read().await.snapshot();
+            match snapshot {
+                Ok(Some(snapshot)) => cursor_store
+                    .save_snapshot(&index.name, &index.tip, snapshot)
+                    .await
+                    .context("couldn't save snapshot")?,
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!(index = %index.name, error = format!("{e:#}"), "could not snapshot the index")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The earliest of the given tips, which is where we need to sync from to serve all of them.
+    fn sync_point<'a>(indexes: impl Iterator<Item = &'a IndexWrapper>) -> Point {
+        indexes
+            .map(|i| &i.tip)
+            .min_by(|l, r| compare_points(l, r))
+            .cloned()
+            .unwrap_or(Point::Origin)
+    }
+
+    /// Where the furthest-behind index is up to, then the points committed before it, newest first,
+    /// so the node can still find somewhere to resume if the newest ones have been rolled back.
+    fn sync_points<'a>(indexes: impl Iterator<Item = &'a IndexWrapper> + Clone) -> Vec<Point> {
+        let from = sync_point(indexes.clone());
+        let mut points = indexes
+            .flat_map(|i| &i.history)
+            .filter(|p| compare_points(p, &from) == Ordering::Less)
+            .cloned()
+            .collect::<Vec<_>>();
+        points.sort_by(|l, r| compare_points(r, l));
+        points.dedup();
+        points.insert(0, from);
+        points.truncate(CURSOR_HISTORY);
+        points
+    }
+
+    /// Starts following the chain from wherever the indexes are up to. If the node had to pick
+    /// an older point than we asked for, returns the rollback the indexes missed.
+    async fn start_sync<'a>(
+        source: &mut dyn ChainSource,
+        indexes: impl Iterator<Item = &'a IndexWrapper> + Clone,
+    ) -> Result<Option<AcropolisMessage>> {
+        let points = sync_points(indexes.clone());
+        let at = source.sync_from(&points).await?;
+        if points.first() == Some(&at) {
+            return Ok(None);
+        }
+        // Everything after the point it picked is gone, starting with the oldest block we know of.
+        let discarded = indexes
+            .flat_map(|i| i.history.iter().chain([&i.tip]))
+            .filter(|p| compare_points(p, &at) == Ordering::Greater)
+            .min_by(|l, r| compare_points(l, r));
+        let Some(Point::Specific(slot, hash)) = discarded else {
+            return Ok(None);
+        };
+        let hash = core::BlockHash::try_from(hash.clone())
+            .map_err(|_| anyhow::anyhow!("malformed block hash {}", hex::encode(hash)))?;
+        // Cursors don't record heights, but nothing rolling back needs one.
+        Ok(Some(AcropolisMessage::Rollback(BlockInfo::new(
+            *slot, hash, 0,
+        ))))
+    }
+
+    fn record_progress(status: &Mutex<IndexerStatus>, indexes: &HashMap<String, IndexWrapper>) {
+        let mut status = status.lock().unwrap();
+        status.processed = indexes
+            .iter()
+            .map(|(name, index)| (name.clone(), index.tip.slot_or_default()))
+            .collect();
+        status.counters.processed_txs = indexes
+            .iter()
+            .map(|(name, index)| (name.clone(), index.filter.processed_txs))
+            .collect();
+        status.counters.block_durations = indexes
+            .iter()
+            .map(|(name, index)| (name.clone(), index.filter.timing.histogram.clone()))
+            .collect();
+    }
+
+    #[derive(Default)]
+    struct Processed {
+        advanced: Vec<String>,
+        // Index names, and the errors they failed with.
+        failed: Vec<(String, String)>,
+        // Index names, and the points they asked to start over from.
+        resync: Vec<(String, Point)>,
+    }
+
+    impl Processed {
+        fn extend(&mut self, other: Processed) {
+            self.advanced.extend(other.advanced);
+            self.failed.extend(other.failed);
+            self.resync.extend(other.resync);
+        }
+    }
+
+    /// A block we've received, along with everything about it which depended on the chain at the time.
+    struct PendingBlock {
+        info: BlockInfo,
+        block: Arc<DecodedBlock>,
+        resolved: Arc<[ResolvedInputs]>,
+        old_epoch: Option<u64>,
+        new_epoch: u64,
+    }
+
+    /// Blocks older than this mean we're still catching up with the chain.
+    const CATCH_UP_AGE: Duration = Duration::from_secs(10 * 60);
+    /// The most blocks an index which prefers batches gets at once.
+    const MAX_BATCH: usize = 100;
+
+    /// Hands a block to every index which is ready for it, or in a dry run, just shows it to them.
+    async fn apply_block<'a>(
+        indexes: impl Iterator<Item = &'a mut IndexWrapper>,
+        pending: &PendingBlock,
+        dry_run: bool,
+    ) -> Processed {
+        let block = pending.block.block();
+        let info = &pending.info;
+        let resolved = &pending.resolved;
+        let (old_epoch, new_epoch) = (pending.old_epoch, pending.new_epoch);
+        let at = Point::Specific(info.slot, info.hash.to_vec());
+        let catching_up = info.timestamp.elapsed().is_ok_and(|age| age > CATCH_UP_AGE);
+        let span = tracing::info_span!("block", slot = info.slot, height = info.height);
+        // Each index sees the whole block before its tip moves past it.
+        process_message(indexes, at, |x, filter, batch, _| async move {
+            // Hold the write lock for the whole block, so readers never see half of it.
+            let mut x = x.write().await;
+            if dry_run {
+                inspect_block(&*x, filter, info, block);
+                return Ok(x);
+            }
+            let batching = catching_up && x.prefers_batches();
+            // A batch never spans an epoch boundary, and has to be done with
+            // before we go back to handling blocks one at a time.
+            if !batching || old_epoch.is_some() {
+                flush_batch(&mut *x, filter, batch).await?;
+            }
+            // Only time the index itself, not however long the lock took to get.
+            let started = Instant::now();
+            if let Some(old_epoch) = old_epoch {
+                x.on_epoch_boundary(old_epoch, new_epoch).await?;
+            }
+            if batching {
+                batch.push((info.clone(), pending.block.clone(), resolved.clone()));
+                if batch.len() >= MAX_BATCH {
+                    flush_batch(&mut *x, filter, batch).await?;
+                }
+            } else {
+                let cbor = Some(pending.block.cbor());
+                feed_block(&mut *x, filter, info, block, cbor, resolved).await?;
+                let elapsed = started.elapsed();
+                filter.timing.record(&x.name(), info.slot, elapsed);
+            }
+            Ok(x)
+        })
+        .instrument(span)
+        .await
+    }
+
+    /// Runs one block through `index`'s filter and `inspect_tx`, and logs what it would have indexed.
+    fn inspect_block<I: ManagedIndex + ?Sized>(
+        index: &I,
+        filter: &mut IndexFilter,
+        info: &BlockInfo,
+        block: &MultiEraBlock<'_>,
+    ) {
+        let mut tally = DryRunTally::default();
+        for tx in block.txs() {
+            if !filter.admit(info.slot, &tx) {
+                continue;
+            }
+            tally.txs += 1;
+            tally.outputs += tx_outputs(&tx)
+                .filter(|(_, o)| filter.filter.matches_output(o))
+                .count() as u64;
+            let inspection = index.inspect_tx(info, &tx);
+            tally.inspection.parsed += inspection.parsed;
+            tally.inspection.failed += inspection.failed;
+        }
+        if !tally.is_empty() {
+            tracing::info!(index = %index.name(), slot = info.slot, "dry run would index {tally}");
+        }
+        filter.dry_run.add(&tally);
+    }
+
+    /// Hands an index the blocks it's been saving up for a batch, through its filter.
+    /// The time the batch took is shared out evenly between its blocks.
+    async fn flush_batch(
+        index: &mut dyn ManagedIndex,
+        filter: &mut IndexFilter,
+        batch: &mut Batch,
+    ) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        if index.wants_raw_cbor() {
+            for (info, block, _) in batch.iter() {
+                index
+                    .handle_raw_block(info, block.block(), block.cbor())
+                    .await?;
+            }
+        }
+        let needs_resolved_inputs = index.needs_resolved_inputs();
+        let blocks = batch
+            .iter()
+            .map(|(info, block, resolved)| BatchedBlock {
+                info,
+                block: block.block(),
+                admitted: admit_txs(filter, info, block.block()),
+                resolved: if needs_resolved_inputs { resolved } else { &[] },
+            })
+            .collect::<Vec<_>>();
+        index.handle_block_batch(&blocks).await?;
+        let each = started.elapsed() / blocks.len() as u32;
+        let name = index.name();
+        for batched in &blocks {
+            filter.timing.record(&name, batched.info.slot, each);
+        }
+        drop(blocks);
+        batch.clear();
+        Ok(())
+    }
+
+    /// Runs one block through every hook of `index`, skipping the txs its filter doesn't admit.
+    /// The raw hook only gets called if we're given the block's CBOR.
+    async fn feed_block<I: ManagedIndex + ?Sized>(
+        index: &mut I,
+        filter: &mut IndexFilter,
+        info: &BlockInfo,
+        block: &MultiEraBlock<'_>,
+        cbor: Option<&[u8]>,
+        resolved: &[ResolvedInputs],
+    ) -> Result<()> {
+        if let Some(cbor) = cbor.filter(|_| index.wants_raw_cbor()) {
+            index.handle_raw_block(info, block, cbor).await?;
+        }
+        let admitted = admit_txs(filter, info, block);
+        feed_txs(index, info, block, &admitted, resolved).await
+    }
+
+    /// Which of a block's txs get through `filter`, counting them as processed.
+    fn admit_txs(
+        filter: &mut IndexFilter,
+        info: &BlockInfo,
+        block: &MultiEraBlock<'_>,
+    ) -> Vec<bool> {
+        let admitted = block
+            .txs()
+            .iter()
+            .map(|tx| filter.admit(info.slot, tx))
+            .collect::<Vec<_>>();
+        filter.processed_txs += admitted.iter().filter(|a| **a).count() as u64;
+        filter.matched.prune(info.slot);
+        admitted
+    }
+
+    /// Runs one block through the per-block hook, and the txs `admitted` says to through the
+    /// per-tx hooks.
+    async fn feed_txs<I: ManagedIndex + ?Sized>(
+        index: &mut I,
+        info: &BlockInfo,
+        block: &MultiEraBlock<'_>,
+        admitted: &[bool],
+        resolved: &[ResolvedInputs],
+    ) -> Result<()> {
+        index.handle_block(info, block).await?;
+        let retry = index.retry_policy();
+        for (i, tx) in block.txs().iter().enumerate() {
+            if !admitted.get(i).copied().unwrap_or(true) {
+                continue;
+            }
+            let mut attempt = 0;
+            loop {
+                let handled = match resolved.get(i) {
+                    Some(inputs) if index.needs_resolved_inputs() => {
+                        index.handle_onchain_tx_with_inputs(info, tx, inputs).await
+                    }
+                    _ => index.handle_onchain_tx(info, tx).await,
+                };
+                match handled {
+                    Ok(()) => break,
+                    Err(e) => retry.wait(&mut attempt, e).await?,
+                }
+            }
+            // A tx whose scripts failed only took its collateral. Nothing else it says happened.
+            if !tx.is_valid() {
+                continue;
+            }
+            for mint in tx.mints() {
+                index
+                    .handle_mint(info, mint.policy(), &mint.assets())
+                    .await?;
+            }
+            let metadata = tx.metadata();
+            if let Some(metadata) = metadata.as_alonzo()
+                && !metadata.is_empty()
+            {
+                index.handle_metadata(info, tx, metadata).await?;
+            }
+            for cert in tx.certs() {
+                index.handle_certificate(info, &cert).await?;
+            }
+            for (reward_account, amount) in tx.withdrawals_sorted_set() {
+                index
+                    .handle_withdrawal(info, reward_account, amount)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs recorded blocks (CBOR files, in chain order) straight through `index`,
+    /// the same way the indexer would, for reproducing what it does with real blocks.
+    #[cfg(test)]
+    pub async fn replay_blocks<I: ManagedIndex>(
+        index: &mut I,
+        chain_params: &ChainParams,
+        fixtures: &[std::path::PathBuf],
+    ) -> Result<()> {
+        let blocks = fixtures
+            .iter()
+            .map(|path| {
+                let cbor = std::fs::read(path)
+                    .with_context(|| format!("could not read {}", path.display()))?;
+                Ok((path.display().to_string(), cbor))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        replay_cbor(index, chain_params, &blocks).await
+    }
+
+    /// Like `replay_blocks`, but with the blocks already read, each along with a name to
+    /// report it by.
+    #[cfg(test)]
+    pub async fn replay_cbor<I: ManagedIndex>(
+        index: &mut I,
+        chain_params: &ChainParams,
+        blocks: &[(String, Vec<u8>)],
+    ) -> Result<()> {
+        let mut filter = IndexFilter::new(index.tx_filter());
+        let mut utxos = UtxoCache::default();
+        for (path, cbor) in blocks {
+            let block =
+                MultiEraBlock::decode(cbor).with_context(|| format!("{path} isn't a block"))?;
+            let hash = core::BlockHash::try_from(block.hash().to_vec())
+                .map_err(|_| anyhow::anyhow!("{path} has a malformed hash"))?;
+            let mut info = BlockInfo::new(block.slot(), hash, block.number());
+            info.locate(chain_params);
+            let resolved = if index.needs_resolved_inputs() {
+                let wanted = [index.resolved_outputs()];
+                let resolved = block
+                    .txs()
+                    .iter()
+                    .map(|tx| utxos.apply(info.slot, tx, &wanted))
+                    .collect();
+                utxos.prune(info.slot);
+                resolved
+            } else {
+                vec![]
+            };
+            feed_block(index, &mut filter, &info, &block, Some(cbor), &resolved)
+                .await
+                .with_context(|| format!("{} failed on {path}", index.name()))?;
+        }
+        Ok(())
+    }
+
+    /// What the indexes which haven't faulted want resolved, if any want inputs resolved.
+    fn outputs_to_resolve(indexes: &HashMap<String, IndexWrapper>) -> Vec<ResolvedOutputs> {
+        indexes
+            .values()
+            .filter(|i| !i.faulted)
+            .filter_map(|i| i.resolved_outputs.clone())
+            .collect()
+    }
+
+    /// Runs `f` against every index in `indexes` which hasn't faulted,
+    /// and reports which ones advanced to `at` and which ones failed.
+    /// Indexes run concurrently, so a slow one only costs its own time, and every index gets to
+    /// finish even if another fails (which is why this isn't a `try_join_all`).
+    // `f` hands back the index's lock once it's done, so the tip can move before anyone reads the index.
+    async fn process_message<'a, F, Fut>(
+        indexes: impl Iterator<Item = &'a mut IndexWrapper>,
+        at: Point,
+        f: F,
+    ) -> Processed
+    where
+        F: Fn(&'a RwLock<dyn ManagedIndex>, &'a mut IndexFilter, &'a mut Batch, Point) -> Fut,
+        Fut: Future<Output = Result<RwLockWriteGuard<'a, dyn ManagedIndex>>>,
+    {
+        let mut fut = FuturesUnordered::new();
+        for index in indexes {
+            if !index.faulted {
+                let span = tracing::info_span!("index", name = %index.name);
+                fut.push(
+                    async {
+                        let tip = index.tip.clone();
+                        let mut processed = Processed::default();
+                        match f(&index.index, &mut index.filter, &mut index.batch, tip).await {
+                            Ok(mut x) => {
+                                index.events.extend(x.drain_events());
+                                index.tip = at.clone();
+                                index
+                                    .applied
+                                    .store(at.slot_or_default(), atomic::Ordering::Relaxed);
+                                processed.advanced.push(index.name.clone());
+                            }
+                            Err(e) if e.is::<ResyncFrom>() => {
+                                let ResyncFrom { point } = e.downcast().unwrap();
+                                tracing::warn!(?point, "starting over");
+                                processed.resync.push((index.name.clone(), point));
+                            }
+                            Err(e) => {
+                                tracing::error!(error = format!("{e:#}"), "index faulted");
+                                index.faulted = true;
+                                processed
+                                    .failed
+                                    .push((index.name.clone(), format!("{e:#}")));
+                            }
+                        }
+                        processed
+                    }
+                    .instrument(span),
+                );
+            }
+        }
+        let mut processed = Processed::default();
+        while let Some(result) = fut.next().await {
+            processed.extend(result);
+        }
+        processed
+    }
+
+    /// Checkpoints every index with progress since its last commit, then commits their cursors.
+    /// If any index fails to checkpoint, nothing is committed, and the failures are returned.
+    /// Given a volatile window, each index is also pruned back to that far behind its new cursor.
+    async fn commit_pending<CS: CursorStore>(
+        indexes: &mut HashMap<String, IndexWrapper>,
+        pending: &mut HashMap<String, Point>,
+        cursor_store: &mut CS,
+        sinks: &mut [Box<dyn EventSink>],
+        volatile_window: Option<u64>,
+        persist_dir: Option<&Path>,
+        status: &Mutex<IndexerStatus>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut fut = FuturesUnordered::new();
+        for index in indexes
+            .values_mut()
+            .filter(|i| pending.contains_key(&i.name))
+        {
+            let cutoff = volatile_window
+                .and_then(|w| pending[&index.name].slot_or_default().checked_sub(w))
+                .filter(|c| index.pruned_before.is_none_or(|p| p < *c));
+            fut.push(async move {
+                let mut x = index.index.write().await;
+                let mut result =
+                    match flush_batch(&mut *x, &mut index.filter, &mut index.batch).await {
+                        Ok(()) => x.checkpoint().await,
+                        Err(e) => Err(e),
+                    };
+                if let (Ok(()), Some(cutoff)) = (&result, cutoff) {
+                    result = x.prune(cutoff).await;
+                    if result.is_ok() {
+                        index.pruned_before = Some(cutoff);
+                    }
+                }
+                result.map_err(|e| {
+                    tracing::error!(index = %index.name, error = format!("{e:#}"), "index faulted");
+                    index.faulted = true;
+                    (index.name.clone(), format!("{e:#}"))
+                })
+            });
+        }
+        let mut failed = vec![];
+        while let Some(result) = fut.next().await {
+            if let Err(failure) = result {
+                failed.push(failure);
+            }
+        }
+        drop(fut);
+        if !failed.is_empty() {
+            pending.retain(|name, _| !indexes[name].faulted);
+            return Ok(failed);
+        }
+        let updates = pending.drain().collect::<Vec<_>>();
+        cursor_store
+            .commit_batch(&updates)
+            .await
+            .context("couldn't save cursors")?;
+        status
+            .lock()
+            .unwrap()
+            .committed
+            .extend(updates.iter().cloned());
+        for (name, tip) in updates {
+            let index = indexes.get_mut(&name).unwrap();
+            index.history.insert(0, tip);
+            index.history.truncate(CURSOR_HISTORY);
+            if let Some(dir) = persist_dir {
+                persist_index(dir, index).await;
+            }
+            let events = std::mem::take(&mut index.events);
+            if events.is_empty() {
+                continue;
+            }
+            for sink in sinks.iter_mut() {
+                // The blocks are committed either way, so there's no taking these back.
+                if let Err(e) = sink.publish(&name, &events).await {
+                    tracing::error!(index = %name, error = format!("{e:#}"), "could not publish events");
+                }
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Tells every index that we've caught up with the chain.
+    async fn reach_tip<'a>(indexes: impl Iterator<Item = &'a mut IndexWrapper>) -> Processed {
+        let mut fut = FuturesUnordered::new();
+        for index in indexes.filter(|i| !i.faulted) {
+            fut.push(async {
+                let mut x = index.index.write().await;
+                // Anything still batched up is the last of the catch-up.
+                let result = match flush_batch(&mut *x, &mut index.filter, &mut index.batch).await {
+                    Ok(()) => x.on_tip_reached().await,
+                    Err(e) => Err(e),
+                };
+                result.map_err(|e| {
+                    tracing::error!(index = %index.name, error = format!("{e:#}"), "index faulted");
+                    index.faulted = true;
+                    (index.name.clone(), format!("{e:#}"))
+                })
+            });
+        }
+        let mut processed = Processed::default();
+        while let Some(result) = fut.next().await {
+            if let Err(failure) = result {
+                processed.failed.push(failure);
+            }
+        }
+        processed
+    }
+
+    fn compare_points(lhs: &Point, rhs: &Point) -> Ordering {
+        lhs.slot_or_default().cmp(&rhs.slot_or_default())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::testing;
+
+        // Remembers the slot of every block it's handled, and fails on the block at `fail_at`.
+        // Each block takes it `delay`.
+        struct Recorder {
+            name: String,
+            slots: Vec<u64>,
+            fail_at: Option<u64>,
+            delay: Duration,
+            // When each block's `handle_block` started and finished.
+            spans: Vec<(Instant, Instant)>,
+        }
+
+        impl Recorder {
+            fn new(name: &str) -> Self {
+                Self {
+                    name: name.into(),
+                    slots: vec![],
+                    fail_at: None,
+                    delay: Duration::ZERO,
+                    spans: vec![],
+                }
+            }
+
+            fn failing_at(mut self, slot: u64) -> Self {
+                self.fail_at = Some(slot);
+                self
+            }
+
+            fn taking(mut self, delay: Duration) -> Self {
+                self.delay = delay;
+                self
+            }
+        }
+
+        #[async_trait]
+        impl ManagedIndex for Recorder {
+            fn name(&self) -> String {
+                self.name.clone()
+            }
+
+            async fn handle_block(&mut self, info: &BlockInfo, _: &MultiEraBlock) -> Result<()> {
+                let started = Instant::now();
+                tokio::time::sleep(self.delay).await;
+                if self.fail_at == Some(info.slot) {
+                    anyhow::bail!("failed at slot {}", info.slot);
+                }
+                self.slots.push(info.slot);
+                self.spans.push((started, Instant::now()));
+                Ok(())
+            }
+
+            async fn handle_rollback(&mut self, info: &BlockInfo) -> Result<()> {
+                self.slots.retain(|slot| *slot < info.slot);
+                Ok(())
+            }
+
+            async fn reset(&mut self) -> Result<()> {
+                self.slots.clear();
+                Ok(())
+            }
+        }
+
+        fn point(info: &BlockInfo) -> Point {
+            info.hash.to_point(info.slot)
+        }
+
+        type Batches = Arc<Mutex<Vec<Vec<(String, Point)>>>>;
+
+        // An in-memory store which keeps a copy of every batch committed to it, bar empty ones.
+        struct RecordingStore {
+            inner: InMemoryCursorStore,
+            batches: Batches,
+        }
+
+        impl RecordingStore {
+            fn new() -> (Self, Batches) {
+                let batches = Arc::new(Mutex::new(vec![]));
+                let store = Self {
+                    inner: InMemoryCursorStore::new(vec![]),
+                    batches: batches.clone(),
+                };
+                (store, batches)
+            }
+        }
+
+        impl CursorStore for RecordingStore {
+            async fn get(&self, name: &str) -> Result<Option<Point>> {
+                self.inner.get(name).await
+            }
+
+            async fn latest(&self) -> Result<BTreeMap<String, Point>> {
+                self.inner.latest().await
+            }
+
+            async fn commit_batch(&mut self, updates: &[(String, Point)]) -> Result<()> {
+                let mut batch = updates.to_vec();
+                batch.sort_by(|l, r| l.0.cmp(&r.0));
+                if !batch.is_empty() {
+                    self.batches.lock().unwrap().push(batch);
+                }
+                self.inner.commit_batch(updates).await
+            }
+        }
+
+        #[tokio::test]
+        async fn each_index_commits_its_own_cursor() {
+            let blocks = testing::chain(&[10, 20, 30, 40]);
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            let ahead = indexer
+                .add_index(Recorder::new("ahead"), Point::Origin, false, 0)
+                .unwrap();
+            let behind = indexer
+                .add_index(
+                    Recorder::new("behind").failing_at(30),
+                    Point::Origin,
+                    false,
+                    0,
+                )
+                .unwrap();
+            let handle = testing::sync(indexer, testing::roll_forward(&blocks))
+                .await
+                .unwrap();
+
+            let cursors = handle.committed_cursors();
+            assert_eq!(cursors["ahead"], point(&blocks[3].0));
+            assert_eq!(cursors["behind"], point(&blocks[1].0));
+            assert_eq!(ahead.read().await.slots, [10, 20, 30, 40]);
+            assert_eq!(behind.read().await.slots, [10, 20]);
+            let faulted = handle.faulted_indexes();
+            assert_eq!(faulted.len(), 1);
+            assert_eq!(faulted[0].0, "behind");
+        }
+
+        #[tokio::test]
+        async fn a_block_one_index_fails_on_commits_no_cursors() {
+            let blocks = testing::chain(&[10, 20, 30, 40]);
+            let (store, batches) = RecordingStore::new();
+            let mut indexer = ChainIndexer::new(store);
+            indexer
+                .add_index(Recorder::new("first"), Point::Origin, false, 0)
+                .unwrap();
+            indexer
+                .add_index(
+                    Recorder::new("second").failing_at(30),
+                    Point::Origin,
+                    false,
+                    0,
+                )
+                .unwrap();
+            testing::sync(indexer, testing::roll_forward(&blocks))
+                .await
+                .unwrap();
+
+            // Nothing was committed for slot 30, not even for the index which handled it.
+            let at = |name: &str, i: usize| (name.to_string(), point(&blocks[i].0));
+            assert_eq!(
+                *batches.lock().unwrap(),
+                [
+                    vec![at("first", 0), at("second", 0)],
+                    vec![at("first", 1), at("second", 1)],
+                    vec![at("first", 3)],
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn a_scripted_rollback_replaces_the_blocks_it_discards() {
+            let blocks = testing::chain(&[10, 20, 30]);
+            let fork_30 = testing::fork_block(30, Some(&blocks[1].0), &[]);
+            let fork_40 = testing::block(40, Some(&fork_30.0), &[]);
+            let mut events = testing::roll_forward(&blocks);
+            events.push(ChainEvent::RollBackward(point(&blocks[1].0)));
+            events.extend(testing::roll_forward(&[fork_30.clone(), fork_40.clone()]));
+
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            let recorder = indexer
+                .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                .unwrap();
+            let handle = testing::sync(indexer, events).await.unwrap();
+
+            // Had the first 30 not been rolled back, it would be in there twice.
+            assert_eq!(recorder.read().await.slots, [10, 20, 30, 40]);
+            assert_ne!(point(&fork_30.0), point(&blocks[2].0));
+            assert_eq!(
+                handle.committed_cursors().get("recorder"),
+                Some(&point(&fork_40.0))
+            );
+        }
+
+        // Syncs a strict indexer over `events`, and returns what it stopped at, and the slots
+        // its index saw.
+        async fn strict_sync(events: Vec<ChainEvent>) -> (Option<ContinuityError>, Vec<u64>) {
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            indexer.set_strict_continuity(true);
+            let recorder = indexer
+                .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                .unwrap();
+            let handle = testing::sync(indexer, events).await.unwrap();
+            let slots = recorder.read().await.slots.clone();
+            (handle.continuity_error(), slots)
+        }
+
+        #[tokio::test]
+        async fn the_worst_lag_is_the_index_furthest_behind() {
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            assert_eq!(indexer.handle().worst_lag(), None);
+            indexer
+                .add_index(Recorder::new("keeping-up"), Point::Origin, false, 0)
+                .unwrap();
+            indexer
+                .add_index(
+                    Recorder::new("stuck").failing_at(20),
+                    Point::Origin,
+                    false,
+                    0,
+                )
+                .unwrap();
+            let blocks = testing::chain(&[10, 20, 30, 40]);
+            let handle = testing::sync(indexer, testing::roll_forward(&blocks))
+                .await
+                .unwrap();
+            // The stuck index never got past 10, and the tip is at 40.
+            assert_eq!(handle.worst_lag(), Some(("stuck".into(), 30)));
+        }
+
+        #[tokio::test]
+        async fn strict_mode_lets_a_byron_block_share_its_epoch_boundarys_slot() {
+            let before = testing::block(21_599, None, &[]);
+            let boundary = testing::epoch_boundary_block(1, &before.0);
+            let after = testing::byron_block(1, 0, &boundary.0, &[]);
+            assert_eq!(boundary.0.slot, after.0.slot);
+            let (error, slots) =
+                strict_sync(testing::roll_forward(&[before, boundary, after])).await;
+            assert!(error.is_none(), "{error:?}");
+            assert_eq!(slots, [21_599, 21_600, 21_600]);
+        }
+
+        // Syncs over a block from slot 20 that can't be decoded, between two that can, and
+        // returns the slots the index saw and where its cursor was left.
+        async fn sync_past_an_undecodable_block(policy: UnsupportedEra) -> (Vec<u64>, Point) {
+            let before = testing::block(10, None, &[]);
+            let undecodable = (
+                BlockInfo::new(20, core::BlockHash::try_from(vec![0x20; 32]).unwrap(), 1),
+                vec![0x82, 0x0f],
+            );
+            let after = testing::block(30, Some(&undecodable.0), &[]);
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            indexer.set_on_unsupported_era(policy);
+            let recorder = indexer
+                .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                .unwrap();
+            let handle = testing::sync(
+                indexer,
+                testing::roll_forward(&[before, undecodable, after]),
+            )
+            .await
+            .unwrap();
+            let slots = recorder.read().await.slots.clone();
+            (slots, handle.committed_cursors()["recorder"].clone())
+        }
+
+        #[tokio::test]
+        async fn an_undecodable_block_stops_the_sync_by_default() {
+            let (slots, cursor) = sync_past_an_undecodable_block(UnsupportedEra::default()).await;
+            assert_eq!(slots, [10]);
+            assert_eq!(cursor.slot_or_default(), 10);
+        }
+
+        #[tokio::test]
+        async fn an_undecodable_block_can_be_skipped() {
+            let (slots, cursor) = sync_past_an_undecodable_block(UnsupportedEra::Skip).await;
+            assert_eq!(slots, [10, 30]);
+            assert_eq!(cursor.slot_or_default(), 30);
+        }
+
+        #[tokio::test]
+        async fn strict_mode_stops_at_a_missing_block() {
+            let blocks = testing::chain(&[10, 20, 30, 40]);
+            let skipping = [&blocks[0], &blocks[1], &blocks[3]].map(|b| b.clone());
+            let (error, slots) = strict_sync(testing::roll_forward(&skipping)).await;
+            let error = error.expect("the gap went unnoticed");
+            assert_eq!(error.expected, point(&blocks[1].0));
+            assert_eq!(error.got, point(&blocks[3].0));
+            assert_eq!(slots, [10, 20]);
+
+            // Without strict mode, the index is none the wiser.
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            let recorder = indexer
+                .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                .unwrap();
+            let handle = testing::sync(indexer, testing::roll_forward(&skipping))
+                .await
+                .unwrap();
+            assert!(handle.continuity_error().is_none());
+            assert_eq!(recorder.read().await.slots, [10, 20, 40]);
+        }
+
+        #[tokio::test]
+        async fn strict_mode_stops_at_a_block_from_the_past() {
+            let blocks = testing::chain(&[10, 20]);
+            // It names the tip as its parent, but comes from an earlier slot.
+            let earlier = testing::block(15, Some(&blocks[1].0), &[]);
+            let mut events = testing::roll_forward(&blocks);
+            events.extend(testing::roll_forward(std::slice::from_ref(&earlier)));
+            let (error, slots) = strict_sync(events).await;
+            assert_eq!(error.map(|e| e.got), Some(point(&earlier.0)));
+            assert_eq!(slots, [10, 20]);
+        }
+
+        #[tokio::test]
+        async fn strict_mode_follows_a_rollback_onto_a_fork() {
+            let blocks = testing::chain(&[10, 20, 30]);
+            let fork_30 = testing::fork_block(30, Some(&blocks[1].0), &[]);
+            let mut events = testing::roll_forward(&blocks);
+            events.push(ChainEvent::RollBackward(point(&blocks[1].0)));
+            events.extend(testing::roll_forward(&[fork_30]));
+            let (error, slots) = strict_sync(events).await;
+            assert!(error.is_none(), "{error:?}");
+            assert_eq!(slots, [10, 20, 30]);
+        }
+
+        #[tokio::test]
+        async fn slow_indexes_take_as_long_as_the_slowest_not_all_of_them_together() {
+            let blocks = testing::chain(&[10, 20, 30, 40, 50]);
+            let delay = Duration::from_millis(60);
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            let slow = indexer
+                .add_index(Recorder::new("slow").taking(delay), Point::Origin, false, 0)
+                .unwrap();
+            let also_slow = indexer
+                .add_index(
+                    Recorder::new("also slow").taking(delay),
+                    Point::Origin,
+                    false,
+                    0,
+                )
+                .unwrap();
+            let fast = indexer
+                .add_index(Recorder::new("fast"), Point::Origin, false, 0)
+                .unwrap();
+            testing::sync(indexer, testing::roll_forward(&blocks))
+                .await
+                .unwrap();
+
+            for index in [&slow, &also_slow, &fast] {
+                assert_eq!(index.read().await.slots, [10, 20, 30, 40, 50]);
+            }
+            // Each block was being applied by both slow indexes at once, rather than one
+            // after the other.
+            let (slow, also_slow) = (slow.read().await, also_slow.read().await);
+            for (a, b) in slow.spans.iter().zip(&also_slow.spans) {
+                assert!(a.0 < b.1 && b.0 < a.1, "{a:?} and {b:?} don't overlap");
+            }
+        }
+
+        // Plays `events` like MockChainSource, but drops the connection once, after `drop_after`
+        // messages. Each connection starts over from the script, as a new node session would.
+        struct Flaky {
+            events: Vec<ChainEvent>,
+            connection: Option<MockChainSource>,
+            drop_after: Option<usize>,
+            sent: usize,
+        }
+
+        impl Flaky {
+            fn new(events: Vec<ChainEvent>, drop_after: usize) -> Self {
+                Self {
+                    events,
+                    connection: None,
+                    drop_after: Some(drop_after),
+                    sent: 0,
+                }
+            }
+        }
+
+        #[async_trait]
+        impl ChainSource for Flaky {
+            async fn sync_from(&mut self, points: &[Point]) -> Result<Point> {
+                let connection = self
+                    .connection
+                    .insert(MockChainSource::new(self.events.clone()));
+                self.sent = 0;
+                connection.sync_from(points).await
+            }
+
+            async fn next(&mut self) -> Result<AcropolisMessage> {
+                if self.drop_after == Some(self.sent) {
+                    self.drop_after = None;
+                    self.connection = None;
+                    anyhow::bail!("connection reset");
+                }
+                let Some(connection) = &mut self.connection else {
+                    anyhow::bail!("not connected");
+                };
+                self.sent += 1;
+                connection.next().await
+            }
+
+            async fn find_intersect(&mut self, point: &Point) -> Result<Intersect> {
+                MockChainSource::new(self.events.clone())
+                    .find_intersect(point)
+                    .await
+            }
+        }
+
+        #[tokio::test]
+        async fn a_dropped_connection_picks_up_where_it_left_off() {
+            let blocks = testing::chain(&[10, 20, 30, 40]);
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            indexer.set_reconnect_policy(ReconnectPolicy {
+                max_retries: 3,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+            });
+            let recorder = indexer
+                .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                .unwrap();
+            let source = Flaky::new(testing::roll_forward(&blocks), 2);
+            let handle = testing::sync_from_source(indexer, source).await.unwrap();
+
+            // Every block once, none missed and none twice.
+            assert_eq!(recorder.read().await.slots, [10, 20, 30, 40]);
+            assert_eq!(
+                handle.committed_cursors().get("recorder"),
+                Some(&point(&blocks[3].0))
+            );
+        }
+
+        #[tokio::test]
+        async fn a_rollback_to_origin_empties_an_index_but_leaves_it_running() {
+            // With a volatile window, the index has forgotten how to undo the early blocks,
+            // so it's reset rather than rolled back; either way it ends up empty.
+            for window in [None, Some(15)] {
+                let blocks = testing::chain(&[10, 20, 30, 40]);
+                let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+                if let Some(window) = window {
+                    indexer.set_volatile_window(window);
+                }
+                let recorder = indexer
+                    .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                    .unwrap();
+                let mut events = testing::roll_forward(&blocks);
+                events.push(ChainEvent::RollBackward(Point::Origin));
+                let handle = testing::sync(indexer, events.clone()).await.unwrap();
+                assert!(recorder.read().await.slots.is_empty(), "{window:?}");
+                assert!(handle.faulted_indexes().is_empty(), "{window:?}");
+
+                // And it carries on with the new chain.
+                let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+                if let Some(window) = window {
+                    indexer.set_volatile_window(window);
+                }
+                let recorder = indexer
+                    .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                    .unwrap();
+                let new_chain = testing::fork_block(50, None, &[]);
+                events.push(ChainEvent::RollForward(new_chain.0.clone(), new_chain.1));
+                let handle = testing::sync(indexer, events).await.unwrap();
+                assert_eq!(recorder.read().await.slots, [50], "{window:?}");
+                assert_eq!(
+                    handle.committed_cursors().get("recorder"),
+                    Some(&point(&new_chain.0))
+                );
+            }
+        }
+
+        #[tokio::test]
+        async fn a_fork_block_before_the_discarded_one_is_still_applied() {
+            // With nothing before the rolled-back block but the origin, the index has to
+            // go back there, or the fork's first block, at a lower slot, looks old.
+            let first = testing::block(30, None, &[]);
+            let fork = testing::fork_block(20, None, &[]);
+            let mut events = testing::roll_forward(&[first]);
+            events.push(ChainEvent::RollBackward(Point::Origin));
+            events.push(ChainEvent::RollForward(fork.0.clone(), fork.1));
+
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            let recorder = indexer
+                .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                .unwrap();
+            let handle = testing::sync(indexer, events).await.unwrap();
+            assert_eq!(recorder.read().await.slots, [20]);
+            assert_eq!(
+                handle.committed_cursors().get("recorder"),
+                Some(&point(&fork.0))
+            );
+        }
+
+        #[tokio::test]
+        async fn a_closure_index_counts_txs_and_forgets_rolled_back_slots() {
+            let tx = |fee| testing::Tx::new().fee(fee);
+            let first = testing::block(10, None, &[tx(1), tx(2)]);
+            let second = testing::block(20, Some(&first.0), &[tx(3)]);
+            let third = testing::block(30, Some(&second.0), &[tx(4), tx(5), tx(6)]);
+            let fork = testing::fork_block(25, Some(&second.0), &[tx(7)]);
+
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            let counts = indexer
+                .add_index(
+                    FnIndex::<BTreeMap<u64, u64>>::new(
+                        "count",
+                        |info, _tx, counts| {
+                            *counts.entry(info.slot).or_default() += 1;
+                            Ok(())
+                        },
+                        |info, counts| {
+                            counts.split_off(&info.slot);
+                            Ok(())
+                        },
+                    ),
+                    Point::Origin,
+                    false,
+                    0,
+                )
+                .unwrap();
+            let mut events = testing::roll_forward(&[first, second.clone(), third]);
+            events.push(ChainEvent::RollBackward(point(&second.0)));
+            events.push(ChainEvent::RollForward(fork.0, fork.1));
+            let handle = testing::sync(indexer, events).await.unwrap();
+            assert!(handle.faulted_indexes().is_empty());
+            assert_eq!(
+                *counts.read().await.state(),
+                BTreeMap::from([(10, 2), (20, 1), (25, 1)])
+            );
+        }
+
+        // Fails each call to its tx and rollback hooks `failures` times before it goes through,
+        // like a database that's locked for a moment. Keeps the slot of every tx it's handled.
+        struct FlakyIndex {
+            max_attempts: u32,
+            failures: u32,
+            failed: u32,
+            calls: u32,
+            txs: Vec<u64>,
+        }
+
+        impl FlakyIndex {
+            fn new(failures: u32, max_attempts: u32) -> Self {
+                Self {
+                    max_attempts,
+                    failures,
+                    failed: 0,
+                    calls: 0,
+                    txs: vec![],
+                }
+            }
+
+            fn flake(&mut self) -> Result<()> {
+                self.calls += 1;
+                if self.failed < self.failures {
+                    self.failed += 1;
+                    anyhow::bail!("database is locked");
+                }
+                self.failed = 0;
+                Ok(())
+            }
+        }
+
+        #[async_trait]
+        impl ManagedIndex for FlakyIndex {
+            fn name(&self) -> String {
+                "flaky".into()
+            }
+
+            async fn handle_onchain_tx(&mut self, info: &BlockInfo, _: &MultiEraTx) -> Result<()> {
+                self.flake()?;
+                self.txs.push(info.slot);
+                Ok(())
+            }
+
+            async fn handle_rollback(&mut self, info: &BlockInfo) -> Result<()> {
+                self.flake()?;
+                self.txs.retain(|slot| *slot < info.slot);
+                Ok(())
+            }
+
+            fn retry_policy(&self) -> RetryPolicy {
+                RetryPolicy {
+                    max_attempts: self.max_attempts,
+                    backoff: Duration::from_millis(1),
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn a_flaky_index_is_retried_until_it_goes_through() {
+            let tx = |fee| testing::Tx::new().fee(fee);
+            let first = testing::block(10, None, &[tx(1)]);
+            let second = testing::block(20, Some(&first.0), &[tx(2)]);
+            let fork = testing::fork_block(25, Some(&first.0), &[tx(3)]);
+            let mut events = testing::roll_forward(&[first.clone(), second]);
+            events.push(ChainEvent::RollBackward(point(&first.0)));
+            events.push(ChainEvent::RollForward(fork.0.clone(), fork.1));
+
+            let run = |index| {
+                let events = events.clone();
+                async move {
+                    let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+                    let index = indexer.add_index(index, Point::Origin, false, 0).unwrap();
+                    let handle = testing::sync(indexer, events).await.unwrap();
+                    (handle, index)
+                }
+            };
+
+            // Failing twice, then going through on the third attempt, every time.
+            let (handle, index) = run(FlakyIndex::new(2, 3)).await;
+            assert!(handle.faulted_indexes().is_empty());
+            let index = index.read().await;
+            assert_eq!(index.txs, [10, 25]);
+            // Three txs and a rollback, three calls each.
+            assert_eq!(index.calls, 12);
+            assert_eq!(handle.committed_cursors()["flaky"], point(&fork.0));
+
+            // One attempt short, and it's faulted on the first tx.
+            let (handle, index) = run(FlakyIndex::new(2, 2)).await;
+            let faulted = handle.faulted_indexes();
+            assert_eq!(faulted.len(), 1);
+            assert_eq!(faulted[0].0, "flaky");
+            let index = index.read().await;
+            assert!(index.txs.is_empty());
+            assert_eq!(index.calls, 2);
+        }
+
+        // Hashes each block's header and each tx's body for itself, out of the raw CBOR.
+        #[derive(Default)]
+        struct RawHashes {
+            blocks: Vec<Hash<32>>,
+            txs: Vec<(Hash<32>, Hash<32>)>,
+        }
+
+        #[async_trait]
+        impl ManagedIndex for RawHashes {
+            fn name(&self) -> String {
+                "raw".into()
+            }
+
+            fn wants_raw_cbor(&self) -> bool {
+                true
+            }
+
+            async fn handle_raw_block(
+                &mut self,
+                _: &BlockInfo,
+                block: &MultiEraBlock,
+                cbor: &[u8],
+            ) -> Result<()> {
+                // An era tag, then the block: its header, then the bodies of its txs.
+                let mut decoder = pallas_codec::minicbor::Decoder::new(cbor);
+                decoder.array()?;
+                decoder.u16()?;
+                decoder.array()?;
+                let start = decoder.position();
+                decoder.skip()?;
+                let header = &cbor[start..decoder.position()];
+                self.blocks.push(Hasher::<256>::hash(header));
+                let bodies = decoder.array()?.context("indefinite tx bodies")?;
+                let txs = block.txs();
+                anyhow::ensure!(txs.len() as u64 == bodies, "tx count doesn't match");
+                for tx in txs {
+                    let start = decoder.position();
+                    decoder.skip()?;
+                    let body = &cbor[start..decoder.position()];
+                    self.txs.push((tx.hash(), Hasher::<256>::hash(body)));
+                }
+                Ok(())
+            }
+        }
+
+        #[tokio::test]
+        async fn the_raw_cbor_is_the_block_as_it_came() {
+            let pay = |n| testing::Tx::new().pay(testing::output(&testing::key_address(n), 5));
+            let first = testing::block(10, None, &[pay(1), pay(2)]);
+            let second = testing::block(20, Some(&first.0), &[pay(3)]);
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            let raw = indexer
+                .add_index(RawHashes::default(), Point::Origin, false, 0)
+                .unwrap();
+            testing::sync(
+                indexer,
+                testing::roll_forward(&[first.clone(), second.clone()]),
+            )
+            .await
+            .unwrap();
+
+            let raw = raw.read().await;
+            let hashes: Vec<_> = raw.blocks.iter().map(|h| h.to_vec()).collect();
+            assert_eq!(hashes, [first.0.hash.to_vec(), second.0.hash.to_vec()]);
+            assert_eq!(raw.txs.len(), 3);
+            for (hash, body) in &raw.txs {
+                assert_eq!(hash, body);
+            }
+        }
+
+        fn persist_dir(test: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("{test}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            dir
+        }
+
+        #[tokio::test]
+        async fn a_persisted_index_picks_up_where_it_left_off() {
+            let dir = persist_dir("persisted-index");
+            let blocks = testing::chain(&[10, 20, 30, 40]);
+            let run = |blocks: &[(BlockInfo, Vec<u8>)]| {
+                let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+                indexer.set_persist_dir(&dir);
+                let recorder = indexer
+                    .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                    .unwrap();
+                let events = testing::roll_forward(blocks);
+                async move {
+                    testing::sync(indexer, events).await.unwrap();
+                    recorder.read().await.slots.clone()
+                }
+            };
+            assert_eq!(run(&blocks[..3]).await, [10, 20, 30]);
+            assert!(persisted_path(&dir, "recorder", "").exists());
+            // A fresh cursor store knows nothing, so only the file says where it got to.
+            assert_eq!(run(&blocks).await, [40]);
+            let persisted = read_persisted(&dir, "recorder").unwrap();
+            assert_eq!(persisted.points[0], point(&blocks[3].0));
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn a_damaged_persisted_file_falls_back_to_the_one_before_it() {
+            let dir = persist_dir("damaged-index");
+            std::fs::create_dir_all(&dir).unwrap();
+            let at = |slot| Point::Specific(slot, vec![slot as u8; 32]);
+            for slot in [10, 20] {
+                let persisted = Persisted {
+                    points: vec![at(slot)],
+                    snapshot: Some(vec![slot as u8]),
+                };
+                write_persisted(&dir, "index", &persisted).unwrap();
+            }
+            let read = || read_persisted(&dir, "index").map(|p| (p.points, p.snapshot));
+            assert_eq!(read(), Some((vec![at(20)], Some(vec![20]))));
+
+            // Flip a byte, as a torn write might.
+            let damage = |suffix| {
+                let path = persisted_path(&dir, "index", suffix);
+                let mut bytes = std::fs::read(&path).unwrap();
+                *bytes.last_mut().unwrap() ^= 1;
+                std::fs::write(path, bytes).unwrap();
+            };
+            damage("");
+            assert_eq!(read(), Some((vec![at(10)], Some(vec![10]))));
+            damage(".prev");
+            assert_eq!(read(), None);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        // Records which blocks it was handed in batches, and which one at a time.
+        #[derive(Default)]
+        struct Batcher {
+            batches: Vec<Vec<u64>>,
+            singles: Vec<u64>,
+        }
+
+        #[async_trait]
+        impl ManagedIndex for Batcher {
+            fn name(&self) -> String {
+                "batcher".into()
+            }
+
+            fn prefers_batches(&self) -> bool {
+                true
+            }
+
+            async fn handle_block(&mut self, info: &BlockInfo, _: &MultiEraBlock) -> Result<()> {
+                self.singles.push(info.slot);
+                Ok(())
+            }
+
+            async fn handle_block_batch(&mut self, blocks: &[BatchedBlock<'_>]) -> Result<()> {
+                self.batches
+                    .push(blocks.iter().map(|b| b.info.slot).collect());
+                Ok(())
+            }
+
+            async fn handle_rollback(&mut self, _: &BlockInfo) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        #[tokio::test]
+        async fn old_blocks_come_in_batches_and_recent_ones_one_at_a_time() {
+            let mainnet = ChainParams::mainnet();
+            let shelley_start = mainnet.slot_to_time(mainnet.shelley_start_slot);
+            let since_shelley = std::time::SystemTime::now()
+                .duration_since(shelley_start)
+                .unwrap();
+            let now_slot = mainnet.shelley_start_slot + since_shelley.as_secs();
+            let mut slots = (1..=250).collect::<Vec<_>>();
+            slots.push(now_slot);
+            let blocks = testing::chain(&slots);
+
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            indexer.set_chain_params(mainnet);
+            indexer.set_checkpoint_interval(CheckpointInterval::Blocks(1000));
+            let batcher = indexer
+                .add_index(Batcher::default(), Point::Origin, false, 0)
+                .unwrap();
+            let recorder = indexer
+                .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                .unwrap();
+            testing::sync(indexer, testing::roll_forward(&blocks))
+                .await
+                .unwrap();
+
+            let batcher = batcher.read().await;
+            let sizes = batcher.batches.iter().map(Vec::len).collect::<Vec<_>>();
+            assert_eq!(sizes, [MAX_BATCH, MAX_BATCH, 50]);
+            assert_eq!(batcher.batches.concat(), slots[..250]);
+            assert_eq!(batcher.singles, [now_slot]);
+            // An index which doesn't batch sees the same blocks, one at a time.
+            assert_eq!(recorder.read().await.slots, slots);
+        }
+
+        // Takes batches, but leaves them to the default `handle_block_batch`, and only
+        // wants the txs to do with one address. Records each tx it's given, along with
+        // how much its first input held.
+        struct FilteredBatcher {
+            address: Address,
+            txs: Vec<(Hash<32>, Option<u64>)>,
+        }
+
+        #[async_trait]
+        impl ManagedIndex for FilteredBatcher {
+            fn name(&self) -> String {
+                "filtered".into()
+            }
+
+            fn tx_filter(&self) -> TxFilter {
+                TxFilter::Address(self.address.clone())
+            }
+
+            fn prefers_batches(&self) -> bool {
+                true
+            }
+
+            fn needs_resolved_inputs(&self) -> bool {
+                true
+            }
+
+            async fn handle_block(&mut self, _: &BlockInfo, _: &MultiEraBlock) -> Result<()> {
+                Ok(())
+            }
+
+            async fn handle_onchain_tx_with_inputs(
+                &mut self,
+                _: &BlockInfo,
+                tx: &MultiEraTx,
+                inputs: &ResolvedInputs,
+            ) -> Result<()> {
+                let spent = tx
+                    .inputs()
+                    .first()
+                    .and_then(|i| inputs.get(&i.output_ref()))
+                    .map(|o| o.value().coin());
+                self.txs.push((tx.hash(), spent));
+                Ok(())
+            }
+
+            async fn handle_rollback(&mut self, _: &BlockInfo) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        #[tokio::test]
+        async fn a_batch_goes_through_the_filter_with_its_inputs_resolved() {
+            let ours = testing::key_address(1);
+            let paid = testing::Tx::new().pay(testing::output(&ours, 100));
+            let elsewhere = testing::Tx::new().pay(testing::output(&testing::key_address(2), 50));
+            let spent = testing::Tx::new()
+                .spend(&paid.output_ref(0))
+                .pay(testing::output(&testing::key_address(3), 100));
+            let unrelated = testing::Tx::new()
+                .spend(&elsewhere.output_ref(0))
+                .pay(testing::output(&testing::key_address(3), 50));
+            let first = testing::block(1, None, &[paid.clone(), elsewhere]);
+            let second = testing::block(2, Some(&first.0), &[spent.clone(), unrelated]);
+
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            indexer.set_chain_params(ChainParams::mainnet());
+            indexer.set_checkpoint_interval(CheckpointInterval::Blocks(1000));
+            let index = FilteredBatcher {
+                address: testing::address(&ours),
+                txs: vec![],
+            };
+            let index = indexer.add_index(index, Point::Origin, false, 0).unwrap();
+            let handle = testing::sync(indexer, testing::roll_forward(&[first, second]))
+                .await
+                .unwrap();
+
+            // Only the tx paying the address, and the one spending what it paid, get through.
+            assert_eq!(
+                index.read().await.txs,
+                [(paid.hash(), None), (spent.hash(), Some(100))]
+            );
+            let counters = handle.counters();
+            assert_eq!(counters.processed_txs["filtered"], 2);
+            // Both blocks are timed, even though they were handled together.
+            let durations = &counters.block_durations["filtered"];
+            assert_eq!(durations.buckets[BLOCK_DURATION_BUCKETS.len()], 2);
+        }
+
+        // Counts how many messages have been taken from it.
+        struct Counting {
+            inner: MockChainSource,
+            taken: Arc<AtomicU64>,
+        }
+
+        #[async_trait]
+        impl ChainSource for Counting {
+            async fn sync_from(&mut self, points: &[Point]) -> Result<Point> {
+                self.inner.sync_from(points).await
+            }
+
+            async fn next(&mut self) -> Result<AcropolisMessage> {
+                self.taken.fetch_add(1, atomic::Ordering::SeqCst);
+                self.inner.next().await
+            }
+        }
+
+        #[tokio::test]
+        async fn a_full_buffer_stops_fetching_until_its_read_from() {
+            let blocks = testing::chain(&(1..=20).collect::<Vec<_>>());
+            let taken = Arc::new(AtomicU64::new(0));
+            let source = Counting {
+                inner: MockChainSource::new(testing::roll_forward(&blocks)),
+                taken: taken.clone(),
+            };
+            let mut buffer = BlockBuffer::new(Box::new(source), 4, 1);
+            buffer.sync_from(&[Point::Origin]).await.unwrap();
+            let settle = || tokio::time::sleep(Duration::from_millis(20));
+
+            // Four messages buffered, and the fetcher holding a fifth until there's room.
+            settle().await;
+            assert_eq!(buffer.depth(), 4);
+            assert_eq!(taken.load(atomic::Ordering::SeqCst), 5);
+
+            let AcropolisMessage::NewBlock(info, _) = buffer.next().await.unwrap() else {
+                panic!("expected the first block");
+            };
+            assert_eq!(info.slot, 1);
+            settle().await;
+            assert_eq!(buffer.depth(), 4);
+            assert_eq!(taken.load(atomic::Ordering::SeqCst), 6);
+        }
+
+        #[tokio::test]
+        async fn a_batch_is_all_buffered_before_the_next_is_fetched() {
+            let blocks = testing::chain(&(1..=20).collect::<Vec<_>>());
+            let taken = Arc::new(AtomicU64::new(0));
+            let source = Counting {
+                inner: MockChainSource::new(testing::roll_forward(&blocks)),
+                taken: taken.clone(),
+            };
+            let mut buffer = BlockBuffer::new(Box::new(source), 4, 3);
+            buffer.sync_from(&[Point::Origin]).await.unwrap();
+            let settle = || tokio::time::sleep(Duration::from_millis(20));
+
+            // Two batches taken, and the last two of the second waiting for room.
+            settle().await;
+            assert_eq!(buffer.depth(), 4);
+            assert_eq!(taken.load(atomic::Ordering::SeqCst), 6);
+
+            buffer.next().await.unwrap();
+            settle().await;
+            assert_eq!(buffer.depth(), 4);
+            assert_eq!(taken.load(atomic::Ordering::SeqCst), 6);
+
+            // The second batch is all in, so the third is fetched.
+            buffer.next().await.unwrap();
+            settle().await;
+            assert_eq!(buffer.depth(), 4);
+            assert_eq!(taken.load(atomic::Ordering::SeqCst), 9);
+            let mut slots = vec![];
+            for _ in 0..7 {
+                if let AcropolisMessage::NewBlock(info, _) = buffer.next().await.unwrap() {
+                    slots.push(info.slot);
+                }
+            }
+            assert_eq!(slots, [3, 4, 5, 6, 7, 8, 9]);
+        }
+
+        #[tokio::test]
+        async fn a_batch_stops_at_the_tip() {
+            let blocks = testing::chain(&[1, 2]);
+            let mut source = MockChainSource::new(testing::roll_forward(&blocks));
+            source.sync_from(&[Point::Origin]).await.unwrap();
+            let batch = source.next_batch(10).await.unwrap();
+            assert_eq!(batch.len(), 3);
+            assert!(matches!(batch[2], AcropolisMessage::AwaitingBlocks));
+        }
+
+        #[test]
+        fn a_decoded_block_outlives_the_bytes_it_came_from() {
+            let tx = testing::Tx::new().pay(testing::output(&testing::key_address(1), 5));
+            let (info, cbor) = testing::block(10, None, std::slice::from_ref(&tx));
+            let Prefetched::Block(_, block) =
+                Prefetched::new(AcropolisMessage::NewBlock(info, cbor))
+            else {
+                panic!("the block didn't decode");
+            };
+            // The message and its CBOR are gone, and the block's been moved about since.
+            let blocks = vec![block; 3];
+            assert_eq!(blocks[2].block().slot(), 10);
+            assert_eq!(blocks[2].block().txs()[0].hash(), tx.hash());
+
+            let garbage = AcropolisMessage::NewBlock(
+                BlockInfo::new(
+                    20,
+                    crate::acropolis::core::BlockHash::try_from(vec![0; 32]).unwrap(),
+                    0,
+                ),
+                vec![0x82, 0x0f],
+            );
+            assert!(
+                matches!(Prefetched::new(garbage), Prefetched::Undecodable(info, cbor, _) if info.slot == 20 && cbor == [0x82, 0x0f])
+            );
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn cursors_can_be_read_while_theyre_committed() {
+            let store = Arc::new(RwLock::new(InMemoryCursorStore::new(vec![])));
+            let writer = tokio::spawn({
+                let store = store.clone();
+                async move {
+                    for slot in 1..=500 {
+                        let point = Point::Specific(slot, vec![0; 32]);
+                        let batch = [("a".to_string(), point.clone()), ("b".to_string(), point)];
+                        store.write().await.commit_batch(&batch).await.unwrap();
+                        tokio::task::yield_now().await;
+                    }
+                }
+            });
+            let readers = (0..4).map(|_| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let mut last = 0;
+                    loop {
+                        let latest = store.read().await.latest().await.unwrap();
+                        let slots: Vec<_> = latest.values().map(|p| p.slot_or_default()).collect();
+                        // Both or neither of a batch, and never going backwards.
+                        assert!(slots.is_empty() || slots == [slots[0]; 2]);
+                        let slot = slots.first().copied().unwrap_or(0);
+                        assert!(slot >= last);
+                        last = slot;
+                        if slot == 500 {
+                            break;
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                })
+            });
+            let all = async {
+                writer.await.unwrap();
+                for reader in readers.collect::<Vec<_>>() {
+                    reader.await.unwrap();
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(10), all)
+                .await
+                .expect("reading the cursors held up committing them, or the other way round");
+        }
+
+        #[test]
+        fn spend_redeemers_point_at_inputs_in_sorted_order() {
+            let utxo = |hash, index| OutputRef::new(Hash::new([hash; 32]), index);
+            let tag = |n| pallas_primitives::PlutusData::BoundedBytes(vec![n].into());
+            // Listed out of order, so the redeemers' positions only line up once they're sorted.
+            let tx = testing::Tx::new()
+                .spend(&utxo(2, 0))
+                .spend(&utxo(1, 5))
+                .spend(&utxo(1, 0))
+                .redeemer(RedeemerTag::Spend, 0, tag(10))
+                .redeemer(RedeemerTag::Spend, 2, tag(12))
+                .redeemer(RedeemerTag::Mint, 1, tag(99));
+            let (_, cbor) = testing::block(10, None, &[tx]);
+            let block = MultiEraBlock::decode(&cbor).unwrap();
+            let tx = &block.txs()[0];
+            let redeemers = spend_redeemers(tx);
+            assert_eq!(redeemers.len(), 2);
+            assert_eq!(*redeemers[&utxo(1, 0)].data(), tag(10));
+            assert_eq!(*redeemers[&utxo(2, 0)].data(), tag(12));
+        }
+
+        // A cursor store which can be read, but never written.
+        struct FullDisk;
+
+        impl CursorStore for FullDisk {
+            async fn get(&self, _: &str) -> Result<Option<Point>> {
+                Ok(None)
+            }
+
+            async fn latest(&self) -> Result<BTreeMap<String, Point>> {
+                Ok(BTreeMap::new())
+            }
+
+            async fn commit_batch(&mut self, _: &[(String, Point)]) -> Result<()> {
+                anyhow::bail!("no space left on device")
+            }
+        }
+
+        #[tokio::test]
+        async fn a_cursor_store_failure_stops_the_process_with_its_error() {
+            let blocks = testing::chain(&[10, 20]);
+            let mut indexer = ChainIndexer::new(FullDisk);
+            indexer
+                .add_index(Recorder::new("recorder"), Point::Origin, false, 0)
+                .unwrap();
+            let handle = indexer.handle();
+            let Err(error) = testing::sync(indexer, testing::roll_forward(&blocks)).await else {
+                panic!("the failed commit went unnoticed");
+            };
+            assert_eq!(
+                format!("{error:#}"),
+                "couldn't save cursors: no space left on device"
+            );
+            assert!(!handle.is_running());
+            assert_eq!(handle.error(), Some(format!("{error:#}")));
+        }
+
+        #[tokio::test]
+        async fn indexes_come_and_go_while_the_indexer_runs() {
+            let blocks = testing::chain(&[10, 20]);
+            let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+            indexer.set_chain_source(MockChainSource::new(testing::roll_forward(&blocks)));
+            indexer
+                .add_index(Recorder::new("first"), Point::Origin, false, 0)
+                .unwrap();
+            let handle = indexer.handle();
+            let token = crate::acropolis::core::CancellationToken::new();
+            let mut process = crate::acropolis::core::Process::create();
+            process.register(indexer);
+            let running = tokio::spawn(process.run_until(token.clone()));
+
+            let names = || {
+                let status = handle.sync_status().into_iter();
+                status.map(|s| s.index).collect::<Vec<_>>()
+            };
+            handle
+                .add_index_live(Recorder::new("second"), Point::Origin, false, 0)
+                .await
+                .unwrap();
+            let again = handle.add_index_live(Recorder::new("first"), Point::Origin, false, 0);
+            assert!(matches!(again.await, Err(e) if e.is::<DuplicateIndex>()));
+            assert_eq!(names(), ["first", "second"]);
+            handle.remove_index("first").await.unwrap();
+            assert!(handle.remove_index("first").await.is_err());
+            assert_eq!(names(), ["second"]);
+            token.cancel();
+            running.await.unwrap().unwrap();
+        }
+
+        // Runs `txs` through `cache` as one block at `slot`.
+        fn apply(
+            cache: &mut UtxoCache,
+            slot: u64,
+            txs: Vec<testing::Tx>,
+            wanted: &[ResolvedOutputs],
+        ) -> Vec<ResolvedInputs> {
+            let (_, cbor) = testing::block(slot, None, &txs);
+            let block = MultiEraBlock::decode(&cbor).unwrap();
+            let resolved = block
+                .txs()
+                .iter()
+                .map(|tx| cache.apply(slot, tx, wanted))
+                .collect();
+            cache.prune(slot);
+            resolved
+        }
+
+        fn lovelace(resolved: &ResolvedInputs, output_ref: &OutputRef) -> Option<u64> {
+            resolved.get(output_ref).map(|o| o.value().coin())
+        }
+
+        #[test]
+        fn inputs_resolve_to_outputs_from_earlier_in_the_block_or_before() {
+            let mut cache = UtxoCache::default();
+            let first = testing::Tx::new().pay(testing::output(&testing::key_address(1), 100));
+            apply(&mut cache, 10, vec![first.clone()], &[ResolvedOutputs::All]);
+
+            let second = testing::Tx::new()
+                .spend(&first.output_ref(0))
+                .pay(testing::output(&testing::key_address(2), 60));
+            let third = testing::Tx::new()
+                .spend(&second.output_ref(0))
+                .spend(&OutputRef::new(Hash::new([1; 32]), 0));
+            let resolved = apply(
+                &mut cache,
+                20,
+                vec![second.clone(), third],
+                &[ResolvedOutputs::All],
+            );
+            assert_eq!(lovelace(&resolved[0], &first.output_ref(0)), Some(100));
+            assert_eq!(lovelace(&resolved[1], &second.output_ref(0)), Some(60));
+            // Whatever was created before we started can't be resolved.
+            assert!(
+                resolved[1]
+                    .get(&OutputRef::new(Hash::new([1; 32]), 0))
+                    .is_none()
+            );
+        }
+
+        #[test]
+        fn only_wanted_outputs_are_cached() {
+            let mut cache = UtxoCache::default();
+            let datum =
+                pallas_primitives::PlutusData::BigInt(pallas_primitives::BigInt::Int(1.into()));
+            let tx = testing::Tx::new()
+                .pay(testing::output(&testing::key_address(1), 100))
+                .pay(testing::with_datum(
+                    testing::output(&testing::key_address(2), 200),
+                    datum,
+                ));
+            apply(
+                &mut cache,
+                10,
+                vec![tx.clone()],
+                &[ResolvedOutputs::InlineDatums],
+            );
+            assert!(!cache.outputs.contains_key(&tx.output_ref(0)));
+            assert!(cache.outputs.contains_key(&tx.output_ref(1)));
+
+            // Neither of them came from a Byron tx.
+            let mut cache = UtxoCache::default();
+            apply(&mut cache, 10, vec![tx], &[ResolvedOutputs::Byron]);
+            assert!(cache.outputs.is_empty());
+        }
+
+        #[test]
+        fn spent_outputs_are_pruned_once_they_cant_come_back() {
+            let mut cache = UtxoCache::default();
+            let created = testing::Tx::new().pay(testing::output(&testing::key_address(1), 100));
+            let kept = testing::Tx::new().pay(testing::output(&testing::key_address(2), 100));
+            apply(
+                &mut cache,
+                10,
+                vec![created.clone(), kept.clone()],
+                &[ResolvedOutputs::All],
+            );
+            let spend = testing::Tx::new().spend(&created.output_ref(0));
+            apply(&mut cache, 20, vec![spend], &[ResolvedOutputs::All]);
+
+            apply(&mut cache, 20 + SPENT_OUTPUT_RETENTION_SLOTS, vec![], &[]);
+            assert!(cache.outputs.contains_key(&created.output_ref(0)));
+            apply(&mut cache, 21 + SPENT_OUTPUT_RETENTION_SLOTS, vec![], &[]);
+            assert!(!cache.outputs.contains_key(&created.output_ref(0)));
+            assert!(cache.spent.is_empty());
+            // Unspent outputs stay, however old they get.
+            assert!(cache.outputs.contains_key(&kept.output_ref(0)));
+        }
+
+        #[test]
+        fn rollback_unspends_and_forgets_what_it_undoes() {
+            let mut cache = UtxoCache::default();
+            let created = testing::Tx::new().pay(testing::output(&testing::key_address(1), 100));
+            apply(
+                &mut cache,
+                10,
+                vec![created.clone()],
+                &[ResolvedOutputs::All],
+            );
+            let spend = testing::Tx::new()
+                .spend(&created.output_ref(0))
+                .pay(testing::output(&testing::key_address(2), 100));
+            apply(&mut cache, 20, vec![spend.clone()], &[ResolvedOutputs::All]);
+
+            cache.rollback(20);
+            assert!(cache.outputs[&created.output_ref(0)].spent_at.is_none());
+            assert!(!cache.outputs.contains_key(&spend.output_ref(0)));
+
+            // Spent again later, it's pruned by when that happened, not by the undone spend.
+            let respend = testing::Tx::new().spend(&created.output_ref(0));
+            apply(&mut cache, 30, vec![respend], &[ResolvedOutputs::All]);
+            apply(&mut cache, 25 + SPENT_OUTPUT_RETENTION_SLOTS, vec![], &[]);
+            assert!(cache.outputs.contains_key(&created.output_ref(0)));
+            apply(&mut cache, 31 + SPENT_OUTPUT_RETENTION_SLOTS, vec![], &[]);
+            assert!(!cache.outputs.contains_key(&created.output_ref(0)));
+        }
+
+        #[test]
+        fn fees_can_be_worked_out_from_resolved_inputs() {
+            let mut cache = UtxoCache::default();
+            let created = testing::Tx::new().pay(testing::output(&testing::key_address(1), 1_000));
+            apply(
+                &mut cache,
+                10,
+                vec![created.clone()],
+                &[ResolvedOutputs::All],
+            );
+            let honest = testing::Tx::new()
+                .spend(&created.output_ref(0))
+                .pay(testing::output(&testing::key_address(2), 800))
+                .fee(200);
+            let unresolvable = testing::Tx::new()
+                .spend(&OutputRef::new(Hash::new([1; 32]), 0))
+                .fee(200);
+            let (_, cbor) =
+                testing::block(20, None, &[honest.clone().fee(150), honest, unresolvable]);
+            let block = MultiEraBlock::decode(&cbor).unwrap();
+            let txs = block.txs();
+            // Both spend the same output, so resolve them separately.
+            let resolved: Vec<_> = txs.iter().map(|tx| cache.resolve(tx)).collect();
+            assert_eq!(resolved[0].implied_fee(&txs[0]), Some(200));
+            assert_eq!(resolved[0].fee_matches(&txs[0]), Some(false));
+            assert_eq!(resolved[1].fee_matches(&txs[1]), Some(true));
+            assert_eq!(resolved[2].implied_fee(&txs[2]), None);
+            assert_eq!(resolved[2].fee_matches(&txs[2]), None);
+        }
     }
 }
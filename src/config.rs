@@ -0,0 +1,334 @@
+use std::{collections::HashSet, path::Path, str::FromStr};
+
+use anyhow::{Context, Result, bail};
+use pallas_addresses::Address;
+use pallas_crypto::hash::Hash;
+use pallas_network::miniprotocols::Point;
+use serde::Deserialize;
+
+use crate::acropolis::core::{BlockHash, ChainParams};
+
+/// Which indexes to run, and where to sync them from, as read from `--config`.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub connection: ConnectionConfig,
+    #[serde(default, rename = "index")]
+    pub indexes: Vec<IndexConfig>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub addr: Option<String>,
+    pub protocol: Option<Protocol>,
+    pub network: Option<Network>,
+    pub magic: Option<u64>,
+}
+
+impl ConnectionConfig {
+    /// The network the config names, through either `network` or `magic` (but not both).
+    pub fn network(&self) -> Result<Option<Network>> {
+        match (self.network, self.magic) {
+            (Some(_), Some(_)) => bail!("the config gives both a network and a magic"),
+            (network, magic) => Ok(network.or(magic.map(Network::Custom))),
+        }
+    }
+}
+
+/// How we talk to the node: node-to-node over TCP, or node-to-client over its local socket.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    N2n,
+    N2c,
+}
+
+impl Protocol {
+    /// Checks `addr` is the kind of address this protocol connects to.
+    pub fn check_addr(&self, addr: &str) -> Result<()> {
+        let host_port = addr
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        match self {
+            Protocol::N2n if !host_port => {
+                bail!("n2n connects to a host:port, but the node address is {addr}")
+            }
+            Protocol::N2c if host_port => {
+                bail!("n2c connects to the node's socket path, but the node address is {addr}")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Which chain we're following.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(try_from = "String")]
+pub enum Network {
+    Mainnet,
+    Preprod,
+    Preview,
+    /// Any other network, by its protocol magic.
+    Custom(u64),
+}
+
+impl Network {
+    pub fn magic(&self) -> u64 {
+        match self {
+            Network::Mainnet => 764_824_073,
+            Network::Preprod => 1,
+            Network::Preview => 2,
+            Network::Custom(magic) => *magic,
+        }
+    }
+
+    /// The genesis parameters for epoch math, if we know them.
+    pub fn chain_params(&self) -> Option<ChainParams> {
+        match self {
+            Network::Mainnet => Some(ChainParams::mainnet()),
+            Network::Preprod => Some(ChainParams::preprod()),
+            Network::Preview => Some(ChainParams::preview()),
+            Network::Custom(_) => None,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "preprod" => Ok(Network::Preprod),
+            "preview" => Ok(Network::Preview),
+            _ => match s.strip_prefix("custom:") {
+                Some(magic) => Ok(Network::Custom(magic.parse()?)),
+                None => bail!("expected mainnet, preprod, preview, or custom:<magic>"),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexConfig {
+    /// Defaults to the index's own name. Two indexes of the same kind need different names.
+    pub name: Option<String>,
+    pub kind: IndexKind,
+    /// Where to start if the index has no cursor yet. Leave both out to start from the origin.
+    pub start_slot: Option<u64>,
+    pub start_hash: Option<String>,
+    /// The address a wallet index watches, or the script address a pool index filters on.
+    pub address: Option<String>,
+    /// More addresses for a wallet index to watch, alongside `address`.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// The script a script index watches, in hex.
+    pub script_hash: Option<String>,
+    /// In hex, the only minting policy an NFT index follows (every policy if left out),
+    /// or the policy of the pool NFTs for a pool index.
+    pub policy: Option<String>,
+    /// How many slots a wallet index keeps spent UTXOs for. Forever if left out.
+    pub history_slots: Option<u64>,
+    /// How many slots of history a pool index keeps for rollbacks. Defaults to the volatile
+    /// window, or forever without one.
+    pub rollback_window: Option<u64>,
+    /// Whether a wallet index starts from the node's UTXO set, over n2c, rather than replaying.
+    #[serde(default)]
+    pub bootstrap: bool,
+    /// Whether a fee index checks every tx's declared fee, which means keeping every output
+    /// around to resolve inputs with. Without it, only Byron txs have their inputs resolved.
+    #[serde(default)]
+    pub check_fees: bool,
+    #[serde(default)]
+    pub confirmations: u64,
+    #[serde(default)]
+    pub force_restart: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    Pools,
+    #[serde(rename = "pool_datums")]
+    PoolDatums,
+    Orders,
+    Wallet,
+    Mints,
+    Cip25,
+    Stake,
+    Script,
+    #[serde(rename = "script_registry")]
+    ScriptRegistry,
+    Nfts,
+    Fees,
+    Txs,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Read a config from its TOML.
+    pub fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+impl IndexConfig {
+    pub fn new(kind: IndexKind) -> Self {
+        Self {
+            name: None,
+            kind,
+            start_slot: None,
+            start_hash: None,
+            address: None,
+            addresses: vec![],
+            script_hash: None,
+            policy: None,
+            history_slots: None,
+            rollback_window: None,
+            bootstrap: false,
+            check_fees: false,
+            confirmations: 0,
+            force_restart: false,
+        }
+    }
+
+    /// Read one index's config from its TOML, laid out like an `[[indexes]]` entry.
+    #[cfg(feature = "metrics")]
+    pub fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    pub fn start(&self) -> Result<Point> {
+        match (self.start_slot, &self.start_hash) {
+            (None, None) => Ok(Point::Origin),
+            (Some(slot), Some(hash)) => Ok(BlockHash::from_hex(hash)?.to_point(slot)),
+            _ => bail!("an index needs both start_slot and start_hash, or neither"),
+        }
+    }
+
+    pub fn address(&self) -> Result<Option<Address>> {
+        self.address.as_deref().map(parse_address).transpose()
+    }
+
+    pub fn script_hash(&self) -> Result<Option<Hash<28>>> {
+        self.script_hash
+            .as_deref()
+            .map(|h| {
+                h.parse()
+                    .with_context(|| format!("invalid script hash {h}"))
+            })
+            .transpose()
+    }
+
+    pub fn policy(&self) -> Result<Option<Hash<28>>> {
+        self.policy
+            .as_deref()
+            .map(|p| p.parse().with_context(|| format!("invalid policy {p}")))
+            .transpose()
+    }
+
+    /// Every address the index was given, whether through `address` or `addresses`.
+    pub fn addresses(&self) -> Result<HashSet<Address>> {
+        self.address
+            .iter()
+            .chain(&self.addresses)
+            .map(|a| parse_address(a))
+            .collect()
+    }
+}
+
+fn parse_address(address: &str) -> Result<Address> {
+    address
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid address {address}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_readme_example_parses() {
+        let config = Config::parse(
+            r#"
+[connection]
+addr = "relay.example:3001"
+network = "mainnet"
+
+[[index]]
+kind = "pools"
+
+[[index]]
+kind = "wallet"
+name = "treasury"
+addresses = ["addr1...", "addr1..."]
+confirmations = 10
+
+[[index]]
+kind = "mints"
+start_slot = 134000000
+start_hash = "0000000000000000000000000000000000000000000000000000000000000000"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.connection.addr.as_deref(),
+            Some("relay.example:3001")
+        );
+        assert!(matches!(
+            config.connection.network(),
+            Ok(Some(Network::Mainnet))
+        ));
+        let kinds: Vec<_> = config.indexes.iter().map(|i| i.kind).collect();
+        assert!(kinds == [IndexKind::Pools, IndexKind::Wallet, IndexKind::Mints]);
+        assert_eq!(config.indexes[1].name.as_deref(), Some("treasury"));
+        assert_eq!(config.indexes[1].addresses.len(), 2);
+        assert_eq!(config.indexes[1].confirmations, 10);
+        assert_eq!(
+            config.indexes[2].start().unwrap(),
+            Point::Specific(134_000_000, vec![0; 32])
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert!(Config::parse("[[index]]\nkind = \"pools\"\nstart = 5").is_err());
+        assert!(Config::parse("[connection]\nport = 3001").is_err());
+    }
+
+    #[test]
+    fn a_key_or_table_given_twice_is_refused() {
+        let twice = Config::parse("[connection]\naddr = \"a:1\"\naddr = \"b:2\"");
+        assert!(twice.is_err());
+        let twice = Config::parse("[connection]\naddr = \"a:1\"\n\n[connection]\nmagic = 2");
+        assert!(twice.is_err());
+        // An array of tables is meant to be given more than once, though.
+        let indexes = Config::parse("[[index]]\nkind = \"pools\"\n\n[[index]]\nkind = \"fees\"");
+        assert_eq!(indexes.unwrap().indexes.len(), 2);
+    }
+
+    #[test]
+    fn mistakes_say_which_line_theyre_on() {
+        let error = Config::parse("[connection]\naddr = \"a:1\"\nmagic = \n")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("line 3"), "{error}");
+    }
+}
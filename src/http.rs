@@ -0,0 +1,732 @@
+// Just enough HTTP/1.1 to answer simple GET requests, and POSTs with a small body, which is all
+// the metrics and query endpoints need, and to hand a connection over to WebSockets for live events.
+#[cfg(feature = "http")]
+use std::net::SocketAddr;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::Result;
+#[cfg(feature = "http")]
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+#[cfg(feature = "http")]
+use pallas_network::miniprotocols::Point;
+#[cfg(feature = "http")]
+use serde_json::json;
+#[cfg(feature = "http")]
+use tokio::sync::RwLock;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::acropolis::{core::CancellationToken, indexer::ChainIndexerHandle};
+#[cfg(feature = "http")]
+use crate::{
+    acropolis::{
+        core::{Context, Module},
+        indexer::BroadcastSink,
+    },
+    ws,
+};
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Whatever came after the `?` in the target, if anything.
+    #[cfg(feature = "http")]
+    pub query: String,
+    /// By lowercased name.
+    pub headers: HashMap<String, String>,
+    /// Whatever `Content-Length` said followed the headers, which has to be UTF-8.
+    /// Only the metrics server's control endpoints take one.
+    #[cfg(feature = "metrics")]
+    pub body: String,
+}
+
+/// What to do with a connection once it's been switched over to WebSockets.
+pub type Upgrade = Box<dyn FnOnce(BufReader<TcpStream>) -> BoxFuture<'static, Result<()>> + Send>;
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+    /// The `Sec-WebSocket-Accept` key and what to do next, if we're switching protocols.
+    pub upgrade: Option<(String, Upgrade)>,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: String) -> Self {
+        Self {
+            status: 200,
+            content_type,
+            body,
+            upgrade: None,
+        }
+    }
+
+    #[cfg(feature = "http")]
+    pub fn json(body: serde_json::Value) -> Self {
+        Self::ok("application/json", body.to_string())
+    }
+
+    pub fn not_found() -> Self {
+        Self::error(404, "not found\n")
+    }
+
+    pub fn error(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: body.into(),
+            upgrade: None,
+        }
+    }
+
+    #[cfg(any(feature = "http", feature = "metrics"))]
+    pub fn bad_request(body: String) -> Self {
+        Self {
+            status: 400,
+            ..Self::ok("text/plain", body)
+        }
+    }
+
+    /// Accept a WebSocket handshake with `key`, then hand the connection to `upgrade`.
+    #[cfg(feature = "http")]
+    pub fn websocket(
+        key: &str,
+        upgrade: impl FnOnce(BufReader<TcpStream>) -> BoxFuture<'static, Result<()>> + Send + 'static,
+    ) -> Self {
+        Self {
+            status: 101,
+            upgrade: Some((ws::accept_key(key), Box::new(upgrade))),
+            ..Self::ok("text/plain", String::new())
+        }
+    }
+}
+
+/// The liveness probe: 200 while we're still fetching blocks and no index has faulted.
+pub fn healthz(indexer: &ChainIndexerHandle) -> Response {
+    let healthy = indexer.is_running() && indexer.faulted_indexes().is_empty();
+    probe(indexer, healthy)
+}
+
+/// The readiness probe: 200 once we've reached the tip, or while every index is less than
+/// `max_lag` slots behind it.
+pub fn readyz(indexer: &ChainIndexerHandle, max_lag: u64) -> Response {
+    let ready = indexer.is_at_tip() || indexer.worst_lag().is_some_and(|(_, lag)| lag < max_lag);
+    probe(indexer, ready)
+}
+
+// Either probe answers with where every index has got to, to help work out why it's failing.
+fn probe(indexer: &ChainIndexerHandle, ok: bool) -> Response {
+    let faulted: HashMap<_, _> = indexer.faulted_indexes().into_iter().collect();
+    let indexes: Vec<_> = indexer
+        .sync_status()
+        .into_iter()
+        .map(|s| {
+            serde_json::json!({
+                "index": s.index,
+                "processed_slot": s.processed_slot,
+                "lag_slots": s.lag_slots,
+                "faulted": faulted.get(&s.index),
+            })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "running": indexer.is_running(),
+        "error": indexer.error(),
+        "at_tip": indexer.is_at_tip(),
+        "worst_lag": indexer.worst_lag().map(|(index, lag)| serde_json::json!({ "index": index, "lag_slots": lag })),
+        "indexes": indexes,
+    });
+    Response {
+        status: if ok { 200 } else { 503 },
+        ..Response::ok("application/json", body.to_string())
+    }
+}
+
+pub type Handler = Arc<dyn Fn(Request) -> BoxFuture<'static, Response> + Send + Sync>;
+
+/// Answers requests on `listener` with `handler` until `shutdown` is cancelled.
+pub async fn serve(listener: TcpListener, shutdown: CancellationToken, handler: Handler) {
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            conn = listener.accept() => match conn {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!(error = %e, "could not accept a connection");
+                    continue;
+                }
+            },
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, handler).await {
+                tracing::warn!(error = format!("{e:#}"), "request failed");
+            }
+        });
+    }
+}
+
+// Limits on the request line, headers and body, so a client can't tie up a connection, or fill
+// up memory, by never finishing them.
+const MAX_LINE_BYTES: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+#[cfg(feature = "metrics")]
+const MAX_BODY_BYTES: usize = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn respond(stream: TcpStream, handler: Handler) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await;
+    let response = match request {
+        Ok(Ok(request)) => handler(request).await,
+        Ok(Err(response)) => response,
+        Err(_) => Response::error(408, "took too long to send the request\n"),
+    };
+    if let Some((accept, upgrade)) = response.upgrade {
+        let head = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+        );
+        stream.get_mut().write_all(head.as_bytes()).await?;
+        return upgrade(stream).await;
+    }
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    Ok(())
+}
+
+// The request line, headers and body, or what to answer if they're no good.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Request, Response> {
+    let line = read_line(stream).await.map_err(|e| match e {
+        LineError::TooLong => Response::error(414, "request line too long\n"),
+        LineError::Unreadable => malformed(),
+    })?;
+    let mut headers = HashMap::new();
+    for count in 0.. {
+        let header = read_line(stream).await.map_err(|e| match e {
+            LineError::TooLong => Response::error(431, "header too long\n"),
+            LineError::Unreadable => malformed(),
+        })?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            return Err(Response::error(431, "too many headers\n"));
+        }
+        // There's no whitespace allowed between a header's name and its colon.
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(malformed());
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(malformed());
+        }
+        headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+    }
+    let parts = line.split_whitespace().collect::<Vec<_>>();
+    let [method, target, version] = parts[..] else {
+        return Err(malformed());
+    };
+    if !target.starts_with('/') || !version.starts_with("HTTP/1.") {
+        return Err(malformed());
+    }
+    #[cfg(feature = "metrics")]
+    let body = read_body(stream, &headers).await?;
+    let method = method.to_string();
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    Ok(Request {
+        method,
+        path: path.to_string(),
+        #[cfg(feature = "http")]
+        query: target
+            .split_once('?')
+            .map_or("", |(_, query)| query)
+            .to_string(),
+        headers,
+        #[cfg(feature = "metrics")]
+        body,
+    })
+}
+
+// As much as `Content-Length` says there is, if it's no more than MAX_BODY_BYTES.
+#[cfg(feature = "metrics")]
+async fn read_body(
+    stream: &mut BufReader<TcpStream>,
+    headers: &HashMap<String, String>,
+) -> Result<String, Response> {
+    let length = match headers.get("content-length") {
+        Some(length) => length.parse::<usize>().map_err(|_| malformed())?,
+        None => 0,
+    };
+    if length > MAX_BODY_BYTES {
+        return Err(Response::error(413, "request body too large\n"));
+    }
+    let mut body = vec![0; length];
+    stream
+        .read_exact(&mut body)
+        .await
+        .map_err(|_| malformed())?;
+    String::from_utf8(body).map_err(|_| malformed())
+}
+
+fn malformed() -> Response {
+    Response::error(400, "malformed request\n")
+}
+
+// Why a line of the request couldn't be read.
+enum LineError {
+    TooLong,
+    // It wasn't UTF-8, or the connection failed partway through it.
+    Unreadable,
+}
+
+// One line, of at most MAX_LINE_BYTES. Empty once the client has nothing more to send.
+async fn read_line(stream: &mut BufReader<TcpStream>) -> Result<String, LineError> {
+    let mut line = String::new();
+    let read = (&mut *stream)
+        .take(MAX_LINE_BYTES as u64)
+        .read_line(&mut line)
+        .await
+        .map_err(|_| LineError::Unreadable)?;
+    if read == MAX_LINE_BYTES && !line.ends_with('\n') {
+        return Err(LineError::TooLong);
+    }
+    Ok(line)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// The `{name}` segments of a matched route.
+#[cfg(feature = "http")]
+pub struct Params(HashMap<String, String>);
+
+#[cfg(feature = "http")]
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|v| v.as_str())
+    }
+}
+
+#[cfg(feature = "http")]
+type RouteHandler<I> = Box<dyn Fn(&I, &Params) -> Response + Send + Sync>;
+
+/// The GET routes an index serves, relative to wherever it's mounted.
+/// Handlers only ever see the index between blocks, never partway through one.
+#[cfg(feature = "http")]
+pub struct Router<I> {
+    routes: Vec<(Vec<String>, RouteHandler<I>)>,
+}
+
+#[cfg(feature = "http")]
+impl<I> Router<I> {
+    pub fn new() -> Self {
+        Self { routes: vec![] }
+    }
+
+    /// Serve `path` with `handler`. A path segment like `{ident}` matches anything,
+    /// and the handler can read what it matched from its `Params`.
+    pub fn get(
+        mut self,
+        path: &str,
+        handler: impl Fn(&I, &Params) -> Response + Send + Sync + 'static,
+    ) -> Self {
+        self.routes.push((segments(path), Box::new(handler)));
+        self
+    }
+
+    fn find(&self, path: &str) -> Option<(&RouteHandler<I>, Params)> {
+        let path = segments(path);
+        self.routes.iter().find_map(|(pattern, handler)| {
+            if pattern.len() != path.len() {
+                return None;
+            }
+            let mut params = HashMap::new();
+            for (expected, actual) in pattern.iter().zip(&path) {
+                match expected.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                    Some(name) => {
+                        params.insert(name.to_string(), actual.clone());
+                    }
+                    None if expected == actual => {}
+                    None => return None,
+                }
+            }
+            Some((handler, Params(params)))
+        })
+    }
+}
+
+#[cfg(feature = "http")]
+fn segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// A `Router` bound to the index it reads from.
+#[cfg(feature = "http")]
+pub struct IndexRoutes(Box<dyn BoundRouter>);
+
+#[cfg(feature = "http")]
+impl IndexRoutes {
+    pub fn new<I: Send + Sync + 'static>(router: Router<I>, index: Arc<RwLock<I>>) -> Self {
+        Self(Box::new((router, index)))
+    }
+}
+
+#[cfg(feature = "http")]
+trait BoundRouter: Send + Sync {
+    fn call<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Option<Response>>;
+}
+
+#[cfg(feature = "http")]
+impl<I: Send + Sync + 'static> BoundRouter for (Router<I>, Arc<RwLock<I>>) {
+    fn call<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Option<Response>> {
+        Box::pin(async move {
+            let (handler, params) = self.0.find(path)?;
+            // The indexer holds the write lock for as long as it takes to apply a block,
+            // so this read waits for whatever block is in flight and sees all of it.
+            let index = self.1.read().await;
+            Some(handler(&index, &params))
+        })
+    }
+}
+
+/// Every index's committed cursor, as its slot and block hash (or null for the origin).
+/// It's read from the indexer's copy, so it never touches the cursor store.
+#[cfg(feature = "http")]
+fn cursors(indexer: &ChainIndexerHandle) -> Response {
+    let cursors: serde_json::Map<_, _> = indexer
+        .committed_cursors()
+        .into_iter()
+        .map(|(index, point)| {
+            let point = match point {
+                Point::Origin => serde_json::Value::Null,
+                Point::Specific(slot, hash) => {
+                    serde_json::json!({ "slot": slot, "hash": hex::encode(hash) })
+                }
+            };
+            (index, point)
+        })
+        .collect();
+    Response::json(cursors.into())
+}
+
+/// Upgrades a request for `/ws/{index}` to a WebSocket, which gets the index's events as they're
+/// committed, rollbacks included. With `?snapshot=true`, the first frame is the index's exported
+/// table, whose `slot` says how far it goes, so the client can tell which later events it
+/// already reflects.
+#[cfg(feature = "http")]
+async fn live_events(
+    request: &Request,
+    index: &str,
+    indexer: &ChainIndexerHandle,
+    events: &BroadcastSink,
+    shutdown: CancellationToken,
+) -> Response {
+    if !indexer.has_index(index) {
+        return Response::not_found();
+    }
+    let key = match websocket_key(request) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let events = events.subscribe();
+    let mut snapshot = None;
+    if request.query.split('&').any(|p| p == "snapshot=true") {
+        match indexer.export_table(index).await {
+            Ok((table, slot)) => {
+                snapshot = Some(json!({
+                    "index": index,
+                    "kind": "snapshot",
+                    "slot": slot,
+                    "data": { "columns": table.columns, "rows": table.rows },
+                }));
+            }
+            Err(e) => return Response::bad_request(format!("{e:#}\n")),
+        }
+    }
+    let index = index.to_string();
+    Response::websocket(key, move |stream| {
+        Box::pin(ws::stream_events(stream, index, events, snapshot, shutdown))
+    })
+}
+
+/// The client's `Sec-WebSocket-Key`, if `request` is a WebSocket handshake we can accept:
+/// one asking to upgrade to `websocket`, for version 13 of the protocol.
+#[cfg(feature = "http")]
+fn websocket_key(request: &Request) -> Result<&str, Response> {
+    // Either header can list more than one thing, as in `Connection: keep-alive, Upgrade`.
+    let lists = |name: &str, token: &str| {
+        request
+            .headers
+            .get(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if !lists("upgrade", "websocket") || !lists("connection", "upgrade") {
+        return Err(Response::bad_request(
+            "expected a WebSocket handshake\n".into(),
+        ));
+    }
+    if request
+        .headers
+        .get("sec-websocket-version")
+        .map(String::as_str)
+        != Some("13")
+    {
+        return Err(Response::bad_request(
+            "only WebSocket version 13 is supported\n".into(),
+        ));
+    }
+    // The key is 16 random bytes in base64, which always comes to 24 characters.
+    match request.headers.get("sec-websocket-key") {
+        Some(key) if key.len() == 24 => Ok(key),
+        _ => Err(Response::bad_request(
+            "expected a WebSocket handshake\n".into(),
+        )),
+    }
+}
+
+/// Serves every index's routes under `/index/{name}`, along with the `/healthz` and `/readyz`
+/// probes, which are ready once every index is within `ready_lag` slots of the tip,
+/// `/cursors`, with each index's committed cursor, and each index's live events on `/ws/{name}`.
+#[cfg(feature = "http")]
+pub struct QueryServer {
+    addr: SocketAddr,
+    indexes: Arc<HashMap<String, IndexRoutes>>,
+    indexer: ChainIndexerHandle,
+    events: BroadcastSink,
+    ready_lag: u64,
+}
+
+#[cfg(feature = "http")]
+impl QueryServer {
+    pub fn new(
+        addr: SocketAddr,
+        indexes: Vec<(String, IndexRoutes)>,
+        indexer: ChainIndexerHandle,
+        events: BroadcastSink,
+        ready_lag: u64,
+    ) -> Self {
+        Self {
+            addr,
+            indexes: Arc::new(indexes.into_iter().collect()),
+            indexer,
+            events,
+            ready_lag,
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+#[async_trait]
+impl Module for QueryServer {
+    fn name(&self) -> String {
+        "query".into()
+    }
+
+    async fn init(&mut self, context: Arc<Context>) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        let indexes = self.indexes.clone();
+        let indexer = self.indexer.clone();
+        let events = self.events.clone();
+        let shutdown = context.shutdown_token();
+        let ready_lag = self.ready_lag;
+        let handler: Handler = Arc::new(move |request: Request| {
+            let indexes = indexes.clone();
+            let indexer = indexer.clone();
+            let events = events.clone();
+            let shutdown = shutdown.clone();
+            Box::pin(async move {
+                if request.method != "GET" {
+                    return Response::not_found();
+                }
+                if let Some(index) = request.path.strip_prefix("/ws/") {
+                    return live_events(&request, index, &indexer, &events, shutdown).await;
+                }
+                match request.path.as_str() {
+                    "/healthz" => return healthz(&indexer),
+                    "/readyz" => return readyz(&indexer, ready_lag),
+                    "/cursors" => return cursors(&indexer),
+                    _ => {}
+                }
+                let Some(rest) = request.path.strip_prefix("/index/") else {
+                    return Response::not_found();
+                };
+                let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+                let Some(routes) = indexes.get(name) else {
+                    return Response::not_found();
+                };
+                routes
+                    .0
+                    .call(path)
+                    .await
+                    .unwrap_or_else(Response::not_found)
+            })
+        });
+        let shutdown = context.shutdown_token();
+        context.run(async move {
+            serve(listener, shutdown, handler).await;
+            Ok(())
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    // Sends `request` to a server which answers everything with 200, and returns the status line.
+    // A refused request should stop where the server stops reading, or the server hanging up on
+    // the rest resets the connection before the client reads the answer.
+    async fn send(request: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let handler: Handler =
+            Arc::new(|_| Box::pin(async { Response::ok("text/plain", "ok\n".into()) }));
+        tokio::spawn(serve(listener, shutdown.clone(), handler));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        shutdown.cancel();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn a_normal_request_is_answered() {
+        let status = send(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn an_overlong_request_line_is_refused() {
+        let line = format!("GET /{}", "a".repeat(MAX_LINE_BYTES - 5));
+        let status = send(line.as_bytes()).await;
+        assert_eq!(status, "HTTP/1.1 414 URI Too Long");
+    }
+
+    #[tokio::test]
+    async fn overlong_or_too_many_headers_are_refused() {
+        let header = format!("X-Big: {}", "a".repeat(MAX_LINE_BYTES - 7));
+        let status = send(format!("GET / HTTP/1.1\r\n{header}").as_bytes()).await;
+        assert_eq!(status, "HTTP/1.1 431 Request Header Fields Too Large");
+
+        let headers: String = (0..=MAX_HEADERS)
+            .map(|i| format!("X-{i}: {i}\r\n"))
+            .collect();
+        let status = send(format!("GET / HTTP/1.1\r\n{headers}").as_bytes()).await;
+        assert_eq!(status, "HTTP/1.1 431 Request Header Fields Too Large");
+    }
+
+    #[tokio::test]
+    async fn a_malformed_request_is_a_bad_request() {
+        for request in [
+            &b"GET /metrics\r\n\r\n"[..],
+            b"GET /metrics HTTP/1.1 extra\r\n\r\n",
+            b"GET metrics HTTP/1.1\r\n\r\n",
+            b"GET /metrics SMTP\r\n\r\n",
+            b"GET /metrics HTTP/1.1\r\nno colon here\r\n\r\n",
+            b"GET /metrics HTTP/1.1\r\nHost : localhost\r\n\r\n",
+            b"GET /\xff HTTP/1.1\r\n\r\n",
+        ] {
+            let status = send(request).await;
+            assert_eq!(status, "HTTP/1.1 400 Bad Request", "{request:?}");
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn a_body_is_read_up_to_a_limit() {
+        let status = send(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let head = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_BYTES + 1
+        );
+        let status = send(head.as_bytes()).await;
+        assert_eq!(status, "HTTP/1.1 413 Content Too Large");
+        for request in [
+            &b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\n\r\n\xff",
+        ] {
+            let status = send(request).await;
+            assert_eq!(status, "HTTP/1.1 400 Bad Request", "{request:?}");
+        }
+    }
+
+    #[cfg(feature = "http")]
+    fn handshake(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".into(),
+            path: "/ws/pools".into(),
+            query: String::new(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            #[cfg(feature = "metrics")]
+            body: String::new(),
+        }
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn only_a_proper_websocket_handshake_is_upgraded() {
+        let key = ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==");
+        let good = [
+            ("upgrade", "websocket"),
+            ("connection", "keep-alive, Upgrade"),
+            ("sec-websocket-version", "13"),
+            key,
+        ];
+        assert!(matches!(websocket_key(&handshake(&good)), Ok(k) if k == key.1));
+        for missing in 0..good.len() {
+            let mut headers = good.to_vec();
+            headers.remove(missing);
+            let refused = websocket_key(&handshake(&headers)).err().unwrap();
+            assert_eq!(refused.status, 400, "without {:?}", good[missing]);
+        }
+        for wrong in [
+            ("upgrade", "h2c"),
+            ("connection", "close"),
+            ("sec-websocket-version", "8"),
+            ("sec-websocket-key", "short"),
+        ] {
+            let mut headers = good.to_vec();
+            headers.retain(|(name, _)| *name != wrong.0);
+            headers.push(wrong);
+            let refused = websocket_key(&handshake(&headers)).err().unwrap();
+            assert_eq!(refused.status, 400, "with {wrong:?}");
+        }
+    }
+}
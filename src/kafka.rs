@@ -0,0 +1,144 @@
+// Index events published to a Kafka topic, for consumers further down the line.
+use std::collections::{BTreeMap, HashMap, hash_map::Entry};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use rskafka::{
+    client::{
+        Client, ClientBuilder,
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+    },
+    record::Record,
+};
+
+use crate::acropolis::indexer::{EventSink, IndexEvent};
+
+/// Publishes each event as a record of JSON, keyed by the name of the index it came from.
+/// Records go to the partition Kafka's own clients would pick for that key, so each index's
+/// events stay in order, and land where a producer in any other language would put them.
+pub struct KafkaSink {
+    client: Client,
+    topic: String,
+    partitions: Vec<i32>,
+    // One for each partition, made the first time something's published to it.
+    producers: HashMap<i32, PartitionClient>,
+}
+
+impl KafkaSink {
+    /// Connects through `brokers` (each `host:port`) and looks up `topic`, which has to exist.
+    pub async fn connect(brokers: Vec<String>, topic: &str) -> Result<Self> {
+        let client = ClientBuilder::new(brokers)
+            .build()
+            .await
+            .context("could not connect to Kafka")?;
+        let topics = client
+            .list_topics()
+            .await
+            .context("could not list the Kafka topics")?;
+        let Some(found) = topics.into_iter().find(|t| t.name == topic) else {
+            bail!("there's no Kafka topic called {topic}");
+        };
+        if found.partitions.is_empty() {
+            bail!("the Kafka topic {topic} has no partitions");
+        }
+        Ok(Self {
+            client,
+            topic: topic.into(),
+            partitions: found.partitions.into_iter().collect(),
+            producers: HashMap::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl EventSink for KafkaSink {
+    async fn publish(&mut self, index: &str, events: &[IndexEvent]) -> Result<()> {
+        let partition = partition_for(index.as_bytes(), &self.partitions);
+        let producer = match self.producers.entry(partition) {
+            Entry::Occupied(producer) => producer.into_mut(),
+            Entry::Vacant(entry) => {
+                let producer = self
+                    .client
+                    .partition_client(self.topic.clone(), partition, UnknownTopicHandling::Retry)
+                    .await
+                    .with_context(|| format!("could not reach partition {partition}"))?;
+                entry.insert(producer)
+            }
+        };
+        let timestamp = chrono::Utc::now();
+        let records = events
+            .iter()
+            .map(|event| Record {
+                key: Some(index.as_bytes().to_vec()),
+                value: Some(event.to_json(index).to_string().into_bytes()),
+                headers: BTreeMap::new(),
+                timestamp,
+            })
+            .collect();
+        producer
+            .produce(records, Compression::NoCompression)
+            .await
+            .with_context(|| format!("could not publish to {}", self.topic))?;
+        Ok(())
+    }
+}
+
+// Which of `partitions` (sorted) a record keyed `key` goes to, the way Kafka's default
+// partitioner picks one.
+fn partition_for(key: &[u8], partitions: &[i32]) -> i32 {
+    let positive = murmur2(key) & 0x7fff_ffff;
+    partitions[positive as usize % partitions.len()]
+}
+
+// The 32-bit MurmurHash2 Kafka hashes keys with, seed and all.
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate().rev() {
+            h ^= (*byte as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_hash_the_way_kafka_hashes_them() {
+        // From Kafka's own tests of its murmur2.
+        let cases: [(&[u8], i32); 6] = [
+            (b"21", -973932308),
+            (b"foobar", -790332482),
+            (b"a-little-bit-long-string", -985981536),
+            (b"a-little-bit-longer-string", -1486304829),
+            (
+                b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8",
+                -58897971,
+            ),
+            (b"abc", 479470107),
+        ];
+        for (key, hash) in cases {
+            assert_eq!(
+                murmur2(key) as i32,
+                hash,
+                "{}",
+                String::from_utf8_lossy(key)
+            );
+        }
+    }
+}
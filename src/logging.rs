@@ -0,0 +1,51 @@
+// The binary's tracing subscriber: tracing-subscriber's fmt, on stderr, filtered by `RUST_LOG`.
+use std::io::IsTerminal as _;
+
+use tracing_subscriber::EnvFilter;
+
+/// Logs each event to stderr as a line, with a timestamp and the spans it happened in.
+/// `RUST_LOG` picks what gets logged, as an `EnvFilter`: a level (`warn`), a level for a module
+/// (`info,acropolis_indexer_sample::acropolis=debug`), or a level inside a span, down to a
+/// field's value (`[index{name=pools}]=trace`). Without it, that's `info` and above.
+pub fn init() {
+    let spec = std::env::var("RUST_LOG").ok();
+    let (filter, invalid) = filter(spec.as_deref());
+    // Only fails if something's already set one, which is just as good.
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .try_init();
+    if let Some(error) = invalid {
+        tracing::warn!(%error, "RUST_LOG isn't a filter, so logging at info");
+    }
+}
+
+// The filter `spec` describes, or `info` if it's missing or doesn't parse, along with why not.
+fn filter(spec: Option<&str>) -> (EnvFilter, Option<String>) {
+    match spec.map(EnvFilter::try_new) {
+        Some(Ok(filter)) => (filter, None),
+        Some(Err(error)) => (EnvFilter::new("info"), Some(error.to_string())),
+        None => (EnvFilter::new("info"), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::level_filters::LevelFilter;
+
+    use super::*;
+
+    #[test]
+    fn rust_log_is_a_filter_and_info_is_the_default() {
+        let level = |spec| {
+            let (filter, invalid) = filter(spec);
+            (filter.max_level_hint(), invalid.is_some())
+        };
+        assert_eq!(level(None), (Some(LevelFilter::INFO), false));
+        assert_eq!(level(Some("warn")), (Some(LevelFilter::WARN), false));
+        let spans = "warn,[index{name=pools}]=trace";
+        assert_eq!(level(Some(spans)), (Some(LevelFilter::TRACE), false));
+        assert_eq!(level(Some("info,=nope")), (Some(LevelFilter::INFO), true));
+    }
+}
@@ -1,187 +1,4310 @@
 mod acropolis;
+mod config;
+#[cfg(any(feature = "metrics", feature = "http"))]
+mod http;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod logging;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod multisig;
+mod n2c;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 mod sundaev3;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "http")]
+mod ws;
 
-use std::collections::{BTreeMap, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque, hash_map::Entry},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use async_trait::async_trait;
 use clap::Parser as _;
-use pallas_addresses::Address;
-use pallas_crypto::hash::Hasher;
+use futures::{Stream, StreamExt as _};
+use pallas_addresses::{Address, ShelleyDelegationPart, ShelleyPaymentPart};
+use pallas_crypto::hash::{Hash, Hasher};
 use pallas_network::miniprotocols::Point;
-use pallas_primitives::conway::{MintedDatumOption, Value};
-use pallas_traverse::{MultiEraOutput, MultiEraTx, OutputRef};
+use pallas_primitives::{
+    BigInt, PlutusData, StakeCredential,
+    alonzo::Metadata,
+    conway::{MintedDatumOption, Value},
+};
+#[cfg(any(test, feature = "http"))]
+use pallas_primitives::{Bytes, NonEmptyKeyValuePairs, PositiveCoin};
+use pallas_traverse::{MultiEraAsset, MultiEraCert, MultiEraOutput, MultiEraTx, OutputRef};
 use plutus_parser::AsPlutus;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, broadcast};
 
+#[cfg(feature = "http")]
+use crate::acropolis::indexer::BroadcastSink;
 use crate::{
     acropolis::{
         core::{BlockHash, BlockInfo, Process},
-        indexer::{ChainIndexer, InMemoryCursorStore, ManagedIndex},
+        indexer::{
+            CURSOR_HISTORY, ChainIndexer, ChainIndexerHandle, ChainSource, CheckpointInterval,
+            CursorStore, ExportFormat, FnIndex, InMemoryCursorStore, IndexEvent, Inspection,
+            Intersect, JsonlSink, ManagedIndex, NotExportable, ReconnectPolicy, ResolvedInputs,
+            ResolvedOutputs, ResyncFrom, ScriptLanguage, Table, TxFilter, UnsupportedEra,
+            payment_credential, reference_script, spend_redeemers, tx_fee, tx_outputs, tx_size,
+        },
     },
-    sundaev3::{Ident, PoolDatum},
+    config::{Config, IndexConfig, IndexKind, Network, Protocol},
+    n2c::N2cChainSource,
+    sundaev3::{AssetClass, Ident, OrderDatum, PolicyId, PoolDatum, PoolRedeemer},
 };
 
+#[cfg(feature = "http")]
+use crate::http::{Response, Router};
+
+#[derive(Serialize, Deserialize)]
 struct PoolInfo {
+    // Every datum this pool has had, oldest first, keyed by the slot it appeared in.
+    // Rollbacks pop versions off the end until they're back before the rollback point.
+    // Each version also keeps the value of the pool's UTXO, which is where its reserves are.
+    versions: Vec<(u64, PoolDatum, Value)>,
+    // How much the LP supply changed by, and when, oldest first.
+    lp_history: Vec<(u64, BigInt)>,
+    // How much of each asset has been swapped through the pool, in and out.
+    #[serde(default)]
+    volume: (u128, u128),
+    // What each swap did to the reserves, and when, oldest first, so a rollback can take them
+    // back out of the volume.
+    #[serde(default)]
+    swaps: Vec<(u64, i128, i128)>,
+    // The UTXO (as `hash#index`) holding each of `versions`, and when it was created, so we
+    // can tell which input spends the pool. Snapshots from before this don't have them.
+    #[serde(default)]
+    utxos: Vec<(u64, String)>,
+    // The slot the pool was created in, which pruning the versions doesn't lose.
+    // Snapshots from before this don't have it, so it's filled in from their oldest version.
+    #[serde(default)]
     created_at: u64,
-    #[allow(unused)]
-    datum: PoolDatum,
 }
+
+impl PoolInfo {
+    /// The pool's current UTXO, if we know it.
+    fn utxo(&self) -> Option<OutputRef> {
+        self.utxos.last()?.1.parse().ok()
+    }
+
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    /// The pool's current datum. A pool only goes without one while its first is being added.
+    fn datum(&self) -> Option<&PoolDatum> {
+        let (_, datum, _) = self.versions.last()?;
+        Some(datum)
+    }
+
+    fn pair(&self) -> Option<AssetPair> {
+        let (_, datum, _) = self.versions.last()?;
+        Some(asset_pair(&datum.assets.0, &datum.assets.1))
+    }
+
+    /// Every change to the pool's LP supply, along with the slot it happened in.
+    /// Positive deltas are deposits, negative ones are withdrawals.
+    #[cfg(any(test, feature = "http"))]
+    fn lp_history(&self) -> &[(u64, BigInt)] {
+        &self.lp_history
+    }
+
+    /// How much of each of the pool's two assets has been swapped through it, either way.
+    /// A swap is any update which moves the reserves but leaves the LP supply alone, so
+    /// a tx which swaps and deposits at once isn't counted, and a batch of swaps only counts
+    /// for what it moved the reserves by overall.
+    #[cfg(any(test, feature = "http"))]
+    fn volume(&self) -> (u128, u128) {
+        self.volume
+    }
+
+    /// What each swap did to the reserves, along with the slot it happened in. Positive
+    /// deltas went into the pool, negative ones came out.
+    #[cfg(any(test, feature = "http"))]
+    fn swaps(&self) -> &[(u64, i128, i128)] {
+        &self.swaps
+    }
+
+    fn add_swap(&mut self, slot: u64, delta_a: i128, delta_b: i128) {
+        self.volume.0 += delta_a.unsigned_abs();
+        self.volume.1 += delta_b.unsigned_abs();
+        self.swaps.push((slot, delta_a, delta_b));
+    }
+
+    // Takes every swap at or after `slot` back out of the volume.
+    fn revert_swaps(&mut self, slot: u64) {
+        while self.swaps.last().is_some_and(|(s, ..)| *s >= slot) {
+            let (_, delta_a, delta_b) = self.swaps.pop().unwrap();
+            self.volume.0 -= delta_a.unsigned_abs();
+            self.volume.1 -= delta_b.unsigned_abs();
+        }
+    }
+
+    /// How much of each of the pool's two assets it holds.
+    /// The lovelace set aside for protocol fees doesn't count.
+    fn reserves(&self) -> Option<(u64, u64)> {
+        let (_, datum, value) = self.versions.last()?;
+        let reserve = |asset: &AssetClass| {
+            let held = quantity(value, asset);
+            if !asset.is_ada() {
+                return held;
+            }
+            let protocol_fees = big_int_to_i128(&datum.protocol_fees).unwrap_or_default();
+            held.saturating_sub(u64::try_from(protocol_fees).unwrap_or_default())
+        };
+        Some((reserve(&datum.assets.0), reserve(&datum.assets.1)))
+    }
+
+    /// How much of the second asset one of the first is worth, ignoring fees.
+    /// None while the pool holds none of the first, when there's no price to speak of.
+    fn price(&self) -> Option<f64> {
+        let (a, b) = self.reserves()?;
+        (a != 0).then(|| b as f64 / a as f64)
+    }
+
+    /// The bid and ask fees, as fractions of what's swapped.
+    fn fees(&self) -> Option<(f64, f64)> {
+        let datum = self.datum()?;
+        let fee = |per_10k| big_int_to_i128(per_10k).unwrap_or_default() as f64 / 10_000.0;
+        Some((
+            fee(&datum.bid_fees_per_10_thousand),
+            fee(&datum.ask_fees_per_10_thousand),
+        ))
+    }
+
+    /// What one of the first asset actually buys of the second, once the bid fee is taken,
+    /// for a swap too small to move the price.
+    fn effective_price(&self) -> Option<f64> {
+        let (bid, _) = self.fees()?;
+        Some(self.price()? * (1.0 - bid))
+    }
+}
+
+/// Two assets, in order, so a pool trading A for B and one trading B for A are under the same pair.
+type AssetPair = (AssetClass, AssetClass);
+
+fn asset_pair(a: &AssetClass, b: &AssetClass) -> AssetPair {
+    if a <= b {
+        (a.clone(), b.clone())
+    } else {
+        (b.clone(), a.clone())
+    }
+}
+
+fn big_int_to_i128(value: &BigInt) -> Result<i128> {
+    let magnitude = |bytes: &[u8]| {
+        if bytes.len() > 16 {
+            bail!("integer too large");
+        }
+        let magnitude = bytes.iter().fold(0u128, |acc, b| (acc << 8) | *b as u128);
+        i128::try_from(magnitude).map_err(|_| anyhow!("integer too large"))
+    };
+    match value {
+        BigInt::Int(int) => Ok(i128::from(*int)),
+        BigInt::BigUInt(bytes) => magnitude(bytes),
+        BigInt::BigNInt(bytes) => Ok(-1 - magnitude(bytes)?),
+    }
+}
+
+fn i128_to_big_int(value: i128) -> Result<BigInt> {
+    let int = value.try_into().map_err(|_| anyhow!("integer too large"))?;
+    Ok(BigInt::Int(int))
+}
+
+// How much the LP supply went up by, from `previous` (nothing, for a new pool) to `current`,
+// both as a number and as a datum would hold it. None if either is out of range.
+fn lp_delta(previous: Option<&BigInt>, current: &BigInt) -> Option<(i128, BigInt)> {
+    let previous = previous.map_or(Ok(0), big_int_to_i128).ok()?;
+    let delta = big_int_to_i128(current).ok()?.checked_sub(previous)?;
+    Some((delta, i128_to_big_int(delta).ok()?))
+}
+
+// The class of the token called `name` under `policy`, as a block has it. The ledger keeps
+// names to 32 bytes, so one that's longer can't be a real token, and has no class.
+fn asset_class(policy: &Hash<28>, name: &[u8]) -> Option<AssetClass> {
+    Some(AssetClass::new(**policy, name.try_into().ok()?))
+}
+
+// How much of `asset` is in `value`.
+fn quantity(value: &Value, asset: &AssetClass) -> u64 {
+    match (value, &asset.policy) {
+        (Value::Coin(coin) | Value::Multiasset(coin, _), None) => *coin,
+        (Value::Coin(_), Some(_)) => 0,
+        (Value::Multiasset(_, assets), Some(wanted)) => assets
+            .iter()
+            .filter(|(policy, _)| policy.as_ref() == wanted.as_ref())
+            .flat_map(|(_, names)| names.iter())
+            .filter(|(name, _)| name.as_slice() == asset.name.as_ref())
+            .map(|(_, quantity)| u64::from(quantity))
+            .sum(),
+    }
+}
+
+/// Adds up `values`, merging native assets with the same policy and name.
+#[cfg(any(test, feature = "http"))]
+fn sum_values<'a>(values: impl IntoIterator<Item = &'a Value>) -> Value {
+    let mut coin = 0;
+    let mut assets = BTreeMap::<Hash<28>, BTreeMap<Bytes, u64>>::new();
+    for value in values {
+        match value {
+            Value::Coin(c) => coin += c,
+            Value::Multiasset(c, multiasset) => {
+                coin += c;
+                for (policy, names) in multiasset.iter() {
+                    let totals = assets.entry(*policy).or_default();
+                    for (name, quantity) in names.iter() {
+                        *totals.entry(name.clone()).or_default() += u64::from(quantity);
+                    }
+                }
+            }
+        }
+    }
+    let assets = assets
+        .into_iter()
+        .filter_map(|(policy, names)| {
+            let names = names
+                .into_iter()
+                .filter_map(|(name, quantity)| Some((name, PositiveCoin::try_from(quantity).ok()?)))
+                .collect();
+            Some((policy, NonEmptyKeyValuePairs::from_vec(names)?))
+        })
+        .collect();
+    match NonEmptyKeyValuePairs::from_vec(assets) {
+        Some(assets) => Value::Multiasset(coin, assets),
+        None => Value::Coin(coin),
+    }
+}
+
+/// Every asset in `value` along with how much of it there is, starting with lovelace.
+#[cfg(any(test, feature = "http"))]
+fn asset_quantities(value: &Value) -> impl Iterator<Item = (AssetClass, u64)> + '_ {
+    let (coin, assets) = match value {
+        Value::Coin(coin) => (*coin, None),
+        Value::Multiasset(coin, assets) => (*coin, Some(assets)),
+    };
+    let lovelace = AssetClass::ada();
+    let assets = assets
+        .into_iter()
+        .flat_map(|assets| assets.iter())
+        .flat_map(|(policy, names)| {
+            names.iter().filter_map(|(name, quantity)| {
+                Some((asset_class(policy, name)?, u64::from(quantity)))
+            })
+        });
+    std::iter::once((lovelace, coin)).chain(assets)
+}
+
 struct PoolIndex {
+    // Pools only ever live at the pool script's address; if we know it, we can skip every other tx.
+    script_address: Option<Address>,
+    // Every pool output holds the pool's NFT, so if we know its policy, we can skip
+    // decoding any datum on an output without it.
+    policy: Option<PolicyId>,
     // Pretend this is something persistent like a database.
     pools: BTreeMap<Ident, PoolInfo>,
+    // Which pools trade each pair of assets, as of their latest datum.
+    by_pair: BTreeMap<AssetPair, BTreeSet<Ident>>,
+    // Outputs at the pool address whose datum wasn't a pool datum.
+    skipped_datums: u64,
+    // Outputs at the pool address with no datum at all, which can't be pools.
+    datumless_outputs: u64,
+    // How many datums we've tried to decode, and how many we didn't have to thanks to the policy.
+    decoded_datums: u64,
+    unchecked_datums: u64,
+    // Pool outputs with only a datum hash, whose datum we couldn't find.
+    unresolved_datums: u64,
+    // Whether to look for datums in the inline datums of reference inputs, too.
+    reference_datums: bool,
+    // How many slots of history to keep for rollbacks. Forever if None.
+    rollback_window: Option<u64>,
+    // History from before this slot has been dropped, so a rollback past it can't be undone.
+    pruned_before: Option<u64>,
+    events: Vec<IndexEvent>,
+    // Every pool update, for whoever's subscribed.
+    updates: broadcast::Sender<PoolUpdate>,
+}
+
+// How many updates a subscriber can fall behind by before it starts missing them.
+const POOL_UPDATE_BACKLOG: usize = 1024;
+
+// How many events the indexer holds on to for WebSocket clients which haven't picked them up yet.
+#[cfg(feature = "http")]
+const LIVE_EVENT_BACKLOG: usize = 4096;
+
+/// Something that's happened to one pool, for subscribers.
+#[derive(Clone)]
+enum PoolUpdate {
+    /// The pool has a new datum, or the same datum on a UTXO with a new value.
+    Updated {
+        ident: Ident,
+        slot: u64,
+        datum: PoolDatum,
+        value: Value,
+        /// What the tx says it did to the pool, from the redeemer spending its old UTXO.
+        /// None for a new pool, or if we don't know its old UTXO or can't decode the redeemer.
+        action: Option<PoolRedeemer>,
+    },
+    /// A rollback to `slot` undid `reverted` of the pool's updates. If that was all of them,
+    /// the pool's gone; otherwise, it's back to `datum` and `value`.
+    RolledBack {
+        ident: Ident,
+        slot: u64,
+        reverted: usize,
+        current: Option<(PoolDatum, Value)>,
+    },
+}
+
+impl PoolUpdate {
+    fn ident(&self) -> &Ident {
+        match self {
+            PoolUpdate::Updated { ident, .. } | PoolUpdate::RolledBack { ident, .. } => ident,
+        }
+    }
 }
 
 impl PoolIndex {
-    fn new() -> Self {
+    fn new(script_address: Option<Address>, policy: Option<PolicyId>) -> Self {
         Self {
+            script_address,
+            policy,
             pools: BTreeMap::new(),
+            by_pair: BTreeMap::new(),
+            skipped_datums: 0,
+            datumless_outputs: 0,
+            decoded_datums: 0,
+            unchecked_datums: 0,
+            unresolved_datums: 0,
+            reference_datums: false,
+            rollback_window: None,
+            pruned_before: None,
+            events: vec![],
+            updates: broadcast::Sender::new(POOL_UPDATE_BACKLOG),
         }
     }
-}
 
-fn parse_datum<T: AsPlutus>(output: &MultiEraOutput, tx: &MultiEraTx) -> Option<T> {
-    match output.datum()? {
-        MintedDatumOption::Data(d) => T::from_plutus(d.0.unwrap()).ok(),
-        MintedDatumOption::Hash(h) => tx.plutus_data().iter().find_map(|d| {
-            let hash = Hasher::<256>::hash(d.raw_cbor());
-            if hash != h {
-                return None;
+    /// Every update to the pool `ident` from now on, rollbacks included. Updates are sent as
+    /// the index handles each block, and a subscriber which falls too far behind misses some
+    /// (with a warning saying how many) rather than holding the index up.
+    fn subscribe(&self, ident: Ident) -> impl Stream<Item = PoolUpdate> + use<> {
+        let receiver = self.updates.subscribe();
+        futures::stream::unfold(receiver, move |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(update) if *update.ident() == ident => return Some((update, receiver)),
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!(
+                            ident = hex::encode(ident),
+                            missed,
+                            "a pool subscriber fell behind"
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Only keep enough of each pool's history to roll back `slots`, rather than all of it.
+    /// A deeper rollback starts the index over from the origin.
+    fn with_rollback_window(mut self, slots: u64) -> Self {
+        self.rollback_window = Some(slots);
+        self
+    }
+
+    fn pruned(&mut self, before_slot: u64) {
+        self.pruned_before = self.pruned_before.max(Some(before_slot));
+    }
+
+    /// How many outputs at the pool address had a datum we couldn't decode.
+    /// Anything but zero probably means the datum format has changed.
+    fn skipped_datums(&self) -> u64 {
+        self.skipped_datums
+    }
+
+    /// How many outputs at the pool address had no datum. Anyone can send funds there, so
+    /// these are just ignored, and unlike `skipped_datums`, they say nothing about the format.
+    fn datumless_outputs(&self) -> u64 {
+        self.datumless_outputs
+    }
+
+    /// How many pool outputs we've had to skip because they only had a datum hash, and the
+    /// datum wasn't in the tx. If this starts rising, pools are being lost.
+    fn unresolved_datums(&self) -> u64 {
+        self.unresolved_datums
+    }
+
+    /// Also resolve datum hashes against the inline datums of a tx's reference inputs.
+    /// That needs resolved inputs, which cost memory, so it's off by default.
+    fn with_reference_datums(mut self) -> Self {
+        self.reference_datums = true;
+        self
+    }
+
+    /// How many datums we've decoded, and how many we've passed over because
+    /// their output didn't hold a token under the pool policy.
+    fn datum_counts(&self) -> (u64, u64) {
+        (self.decoded_datums, self.unchecked_datums)
+    }
+
+    /// Every pool trading `a` for `b`, or `b` for `a`.
+    #[cfg(any(test, feature = "http"))]
+    fn pools_for_pair(&self, a: &AssetClass, b: &AssetClass) -> Vec<&PoolInfo> {
+        self.by_pair
+            .get(&asset_pair(a, b))
+            .into_iter()
+            .flatten()
+            .map(|ident| &self.pools[ident])
+            .collect()
+    }
+
+    // Indexes every pool output in `tx`, with `datums` to resolve their datums.
+    fn index_tx(
+        &mut self,
+        info: &BlockInfo,
+        tx: &MultiEraTx,
+        mut datums: DatumCache<PoolDatum>,
+    ) -> anyhow::Result<()> {
+        let redeemers = spend_redeemers(tx);
+        for (output_ref, output) in tx_outputs(tx) {
+            let output = &output;
+            if !self.holds_policy(output) {
+                if output.datum().is_some() {
+                    self.unchecked_datums += 1;
+                }
+                continue;
             }
-            T::from_plutus(d.clone().unwrap()).ok()
-        }),
+            // Plenty of outputs carry datums which aren't pools, so a decode error isn't fatal here.
+            // At the pool address, though, every datum should be a pool, so we keep count.
+            let parsed = datums.parse(output);
+            let is_pool = self.is_pool_address(output) || self.policy.is_some();
+            let pd = match parsed {
+                Ok(Some(pd)) => {
+                    self.decoded_datums += 1;
+                    pd
+                }
+                Ok(None) => {
+                    if self.is_pool_address(output) {
+                        self.datumless_outputs += 1;
+                    }
+                    continue;
+                }
+                Err(e) if e.is::<UnresolvedDatum>() => {
+                    if is_pool {
+                        tracing::warn!(
+                            output = %output_ref,
+                            "skipping a pool: {e}"
+                        );
+                        self.unresolved_datums += 1;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    self.decoded_datums += 1;
+                    if self.is_pool_address(output) {
+                        if self.skipped_datums == 0 {
+                            tracing::warn!(
+                                output = %output_ref,
+                                error = format!("{e:#}"),
+                                "skipping a pool datum we can't decode (and any more after it, quietly)"
+                            );
+                        }
+                        self.skipped_datums += 1;
+                    }
+                    continue;
+                }
+            };
+            // An LP supply, or a change in it, too big for a datum to hold isn't a real pool,
+            // so it's skipped like any other datum we can't make sense of.
+            let previous = self.pools.get(&pd.ident).and_then(|p| p.datum());
+            let Some((delta, lp_change)) =
+                lp_delta(previous.map(|d| &*d.circulating_lp), &pd.circulating_lp)
+            else {
+                tracing::warn!(output = %output_ref, "skipping a pool whose LP supply is out of range");
+                self.skipped_datums += 1;
+                continue;
+            };
+            // In reality, this would probably be updating a DB
+            let ident = pd.ident;
+            let pool = self.pools.entry(pd.ident).or_insert_with(|| PoolInfo {
+                versions: vec![],
+                lp_history: vec![],
+                volume: (0, 0),
+                swaps: vec![],
+                utxos: vec![],
+                created_at: info.slot,
+            });
+            let action = pool
+                .utxo()
+                .and_then(|spent| redeemers.get(&spent))
+                .and_then(|r| PoolRedeemer::from_plutus(r.data().clone()).ok());
+            // A change in LP supply means someone deposited or withdrew.
+            if delta != 0 {
+                pool.lp_history.push((info.slot, lp_change));
+            }
+            self.events.push(IndexEvent::new(
+                "pool_updated",
+                info.slot,
+                serde_json::json!({
+                    "ident": hex::encode(pd.ident),
+                    "circulating_lp": pd.circulating_lp,
+                    "action": action.as_ref().map(PoolRedeemer::action),
+                }),
+            ));
+            let old_pair = pool.pair();
+            let old_reserves = pool.reserves();
+            let value = output.value().into_conway();
+            // Nobody listening isn't a problem.
+            let _ = self.updates.send(PoolUpdate::Updated {
+                ident,
+                slot: info.slot,
+                datum: pd.clone(),
+                value: value.clone(),
+                action,
+            });
+            pool.versions.push((info.slot, pd, value));
+            pool.utxos.push((info.slot, output_ref.to_string()));
+            relink_pair(&mut self.by_pair, &ident, old_pair, pool.pair());
+            // With the LP supply unchanged, anything that moved the reserves was a swap.
+            if let Some(((old_a, old_b), (a, b))) =
+                old_reserves.filter(|_| delta == 0).zip(pool.reserves())
+            {
+                let (delta_a, delta_b) = (a as i128 - old_a as i128, b as i128 - old_b as i128);
+                if delta_a != 0 || delta_b != 0 {
+                    pool.add_swap(info.slot, delta_a, delta_b);
+                }
+            }
+            // Only the pool that's changed has any history to drop.
+            if let Some(cutoff) = self.rollback_window.and_then(|w| info.slot.checked_sub(w)) {
+                prune_versions(&mut pool.versions, |(slot, ..)| *slot, cutoff);
+                prune_versions(&mut pool.utxos, |(slot, _)| *slot, cutoff);
+                self.pruned(cutoff);
+            }
+        }
+        // This method is fallible; if it fails, the indexer will stop updating this index.
+        Ok(())
+    }
+
+    fn is_pool_address(&self, output: &MultiEraOutput) -> bool {
+        self.script_address.is_some() && output.address().ok() == self.script_address
+    }
+
+    fn holds_policy(&self, output: &MultiEraOutput) -> bool {
+        let Some(policy) = &self.policy else {
+            return true;
+        };
+        output
+            .value()
+            .assets()
+            .iter()
+            .any(|p| p.policy().as_ref() == policy.as_ref())
+    }
+}
+
+/// Decodes the datums of one tx's outputs. Each distinct datum is only decoded once, however
+/// many outputs carry it, and the tx's witnessed datums are only hashed once, the first time an
+/// output needs one. It's scoped to a single tx, since that's where a datum hash gets resolved.
+struct DatumCache<'a, 'b, T> {
+    tx: &'a MultiEraTx<'b>,
+    // The inline datums of the tx's (already resolved) reference inputs, which can also
+    // resolve a datum hash.
+    referenced: HashMap<Hash<32>, PlutusData>,
+    witnessed: Option<HashMap<Hash<32>, PlutusData>>,
+    // By datum hash, along with the error if it wasn't a T.
+    decoded: HashMap<Hash<32>, Result<T, String>>,
+}
+
+impl<'a, 'b, T: AsPlutus + Clone> DatumCache<'a, 'b, T> {
+    fn new(tx: &'a MultiEraTx<'b>) -> Self {
+        Self::with_refs(tx, &[])
+    }
+
+    fn with_refs(tx: &'a MultiEraTx<'b>, resolved_ref_inputs: &[MultiEraOutput]) -> Self {
+        let referenced = resolved_ref_inputs
+            .iter()
+            .filter_map(|o| match o.datum()? {
+                MintedDatumOption::Data(d) => {
+                    Some((Hasher::<256>::hash(d.0.raw_cbor()), d.0.unwrap()))
+                }
+                MintedDatumOption::Hash(_) => None,
+            })
+            .collect();
+        Self {
+            tx,
+            referenced,
+            witnessed: None,
+            decoded: HashMap::new(),
+        }
+    }
+
+    /// Returns Ok(None) if the output has no datum, and an error if it has one but it isn't
+    /// a T, or if it only has a datum hash which neither the tx nor its reference inputs
+    /// resolve (that error is an `UnresolvedDatum`). The output has to belong to this cache's tx.
+    fn parse(&mut self, output: &MultiEraOutput) -> Result<Option<T>> {
+        let (hash, inline) = match output.datum() {
+            None => return Ok(None),
+            Some(MintedDatumOption::Data(d)) => (Hasher::<256>::hash(d.0.raw_cbor()), Some(d)),
+            Some(MintedDatumOption::Hash(h)) => (h, None),
+        };
+        if let Some(decoded) = self.decoded.get(&hash) {
+            return decoded.clone().map(Some).map_err(|e| anyhow!(e));
+        }
+        let data = match inline {
+            Some(d) => d.0.unwrap(),
+            None => match self.resolve(&hash) {
+                Some(d) => d,
+                None => return Err(UnresolvedDatum(hash).into()),
+            },
+        };
+        let decoded = T::from_plutus(data).map_err(|e| e.to_string());
+        self.decoded.insert(hash, decoded.clone());
+        decoded.map(Some).map_err(|e| anyhow!(e))
+    }
+
+    fn resolve(&mut self, hash: &Hash<32>) -> Option<PlutusData> {
+        let tx = self.tx;
+        let witnessed = self.witnessed.get_or_insert_with(|| {
+            tx.plutus_data()
+                .iter()
+                .map(|d| (Hasher::<256>::hash(d.raw_cbor()), d.clone().unwrap()))
+                .collect()
+        });
+        witnessed
+            .get(hash)
+            .or_else(|| self.referenced.get(hash))
+            .cloned()
+    }
+}
+
+/// An output's datum hash, whose datum isn't anywhere we can see it.
+#[derive(Debug)]
+struct UnresolvedDatum(Hash<32>);
+
+impl std::fmt::Display for UnresolvedDatum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "datum hash {} isn't resolved by its tx", self.0)
+    }
+}
+
+impl std::error::Error for UnresolvedDatum {}
+
+/// The latest datum of each kind of thing whose datums decode as a T, keyed by whatever `key`
+/// picks out of the datum, with every earlier version kept so rollbacks can undo them by slot.
+/// It's the bones of `PoolIndex`, without anything pool-specific.
+struct DatumIndex<T, K> {
+    name: String,
+    key: Box<dyn Fn(&T) -> K + Send + Sync>,
+    filter: TxFilter,
+    // Every version of each key's datum, oldest first, with the slot it appeared in.
+    versions: BTreeMap<K, Vec<(u64, T)>>,
+}
+
+impl<T, K: Ord> DatumIndex<T, K> {
+    fn new(name: impl Into<String>, key: impl Fn(&T) -> K + Send + Sync + 'static) -> Self {
+        Self {
+            name: name.into(),
+            key: Box::new(key),
+            filter: TxFilter::All,
+            versions: BTreeMap::new(),
+        }
+    }
+
+    /// Only look at txs which pass `filter`, say the script address the datums live at.
+    fn with_filter(mut self, filter: TxFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    #[cfg(any(test, feature = "http"))]
+    fn latest(&self, key: &K) -> Option<&T> {
+        let (_, datum) = self.versions.get(key)?.last()?;
+        Some(datum)
+    }
+
+    /// Every datum `key` has had, oldest first, along with the slot it appeared in.
+    #[cfg(any(test, feature = "http"))]
+    fn history(&self, key: &K) -> &[(u64, T)] {
+        self.versions.get(key).map_or(&[], |v| v.as_slice())
     }
 }
 
-// Managed indexes are written in an "event handler" style.
-// They react to a stream of events, starting at a configured point on the chain.
-// Each index can be somewhere different on-chain, so they should be granular.
 #[async_trait]
-impl ManagedIndex for PoolIndex {
+impl<T, K> ManagedIndex for DatumIndex<T, K>
+where
+    T: AsPlutus + Clone + Serialize + Send + Sync + 'static,
+    K: Ord + std::str::FromStr + Send + Sync + 'static,
+{
     fn name(&self) -> String {
-        "pools".into()
+        self.name.clone()
+    }
+
+    fn tx_filter(&self) -> TxFilter {
+        self.filter.clone()
     }
 
     async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> anyhow::Result<()> {
-        for output in tx.outputs() {
-            let Some(pd) = parse_datum::<PoolDatum>(&output, tx) else {
+        let mut datums = DatumCache::<T>::new(tx);
+        // The filter lets the whole tx through, but only its own outputs hold our datums.
+        for (_, output) in tx_outputs(tx).filter(|(_, o)| self.filter.matches_output(o)) {
+            // Any datum that isn't a T just isn't one of ours.
+            let Ok(Some(datum)) = datums.parse(&output) else {
                 continue;
             };
-            // In reality, this would probably be updating a DB
-            self.pools.insert(
-                pd.ident.clone(),
-                PoolInfo {
-                    created_at: info.slot,
-                    datum: pd,
-                },
-            );
+            let key = (self.key)(&datum);
+            self.versions
+                .entry(key)
+                .or_default()
+                .push((info.slot, datum));
         }
-        // This method is fallible; if it fails, the indexer will stop updating this index.
         Ok(())
     }
 
-    async fn handle_rollback(&mut self, info: &acropolis::core::BlockInfo) -> anyhow::Result<()> {
-        self.pools.retain(|_, v| v.created_at < info.slot);
+    fn inspect_tx(&self, _info: &BlockInfo, tx: &MultiEraTx) -> Inspection {
+        let mut datums = DatumCache::<T>::new(tx);
+        let parsed = tx_outputs(tx)
+            .filter(|(_, o)| self.filter.matches_output(o))
+            .filter(|(_, o)| matches!(datums.parse(o), Ok(Some(_))))
+            .count();
+        Inspection {
+            parsed: parsed as u64,
+            failed: 0,
+        }
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        self.versions.retain(|_, versions| {
+            versions.retain(|(slot, _)| *slot < info.slot);
+            !versions.is_empty()
+        });
+        Ok(())
+    }
+
+    async fn prune(&mut self, before_slot: u64) -> anyhow::Result<()> {
+        for versions in self.versions.values_mut() {
+            prune_versions(versions, |(slot, _)| *slot, before_slot);
+        }
         Ok(())
     }
+
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        let router = Router::new()
+            .get("/{key}", |index: &Self, params| {
+                let datum = params
+                    .get("key")
+                    .and_then(|key| key.parse().ok())
+                    .and_then(|key| index.latest(&key))
+                    .and_then(|datum| serde_json::to_value(datum).ok());
+                match datum {
+                    Some(datum) => Response::json(datum),
+                    None => Response::not_found(),
+                }
+            })
+            // Every datum the key has had, oldest first.
+            .get("/{key}/history", |index: &Self, params| {
+                let Some(key) = params.get("key").and_then(|key| key.parse().ok()) else {
+                    return Response::not_found();
+                };
+                let history = index
+                    .history(&key)
+                    .iter()
+                    .map(|(slot, datum)| serde_json::json!({ "slot": slot, "datum": datum }));
+                Response::json(history.collect::<Vec<_>>().into())
+            });
+        Some(router)
+    }
 }
 
-struct WalletIndex {
-    address: Address,
-    utxos: Vec<(OutputRef, Value)>,
+// Every datum each pool has had, and nothing else the pool index keeps: a `DatumIndex`
+// keyed by the pool's ident, at the pool address if there is one.
+fn pool_datums(address: Option<Address>) -> DatumIndex<PoolDatum, Ident> {
+    let index = DatumIndex::new("pool_datums", |datum: &PoolDatum| datum.ident);
+    match address {
+        Some(address) => index.with_filter(TxFilter::Address(address)),
+        None => index,
+    }
 }
-impl WalletIndex {
-    fn new(address: Address) -> Self {
-        Self {
-            address,
-            utxos: vec![],
+
+// Drops every version from before `before_slot` but the last, which is what a rollback
+// to `before_slot` goes back to. `versions` is oldest first.
+fn prune_versions<T>(versions: &mut Vec<T>, slot: impl Fn(&T) -> u64, before_slot: u64) {
+    let older = versions
+        .iter()
+        .take_while(|v| slot(v) < before_slot)
+        .count();
+    if older > 1 {
+        versions.drain(..older - 1);
+    }
+}
+
+// Moves a pool from the pair it traded to the one it trades now, if they're different.
+fn relink_pair(
+    by_pair: &mut BTreeMap<AssetPair, BTreeSet<Ident>>,
+    ident: &Ident,
+    old: Option<AssetPair>,
+    new: Option<AssetPair>,
+) {
+    if old == new {
+        return;
+    }
+    if let Some(old) = old
+        && let Some(idents) = by_pair.get_mut(&old)
+    {
+        idents.remove(ident);
+        if idents.is_empty() {
+            by_pair.remove(&old);
         }
     }
+    if let Some(new) = new {
+        by_pair.entry(new).or_default().insert(*ident);
+    }
 }
 
+// Managed indexes are written in an "event handler" style.
+// They react to a stream of events, starting at a configured point on the chain.
+// Each index can be somewhere different on-chain, so they should be granular.
 #[async_trait]
-impl ManagedIndex for WalletIndex {
+impl ManagedIndex for PoolIndex {
     fn name(&self) -> String {
-        "wallet".into()
+        "pools".into()
     }
 
-    async fn handle_onchain_tx(
+    fn tx_filter(&self) -> TxFilter {
+        match &self.script_address {
+            Some(address) => TxFilter::Address(address.clone()),
+            None => TxFilter::All,
+        }
+    }
+
+    async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> anyhow::Result<()> {
+        self.index_tx(info, tx, DatumCache::new(tx))
+    }
+
+    fn needs_resolved_inputs(&self) -> bool {
+        self.reference_datums
+    }
+
+    // Reference inputs only matter for the datums they hold.
+    fn resolved_outputs(&self) -> ResolvedOutputs {
+        ResolvedOutputs::InlineDatums
+    }
+
+    async fn handle_onchain_tx_with_inputs(
         &mut self,
-        _info: &acropolis::core::BlockInfo,
-        tx: &pallas_traverse::MultiEraTx,
+        info: &BlockInfo,
+        tx: &MultiEraTx,
+        inputs: &ResolvedInputs,
     ) -> anyhow::Result<()> {
-        let spent = tx
-            .inputs()
+        let refs = tx.reference_inputs();
+        let refs: Vec<_> = refs
             .iter()
-            .map(|i| i.output_ref())
-            .collect::<HashSet<_>>();
-        self.utxos.retain(|u| !spent.contains(&u.0));
-        for (out_idx, output) in tx.outputs().iter().enumerate() {
-            if output.address().is_ok_and(|a| a == self.address) {
-                let ref_ = OutputRef::new(tx.hash(), out_idx as u64);
-                self.utxos.push((ref_, output.value().into_conway()));
+            .filter_map(|i| inputs.get(&i.output_ref()))
+            .collect();
+        self.index_tx(info, tx, DatumCache::with_refs(tx, &refs))
+    }
+
+    fn inspect_tx(&self, _info: &BlockInfo, tx: &MultiEraTx) -> Inspection {
+        let mut inspection = Inspection::default();
+        let mut datums = DatumCache::<PoolDatum>::new(tx);
+        for (_, output) in tx_outputs(tx).filter(|(_, o)| self.holds_policy(o)) {
+            match datums.parse(&output) {
+                Ok(Some(_)) => inspection.parsed += 1,
+                Ok(None) => {}
+                // Only a datum at the pool address has to be a pool.
+                Err(_) if self.is_pool_address(&output) => inspection.failed += 1,
+                Err(_) => {}
+            }
+        }
+        inspection
+    }
+
+    async fn handle_rollback(&mut self, info: &acropolis::core::BlockInfo) -> anyhow::Result<()> {
+        if let Some(retained_since) = self.pruned_before.filter(|p| *p > info.slot) {
+            return Err(PoolRollbackTooDeep {
+                to_slot: info.slot,
+                retained_since,
             }
+            .into());
         }
+        let events = &mut self.events;
+        let by_pair = &mut self.by_pair;
+        let updates = &self.updates;
+        self.pools.retain(|ident, pool| {
+            let old_pair = pool.pair();
+            let mut reverted = 0;
+            for (slot, ..) in pool.versions.iter().filter(|(slot, ..)| *slot >= info.slot) {
+                events.push(IndexEvent::new(
+                    "pool_updated",
+                    *slot,
+                    serde_json::json!({ "ident": hex::encode(ident) }),
+                ));
+                reverted += 1;
+            }
+            pool.versions.retain(|(slot, ..)| *slot < info.slot);
+            pool.utxos.retain(|(slot, _)| *slot < info.slot);
+            if reverted > 0 {
+                let current = pool.versions.last();
+                let _ = updates.send(PoolUpdate::RolledBack {
+                    ident: *ident,
+                    slot: info.slot,
+                    reverted,
+                    current: current.map(|(_, datum, value)| (datum.clone(), value.clone())),
+                });
+            }
+            pool.lp_history.retain(|(slot, _)| *slot < info.slot);
+            pool.revert_swaps(info.slot);
+            relink_pair(by_pair, ident, old_pair, pool.pair());
+            !pool.versions.is_empty()
+        });
         Ok(())
     }
-}
 
-#[derive(clap::Parser, Debug)]
-struct Args {
-    #[arg(short, long)]
-    addr: String,
+    // The LP history and the swaps are what the index is for, not just a way to roll back,
+    // so they're kept; only the versions and UTXOs a rollback can't reach any more go.
+    async fn prune(&mut self, before_slot: u64) -> anyhow::Result<()> {
+        for pool in self.pools.values_mut() {
+            prune_versions(&mut pool.versions, |(slot, ..)| *slot, before_slot);
+            prune_versions(&mut pool.utxos, |(slot, _)| *slot, before_slot);
+        }
+        self.pruned(before_slot);
+        Ok(())
+    }
 
-    #[arg(short, long)]
-    magic: u64,
+    fn retained_since(&self) -> Option<u64> {
+        self.pruned_before
+    }
 
-    #[arg(long)]
-    wallet_address: Address,
+    // Starting over from the rollback would lose every pool created before it, so the index
+    // forgets everything and has itself resynced from the origin.
+    async fn reset(&mut self) -> anyhow::Result<()> {
+        for (ident, pool) in std::mem::take(&mut self.pools) {
+            let _ = self.updates.send(PoolUpdate::RolledBack {
+                ident,
+                slot: 0,
+                reverted: pool.versions.len(),
+                current: None,
+            });
+        }
+        self.by_pair.clear();
+        self.skipped_datums = 0;
+        self.datumless_outputs = 0;
+        self.decoded_datums = 0;
+        self.unchecked_datums = 0;
+        self.unresolved_datums = 0;
+        self.pruned_before = None;
+        self.events.clear();
+        Err(ResyncFrom {
+            point: Point::Origin,
+        }
+        .into())
+    }
 
-    #[command(subcommand)]
-    command: Commands,
+    fn drain_events(&mut self) -> Vec<IndexEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn export(&self) -> Option<Table> {
+        let mut table = Table::new(&[
+            "ident",
+            "asset_a",
+            "asset_b",
+            "circulating_lp",
+            "created_at",
+            "reserve_a",
+            "reserve_b",
+            "price",
+            "bid_fee",
+            "ask_fee",
+            "effective_price",
+        ]);
+        for pool in self.pools.values() {
+            let (Some(datum), Some((reserve_a, reserve_b))) = (pool.datum(), pool.reserves())
+            else {
+                continue;
+            };
+            // An LP supply too big for an i128 isn't a real pool, so it's left blank.
+            let circulating_lp = big_int_to_i128(&datum.circulating_lp)
+                .map(|lp| lp.to_string())
+                .unwrap_or_default();
+            let (bid_fee, ask_fee) = pool.fees().unzip();
+            let or_blank = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+            table.push(vec![
+                hex::encode(datum.ident),
+                datum.assets.0.to_string(),
+                datum.assets.1.to_string(),
+                circulating_lp,
+                pool.created_at().to_string(),
+                reserve_a.to_string(),
+                reserve_b.to_string(),
+                or_blank(pool.price()),
+                or_blank(bid_fee),
+                or_blank(ask_fee),
+                or_blank(pool.effective_price()),
+            ]);
+        }
+        Some(table)
+    }
+
+    fn snapshot(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        // JSON maps need string keys, so the pools go in as a list.
+        let pools = self.pools.iter().collect::<Vec<_>>();
+        Ok(Some(serde_json::to_vec(&pools)?))
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()> {
+        let pools: Vec<(Ident, PoolInfo)> = serde_json::from_slice(snapshot)?;
+        self.pools = pools.into_iter().collect();
+        for pool in self.pools.values_mut().filter(|p| p.created_at == 0) {
+            pool.created_at = pool.versions.first().map_or(0, |v| v.0);
+        }
+        // We don't know how much history was dropped before the snapshot, so we assume
+        // as much as could have been.
+        let newest = self
+            .pools
+            .values()
+            .filter_map(|p| p.versions.last())
+            .map(|v| v.0);
+        self.pruned_before = self
+            .rollback_window
+            .zip(newest.max())
+            .and_then(|(w, newest)| newest.checked_sub(w));
+        self.by_pair.clear();
+        for (ident, pool) in &self.pools {
+            relink_pair(&mut self.by_pair, ident, None, pool.pair());
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        let router = Router::new()
+            .get("/{ident}", |index: &Self, params| {
+                let pool = params
+                    .get("ident")
+                    .and_then(|ident| ident.parse::<Ident>().ok())
+                    .and_then(|ident| index.pools.get(&ident));
+                match pool.and_then(pool_json) {
+                    Some(pool) => Response::json(pool),
+                    None => Response::not_found(),
+                }
+            })
+            // Every deposit (positive) and withdrawal (negative) of LP tokens, oldest first.
+            .get("/{ident}/lp", |index: &Self, params| {
+                let pool = params
+                    .get("ident")
+                    .and_then(|ident| ident.parse::<Ident>().ok())
+                    .and_then(|ident| index.pools.get(&ident));
+                let Some(pool) = pool else {
+                    return Response::not_found();
+                };
+                let history = pool
+                    .lp_history()
+                    .iter()
+                    .map(|(slot, delta)| serde_json::json!({ "slot": slot, "delta": delta }))
+                    .collect::<Vec<_>>();
+                Response::json(history.into())
+            })
+            // What every swap through the pool moved its reserves by, oldest first. Positive
+            // deltas went into the pool, negative ones came out.
+            .get("/{ident}/swaps", |index: &Self, params| {
+                let pool = params
+                    .get("ident")
+                    .and_then(|ident| ident.parse::<Ident>().ok())
+                    .and_then(|ident| index.pools.get(&ident));
+                let Some(pool) = pool else {
+                    return Response::not_found();
+                };
+                let swaps = pool
+                    .swaps()
+                    .iter()
+                    .map(|(slot, a, b)| serde_json::json!({ "slot": slot, "deltas": [a, b] }))
+                    .collect::<Vec<_>>();
+                Response::json(swaps.into())
+            })
+            // Each asset is `lovelace` or `policy.name`, in hex.
+            .get("/pair/{a}/{b}", |index: &Self, params| {
+                let pair = params
+                    .get("a")
+                    .zip(params.get("b"))
+                    .and_then(|(a, b)| Some((a.parse().ok()?, b.parse().ok()?)));
+                let Some((a, b)) = pair else {
+                    return Response::not_found();
+                };
+                let pools = index.pools_for_pair(&a, &b);
+                Response::json(pools.into_iter().filter_map(pool_json).collect())
+            });
+        Some(router)
+    }
 }
 
-fn parse_block_hash(bh: &str) -> Result<BlockHash> {
-    let bytes = hex::decode(bh)?;
+/// A rollback the pool index can't undo, since it's dropped the history it would need.
+/// The indexer resets the index before it gets that far, so this is only for its hooks
+/// being called on their own.
+#[derive(Debug)]
+struct PoolRollbackTooDeep {
+    to_slot: u64,
+    retained_since: u64,
+}
 
-    bytes.try_into().map_err(|b: Vec<u8>| {
-        anyhow!(
-            "Expected length {} for block hash, but got {}",
-            BlockHash::BYTES,
-            b.len()
+impl std::fmt::Display for PoolRollbackTooDeep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the chain rolled back to slot {}, but the pool index only keeps history back to slot {}; resync it from before the rollback",
+            self.to_slot, self.retained_since
         )
-    })
+    }
 }
 
-#[derive(clap::Subcommand, Debug)]
-enum Commands {
-    SyncFromOrigin,
-    SyncFromPoint {
-        #[arg(short, long)]
-        slot: u64,
+impl std::error::Error for PoolRollbackTooDeep {}
 
-        #[arg(short, long, value_parser=parse_block_hash)]
-        block_hash: BlockHash,
-    },
+#[cfg(feature = "http")]
+fn pool_json(pool: &PoolInfo) -> Option<serde_json::Value> {
+    let datum = pool.datum()?;
+    let (reserve_a, reserve_b) = pool.reserves()?;
+    Some(serde_json::json!({
+        "ident": hex::encode(datum.ident),
+        "assets": [datum.assets.0.to_string(), datum.assets.1.to_string()],
+        "reserves": [reserve_a, reserve_b],
+        "price": pool.price(),
+        "effective_price": pool.effective_price(),
+        "circulating_lp": datum.circulating_lp,
+        "fees_per_10_thousand": [datum.bid_fees_per_10_thousand, datum.ask_fees_per_10_thousand],
+        "fees": pool.fees().map(|(bid, ask)| [bid, ask]),
+        "market_open": datum.market_open,
+        "protocol_fees": datum.protocol_fees,
+        "volume": [pool.volume().0, pool.volume().1],
+        "created_at": pool.created_at(),
+    }))
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+struct OrderInfo {
+    created_at: u64,
+    spent_at: Option<u64>,
+    // Only the routes look inside it.
+    #[cfg(feature = "http")]
+    datum: OrderDatum,
+}
 
-    let handle = tokio::spawn(async move {
-        let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
-        let point = match args.command {
-            Commands::SyncFromOrigin => Point::Origin,
-            Commands::SyncFromPoint { slot, block_hash } => {
-                Point::Specific(slot, block_hash.to_vec())
-            }
+struct OrderIndex {
+    // Spent orders stay around until they're too old to be rolled back,
+    // in case a rollback means they're open again.
+    orders: HashMap<OutputRef, OrderInfo>,
+}
+
+impl OrderIndex {
+    fn new() -> Self {
+        Self {
+            orders: HashMap::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for OrderIndex {
+    fn name(&self) -> String {
+        "orders".into()
+    }
+
+    async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> anyhow::Result<()> {
+        for input in tx.consumes() {
+            if let Some(order) = self.orders.get_mut(&input.output_ref()) {
+                order.spent_at.get_or_insert(info.slot);
+            }
+        }
+        let mut datums = DatumCache::<OrderDatum>::new(tx);
+        for (output_ref, output) in tx_outputs(tx) {
+            let Ok(Some(datum)) = datums.parse(&output) else {
+                continue;
+            };
+            tracing::debug!(output = %output_ref, kind = datum.details.kind(), "order placed");
+            self.orders.insert(
+                output_ref,
+                OrderInfo {
+                    created_at: info.slot,
+                    spent_at: None,
+                    #[cfg(feature = "http")]
+                    datum,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn inspect_tx(&self, _info: &BlockInfo, tx: &MultiEraTx) -> Inspection {
+        // Orders aren't filtered by address, so a datum which isn't an order isn't a failure.
+        let mut datums = DatumCache::<OrderDatum>::new(tx);
+        let parsed = tx_outputs(tx)
+            .filter(|(_, o)| matches!(datums.parse(o), Ok(Some(_))))
+            .count();
+        Inspection {
+            parsed: parsed as u64,
+            failed: 0,
+        }
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        self.orders.retain(|_, o| o.created_at < info.slot);
+        for order in self.orders.values_mut() {
+            if order.spent_at.is_some_and(|s| s >= info.slot) {
+                order.spent_at = None;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        let router = Router::new()
+            .get("/open", |index: &Self, _| {
+                Response::json(index.open_orders(|_| true).into())
+            })
+            .get("/pool/{ident}", |index: &Self, params| {
+                let Some(ident) = params.get("ident").and_then(|i| i.parse::<Ident>().ok()) else {
+                    return Response::not_found();
+                };
+                let orders = index.open_orders(|datum| datum.pool_ident.as_ref() == Some(&ident));
+                Response::json(orders.into())
+            });
+        Some(router)
+    }
+}
+
+#[cfg(feature = "http")]
+impl OrderIndex {
+    // Every order not yet spent whose datum `filter` accepts, oldest first.
+    fn open_orders(&self, filter: impl Fn(&OrderDatum) -> bool) -> Vec<serde_json::Value> {
+        let mut open = self
+            .orders
+            .iter()
+            .filter(|(_, o)| o.spent_at.is_none() && filter(&o.datum))
+            .collect::<Vec<_>>();
+        open.sort_by_key(|(ref_, o)| (o.created_at, ref_.to_string()));
+        open.into_iter()
+            .map(|(ref_, o)| {
+                serde_json::json!({
+                    "output": ref_.to_string(),
+                    "created_at": o.created_at,
+                    "kind": o.datum.details.kind(),
+                    "pool": o.datum.pool_ident.as_ref().map(hex::encode),
+                })
+            })
+            .collect()
+    }
+}
+
+struct ScriptOutput {
+    address: Address,
+    created_at: u64,
+    spent_at: Option<u64>,
+}
+
+// Every output locked by one script, whichever staking part its address has.
+struct ScriptIndex {
+    script: ShelleyPaymentPart,
+    // Spent outputs are kept (along with the slot they were spent at) so a rollback can restore them.
+    outputs: HashMap<OutputRef, ScriptOutput>,
+}
+
+impl ScriptIndex {
+    fn new(script_hash: Hash<28>) -> Self {
+        Self {
+            script: ShelleyPaymentPart::Script(script_hash),
+            outputs: HashMap::new(),
+        }
+    }
+
+    /// The outputs still sitting at the script.
+    fn unspent(&self) -> impl Iterator<Item = (&OutputRef, &ScriptOutput)> {
+        self.outputs.iter().filter(|(_, o)| o.spent_at.is_none())
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for ScriptIndex {
+    fn name(&self) -> String {
+        "script".into()
+    }
+
+    fn tx_filter(&self) -> TxFilter {
+        TxFilter::PaymentCredential(self.script.clone())
+    }
+
+    async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> anyhow::Result<()> {
+        for input in tx.consumes() {
+            if let Some(output) = self.outputs.get_mut(&input.output_ref()) {
+                output.spent_at.get_or_insert(info.slot);
+            }
+        }
+        for (output_ref, output) in tx_outputs(tx) {
+            let Ok(address) = output.address() else {
+                continue;
+            };
+            if payment_credential(&address) != Some(&self.script) {
+                continue;
+            }
+            self.outputs.insert(
+                output_ref,
+                ScriptOutput {
+                    address,
+                    created_at: info.slot,
+                    spent_at: None,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        self.outputs.retain(|_, o| o.created_at < info.slot);
+        for output in self.outputs.values_mut() {
+            if output.spent_at.is_some_and(|s| s >= info.slot) {
+                output.spent_at = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn export(&self) -> Option<Table> {
+        let mut table = Table::new(&["output", "address", "created_at"]);
+        let mut unspent = self.unspent().collect::<Vec<_>>();
+        unspent.sort_by_key(|(ref_, o)| (o.created_at, ref_.to_string()));
+        for (ref_, output) in unspent {
+            table.push(vec![
+                ref_.to_string(),
+                output.address.to_string(),
+                output.created_at.to_string(),
+            ]);
+        }
+        Some(table)
+    }
+
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        Some(Router::new().get("/unspent", |index: &Self, _| {
+            let outputs = index.unspent().map(|(ref_, output)| {
+                serde_json::json!({
+                    "output": ref_.to_string(),
+                    "address": output.address.to_string(),
+                    "created_at": output.created_at,
+                })
+            });
+            Response::json(outputs.collect::<Vec<_>>().into())
+        }))
+    }
+}
+
+struct RegisteredScript {
+    language: ScriptLanguage,
+    size: usize,
+    first_seen: u64,
+    // The output which first carried it.
+    first_output: OutputRef,
+}
+
+// Every reference script we've seen, by hash. A script can be attached to any number of
+// outputs, but it's only recorded the first time.
+struct ScriptRegistryIndex {
+    scripts: HashMap<Hash<28>, RegisteredScript>,
+}
+
+impl ScriptRegistryIndex {
+    fn new() -> Self {
+        Self {
+            scripts: HashMap::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for ScriptRegistryIndex {
+    fn name(&self) -> String {
+        "script_registry".into()
+    }
+
+    async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> anyhow::Result<()> {
+        for (output_ref, output) in tx_outputs(tx) {
+            let Some(script) = reference_script(&output) else {
+                continue;
+            };
+            self.scripts
+                .entry(script.hash)
+                .or_insert_with(|| RegisteredScript {
+                    language: script.language,
+                    size: script.size,
+                    first_seen: info.slot,
+                    first_output: output_ref,
+                });
+        }
+        Ok(())
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        // Every later sighting of a script is rolled back along with its first.
+        self.scripts.retain(|_, s| s.first_seen < info.slot);
+        Ok(())
+    }
+
+    fn export(&self) -> Option<Table> {
+        let mut table = Table::new(&["hash", "language", "size", "first_seen", "first_output"]);
+        for (hash, script) in &self.scripts {
+            table.push(vec![
+                hash.to_string(),
+                script.language.to_string(),
+                script.size.to_string(),
+                script.first_seen.to_string(),
+                script.first_output.to_string(),
+            ]);
+        }
+        Some(table)
+    }
+
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        Some(Router::new().get("/{hash}", |index: &Self, params| {
+            let found = params
+                .get("hash")
+                .and_then(|hash| hash.parse::<Hash<28>>().ok())
+                .and_then(|hash| Some((hash, index.scripts.get(&hash)?)));
+            let Some((hash, script)) = found else {
+                return Response::not_found();
+            };
+            Response::json(serde_json::json!({
+                "hash": hash.to_string(),
+                "language": script.language,
+                "size": script.size,
+                "first_seen": script.first_seen,
+                "first_output": script.first_output.to_string(),
+            }))
+        }))
+    }
+}
+
+struct WalletUtxo {
+    // Which of the wallet's addresses holds it.
+    address: Address,
+    value: Value,
+    created_at: u64,
+    spent_at: Option<u64>,
+}
+
+struct WalletIndex {
+    addresses: HashSet<Address>,
+    // Spent UTXOs are kept (along with the slot they were spent at) so a rollback can restore them.
+    utxos: HashMap<OutputRef, WalletUtxo>,
+    // What's been spent, in the order it was spent in, so forgetting the oldest doesn't
+    // mean going through every UTXO.
+    spent: VecDeque<(u64, OutputRef)>,
+    // How many slots to keep spent UTXOs for, after the slot they were spent at. Forever if None.
+    history_slots: Option<u64>,
+    // Everything spent before this slot has been forgotten.
+    pruned_before: Option<u64>,
+    events: Vec<IndexEvent>,
+}
+impl WalletIndex {
+    fn new(addresses: HashSet<Address>) -> Self {
+        Self {
+            addresses,
+            utxos: HashMap::new(),
+            spent: VecDeque::new(),
+            history_slots: None,
+            pruned_before: None,
+            events: vec![],
+        }
+    }
+
+    /// Start from a UTXO set read from the node at `slot`, rather than from replaying the chain.
+    /// We don't know when they were created, so a rollback to `slot` or before resets the index.
+    fn seed(&mut self, slot: u64, utxos: Vec<(OutputRef, Address, Value)>) {
+        for (ref_, address, value) in utxos {
+            self.utxos.insert(
+                ref_,
+                WalletUtxo {
+                    address,
+                    value,
+                    created_at: slot,
+                    spent_at: None,
+                },
+            );
+        }
+        self.pruned_before = Some(slot + 1);
+    }
+
+    /// Only remember spent UTXOs for `slots` after they were spent, rather than forever.
+    /// `balance_at` can't look back further than that, and neither can a rollback.
+    fn with_history(mut self, slots: u64) -> Self {
+        self.history_slots = Some(slots);
+        self
+    }
+
+    fn forget_spent_before(&mut self, cutoff: u64) {
+        if self.pruned_before.is_some_and(|p| p >= cutoff) {
+            return;
+        }
+        while let Some(&(spent_at, _)) = self.spent.front() {
+            if spent_at >= cutoff {
+                break;
+            }
+            let (_, ref_) = self.spent.pop_front().unwrap();
+            self.utxos.remove(&ref_);
+        }
+        self.pruned_before = Some(cutoff);
+    }
+
+    /// What the wallet held as of the end of `slot`,
+    /// or None if that's from before the UTXOs we've kept.
+    #[cfg(any(test, feature = "http"))]
+    fn balance_at(&self, slot: u64) -> Option<Value> {
+        if self.pruned_before.is_some_and(|p| slot < p) {
+            return None;
+        }
+        let held = self
+            .utxos
+            .iter()
+            .filter(|(_, u)| u.created_at <= slot && u.spent_at.is_none_or(|s| s > slot))
+            .map(|(_, u)| &u.value);
+        Some(sum_values(held))
+    }
+
+    /// Everything the wallet holds now, lovelace and native assets together.
+    #[cfg(any(test, feature = "http"))]
+    fn total_balance(&self) -> Value {
+        let held = self
+            .utxos
+            .iter()
+            .filter(|(_, u)| u.spent_at.is_none())
+            .map(|(_, u)| &u.value);
+        sum_values(held)
+    }
+
+    /// How much of each asset the wallet holds now, lovelace included.
+    #[cfg(any(test, feature = "http"))]
+    fn balances(&self) -> BTreeMap<AssetClass, u64> {
+        asset_quantities(&self.total_balance()).collect()
+    }
+
+    /// Which of the wallet's addresses holds `utxo`, if it's one of ours and unspent.
+    #[cfg(any(test, feature = "http"))]
+    fn holder(&self, utxo: &OutputRef) -> Option<&Address> {
+        self.utxos
+            .get(utxo)
+            .filter(|u| u.spent_at.is_none())
+            .map(|u| &u.address)
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for WalletIndex {
+    fn name(&self) -> String {
+        "wallet".into()
+    }
+
+    fn tx_filter(&self) -> TxFilter {
+        TxFilter::Addresses(self.addresses.clone())
+    }
+
+    async fn handle_block(
+        &mut self,
+        info: &acropolis::core::BlockInfo,
+        _: &pallas_traverse::MultiEraBlock,
+    ) -> anyhow::Result<()> {
+        if let Some(cutoff) = self.history_slots.and_then(|h| info.slot.checked_sub(h)) {
+            self.forget_spent_before(cutoff);
+        }
+        Ok(())
+    }
+
+    async fn handle_onchain_tx(
+        &mut self,
+        info: &acropolis::core::BlockInfo,
+        tx: &pallas_traverse::MultiEraTx,
+    ) -> anyhow::Result<()> {
+        for ref_ in tx.consumes().iter().map(|i| i.output_ref()) {
+            let Some(utxo) = self.utxos.get_mut(&ref_) else {
+                continue;
+            };
+            if utxo.spent_at.is_none() {
+                utxo.spent_at = Some(info.slot);
+                self.spent.push_back((info.slot, ref_.clone()));
+                self.events.push(IndexEvent::new(
+                    "utxo_spent",
+                    info.slot,
+                    serde_json::json!({
+                        "tx_hash": ref_.hash().to_string(),
+                        "index": ref_.index(),
+                        "address": utxo.address.to_string(),
+                        "value": utxo.value,
+                    }),
+                ));
+            }
+        }
+        for (ref_, output) in tx_outputs(tx) {
+            let Some(address) = output.address().ok().filter(|a| self.addresses.contains(a)) else {
+                continue;
+            };
+            // Seeing a block again (say, resuming from a cursor a little behind the index)
+            // mustn't count its outputs twice, or forget they've been spent since.
+            let Entry::Vacant(entry) = self.utxos.entry(ref_.clone()) else {
+                continue;
+            };
+            self.events.push(IndexEvent::new(
+                "utxo_received",
+                info.slot,
+                serde_json::json!({
+                    "tx_hash": ref_.hash().to_string(),
+                    "index": ref_.index(),
+                    "address": address.to_string(),
+                    "value": output.value().into_conway(),
+                }),
+            ));
+            entry.insert(WalletUtxo {
+                address,
+                value: output.value().into_conway(),
+                created_at: info.slot,
+                spent_at: None,
+            });
+        }
+        Ok(())
+    }
+
+    async fn handle_rollback(&mut self, info: &acropolis::core::BlockInfo) -> anyhow::Result<()> {
+        for (ref_, utxo) in &self.utxos {
+            let reverted = if utxo.created_at >= info.slot {
+                ("utxo_received", utxo.created_at)
+            } else if let Some(spent_at) = utxo.spent_at.filter(|s| *s >= info.slot) {
+                ("utxo_spent", spent_at)
+            } else {
+                continue;
+            };
+            self.events.push(IndexEvent::new(
+                reverted.0,
+                reverted.1,
+                serde_json::json!({ "tx_hash": ref_.hash().to_string(), "index": ref_.index() }),
+            ));
+        }
+        self.utxos.retain(|_, u| u.created_at < info.slot);
+        while self.spent.back().is_some_and(|(s, _)| *s >= info.slot) {
+            let (_, ref_) = self.spent.pop_back().unwrap();
+            if let Some(utxo) = self.utxos.get_mut(&ref_) {
+                utxo.spent_at = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn drain_events(&mut self) -> Vec<IndexEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn retained_since(&self) -> Option<u64> {
+        self.pruned_before
+    }
+
+    async fn prune(&mut self, before_slot: u64) -> anyhow::Result<()> {
+        self.forget_spent_before(before_slot);
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> anyhow::Result<()> {
+        self.utxos.clear();
+        self.spent.clear();
+        self.pruned_before = None;
+        // Whatever these were about is gone with the rest of it.
+        self.events.clear();
+        Ok(())
+    }
+
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        let router = Router::new().get("/balance", |index: &Self, _| {
+            let balances = index
+                .balances()
+                .into_iter()
+                .map(|(asset, quantity)| (asset.to_string(), quantity.into()))
+                .collect::<serde_json::Map<_, _>>();
+            Response::json(balances.into())
+        });
+        let router = router.get("/balance/{slot}", |index: &Self, params| {
+            let slot = params.get("slot").and_then(|s| s.parse().ok());
+            match slot.and_then(|slot| index.balance_at(slot)) {
+                Some(held) => {
+                    let balances = asset_quantities(&held)
+                        .map(|(asset, quantity)| (asset.to_string(), quantity.into()))
+                        .collect::<serde_json::Map<_, _>>();
+                    Response::json(balances.into())
+                }
+                None => Response::not_found(),
+            }
+        });
+        let router = router.get("/holder/{tx_hash}/{index}", |index: &Self, params| {
+            let utxo = params
+                .get("tx_hash")
+                .and_then(|hash| hash.parse().ok())
+                .zip(params.get("index").and_then(|i| i.parse().ok()))
+                .map(|(hash, i)| OutputRef::new(hash, i));
+            match utxo.as_ref().and_then(|u| index.holder(u)) {
+                Some(address) => Response::json(address.to_string().into()),
+                None => Response::not_found(),
+            }
+        });
+        Some(router.get("/utxos", |index: &Self, _| {
+            let utxos = index
+                .utxos
+                .iter()
+                .filter(|(_, u)| u.spent_at.is_none())
+                .map(|(ref_, u)| {
+                    serde_json::json!({
+                        "tx_hash": ref_.hash().to_string(),
+                        "index": ref_.index(),
+                        "address": u.address.to_string(),
+                        "value": u.value,
+                        "created_at": u.created_at,
+                    })
+                })
+                .collect::<Vec<_>>();
+            Response::json(utxos.into())
+        }))
+    }
+}
+
+struct MintIndex {
+    supply: BTreeMap<AssetClass, i128>,
+    // Every mint/burn in the order we saw it, so a rollback can undo them.
+    // Only the ones a rollback could still reach are kept, once the index has been pruned.
+    history: Vec<(u64, AssetClass, i128)>,
+    pruned_before: Option<u64>,
+}
+impl MintIndex {
+    fn new() -> Self {
+        Self {
+            supply: BTreeMap::new(),
+            history: vec![],
+            pruned_before: None,
+        }
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for MintIndex {
+    fn name(&self) -> String {
+        "mints".into()
+    }
+
+    async fn handle_mint(
+        &mut self,
+        info: &BlockInfo,
+        policy: &Hash<28>,
+        assets: &[MultiEraAsset],
+    ) -> anyhow::Result<()> {
+        for asset in assets {
+            let Some(quantity) = asset.mint_coin() else {
+                continue;
+            };
+            let Some(class) = asset_class(policy, asset.name()) else {
+                continue;
+            };
+            *self.supply.entry(class.clone()).or_default() += quantity as i128;
+            self.history.push((info.slot, class, quantity as i128));
+        }
+        Ok(())
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        while self
+            .history
+            .last()
+            .is_some_and(|(slot, _, _)| *slot >= info.slot)
+        {
+            let (_, class, quantity) = self.history.pop().unwrap();
+            let supply = self.supply.entry(class.clone()).or_default();
+            *supply -= quantity;
+            if *supply == 0 {
+                self.supply.remove(&class);
+            }
+        }
+        Ok(())
+    }
+
+    fn retained_since(&self) -> Option<u64> {
+        self.pruned_before
+    }
+
+    // The supply is a running total, so the mints that went into it don't need keeping
+    // once a rollback can't undo them.
+    async fn prune(&mut self, before_slot: u64) -> anyhow::Result<()> {
+        self.history.retain(|(slot, _, _)| *slot >= before_slot);
+        self.pruned_before = Some(before_slot);
+        Ok(())
+    }
+
+    // A running total can't start over partway, so it's resynced from the origin.
+    async fn reset(&mut self) -> anyhow::Result<()> {
+        self.supply.clear();
+        self.history.clear();
+        self.pruned_before = None;
+        Err(ResyncFrom {
+            point: Point::Origin,
+        }
+        .into())
+    }
+}
+
+#[derive(Default)]
+struct Nft {
+    // Every mint (positive) and burn (negative) of the token, oldest first.
+    mints: Vec<(u64, i64)>,
+    // Every output the token has been paid to, oldest first, along with its address.
+    holders: Vec<(u64, OutputRef, Address)>,
+}
+
+impl Nft {
+    fn supply(&self) -> i64 {
+        self.mints.iter().map(|(_, quantity)| quantity).sum()
+    }
+}
+
+// Where every NFT is, and when it was minted and burnt. Anything minted more than one of
+// gets its mints recorded, but isn't followed around.
+struct NftIndex {
+    // Only NFTs under this policy, if it's set.
+    policy: Option<Hash<28>>,
+    nfts: BTreeMap<AssetClass, Nft>,
+}
+
+impl NftIndex {
+    fn new(policy: Option<Hash<28>>) -> Self {
+        Self {
+            policy,
+            nfts: BTreeMap::new(),
+        }
+    }
+
+    /// The output holding `asset` now, and its address, unless it's been burnt or isn't an NFT.
+    #[cfg(feature = "http")]
+    fn holder(&self, asset: &AssetClass) -> Option<(&OutputRef, &Address)> {
+        let nft = self.nfts.get(asset).filter(|nft| nft.supply() == 1)?;
+        let (_, output_ref, address) = nft.holders.last()?;
+        Some((output_ref, address))
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for NftIndex {
+    fn name(&self) -> String {
+        "nfts".into()
+    }
+
+    fn tx_filter(&self) -> TxFilter {
+        match self.policy {
+            Some(policy) => TxFilter::Policy(policy),
+            None => TxFilter::All,
+        }
+    }
+
+    // This runs before `handle_mint`, so a token minted in this tx isn't tracked yet,
+    // and has to be picked out of the tx's mints. A tx whose scripts failed mints nothing.
+    async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> anyhow::Result<()> {
+        let mints = if tx.is_valid() { tx.mints() } else { vec![] };
+        let minting = mints
+            .iter()
+            .flat_map(|m| {
+                let policy = *m.policy();
+                m.assets()
+                    .into_iter()
+                    .filter(|a| a.mint_coin() == Some(1))
+                    .filter_map(move |a| asset_class(&policy, a.name()))
+            })
+            .collect::<HashSet<_>>();
+        for (output_ref, output) in tx_outputs(tx) {
+            for policy in output.value().assets() {
+                if self.policy.is_some_and(|p| p != *policy.policy()) {
+                    continue;
+                }
+                for asset in policy.assets() {
+                    if asset.output_coin() != Some(1) {
+                        continue;
+                    }
+                    let Some(class) = asset_class(policy.policy(), asset.name()) else {
+                        continue;
+                    };
+                    let tracked = self.nfts.get(&class).is_some_and(|nft| nft.supply() == 1);
+                    if !tracked && !minting.contains(&class) {
+                        continue;
+                    }
+                    let Ok(address) = output.address() else {
+                        continue;
+                    };
+                    self.nfts.entry(class).or_default().holders.push((
+                        info.slot,
+                        output_ref.clone(),
+                        address,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_mint(
+        &mut self,
+        info: &BlockInfo,
+        policy: &Hash<28>,
+        assets: &[MultiEraAsset],
+    ) -> anyhow::Result<()> {
+        if self.policy.is_some_and(|p| p != *policy) {
+            return Ok(());
+        }
+        for asset in assets {
+            let Some(quantity) = asset.mint_coin() else {
+                continue;
+            };
+            let Some(class) = asset_class(policy, asset.name()) else {
+                continue;
+            };
+            self.nfts
+                .entry(class)
+                .or_default()
+                .mints
+                .push((info.slot, quantity));
+        }
+        Ok(())
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        self.nfts.retain(|_, nft| {
+            nft.mints.retain(|(slot, _)| *slot < info.slot);
+            nft.holders.retain(|(slot, ..)| *slot < info.slot);
+            !nft.mints.is_empty()
+        });
+        Ok(())
+    }
+
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        Some(
+            Router::new().get("/{policy}/{name}", |index: &Self, params| {
+                let asset =
+                    params
+                        .get("policy")
+                        .zip(params.get("name"))
+                        .and_then(|(policy, name)| {
+                            format!("{policy}.{name}").parse::<AssetClass>().ok()
+                        });
+                let Some((asset, nft)) = asset.and_then(|a| Some((a.clone(), index.nfts.get(&a)?)))
+                else {
+                    return Response::not_found();
+                };
+                let holder = index.holder(&asset).map(|(output_ref, address)| {
+                    serde_json::json!({
+                        "output": output_ref.to_string(),
+                        "address": address.to_string(),
+                    })
+                });
+                Response::json(serde_json::json!({
+                    "asset": asset.to_string(),
+                    "supply": nft.supply(),
+                    "holder": holder,
+                    "mints": nft.mints,
+                }))
+            }),
+        )
+    }
+}
+
+// CIP-25 NFT metadata lives under this label.
+const CIP25_LABEL: u64 = 721;
+
+struct Cip25Index {
+    txs: Vec<(u64, Hash<32>)>,
+}
+impl Cip25Index {
+    fn new() -> Self {
+        Self { txs: vec![] }
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for Cip25Index {
+    fn name(&self) -> String {
+        "cip25".into()
+    }
+
+    async fn handle_metadata(
+        &mut self,
+        info: &BlockInfo,
+        tx: &MultiEraTx,
+        metadata: &Metadata,
+    ) -> anyhow::Result<()> {
+        if metadata.iter().any(|(label, _)| *label == CIP25_LABEL) {
+            self.txs.push((info.slot, tx.hash()));
+        }
+        Ok(())
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        self.txs.retain(|(slot, _)| *slot < info.slot);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum StakeEvent {
+    Registered,
+    Delegated(Hash<28>),
+    Deregistered,
+}
+
+// The stake registrations and delegations a certificate makes, if any.
+fn stake_events(cert: &MultiEraCert) -> Vec<(StakeCredential, StakeEvent)> {
+    use StakeEvent::*;
+    if let Some(cert) = cert.as_alonzo() {
+        use pallas_primitives::alonzo::Certificate::*;
+        return match cert {
+            StakeRegistration(cred) => vec![(cred.clone(), Registered)],
+            StakeDeregistration(cred) => vec![(cred.clone(), Deregistered)],
+            StakeDelegation(cred, pool) => vec![(cred.clone(), Delegated(*pool))],
+            _ => vec![],
+        };
+    }
+    if let Some(cert) = cert.as_conway() {
+        use pallas_primitives::conway::Certificate::*;
+        return match cert {
+            StakeRegistration(cred) | Reg(cred, _) => vec![(cred.clone(), Registered)],
+            StakeDeregistration(cred) | UnReg(cred, _) => vec![(cred.clone(), Deregistered)],
+            StakeDelegation(cred, pool) | StakeVoteDeleg(cred, pool, _) => {
+                vec![(cred.clone(), Delegated(*pool))]
+            }
+            StakeRegDeleg(cred, pool, _) | StakeVoteRegDeleg(cred, pool, _, _) => {
+                vec![(cred.clone(), Registered), (cred.clone(), Delegated(*pool))]
+            }
+            _ => vec![],
+        };
+    }
+    vec![]
+}
+
+struct StakeIndex {
+    // Everything that's happened to each stake credential, oldest first.
+    events: BTreeMap<StakeCredential, Vec<(u64, StakeEvent)>>,
+    // The payment addresses we've seen delegating to each stake credential, and when we first saw them.
+    addresses: BTreeMap<StakeCredential, Vec<(u64, Address)>>,
+    // Rewards withdrawn, by reward account.
+    withdrawals: Vec<(u64, Vec<u8>, u64)>,
+}
+impl StakeIndex {
+    fn new() -> Self {
+        Self {
+            events: BTreeMap::new(),
+            addresses: BTreeMap::new(),
+            withdrawals: vec![],
+        }
+    }
+
+    /// The pool a stake credential currently delegates to, if it's registered and delegating.
+    #[cfg(any(test, feature = "http"))]
+    fn delegation(&self, cred: &StakeCredential) -> Option<Hash<28>> {
+        let mut pool = None;
+        for (_, event) in self.events.get(cred)? {
+            match event {
+                StakeEvent::Registered => {}
+                StakeEvent::Delegated(to) => pool = Some(*to),
+                StakeEvent::Deregistered => pool = None,
+            }
+        }
+        pool
+    }
+
+    /// The payment addresses which delegate to a stake credential.
+    #[cfg(any(test, feature = "http"))]
+    fn addresses(&self, cred: &StakeCredential) -> impl Iterator<Item = &Address> {
+        self.addresses
+            .get(cred)
+            .into_iter()
+            .flatten()
+            .map(|(_, a)| a)
+    }
+
+    /// Everything withdrawn from a reward account.
+    #[cfg(any(test, feature = "http"))]
+    fn withdrawn(&self, reward_account: &[u8]) -> u64 {
+        self.withdrawals
+            .iter()
+            .filter(|(_, account, _)| account == reward_account)
+            .map(|(_, _, amount)| amount)
+            .sum()
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for StakeIndex {
+    fn name(&self) -> String {
+        "stake".into()
+    }
+
+    async fn handle_onchain_tx(&mut self, info: &BlockInfo, tx: &MultiEraTx) -> anyhow::Result<()> {
+        for (_, output) in tx_outputs(tx) {
+            let Ok(Address::Shelley(address)) = output.address() else {
+                continue;
+            };
+            let cred = match address.delegation() {
+                ShelleyDelegationPart::Key(hash) => StakeCredential::AddrKeyhash(*hash),
+                ShelleyDelegationPart::Script(hash) => StakeCredential::ScriptHash(*hash),
+                _ => continue,
+            };
+            let seen = self.addresses.entry(cred).or_default();
+            let address = Address::Shelley(address);
+            if !seen.iter().any(|(_, a)| *a == address) {
+                seen.push((info.slot, address));
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_certificate(
+        &mut self,
+        info: &BlockInfo,
+        cert: &MultiEraCert,
+    ) -> anyhow::Result<()> {
+        for (cred, event) in stake_events(cert) {
+            self.events
+                .entry(cred)
+                .or_default()
+                .push((info.slot, event));
+        }
+        Ok(())
+    }
+
+    async fn handle_withdrawal(
+        &mut self,
+        info: &BlockInfo,
+        reward_account: &[u8],
+        amount: u64,
+    ) -> anyhow::Result<()> {
+        self.withdrawals
+            .push((info.slot, reward_account.to_vec(), amount));
+        Ok(())
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        for events in self.events.values_mut() {
+            events.retain(|(slot, _)| *slot < info.slot);
+        }
+        self.events.retain(|_, events| !events.is_empty());
+        for addresses in self.addresses.values_mut() {
+            addresses.retain(|(slot, _)| *slot < info.slot);
+        }
+        self.addresses.retain(|_, addresses| !addresses.is_empty());
+        self.withdrawals.retain(|(slot, _, _)| *slot < info.slot);
+        Ok(())
+    }
+
+    // A stake address, in bech32, gets what's delegating through it and where to.
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        Some(
+            Router::new().get("/{stake_address}", |index: &Self, params| {
+                let address = params.get("stake_address").and_then(|a| a.parse().ok());
+                let Some(Address::Stake(address)) = address else {
+                    return Response::not_found();
+                };
+                let cred = match address.payload() {
+                    pallas_addresses::StakePayload::Stake(hash) => {
+                        StakeCredential::AddrKeyhash(*hash)
+                    }
+                    pallas_addresses::StakePayload::Script(hash) => {
+                        StakeCredential::ScriptHash(*hash)
+                    }
+                };
+                let addresses = index.addresses(&cred).map(|a| a.to_string());
+                Response::json(serde_json::json!({
+                    "delegation": index.delegation(&cred).map(|pool| pool.to_string()),
+                    "addresses": addresses.collect::<Vec<_>>(),
+                    "withdrawn": index.withdrawn(&Address::Stake(address).to_vec()),
+                }))
+            }),
+        )
+    }
+}
+
+// How far back the txs index keeps each block's count, to take it back out if the block is
+// rolled back: the deepest rollback a mainnet node can send.
+const TX_COUNT_ROLLBACK_SLOTS: u64 = 43_200;
+
+// How many txs each epoch has had.
+#[derive(Default)]
+struct TxCounts {
+    epochs: BTreeMap<u64, u64>,
+    // The epoch and tx count of every block recent enough to be rolled back.
+    recent: BTreeMap<u64, (u64, u64)>,
+    // The oldest slot `recent` still covers, once it's started forgetting.
+    retained_since: Option<u64>,
+}
+
+// An example of an index that's just a couple of closures: it counts every tx on chain, by epoch.
+fn tx_counts() -> FnIndex<TxCounts> {
+    let index = FnIndex::new(
+        "txs",
+        |info, _tx, counts: &mut TxCounts| {
+            *counts.epochs.entry(info.epoch).or_default() += 1;
+            counts.recent.entry(info.slot).or_insert((info.epoch, 0)).1 += 1;
+            let oldest = info.slot.saturating_sub(TX_COUNT_ROLLBACK_SLOTS);
+            while counts
+                .recent
+                .first_key_value()
+                .is_some_and(|(slot, _)| *slot < oldest)
+            {
+                counts.recent.pop_first();
+                counts.retained_since = Some(oldest);
+            }
+            Ok(())
+        },
+        |info, counts| {
+            if let Some(retained_since) = counts.retained_since.filter(|s| *s > info.slot) {
+                bail!(
+                    "can't roll the tx counts back to slot {}, since they only go back to {retained_since}",
+                    info.slot
+                );
+            }
+            for (epoch, txs) in counts.recent.split_off(&info.slot).into_values() {
+                let count = counts.epochs.entry(epoch).or_default();
+                *count -= txs;
+                if *count == 0 {
+                    counts.epochs.remove(&epoch);
+                }
+            }
+            Ok(())
+        },
+    );
+    #[cfg(feature = "http")]
+    let index = index.with_view(|counts| {
+        let epochs = counts
+            .epochs
+            .iter()
+            .map(|(epoch, txs)| (epoch.to_string(), (*txs).into()));
+        serde_json::Value::Object(epochs.collect())
+    });
+    index
+}
+
+#[derive(Default)]
+struct BlockFees {
+    txs: u64,
+    fees: u64,
+    size: u64,
+}
+
+impl BlockFees {
+    fn average_fee(&self) -> u64 {
+        self.fees.checked_div(self.txs).unwrap_or_default()
+    }
+}
+
+// The fees paid in each block. Txs whose inputs all resolve also have their declared fee
+// checked against what they actually move.
+struct FeeIndex {
+    blocks: BTreeMap<u64, BlockFees>,
+    // Whether to resolve every tx's inputs to check its fee, or only Byron txs' to work theirs out.
+    check_fees: bool,
+    checked: u64,
+    mismatched: u64,
+}
+impl FeeIndex {
+    fn new() -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            check_fees: false,
+            checked: 0,
+            mismatched: 0,
+        }
+    }
+
+    /// Check each tx's declared fee against what it moves, warning about any that's off.
+    /// That means keeping every output since the indexer started, to resolve inputs with.
+    fn with_fee_checks(mut self) -> Self {
+        self.check_fees = true;
+        self
+    }
+
+    /// The total and average fee paid in the block at `slot`, if it had any txs.
+    #[cfg(any(test, feature = "http"))]
+    fn fees_at(&self, slot: u64) -> Option<(u64, u64)> {
+        let block = self.blocks.get(&slot)?;
+        Some((block.fees, block.average_fee()))
+    }
+
+    /// The average fee of every tx the index has seen, or None before it's seen any.
+    #[cfg(any(test, feature = "http"))]
+    fn average_fee(&self) -> Option<u64> {
+        let (txs, fees) = self.blocks.values().fold((0, 0), |(txs, fees), block| {
+            (txs + block.txs, fees + block.fees)
+        });
+        fees.checked_div(txs)
+    }
+}
+
+#[async_trait]
+impl ManagedIndex for FeeIndex {
+    fn name(&self) -> String {
+        "fees".into()
+    }
+
+    fn needs_resolved_inputs(&self) -> bool {
+        true
+    }
+
+    // Only Byron txs need their inputs to work out the fee, and they only spend Byron outputs.
+    fn resolved_outputs(&self) -> ResolvedOutputs {
+        match self.check_fees {
+            true => ResolvedOutputs::All,
+            false => ResolvedOutputs::Byron,
+        }
+    }
+
+    async fn handle_onchain_tx_with_inputs(
+        &mut self,
+        info: &BlockInfo,
+        tx: &MultiEraTx,
+        inputs: &ResolvedInputs,
+    ) -> anyhow::Result<()> {
+        let block = self.blocks.entry(info.slot).or_default();
+        block.txs += 1;
+        // Byron txs don't declare their fee, but it can still be worked out.
+        block.fees += tx_fee(tx)
+            .or_else(|| inputs.implied_fee(tx))
+            .unwrap_or_default();
+        block.size += tx_size(tx) as u64;
+        match inputs.fee_matches(tx) {
+            Some(true) => self.checked += 1,
+            Some(false) => {
+                self.checked += 1;
+                self.mismatched += 1;
+                tracing::warn!(
+                    tx = %tx.hash(),
+                    declared = tx_fee(tx),
+                    implied = inputs.implied_fee(tx),
+                    "tx pays a different fee from the one it declares"
+                );
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    async fn handle_rollback(&mut self, info: &BlockInfo) -> anyhow::Result<()> {
+        // The fee checks aren't kept per block, so they stay counted.
+        self.blocks.retain(|slot, _| *slot < info.slot);
+        Ok(())
+    }
+
+    #[cfg(feature = "http")]
+    fn router(&self) -> Option<Router<Self>> {
+        let router = Router::new()
+            .get("/average", |index: &Self, _| match index.average_fee() {
+                Some(fee) => Response::json(serde_json::json!({ "average_fee": fee })),
+                None => Response::not_found(),
+            })
+            .get("/{slot}", |index: &Self, params| {
+                let Some(slot) = params.get("slot").and_then(|s| s.parse().ok()) else {
+                    return Response::not_found();
+                };
+                match index.fees_at(slot) {
+                    Some((total, average)) => Response::json(serde_json::json!({
+                        "slot": slot,
+                        "total_fee": total,
+                        "average_fee": average,
+                    })),
+                    None => Response::not_found(),
+                }
+            });
+        Some(router)
+    }
+
+    fn export(&self) -> Option<Table> {
+        let mut table = Table::new(&["slot", "txs", "total_fee", "average_fee", "size"]);
+        for (slot, block) in &self.blocks {
+            table.push(vec![
+                slot.to_string(),
+                block.txs.to_string(),
+                block.fees.to_string(),
+                block.average_fee().to_string(),
+                block.size.to_string(),
+            ]);
+        }
+        Some(table)
+    }
+}
+
+#[derive(clap::Parser, Debug)]
+struct Args {
+    /// A TOML file declaring the connection and the indexes to run.
+    /// Any other flags override what it says.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// The node's host:port, or with `--protocol n2c`, the path to its socket.
+    #[arg(short, long)]
+    addr: Option<String>,
+
+    /// Whether to talk to the node node-to-node (n2n), or node-to-client (n2c) over its socket.
+    #[arg(long, value_enum)]
+    protocol: Option<Protocol>,
+
+    /// mainnet, preprod, preview, or custom:<magic>.
+    #[arg(long, conflicts_with = "magic")]
+    network: Option<Network>,
+
+    /// The protocol magic of some other network. The same as --network custom:<magic>.
+    #[arg(short, long)]
+    magic: Option<u64>,
+
+    /// An address for the wallet index to watch. Can be given more than once.
+    #[arg(long)]
+    wallet_address: Vec<Address>,
+
+    #[arg(long)]
+    pool_address: Option<Address>,
+
+    /// The policy of the pool NFTs. Only outputs holding one get their datums decoded.
+    #[arg(long)]
+    pool_policy: Option<Hash<28>>,
+
+    /// Index every output locked by this script, whatever its staking part.
+    #[arg(long)]
+    script_hash: Option<Hash<28>>,
+
+    /// Follow every NFT under this minting policy.
+    #[arg(long)]
+    nft_policy: Option<Hash<28>>,
+
+    /// How many blocks deep a wallet transaction must be before we index it.
+    #[arg(long)]
+    wallet_confirmations: Option<u64>,
+
+    /// How many slots the wallet index keeps spent UTXOs for, to answer balances from the past.
+    #[arg(long)]
+    wallet_history_slots: Option<u64>,
+
+    /// Have the fee index check every tx's declared fee against what it moves. That keeps
+    /// every output since the indexer started around, to resolve inputs with.
+    #[arg(long)]
+    check_fees: bool,
+
+    /// Snapshot indexes every this many blocks, so they can restore from it on restart.
+    #[arg(long)]
+    snapshot_every: Option<u64>,
+
+    /// Write every index event to this file as JSON lines, or to stdout if it's `-`.
+    #[arg(long)]
+    events_out: Option<PathBuf>,
+
+    /// Publish every index event to this Kafka topic, through --kafka-brokers.
+    #[cfg(feature = "kafka")]
+    #[arg(long, requires = "kafka_brokers")]
+    kafka_topic: Option<String>,
+
+    /// The Kafka brokers to connect through, as comma-separated `host:port`s.
+    #[cfg(feature = "kafka")]
+    #[arg(long, value_delimiter = ',')]
+    kafka_brokers: Vec<String>,
+
+    /// Commit cursors every this many blocks (or seconds, with an `s` suffix), rather than every block.
+    #[arg(long)]
+    checkpoint_every: Option<CheckpointInterval>,
+
+    /// How many blocks to fetch and decode ahead of the indexes.
+    #[arg(long)]
+    block_buffer: Option<usize>,
+
+    /// How many messages to ask the chain source for at a time.
+    #[arg(long)]
+    fetch_batch_size: Option<usize>,
+
+    /// Stop syncing if a block doesn't follow on from the one before it.
+    #[arg(long)]
+    strict_continuity: bool,
+
+    /// What to do with a block we can't decode, such as one from a new era: fail or skip.
+    #[arg(long, default_value = "fail")]
+    on_unsupported_era: UnsupportedEra,
+
+    /// Only log what each index would have indexed, without indexing it or committing cursors.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Have indexes forget history more than this many slots behind their cursor.
+    #[arg(long)]
+    volatile_window: Option<u64>,
+
+    /// How many slots of history the pool index keeps for rollbacks. Defaults to the
+    /// volatile window, and can't be longer than it.
+    #[arg(long)]
+    pool_rollback_window: Option<u64>,
+
+    /// Also look for pool datums in the inline datums of reference inputs, when a pool
+    /// output only has a datum hash.
+    #[arg(long)]
+    pool_reference_datums: bool,
+
+    /// Log every update to this pool, given by its ident in hex, as the pool index makes it.
+    /// Can be given more than once.
+    #[arg(long)]
+    watch_pool: Vec<Ident>,
+
+    /// Keep each index's cursor and snapshot in this directory, and resume from it on restart.
+    #[arg(long)]
+    persist_dir: Option<PathBuf>,
+
+    /// Keep each index's cursor in this SQLite database, and resume from it on restart.
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    cursor_db: Option<PathBuf>,
+
+    /// How many of each index's last committed points to keep, to offer the node when resuming
+    /// (10 at most, which is the default). Only for cursors kept in memory.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=CURSOR_HISTORY as u64))]
+    cursor_history: Option<u64>,
+
+    /// Load cursors exported from another indexer (as JSON) into the cursor store before syncing.
+    #[arg(long)]
+    import_cursors: Option<PathBuf>,
+
+    /// Write every configured index's committed cursors to this file (as JSON) and exit,
+    /// without syncing, for --import-cursors to load elsewhere.
+    #[arg(long)]
+    export_cursors: Option<PathBuf>,
+
+    /// Warn when an index takes longer than this many milliseconds over a block.
+    #[arg(long)]
+    slow_block_ms: Option<u64>,
+
+    /// Write every index which can be exported to this directory, every --export-every
+    /// seconds while syncing, and once more on the way out.
+    #[arg(long)]
+    export_dir: Option<PathBuf>,
+
+    /// How to write exports: csv, or with the parquet feature, parquet.
+    #[arg(long, default_value = "csv")]
+    export_format: ExportFormat,
+
+    /// How often to export, in seconds.
+    #[arg(long, default_value_t = 600)]
+    export_every: u64,
+
+    /// How many times in a row to try reconnecting to the node before giving up.
+    #[arg(long)]
+    reconnect_retries: Option<u32>,
+
+    /// The longest to wait between reconnect attempts, in seconds. The wait starts at a second,
+    /// and doubles each attempt up to this.
+    #[arg(long)]
+    reconnect_max_delay_secs: Option<u64>,
+
+    /// Seed the wallet index with its UTXOs at the node's tip, and sync from there,
+    /// rather than replaying the chain. Needs `--protocol n2c`.
+    #[arg(long)]
+    bootstrap_wallet: bool,
+
+    /// Serve Prometheus metrics on this port.
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// A file holding the token `POST /control/pause` and `/control/resume` on the metrics
+    /// port need, as `Authorization: Bearer <token>`. Without one, they're refused.
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    control_token_file: Option<PathBuf>,
+
+    /// How many slots behind the tip every index has to be within for `/readyz` to pass,
+    /// before the indexer has reached the tip.
+    #[cfg(any(feature = "metrics", feature = "http"))]
+    #[arg(long, default_value_t = 600)]
+    ready_max_lag: u64,
+
+    /// Serve index queries on this port.
+    #[cfg(feature = "http")]
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    /// Where every index starts from, overriding the config.
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Commands {
+    SyncFromOrigin,
+    SyncFromPoint {
+        #[arg(short, long)]
+        slot: u64,
+
+        #[arg(short, long, value_parser = BlockHash::from_hex)]
+        block_hash: BlockHash,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    logging::init();
+
+    let handle = tokio::spawn(run(args));
+    let result = handle
+        .await
+        .unwrap_or_else(|e| Err(anyhow!("the indexer panicked: {e}")));
+    if let Err(e) = result {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        // Without a config, run every index we have.
+        None => Config {
+            indexes: [
+                IndexKind::Pools,
+                IndexKind::PoolDatums,
+                IndexKind::Orders,
+                IndexKind::Wallet,
+                IndexKind::Mints,
+                IndexKind::Cip25,
+                IndexKind::Stake,
+                IndexKind::ScriptRegistry,
+                IndexKind::Fees,
+                IndexKind::Txs,
+            ]
+            .into_iter()
+            .chain(args.script_hash.map(|_| IndexKind::Script))
+            .chain(args.nft_policy.map(|_| IndexKind::Nfts))
+            .map(IndexConfig::new)
+            .collect(),
+            ..Config::default()
+        },
+    };
+    let addr = args
+        .addr
+        .clone()
+        .or(config.connection.addr.take())
+        .context("no node address given")?;
+    let network = match args.network.or(args.magic.map(Network::Custom)) {
+        Some(network) => network,
+        None => config
+            .connection
+            .network()?
+            .context("no network given; pass --network or --magic")?,
+    };
+    let protocol = args
+        .protocol
+        .or(config.connection.protocol)
+        .unwrap_or_default();
+    protocol.check_addr(&addr)?;
+    tracing::info!(addr, ?protocol, magic = network.magic(), "syncing");
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.cursor_db {
+        if args.cursor_history.is_some() {
+            bail!(
+                "--cursor-history is for cursors kept in memory, and --cursor-db keeps only the last"
+            );
+        }
+        let store = sqlite::SqliteCursorStore::open(path)?;
+        return sync(args, config, addr, network, protocol, store).await;
+    }
+    let mut store = InMemoryCursorStore::new(vec![]);
+    if let Some(points) = args.cursor_history {
+        store = store.with_history(points as usize);
+    }
+    sync(args, config, addr, network, protocol, store).await
+}
+
+// Runs every configured index, keeping their cursors in `store`.
+async fn sync<CS: CursorStore>(
+    args: Args,
+    mut config: Config,
+    addr: String,
+    network: Network,
+    protocol: Protocol,
+    store: CS,
+) -> Result<()> {
+    let mut indexer = ChainIndexer::new(store);
+    match network.chain_params() {
+        Some(chain_params) => indexer.set_chain_params(chain_params),
+        None => tracing::warn!(
+            magic = network.magic(),
+            "no genesis parameters for this network, so epochs and block times are worked out as if it were mainnet"
+        ),
+    }
+    // Node-to-node connections are left to Acropolis, which hands us blocks over the bus.
+    let mut n2c = (protocol == Protocol::N2c).then(|| N2cChainSource::new(&addr, network.magic()));
+    if let Some(blocks) = args.snapshot_every {
+        indexer.set_snapshot_interval(blocks);
+    }
+    if let Some(interval) = args.checkpoint_every {
+        indexer.set_checkpoint_interval(interval);
+    }
+    if let Some(capacity) = args.block_buffer {
+        indexer.set_block_buffer(capacity);
+    }
+    if let Some(messages) = args.fetch_batch_size {
+        indexer.set_fetch_batch_size(messages);
+    }
+    let mut reconnect = ReconnectPolicy::default();
+    if let Some(retries) = args.reconnect_retries {
+        reconnect.max_retries = retries;
+    }
+    if let Some(secs) = args.reconnect_max_delay_secs {
+        reconnect.max_delay = std::time::Duration::from_secs(secs);
+    }
+    indexer.set_reconnect_policy(reconnect);
+    indexer.set_strict_continuity(args.strict_continuity);
+    indexer.set_on_unsupported_era(args.on_unsupported_era);
+    indexer.set_dry_run(args.dry_run);
+    if let Some(slots) = args.volatile_window {
+        indexer.set_volatile_window(slots);
+    }
+    if let Some(dir) = args.persist_dir.clone() {
+        indexer.set_persist_dir(dir);
+    }
+    if let Some(path) = &args.import_cursors {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        let snapshot = serde_json::from_str(&contents)
+            .with_context(|| format!("invalid cursors in {}", path.display()))?;
+        indexer.import_cursors(&snapshot).await?;
+    }
+    if let Some(ms) = args.slow_block_ms {
+        indexer.set_slow_block_threshold(Some(std::time::Duration::from_millis(ms)));
+    }
+    match args.events_out.as_deref() {
+        Some(path) if path.as_os_str() == "-" => indexer.add_event_sink(JsonlSink::stdout()),
+        Some(path) => indexer.add_event_sink(JsonlSink::create(path)?),
+        None => {}
+    }
+    #[cfg(feature = "kafka")]
+    if let Some(topic) = &args.kafka_topic {
+        let sink = kafka::KafkaSink::connect(args.kafka_brokers.clone(), topic).await?;
+        indexer.add_event_sink(sink);
+    }
+    // Every event, for the query server to push to WebSocket clients.
+    #[cfg(feature = "http")]
+    let live_events = BroadcastSink::new(LIVE_EVENT_BACKLOG);
+    #[cfg(feature = "http")]
+    if args.http_port.is_some() {
+        indexer.add_event_sink(live_events.clone());
+    }
+    let mut pools = None;
+    for entry in &mut config.indexes {
+        match &args.command {
+            Some(Commands::SyncFromOrigin) => {
+                entry.start_slot = None;
+                entry.start_hash = None;
+            }
+            Some(Commands::SyncFromPoint { slot, block_hash }) => {
+                entry.start_slot = Some(*slot);
+                entry.start_hash = Some(hex::encode(block_hash.to_vec()));
+            }
+            None => {}
+        }
+        match entry.kind {
+            IndexKind::Pools => {
+                let address = args.pool_address.clone().or(entry.address()?);
+                let policy = args.pool_policy.or(entry.policy()?);
+                let mut index = PoolIndex::new(address, policy.map(|p| PolicyId::from(*p)));
+                if args.pool_reference_datums {
+                    index = index.with_reference_datums();
+                }
+                let window = args.pool_rollback_window.or(entry.rollback_window);
+                if let Some(window) = pool_rollback_window(window, args.volatile_window)? {
+                    index = index.with_rollback_window(window);
+                }
+                pools = Some(add_configured(&mut indexer, entry, index)?);
+            }
+            IndexKind::PoolDatums => {
+                let address = args.pool_address.clone().or(entry.address()?);
+                add_configured(&mut indexer, entry, pool_datums(address))?;
+            }
+            IndexKind::Orders => {
+                add_configured(&mut indexer, entry, OrderIndex::new())?;
+            }
+            IndexKind::Wallet => {
+                if let Some(confirmations) = args.wallet_confirmations {
+                    entry.confirmations = confirmations;
+                }
+                let addresses = if args.wallet_address.is_empty() {
+                    entry.addresses()?
+                } else {
+                    args.wallet_address.iter().cloned().collect()
+                };
+                if addresses.is_empty() {
+                    bail!("the wallet index needs an address");
+                }
+                let mut wallet = WalletIndex::new(addresses);
+                if let Some(slots) = args.wallet_history_slots.or(entry.history_slots) {
+                    wallet = wallet.with_history(slots);
+                }
+                if args.bootstrap_wallet || entry.bootstrap {
+                    let source = n2c
+                        .as_mut()
+                        .context("bootstrapping the wallet needs --protocol n2c")?;
+                    // It starts from the node's UTXO set, not from wherever it got to last time.
+                    if let Point::Specific(slot, hash) =
+                        bootstrap_wallet(&mut wallet, source).await?
+                    {
+                        entry.start_slot = Some(slot);
+                        entry.start_hash = Some(hex::encode(hash));
+                    }
+                    entry.force_restart = true;
+                }
+                add_configured(&mut indexer, entry, wallet)?;
+            }
+            IndexKind::Mints => {
+                add_configured(&mut indexer, entry, MintIndex::new())?;
+            }
+            IndexKind::Cip25 => {
+                add_configured(&mut indexer, entry, Cip25Index::new())?;
+            }
+            IndexKind::Stake => {
+                add_configured(&mut indexer, entry, StakeIndex::new())?;
+            }
+            IndexKind::Nfts => {
+                let policy = args.nft_policy.or(entry.policy()?);
+                add_configured(&mut indexer, entry, NftIndex::new(policy))?;
+            }
+            IndexKind::ScriptRegistry => {
+                add_configured(&mut indexer, entry, ScriptRegistryIndex::new())?;
+            }
+            IndexKind::Fees => {
+                let mut fees = FeeIndex::new();
+                if args.check_fees || entry.check_fees {
+                    fees = fees.with_fee_checks();
+                }
+                add_configured(&mut indexer, entry, fees)?;
+            }
+            IndexKind::Txs => {
+                add_configured(&mut indexer, entry, tx_counts())?;
+            }
+            IndexKind::Script => {
+                let script_hash = args
+                    .script_hash
+                    .or(entry.script_hash()?)
+                    .context("the script index needs a script hash")?;
+                add_configured(&mut indexer, entry, ScriptIndex::new(script_hash))?;
+            }
+        }
+    }
+
+    if let Some(source) = n2c {
+        indexer.set_chain_source(source);
+    }
+
+    if let Some(path) = &args.export_cursors {
+        let snapshot = indexer.export_cursors().await?;
+        std::fs::write(path, serde_json::to_vec_pretty(&snapshot)?)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        tracing::info!(path = %path.display(), indexes = snapshot.cursors.len(), "exported cursors");
+        return Ok(());
+    }
+
+    if !args.watch_pool.is_empty() {
+        let pools = pools
+            .as_ref()
+            .context("--watch-pool needs the pool index")?;
+        for ident in args.watch_pool.iter().copied() {
+            let updates = pools.read().await.subscribe(ident);
+            tokio::spawn(watch_pool(updates));
+        }
+    }
+
+    // The rest of the application can read an index while it syncs.
+    if let Some(pools) = pools {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                let pools = pools.read().await;
+                let (decoded, unchecked) = pools.datum_counts();
+                tracing::info!(
+                    pools = pools.pools.len(),
+                    decoded,
+                    skipped = pools.skipped_datums(),
+                    unresolved = pools.unresolved_datums(),
+                    unchecked,
+                    datumless = pools.datumless_outputs(),
+                    "tracking pools"
+                );
+            }
+        });
+    }
+
+    let status = indexer.handle();
+    #[cfg(feature = "http")]
+    let routes = indexer.take_routes();
+    let mut process = Process::create();
+    process.register(indexer);
+    #[cfg(feature = "http")]
+    if let Some(port) = args.http_port {
+        let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+        process.register(http::QueryServer::new(
+            addr,
+            routes,
+            status.clone(),
+            live_events,
+            args.ready_max_lag,
+        ));
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(port) = args.metrics_port {
+        let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+        let control_token = match &args.control_token_file {
+            Some(path) => {
+                let token = std::fs::read_to_string(path)
+                    .with_context(|| format!("could not read {}", path.display()))?;
+                let token = token.trim();
+                if token.is_empty() {
+                    bail!("{} is empty", path.display());
+                }
+                Some(token.to_string())
+            }
+            None => None,
+        };
+        let (handle, volatile_window) = (status.clone(), args.volatile_window);
+        let add_index: metrics::AddIndex =
+            Arc::new(move |config| Box::pin(add_live(handle.clone(), config, volatile_window)));
+        process.register(
+            metrics::MetricsServer::new(addr, status.clone(), args.ready_max_lag, control_token)
+                .with_add_index(add_index),
+        );
+    }
+    if let Some(dir) = args.export_dir.clone() {
+        let (status, format) = (status.clone(), args.export_format);
+        tokio::spawn(async move {
+            let every = std::time::Duration::from_secs(args.export_every.max(1));
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+            loop {
+                interval.tick().await;
+                export(&status, &dir, format).await;
+            }
+        });
+    }
+    process.run().await?;
+    if let Some(dir) = &args.export_dir {
+        export(&status, dir, args.export_format).await;
+    }
+    for (name, error) in status.faulted_indexes() {
+        tracing::error!(index = %name, %error, "index stopped");
+    }
+    for sync in status.sync_status() {
+        tracing::info!(
+            index = %sync.index,
+            slot = sync.processed_slot,
+            lag = sync.lag_slots,
+            tip = sync.tip_slot,
+            "index stopped syncing"
+        );
+    }
+    if let Some(error) = status.continuity_error() {
+        return Err(error.into());
+    }
+    Ok(())
+}
+
+/// Logs each of one pool's updates, for `--watch-pool`.
+async fn watch_pool(updates: impl Stream<Item = PoolUpdate>) {
+    let mut updates = std::pin::pin!(updates);
+    while let Some(update) = updates.next().await {
+        match &update {
+            PoolUpdate::Updated {
+                slot,
+                datum,
+                value,
+                action,
+                ..
+            } => tracing::info!(
+                pool = %update.ident(),
+                slot,
+                ?datum,
+                ?value,
+                ?action,
+                "pool updated"
+            ),
+            PoolUpdate::RolledBack {
+                slot,
+                reverted,
+                current,
+                ..
+            } => tracing::info!(
+                pool = %update.ident(),
+                slot,
+                reverted,
+                ?current,
+                "pool rolled back"
+            ),
+        }
+    }
+}
+
+/// Writes every index which can be exported to `<index>.<extension>` in `dir` (each at a
+/// point in time of its own), logging how it went rather than stopping.
+async fn export(status: &ChainIndexerHandle, dir: &Path, format: ExportFormat) {
+    for sync in status.sync_status() {
+        let path = dir.join(format!("{}.{}", sync.index, format.extension()));
+        match status.export_index(&sync.index, format, &path).await {
+            Ok(slot) => {
+                tracing::info!(index = %sync.index, slot, path = %path.display(), "exported")
+            }
+            Err(e) if e.is::<NotExportable>() => {}
+            Err(e) => {
+                tracing::error!(index = %sync.index, error = format!("{e:#}"), "could not export")
+            }
+        }
+    }
+}
+
+/// Seeds `wallet` with what its addresses hold at the node's tip, and returns the tip,
+/// which is where it should start syncing from.
+async fn bootstrap_wallet(wallet: &mut WalletIndex, source: &mut N2cChainSource) -> Result<Point> {
+    let addresses = wallet.addresses.iter().cloned().collect::<Vec<_>>();
+    // If the tip we queried is rolled back before we can sync from it, query again.
+    for _ in 0..3 {
+        let (point, utxos) = source.utxos_at(&addresses).await?;
+        if let Intersect::Found = source.find_intersect(&point).await? {
+            tracing::info!(utxos = utxos.len(), ?point, "bootstrapped the wallet");
+            wallet.seed(point.slot_or_default(), utxos);
+            return Ok(point);
+        }
+    }
+    bail!("the node's tip kept changing while bootstrapping the wallet")
+}
+
+/// How many slots a pool index keeps for rollbacks, given the window it was asked for.
+fn pool_rollback_window(window: Option<u64>, volatile_window: Option<u64>) -> Result<Option<u64>> {
+    match (window, volatile_window) {
+        // The volatile window prunes the pools too, so a longer window would be a lie.
+        (Some(window), Some(volatile)) if window > volatile => bail!(
+            "the pool index can't keep {window} slots for rollbacks with a volatile window of {volatile}"
+        ),
+        (window, volatile) => Ok(window.or(volatile)),
+    }
+}
+
+/// Builds the index `config` describes, as the TOML of one `[[indexes]]` entry, and adds it to
+/// the running indexer, returning the name it went in under. Unlike the indexes in the config
+/// file, nothing about it comes from the command line.
+#[cfg(feature = "metrics")]
+async fn add_live(
+    indexer: ChainIndexerHandle,
+    config: String,
+    volatile_window: Option<u64>,
+) -> Result<String> {
+    let entry = IndexConfig::parse(&config)?;
+    match entry.kind {
+        IndexKind::Pools => {
+            let policy = entry.policy()?.map(|p| PolicyId::from(*p));
+            let mut index = PoolIndex::new(entry.address()?, policy);
+            if let Some(window) = pool_rollback_window(entry.rollback_window, volatile_window)? {
+                index = index.with_rollback_window(window);
+            }
+            add_live_configured(&indexer, &entry, index).await
+        }
+        IndexKind::PoolDatums => {
+            add_live_configured(&indexer, &entry, pool_datums(entry.address()?)).await
+        }
+        IndexKind::Orders => add_live_configured(&indexer, &entry, OrderIndex::new()).await,
+        IndexKind::Wallet => {
+            if entry.bootstrap {
+                bail!("a wallet can only be bootstrapped at startup");
+            }
+            let addresses = entry.addresses()?;
+            if addresses.is_empty() {
+                bail!("the wallet index needs an address");
+            }
+            let mut wallet = WalletIndex::new(addresses);
+            if let Some(slots) = entry.history_slots {
+                wallet = wallet.with_history(slots);
+            }
+            add_live_configured(&indexer, &entry, wallet).await
+        }
+        IndexKind::Mints => add_live_configured(&indexer, &entry, MintIndex::new()).await,
+        IndexKind::Cip25 => add_live_configured(&indexer, &entry, Cip25Index::new()).await,
+        IndexKind::Stake => add_live_configured(&indexer, &entry, StakeIndex::new()).await,
+        IndexKind::Nfts => {
+            let index = NftIndex::new(entry.policy()?);
+            add_live_configured(&indexer, &entry, index).await
+        }
+        IndexKind::ScriptRegistry => {
+            add_live_configured(&indexer, &entry, ScriptRegistryIndex::new()).await
+        }
+        IndexKind::Fees => {
+            let mut fees = FeeIndex::new();
+            if entry.check_fees {
+                fees = fees.with_fee_checks();
+            }
+            add_live_configured(&indexer, &entry, fees).await
+        }
+        IndexKind::Txs => add_live_configured(&indexer, &entry, tx_counts()).await,
+        IndexKind::Script => {
+            let script_hash = entry
+                .script_hash()?
+                .context("the script index needs a script hash")?;
+            add_live_configured(&indexer, &entry, ScriptIndex::new(script_hash)).await
+        }
+    }
+}
+
+/// `add_configured`, for the running indexer.
+#[cfg(feature = "metrics")]
+async fn add_live_configured<M: ManagedIndex>(
+    indexer: &ChainIndexerHandle,
+    entry: &IndexConfig,
+    index: M,
+) -> Result<String> {
+    let start = entry.start()?;
+    let (force_restart, confirmations) = (entry.force_restart, entry.confirmations);
+    match entry.name.clone() {
+        Some(name) => {
+            indexer
+                .add_index_live_as(name.clone(), index, start, force_restart, confirmations)
+                .await?;
+            Ok(name)
+        }
+        None => {
+            let name = index.name();
+            indexer
+                .add_index_live(index, start, force_restart, confirmations)
+                .await?;
+            Ok(name)
+        }
+    }
+}
+
+fn add_configured<CS: CursorStore, M: ManagedIndex>(
+    indexer: &mut ChainIndexer<CS>,
+    entry: &IndexConfig,
+    index: M,
+) -> Result<Arc<RwLock<M>>> {
+    let start = entry.start()?;
+    match entry.name.clone() {
+        Some(name) => {
+            indexer.add_index_as(name, index, start, entry.force_restart, entry.confirmations)
+        }
+        None => indexer.add_index(index, start, entry.force_restart, entry.confirmations),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use futures::StreamExt;
+    use pallas_primitives::{
+        MaybeIndefArray,
+        conway::{Certificate, PostAlonzoTransactionOutput, RedeemerTag},
+    };
+
+    use super::*;
+    use crate::{
+        acropolis::{
+            core::ChainParams,
+            indexer::{ChainEvent, ExportFormat, replay_blocks, replay_cbor},
+        },
+        testing::{self, Tx},
+    };
+
+    // Runs each block through `index` the way the indexer would.
+    async fn replay(index: &mut impl ManagedIndex, blocks: &[&(BlockInfo, Vec<u8>)]) {
+        let blocks = blocks
+            .iter()
+            .map(|(info, cbor)| (format!("slot {}", info.slot), cbor.clone()))
+            .collect::<Vec<_>>();
+        replay_cbor(index, &ChainParams::mainnet(), &blocks)
+            .await
+            .unwrap();
+    }
+
+    fn wallet(owners: &[u8]) -> WalletIndex {
+        let addresses = owners
+            .iter()
+            .map(|n| testing::address(&testing::key_address(*n)));
+        WalletIndex::new(addresses.collect())
+    }
+
+    const POOL_SCRIPT: [u8; 28] = [8; 28];
+    const POOL_POLICY: [u8; 28] = [9; 28];
+    const TOKEN_POLICY: [u8; 28] = [7; 28];
+
+    fn pool_index() -> PoolIndex {
+        let address = testing::script_address(&Hash::new(POOL_SCRIPT));
+        PoolIndex::new(Some(testing::address(&address)), Some(POOL_POLICY.into()))
+    }
+
+    // A pool's ident, made up from a name that's easier to read.
+    fn ident(name: &[u8]) -> Ident {
+        let mut ident = [0; 28];
+        ident[..name.len()].copy_from_slice(name);
+        Ident::from(ident)
+    }
+
+    // TOKEN_POLICY's token called `name`.
+    fn token_named(name: &[u8]) -> AssetClass {
+        AssetClass::new(TOKEN_POLICY, name.try_into().unwrap())
+    }
+
+    fn int(n: i64) -> sundaev3::Integer {
+        n.into()
+    }
+
+    // A pool trading ADA for TOKEN_POLICY's "TOKEN".
+    fn pool_datum(name: &[u8], circulating_lp: i64) -> PoolDatum {
+        PoolDatum {
+            ident: ident(name),
+            assets: (AssetClass::ada(), token_named(b"TOKEN")),
+            circulating_lp: int(circulating_lp),
+            bid_fees_per_10_thousand: int(30),
+            ask_fees_per_10_thousand: int(30),
+            fee_manager: None,
+            market_open: int(0),
+            protocol_fees: int(0),
+        }
+    }
+
+    // The pool's UTXO, holding its NFT and `reserves` of each asset.
+    fn pool_output(datum: PoolDatum, reserves: (u64, u64)) -> PostAlonzoTransactionOutput {
+        let address = testing::script_address(&Hash::new(POOL_SCRIPT));
+        let nft = (Hash::new(POOL_POLICY), datum.ident);
+        let output = testing::output_with_assets(
+            &address,
+            reserves.0,
+            &[
+                (nft.0, nft.1.as_ref(), 1),
+                (Hash::new(TOKEN_POLICY), b"TOKEN", reserves.1),
+            ],
+        );
+        testing::with_datum(output, datum.to_plutus())
+    }
+
+    #[tokio::test]
+    async fn pool_rollback_restores_the_version_before_it() {
+        let created = Tx::new().pay(pool_output(pool_datum(b"pool", 100), (1_000, 2_000)));
+        let first = testing::block(100, None, std::slice::from_ref(&created));
+        let deposited = Tx::new()
+            .spend(&created.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 150), (1_500, 3_000)));
+        let second = testing::block(200, Some(&first.0), std::slice::from_ref(&deposited));
+
+        let mut index = pool_index();
+        replay(&mut index, &[&first, &second]).await;
+        let pool = &index.pools[&ident(b"pool")];
+        assert_eq!(pool.versions.len(), 2);
+        assert_eq!(
+            big_int_to_i128(&pool.datum().unwrap().circulating_lp).unwrap(),
+            150
+        );
+        assert_eq!(pool.reserves(), Some((1_500, 3_000)));
+
+        // Rolling back to slot 150 undoes the deposit at 200, but not the pool itself.
+        let midway = BlockInfo::new(150, first.0.hash, 150);
+        index.handle_rollback(&midway).await.unwrap();
+        let pool = &index.pools[&ident(b"pool")];
+        assert_eq!(pool.versions.len(), 1);
+        assert_eq!(
+            big_int_to_i128(&pool.datum().unwrap().circulating_lp).unwrap(),
+            100
+        );
+        assert_eq!(pool.reserves(), Some((1_000, 2_000)));
+        assert_eq!(pool.utxo(), Some(created.output_ref(0)));
+        assert_eq!(pool.lp_history().len(), 1);
+
+        // And rolling back to before it was created forgets it altogether.
+        index.handle_rollback(&first.0).await.unwrap();
+        assert!(index.pools.is_empty());
+        assert!(index.by_pair.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pool_updates_are_labelled_by_the_redeemer_spending_the_pool() {
+        let created = Tx::new().pay(pool_output(pool_datum(b"pool", 100), (1_000, 2_000)));
+        let first = testing::block(100, None, std::slice::from_ref(&created));
+        let scoop = PoolRedeemer::PoolScoop {
+            signatory_index: int(0),
+            scoop_index: int(0),
+            input_order: sundaev3::Data(int(0).to_plutus()),
+        };
+        // The pool is the second input in the body, but the first once they're sorted.
+        let scooped = Tx::new()
+            .spend(&OutputRef::new(Hash::new([0xff; 32]), 0))
+            .spend(&created.output_ref(0))
+            .redeemer(RedeemerTag::Spend, 0, scoop.to_plutus())
+            .pay(pool_output(pool_datum(b"pool", 100), (1_100, 1_900)));
+        let second = testing::block(200, Some(&first.0), std::slice::from_ref(&scooped));
+        let managed = Tx::new()
+            .spend(&scooped.output_ref(0))
+            .redeemer(RedeemerTag::Spend, 0, PoolRedeemer::Manage.to_plutus())
+            .pay(pool_output(pool_datum(b"pool", 100), (1_100, 1_900)));
+        let third = testing::block(300, Some(&second.0), &[managed]);
+
+        let mut index = pool_index();
+        let updates = index.subscribe(ident(b"pool"));
+        replay(&mut index, &[&first, &second, &third]).await;
+        let actions = index
+            .drain_events()
+            .into_iter()
+            .filter(|e| e.kind == "pool_updated")
+            .map(|e| e.data["action"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [serde_json::Value::Null, "scoop".into(), "manage".into()]
+        );
+        let labels = updates
+            .take(3)
+            .map(|update| match update {
+                PoolUpdate::Updated { action, .. } => action.as_ref().map(PoolRedeemer::action),
+                PoolUpdate::RolledBack { .. } => panic!("nothing was rolled back"),
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(labels, [None, Some("scoop"), Some("manage")]);
+    }
+
+    #[tokio::test]
+    async fn pruning_a_pool_keeps_its_lp_history_and_swaps() {
+        let created = Tx::new().pay(pool_output(pool_datum(b"pool", 100), (1_000, 2_000)));
+        let first = testing::block(100, None, std::slice::from_ref(&created));
+        let deposited = Tx::new()
+            .spend(&created.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 150), (1_500, 3_000)));
+        let second = testing::block(200, Some(&first.0), std::slice::from_ref(&deposited));
+        let swapped = Tx::new()
+            .spend(&deposited.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 150), (1_600, 2_800)));
+        let third = testing::block(300, Some(&second.0), &[swapped]);
+
+        let mut index = pool_index();
+        replay(&mut index, &[&first, &second, &third]).await;
+        index.prune(1_000).await.unwrap();
+        let pool = &index.pools[&ident(b"pool")];
+        assert_eq!(pool.versions.len(), 1);
+        assert_eq!(pool.utxos.len(), 1);
+        let lp = pool
+            .lp_history()
+            .iter()
+            .map(|(slot, delta)| (*slot, big_int_to_i128(delta).unwrap()));
+        assert_eq!(lp.collect::<Vec<_>>(), [(100, 100), (200, 50)]);
+        assert_eq!(pool.swaps(), [(300, 100, -200)]);
+        assert_eq!(pool.volume(), (100, 200));
+    }
+
+    #[tokio::test]
+    async fn a_pool_remembers_when_it_was_created_after_its_first_version_is_pruned() {
+        let created = Tx::new().pay(pool_output(pool_datum(b"pool", 100), (1_000, 2_000)));
+        let first = testing::block(100, None, std::slice::from_ref(&created));
+        let deposited = Tx::new()
+            .spend(&created.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 150), (1_500, 3_000)));
+        let second = testing::block(200, Some(&first.0), std::slice::from_ref(&deposited));
+        let withdrawn = Tx::new()
+            .spend(&deposited.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 120), (1_200, 2_400)));
+        let third = testing::block(300, Some(&second.0), &[withdrawn]);
+
+        let mut index = pool_index().with_rollback_window(50);
+        replay(&mut index, &[&first, &second, &third]).await;
+        let pool = &index.pools[&ident(b"pool")];
+        // Only the version a rollback to 250 would go back to is kept from before then.
+        assert_eq!(pool.versions[0].0, 200);
+        assert_eq!(pool.created_at(), 100);
+
+        // A snapshot from before `created_at` was kept takes it from its oldest version.
+        let mut snapshot: serde_json::Value =
+            serde_json::from_slice(&index.snapshot().unwrap().unwrap()).unwrap();
+        snapshot[0][1].as_object_mut().unwrap().remove("created_at");
+        let mut restored = pool_index();
+        restored
+            .restore(&serde_json::to_vec(&snapshot).unwrap())
+            .unwrap();
+        assert_eq!(restored.pools[&ident(b"pool")].created_at(), 200);
+    }
+
+    #[tokio::test]
+    async fn a_rollback_past_the_pool_window_resyncs_the_pools_from_the_origin() {
+        let created = Tx::new()
+            .pay(pool_output(pool_datum(b"pool", 100), (1_000, 2_000)))
+            .mint(Hash::new(TOKEN_POLICY), b"TOKEN", 1_000);
+        let first = testing::block(100, None, std::slice::from_ref(&created));
+        let deposited = Tx::new()
+            .spend(&created.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 150), (1_500, 3_000)))
+            .mint(Hash::new(TOKEN_POLICY), b"TOKEN", 500);
+        let second = testing::block(200, Some(&first.0), std::slice::from_ref(&deposited));
+        let withdrawn = Tx::new()
+            .spend(&deposited.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 120), (1_200, 2_400)));
+        let third = testing::block(300, Some(&second.0), &[withdrawn]);
+        let other = Tx::new().pay(pool_output(pool_datum(b"other", 10), (10, 20)));
+        let fork = testing::fork_block(150, Some(&first.0), &[other]);
+
+        let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+        let pools = indexer
+            .add_index(
+                pool_index().with_rollback_window(50),
+                Point::Origin,
+                false,
+                0,
+            )
+            .unwrap();
+        let mints = indexer
+            .add_index(MintIndex::new(), Point::Origin, false, 0)
+            .unwrap();
+        indexer.set_volatile_window(50);
+        let mut events = testing::roll_forward(&[first.clone(), second, third]);
+        events.push(ChainEvent::RollBackward(
+            first.0.hash.to_point(first.0.slot),
+        ));
+        events.push(ChainEvent::RollForward(fork.0.clone(), fork.1));
+        let handle = testing::sync(indexer, events).await.unwrap();
+
+        // Neither can undo the rollback, so rather than faulting, they start over from the
+        // origin and end up with just what's on the fork.
+        assert!(handle.faulted_indexes().is_empty());
+        let pools = pools.read().await;
+        let pool = &pools.pools[&ident(b"pool")];
+        assert_eq!(pool.versions.len(), 1);
+        assert_eq!(pool.reserves(), Some((1_000, 2_000)));
+        assert!(pools.pools.contains_key(&ident(b"other")));
+        assert_eq!(
+            mints.read().await.supply,
+            BTreeMap::from([(token_named(b"TOKEN"), 1_000)])
+        );
+        assert_eq!(
+            handle.committed_cursors()["pools"],
+            fork.0.hash.to_point(fork.0.slot)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_pool_with_nothing_of_its_first_asset_has_no_price() {
+        let priced = Tx::new().pay(pool_output(pool_datum(b"priced", 100), (1_000, 2_000)));
+        let drained = Tx::new().pay(pool_output(pool_datum(b"drained", 100), (0, 2_000)));
+        let block = testing::block(100, None, &[priced, drained]);
+
+        let mut index = pool_index();
+        replay(&mut index, &[&block]).await;
+        let priced = &index.pools[&ident(b"priced")];
+        assert_eq!(priced.price(), Some(2.0));
+        assert_eq!(priced.effective_price(), Some(2.0 * (1.0 - 0.003)));
+        let drained = &index.pools[&ident(b"drained")];
+        assert_eq!(drained.reserves(), Some((0, 2_000)));
+        assert_eq!(drained.price(), None);
+        assert_eq!(drained.effective_price(), None);
+    }
+
+    #[tokio::test]
+    async fn pools_are_found_by_their_pair_either_way_round() {
+        let ada = AssetClass::ada();
+        let token = token_named(b"TOKEN");
+        let other = token_named(b"OTHER");
+        let trading = |ident: &[u8], assets: (&AssetClass, &AssetClass)| PoolDatum {
+            assets: (assets.0.clone(), assets.1.clone()),
+            ..pool_datum(ident, 100)
+        };
+        let forward = Tx::new().pay(pool_output(trading(b"forward", (&ada, &token)), (1, 1)));
+        let reversed = Tx::new().pay(pool_output(trading(b"reversed", (&token, &ada)), (1, 1)));
+        let first = testing::block(100, None, &[forward.clone(), reversed]);
+        // The forward pool moves on to trading OTHER.
+        let moved = Tx::new()
+            .spend(&forward.output_ref(0))
+            .pay(pool_output(trading(b"forward", (&ada, &other)), (1, 1)));
+        let second = testing::block(200, Some(&first.0), &[moved]);
+
+        let mut index = pool_index();
+        let idents = |index: &PoolIndex, a: &AssetClass, b: &AssetClass| {
+            let pools = index.pools_for_pair(a, b);
+            pools
+                .iter()
+                .map(|p| p.datum().unwrap().ident)
+                .collect::<Vec<_>>()
+        };
+        replay(&mut index, &[&first]).await;
+        assert_eq!(
+            idents(&index, &ada, &token),
+            [ident(b"forward"), ident(b"reversed")]
+        );
+        assert_eq!(idents(&index, &token, &ada), idents(&index, &ada, &token));
+
+        replay(&mut index, &[&second]).await;
+        assert_eq!(idents(&index, &ada, &token), [ident(b"reversed")]);
+        assert_eq!(idents(&index, &other, &ada), [ident(b"forward")]);
+
+        // Rolling the move back puts the pool back under the pair it traded before.
+        let midway = BlockInfo::new(150, first.0.hash, 150);
+        index.handle_rollback(&midway).await.unwrap();
+        assert_eq!(
+            idents(&index, &token, &ada),
+            [ident(b"forward"), ident(b"reversed")]
+        );
+        assert!(idents(&index, &ada, &other).is_empty());
+        assert!(!index.by_pair.contains_key(&asset_pair(&ada, &other)));
+    }
+
+    #[tokio::test]
+    async fn a_datum_index_keeps_every_version_until_rolled_back() {
+        let created = Tx::new().pay(pool_output(pool_datum(b"pool", 1_000), (10, 10)));
+        let first = testing::block(100, None, std::slice::from_ref(&created));
+        let deposit = Tx::new()
+            .spend(&created.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 1_500), (15, 15)))
+            // Not at the pool address, so it's left alone.
+            .pay(testing::with_datum(
+                testing::output(&testing::key_address(1), 1),
+                pool_datum(b"fake", 1).to_plutus(),
+            ));
+        let second = testing::block(200, Some(&first.0), &[deposit]);
+
+        let address = testing::script_address(&Hash::new(POOL_SCRIPT));
+        let mut index = pool_datums(Some(testing::address(&address)));
+        replay(&mut index, &[&first, &second]).await;
+        let lp = |index: &DatumIndex<PoolDatum, Ident>| {
+            let history = index.history(&ident(b"pool"));
+            history
+                .iter()
+                .map(|(slot, d)| (*slot, big_int_to_i128(&d.circulating_lp).unwrap()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lp(&index), [(100, 1_000), (200, 1_500)]);
+        assert!(index.latest(&ident(b"fake")).is_none());
+
+        index.handle_rollback(&second.0).await.unwrap();
+        assert_eq!(lp(&index), [(100, 1_000)]);
+        index.handle_rollback(&first.0).await.unwrap();
+        assert!(index.latest(&ident(b"pool")).is_none());
+    }
+
+    #[tokio::test]
+    async fn the_pool_creation_fixture_creates_a_pool() {
+        let fixture =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/pool_created.cbor");
+        let mut index = pool_index();
+        replay_blocks(&mut index, &ChainParams::preprod(), &[fixture])
+            .await
+            .unwrap();
+        let pool = &index.pools[&ident(b"pool")];
+        assert_eq!(pool.created_at(), 70_000_000);
+        assert_eq!(pool.reserves(), Some((5_000_000, 2_000)));
+        assert_eq!(
+            big_int_to_i128(&pool.datum().unwrap().circulating_lp).unwrap(),
+            1_000
+        );
+    }
+
+    #[tokio::test]
+    async fn byron_blocks_replay_through_the_indexes() {
+        let fixtures = ["byron_genesis.cbor", "byron_43191.cbor", "byron_73486.cbor"].map(|f| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(f)
+        });
+        let params = ChainParams::mainnet();
+
+        // The boundary block has no txs, and the other two have one each.
+        let mut txs = tx_counts();
+        replay_blocks(&mut txs, &params, &fixtures).await.unwrap();
+        assert_eq!(txs.state().epochs, BTreeMap::from([(1, 1), (3, 1)]));
+
+        // Their inputs come from blocks that aren't here, so there's nothing to work out
+        // their fees from.
+        let mut fees = FeeIndex::new();
+        replay_blocks(&mut fees, &params, &fixtures).await.unwrap();
+        assert_eq!(fees.fees_at(43_191), Some((0, 0)));
+        assert_eq!(fees.fees_at(73_486), Some((0, 0)));
+
+        let owners = [
+            "DdzFFzCqrht99RHRc3AjA1RuYfz3sKc6yZDgUYkS2DoBTMRuhnGpK59Qc11phBTK9pWn6q55qpwXtjXLhhSuRTjEUzP27VVr5jCCmT1M",
+            "DdzFFzCqrhsjBALPnY7YRYx1agih8ZnCnnoW5frzQ9auky1BUdpovBWgRs3Mw9DBapCeDvZYtd7mwcGpPaXUYb9uyFrCjzBwB3iT7DjD",
+        ];
+        let mut wallet = WalletIndex::new(owners.iter().map(|a| a.parse().unwrap()).collect());
+        replay_blocks(&mut wallet, &params, &fixtures)
+            .await
+            .unwrap();
+        assert_eq!(
+            wallet.balances(),
+            BTreeMap::from([(AssetClass::ada(), 50_000_000)])
+        );
+
+        // Nothing Byron is shaped like a pool.
+        let mut pools = pool_index();
+        replay_blocks(&mut pools, &params, &fixtures).await.unwrap();
+        assert!(pools.pools.is_empty());
+    }
+
+    #[tokio::test]
+    async fn byron_fees_are_worked_out_from_the_outputs_they_spend() {
+        let before = BlockInfo::new(
+            21_599,
+            crate::acropolis::core::BlockHash::try_from(vec![0xac; 32]).unwrap(),
+            21_599,
+        );
+        let boundary = testing::epoch_boundary_block(1, &before);
+        let funding = testing::byron_tx(
+            &[OutputRef::new(Hash::new([0xf0; 32]), 0)],
+            &[(testing::byron_address(1), 1_000_000)],
+        );
+        let spend = testing::byron_tx(
+            &[testing::byron_output_ref(&funding, 0)],
+            &[(testing::byron_address(2), 800_000)],
+        );
+        let txs = testing::byron_block(1, 0, &boundary.0, &[funding, spend]);
+
+        // Byron txs don't declare a fee, and only the second's inputs are there to work
+        // one out from.
+        let mut fees = FeeIndex::new();
+        replay(&mut fees, &[&boundary, &txs]).await;
+        assert_eq!(fees.fees_at(21_600), Some((200_000, 100_000)));
+    }
+
+    #[tokio::test]
+    async fn a_pool_export_is_a_csv_with_its_slot_alongside() {
+        let datum = pool_datum(b"pool", 100);
+        let created = Tx::new().pay(pool_output(datum.clone(), (1_000, 2_000)));
+        let block = testing::block(100, None, &[created]);
+        let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+        indexer
+            .add_index(pool_index(), Point::Origin, false, 0)
+            .unwrap();
+        let handle = testing::sync(indexer, testing::roll_forward(&[block]))
+            .await
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("pools-{}.csv", std::process::id()));
+        let slot = handle
+            .export_index("pools", ExportFormat::Csv, &path)
+            .await
+            .unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let sidecar = std::fs::read_to_string(path.with_extension("csv.json")).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("csv.json"));
+
+        assert_eq!(slot, 100);
+        let row = format!(
+            "{},{},{},100,100,1000,2000,2,0.003,0.003,1.994",
+            datum.ident, datum.assets.0, datum.assets.1
+        );
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "ident,asset_a,asset_b,circulating_lp,created_at,reserve_a,reserve_b,price,bid_fee,ask_fee,effective_price",
+                row.as_str()
+            ]
+        );
+        let sidecar: serde_json::Value = serde_json::from_str(&sidecar).unwrap();
+        assert_eq!(
+            sidecar,
+            serde_json::json!({ "index": "pools", "slot": 100, "rows": 1 })
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn a_pool_export_reads_back_from_parquet() {
+        use arrow_array::{Array, StringArray};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let datum = pool_datum(b"pool", 100);
+        let created = Tx::new().pay(pool_output(datum.clone(), (1_000, 2_000)));
+        let block = testing::block(100, None, &[created]);
+        let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+        indexer
+            .add_index(pool_index(), Point::Origin, false, 0)
+            .unwrap();
+        let handle = testing::sync(indexer, testing::roll_forward(&[block]))
+            .await
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("pools-{}.parquet", std::process::id()));
+        handle
+            .export_index("pools", ExportFormat::Parquet, &path)
+            .await
+            .unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let batches = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("parquet.json"));
+
+        let [batch] = batches.as_slice() else {
+            panic!("expected one batch, got {}", batches.len());
+        };
+        assert_eq!(batch.num_rows(), 1);
+        let column = |name: &str| {
+            let column = batch.column_by_name(name).unwrap();
+            let column = column.as_any().downcast_ref::<StringArray>().unwrap();
+            column.value(0).to_string()
+        };
+        assert_eq!(column("ident"), ident(b"pool").to_string());
+        assert_eq!(column("asset_a"), datum.assets.0.to_string());
+        assert_eq!(column("created_at"), "100");
+        assert_eq!(column("reserve_b"), "2000");
+    }
+
+    // Fails on every block it's given.
+    struct Broken;
+
+    #[async_trait]
+    impl ManagedIndex for Broken {
+        fn name(&self) -> String {
+            "broken".into()
+        }
+
+        async fn handle_block(
+            &mut self,
+            info: &BlockInfo,
+            _: &pallas_traverse::MultiEraBlock,
+        ) -> Result<()> {
+            bail!("can't handle slot {}", info.slot)
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failing_index_leaves_the_pool_index_running() {
+        let created = Tx::new().pay(pool_output(pool_datum(b"pool", 100), (1_000, 2_000)));
+        let first = testing::block(100, None, std::slice::from_ref(&created));
+        let deposited = Tx::new()
+            .spend(&created.output_ref(0))
+            .pay(pool_output(pool_datum(b"pool", 150), (1_500, 3_000)));
+        let second = testing::block(200, Some(&first.0), std::slice::from_ref(&deposited));
+
+        let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+        indexer.add_index(Broken, Point::Origin, false, 0).unwrap();
+        let pools = indexer
+            .add_index(pool_index(), Point::Origin, false, 0)
+            .unwrap();
+        let handle = testing::sync(indexer, testing::roll_forward(&[first, second.clone()]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            handle.faulted_indexes(),
+            [("broken".to_string(), "can't handle slot 100".to_string())]
+        );
+        let pools = pools.read().await;
+        assert_eq!(pools.pools[&ident(b"pool")].versions.len(), 2);
+        let committed = handle.committed_cursors();
+        assert_eq!(
+            committed.get("pools"),
+            Some(&second.0.hash.to_point(second.0.slot))
+        );
+        assert!(!committed.contains_key("broken"));
+    }
+
+    #[tokio::test]
+    async fn malformed_pool_datums_are_counted_not_fatal() {
+        let address = testing::script_address(&Hash::new(POOL_SCRIPT));
+        let nft = [(Hash::new(POOL_POLICY), b"pool".as_slice(), 1)];
+        let empty = PlutusData::Array(MaybeIndefArray::Def(vec![]));
+        let wrong_fields = pool_datum(b"pool", 100).to_plutus();
+        let PlutusData::Constr(mut wrong_fields) = wrong_fields else {
+            unreachable!()
+        };
+        wrong_fields.fields = MaybeIndefArray::Indef(vec![]);
+        let tx = Tx::new()
+            .pay(testing::with_datum(
+                testing::output_with_assets(&address, 2_000_000, &nft),
+                empty,
+            ))
+            .pay(testing::with_datum(
+                testing::output_with_assets(&address, 2_000_000, &nft),
+                PlutusData::Constr(wrong_fields),
+            ))
+            .pay(testing::output_with_assets(&address, 2_000_000, &nft))
+            .pay(pool_output(pool_datum(b"pool", 100), (1_000, 2_000)));
+        let block = testing::block(100, None, &[tx]);
+
+        let mut index = pool_index();
+        replay(&mut index, &[&block]).await;
+        assert_eq!(index.skipped_datums(), 2);
+        assert_eq!(index.datumless_outputs(), 1);
+        assert_eq!(index.pools.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn an_out_of_range_lp_supply_skips_the_pool_not_the_index() {
+        let mut huge = pool_datum(b"huge", 0);
+        huge.circulating_lp = sundaev3::Integer(BigInt::BigUInt(vec![1; 17].into()));
+        let tx = Tx::new()
+            .pay(pool_output(huge, (1_000, 2_000)))
+            .pay(pool_output(pool_datum(b"pool", 100), (1_000, 2_000)));
+        let first = testing::block(100, None, std::slice::from_ref(&tx));
+        // From -2^63 all the way up to 2^64 - 1 is further than a datum can say it moved.
+        let created = Tx::new().pay(pool_output(pool_datum(b"jump", i64::MIN), (1_000, 2_000)));
+        let mut jump = pool_datum(b"jump", 0);
+        jump.circulating_lp = sundaev3::Integer(BigInt::BigUInt(vec![0xff; 8].into()));
+        let jumped = Tx::new()
+            .spend(&created.output_ref(0))
+            .pay(pool_output(jump, (1_000, 2_000)));
+        let second = testing::block(200, Some(&first.0), &[created, jumped]);
+
+        let mut index = pool_index();
+        replay(&mut index, &[&first, &second]).await;
+        assert_eq!(index.skipped_datums(), 2);
+        assert!(!index.pools.contains_key(&ident(b"huge")));
+        assert!(index.pools.contains_key(&ident(b"pool")));
+        assert_eq!(index.pools[&ident(b"jump")].versions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fees_are_only_checked_when_asked() {
+        let funding = Tx::new().pay(testing::output(&testing::key_address(1), 100));
+        let first = testing::block(10, None, std::slice::from_ref(&funding));
+        let spend = Tx::new()
+            .spend(&funding.output_ref(0))
+            .pay(testing::output(&testing::key_address(2), 90))
+            .fee(10);
+        let second = testing::block(20, Some(&first.0), &[spend]);
+
+        let mut unchecked = FeeIndex::new();
+        replay(&mut unchecked, &[&first, &second]).await;
+        assert_eq!(unchecked.fees_at(20), Some((10, 10)));
+        // Conway outputs weren't kept, so there was nothing to check the fee against.
+        assert_eq!((unchecked.checked, unchecked.mismatched), (0, 0));
+
+        let mut checked = FeeIndex::new().with_fee_checks();
+        replay(&mut checked, &[&first, &second]).await;
+        assert_eq!(checked.fees_at(20), Some((10, 10)));
+        assert_eq!((checked.checked, checked.mismatched), (1, 0));
+        // The funding tx paid nothing, so the two average out at 5.
+        assert_eq!(checked.average_fee(), Some(5));
+        assert_eq!(FeeIndex::new().average_fee(), None);
+    }
+
+    #[tokio::test]
+    async fn txs_are_counted_by_epoch_until_too_old_to_roll_back() {
+        let txs = |n| vec![Tx::new(); n];
+        // Byron epochs on mainnet are 21600 slots long.
+        let first = testing::block(10, None, &txs(2));
+        let second = testing::block(30_000, Some(&first.0), &txs(1));
+        let third = testing::block(50_000, Some(&second.0), &txs(3));
+
+        let mut index = tx_counts();
+        replay(&mut index, &[&first, &second, &third]).await;
+        assert_eq!(
+            index.state().epochs,
+            BTreeMap::from([(0, 2), (1, 1), (2, 3)])
+        );
+
+        index.handle_rollback(&second.0).await.unwrap();
+        assert_eq!(index.state().epochs, BTreeMap::from([(0, 2)]));
+
+        // Once the first block's too old to be rolled back, its count is there for good.
+        let later = testing::block(100_000, Some(&first.0), &txs(1));
+        replay(&mut index, &[&later]).await;
+        assert_eq!(index.state().recent.len(), 1);
+        assert!(index.handle_rollback(&first.0).await.is_err());
+        assert_eq!(index.state().epochs.values().sum::<u64>(), 3);
+    }
+
+    #[tokio::test]
+    async fn a_failed_script_tx_pays_the_collateral_it_forfeits() {
+        let mine = testing::key_address(1);
+        let funding = Tx::new()
+            .pay(testing::output(&mine, 5_000_000))
+            .pay(testing::output(&mine, 2_000_000));
+        let first = testing::block(10, None, std::slice::from_ref(&funding));
+        let failed = Tx::new()
+            .spend(&funding.output_ref(0))
+            .collateral(&funding.output_ref(1))
+            .pay(testing::output(&mine, 4_800_000))
+            .collateral_return(testing::output(&mine, 1_500_000))
+            .fee(200_000)
+            .failing();
+        let second = testing::block(20, Some(&first.0), &[failed]);
+
+        let mut fees = FeeIndex::new().with_fee_checks();
+        replay(&mut fees, &[&first, &second]).await;
+        // It declares 0.2 ADA, but what it pays is the 0.5 ADA of collateral it doesn't get back.
+        assert_eq!(fees.fees_at(20), Some((500_000, 500_000)));
+    }
+
+    #[tokio::test]
+    async fn mints_are_undone_by_a_rollback_until_theyre_pruned() {
+        let token = token_named(b"TOKEN");
+        let minted = Tx::new().mint(Hash::new(TOKEN_POLICY), b"TOKEN", 1_000);
+        let first = testing::block(100, None, &[minted]);
+        let burnt = Tx::new().mint(Hash::new(TOKEN_POLICY), b"TOKEN", -400);
+        let second = testing::block(200, Some(&first.0), &[burnt]);
+        let third = testing::block(300, Some(&second.0), &[]);
+
+        let mut index = MintIndex::new();
+        replay(&mut index, &[&first, &second, &third]).await;
+        assert_eq!(index.supply[&token], 600);
+
+        index.prune(150).await.unwrap();
+        assert_eq!(index.retained_since(), Some(150));
+        assert_eq!(index.history, [(200, token.clone(), -400)]);
+        index.handle_rollback(&second.0).await.unwrap();
+        assert_eq!(index.supply[&token], 1_000);
+        assert!(index.history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_stake_credential_keeps_its_delegation_addresses_and_withdrawals() {
+        let cred = StakeCredential::AddrKeyhash(Hash::new([5; 28]));
+        let pool = Hash::new([6; 28]);
+        let delegated = Tx::new()
+            .certificate(Certificate::StakeRegDeleg(cred.clone(), pool, 2_000_000))
+            .pay(testing::output(&testing::base_address(1, 5), 10_000_000));
+        let first = testing::block(100, None, &[delegated]);
+        let withdrawn = Tx::new()
+            .withdraw(&testing::reward_account(5), 300)
+            .pay(testing::output(&testing::base_address(2, 5), 5_000_000))
+            .pay(testing::output(&testing::base_address(3, 4), 5_000_000));
+        let second = testing::block(200, Some(&first.0), &[withdrawn]);
+        let left = Tx::new().certificate(Certificate::StakeDeregistration(cred.clone()));
+        let third = testing::block(300, Some(&second.0), &[left]);
+
+        let mut index = StakeIndex::new();
+        replay(&mut index, &[&first, &second]).await;
+        assert_eq!(index.delegation(&cred), Some(pool));
+        let addresses = [1, 2].map(|n| testing::address(&testing::base_address(n, 5)));
+        assert!(index.addresses(&cred).eq(&addresses));
+        assert_eq!(index.withdrawn(&testing::reward_account(5)), 300);
+        assert_eq!(index.withdrawn(&testing::reward_account(4)), 0);
+
+        replay(&mut index, &[&third]).await;
+        assert_eq!(index.delegation(&cred), None);
+        index.handle_rollback(&second.0).await.unwrap();
+        assert_eq!(index.delegation(&cred), Some(pool));
+        assert!(index.addresses(&cred).eq(&addresses[..1]));
+        assert_eq!(index.withdrawn(&testing::reward_account(5)), 0);
+    }
+
+    #[tokio::test]
+    async fn a_script_index_follows_its_script_whatever_the_staking_part() {
+        let script = Hash::new(POOL_SCRIPT);
+        let mut staked = vec![0x11];
+        staked.extend(POOL_SCRIPT);
+        staked.extend([5; 28]);
+        let paid = Tx::new()
+            .pay(testing::output(
+                &testing::script_address(&script),
+                1_000_000,
+            ))
+            .pay(testing::output(&staked, 2_000_000))
+            .pay(testing::output(&testing::key_address(1), 3_000_000));
+        let first = testing::block(100, None, std::slice::from_ref(&paid));
+        let spent = Tx::new().spend(&paid.output_ref(0));
+        let second = testing::block(200, Some(&first.0), &[spent]);
+
+        let mut index = ScriptIndex::new(script);
+        replay(&mut index, &[&first, &second]).await;
+        let unspent = index
+            .unspent()
+            .map(|(ref_, o)| (ref_.clone(), o.address.clone()));
+        assert_eq!(
+            unspent.collect::<Vec<_>>(),
+            [(paid.output_ref(1), testing::address(&staked))]
+        );
+
+        index.handle_rollback(&second.0).await.unwrap();
+        let table = index.export().unwrap();
+        let outputs = table.rows.iter().map(|row| row[0].as_str());
+        let expected = [paid.output_ref(0), paid.output_ref(1)].map(|r| r.to_string());
+        assert!(outputs.eq(expected.iter().map(String::as_str)));
+    }
+
+    #[tokio::test]
+    async fn wallet_rollback_restores_spent_and_drops_created_utxos() {
+        let mine = testing::key_address(1);
+        let received = Tx::new().pay(testing::output(&mine, 5_000_000));
+        let first = testing::block(10, None, std::slice::from_ref(&received));
+        let spent = Tx::new()
+            .spend(&received.output_ref(0))
+            .pay(testing::output(&mine, 3_000_000))
+            .pay(testing::output(&testing::key_address(2), 2_000_000));
+        let second = testing::block(20, Some(&first.0), std::slice::from_ref(&spent));
+
+        let mut index = wallet(&[1]);
+        replay(&mut index, &[&first, &second]).await;
+        assert_eq!(index.total_balance(), Value::Coin(3_000_000));
+        assert!(index.holder(&received.output_ref(0)).is_none());
+        index.drain_events();
+
+        index.handle_rollback(&second.0).await.unwrap();
+        assert_eq!(index.total_balance(), Value::Coin(5_000_000));
+        assert!(index.holder(&received.output_ref(0)).is_some());
+        assert!(!index.utxos.contains_key(&spent.output_ref(0)));
+        let mut reverted = index
+            .drain_events()
+            .into_iter()
+            .map(|e| (e.kind, e.slot))
+            .collect::<Vec<_>>();
+        reverted.sort();
+        assert_eq!(
+            reverted,
+            [
+                ("utxo_received".to_string(), 20),
+                ("utxo_spent".to_string(), 20)
+            ]
+        );
+
+        // The rolled-back tx can come back on the new fork, and counts once.
+        let second = testing::fork_block(20, Some(&first.0), &[spent]);
+        replay(&mut index, &[&second]).await;
+        assert_eq!(index.total_balance(), Value::Coin(3_000_000));
+    }
+
+    #[tokio::test]
+    async fn a_wallets_utxo_keeps_its_ledger_output_index() {
+        let mine = testing::key_address(1);
+        let received = Tx::new()
+            .pay(testing::output(&testing::key_address(2), 1_000_000))
+            .pay(testing::output(&testing::key_address(3), 2_000_000))
+            .pay(testing::output(&mine, 3_000_000));
+        let first = testing::block(10, None, std::slice::from_ref(&received));
+        // Spending it by that index is what takes it out of the wallet.
+        let spent = Tx::new().spend(&received.output_ref(2));
+        let second = testing::block(20, Some(&first.0), &[spent]);
+        let mut index = wallet(&[1]);
+        replay(&mut index, &[&first]).await;
+        let utxos: Vec<_> = index.utxos.keys().collect();
+        assert_eq!(utxos, [&received.output_ref(2)]);
+        assert_eq!(index.total_balance(), Value::Coin(3_000_000));
+
+        let mut index = wallet(&[1]);
+        replay(&mut index, &[&first, &second]).await;
+        assert!(index.holder(&received.output_ref(2)).is_none());
+        assert_eq!(index.total_balance(), Value::Coin(0));
+    }
+
+    #[tokio::test]
+    async fn a_failed_script_tx_only_moves_its_collateral() {
+        let fixture =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/failed_script_tx.cbor");
+        let cbor = std::fs::read(&fixture).unwrap();
+        let block = pallas_traverse::MultiEraBlock::decode(&cbor).unwrap();
+        let txs = block.txs();
+        assert!(txs[0].is_valid() && !txs[1].is_valid());
+        let (funding, failed) = (txs[0].hash(), txs[1].hash());
+
+        let mut index = wallet(&[1]);
+        replay_blocks(&mut index, &ChainParams::mainnet(), &[fixture])
+            .await
+            .unwrap();
+        // The input it declared is still there, and the outputs it declared never were. The
+        // collateral's gone, and its return comes just after where the outputs would be.
+        assert!(index.holder(&OutputRef::new(funding, 0)).is_some());
+        assert!(index.holder(&OutputRef::new(funding, 1)).is_none());
+        assert!(index.holder(&OutputRef::new(failed, 1)).is_none());
+        assert!(index.holder(&OutputRef::new(failed, 2)).is_some());
+        assert_eq!(index.total_balance(), Value::Coin(6_500_000));
+    }
+
+    #[tokio::test]
+    async fn a_wallets_balance_adds_up_every_asset_it_holds() {
+        let mine = testing::key_address(1);
+        let (hosky, snek) = (Hash::new([1; 28]), Hash::new([2; 28]));
+        let txs = [
+            Tx::new().pay(testing::output(&mine, 1_000_000)),
+            Tx::new().pay(testing::output_with_assets(
+                &mine,
+                2_000_000,
+                &[(hosky, b"HOSKY", 10), (snek, b"SNEK", 7)],
+            )),
+            Tx::new().pay(testing::output_with_assets(
+                &mine,
+                3_000_000,
+                &[(hosky, b"HOSKY", 5), (hosky, b"OTHER", 1)],
+            )),
+            // Not ours.
+            Tx::new().pay(testing::output_with_assets(
+                &testing::key_address(2),
+                4_000_000,
+                &[(snek, b"SNEK", 100)],
+            )),
+        ];
+        let block = testing::block(10, None, &txs);
+        let mut index = wallet(&[1]);
+        replay(&mut index, &[&block]).await;
+
+        let asset =
+            |policy: Hash<28>, name: &[u8]| AssetClass::new(*policy, name.try_into().unwrap());
+        assert_eq!(
+            index.balances(),
+            BTreeMap::from([
+                (AssetClass::ada(), 6_000_000),
+                (asset(hosky, b"HOSKY"), 15),
+                (asset(hosky, b"OTHER"), 1),
+                (asset(snek, b"SNEK"), 7),
+            ])
+        );
+        let Value::Multiasset(coin, assets) = index.total_balance() else {
+            panic!("the tokens went missing");
+        };
+        assert_eq!(coin, 6_000_000);
+        // One entry per policy, with each of its tokens merged into it.
+        let policies = assets
+            .iter()
+            .map(|(policy, names)| (*policy, names.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(policies, [(hosky, 2), (snek, 1)]);
+    }
+
+    #[tokio::test]
+    async fn a_second_index_with_the_same_name_is_refused() {
+        use crate::acropolis::{
+            core::{CancellationToken, Process},
+            indexer::{DuplicateIndex, MockChainSource},
+        };
+        let refused = |added: Result<_>| {
+            added.err().is_some_and(|e| {
+                e.downcast_ref::<DuplicateIndex>()
+                    .is_some_and(|e| e.name == "wallet")
+            })
         };
-        indexer.add_index(PoolIndex::new(), point.clone(), false);
-        indexer.add_index(WalletIndex::new(args.wallet_address), point.clone(), false);
+        let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+        let first = indexer
+            .add_index(wallet(&[1]), Point::Origin, false, 0)
+            .unwrap();
+        let second = indexer.add_index(wallet(&[2]), Point::Origin, false, 0);
+        assert!(refused(second));
 
+        // Adding one while the indexer's running is refused just the same.
+        indexer.set_chain_source(MockChainSource::new(vec![]));
+        let handle = indexer.handle();
+        let token = CancellationToken::new();
         let mut process = Process::create();
         process.register(indexer);
-        process.run().await.unwrap();
-    });
-    let _ = handle.await;
+        let running = tokio::spawn(process.run_until(token.clone()));
+        let live = handle
+            .add_index_live(wallet(&[3]), Point::Origin, false, 0)
+            .await;
+        assert!(refused(live));
+        token.cancel();
+        running.await.unwrap().unwrap();
+        // And the first one is still the one that's registered.
+        assert!(handle.faulted_indexes().is_empty());
+        assert!(
+            first
+                .read()
+                .await
+                .addresses
+                .contains(&testing::address(&testing::key_address(1)))
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn an_index_added_live_is_built_from_its_config() {
+        use crate::acropolis::{
+            core::{CancellationToken, Process},
+            indexer::MockChainSource,
+        };
+        let mut indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![]));
+        indexer.set_chain_source(MockChainSource::new(vec![]));
+        let handle = indexer.handle();
+        let token = CancellationToken::new();
+        let mut process = Process::create();
+        process.register(indexer);
+        let running = tokio::spawn(process.run_until(token.clone()));
+
+        let add = |config: &str| add_live(handle.clone(), config.to_string(), None);
+        let added = add("name = \"treasury\"\nkind = \"mints\"\n")
+            .await
+            .unwrap();
+        assert_eq!(added, "treasury");
+        let indexes = handle.sync_status().into_iter().map(|s| s.index);
+        assert_eq!(indexes.collect::<Vec<_>>(), ["treasury"]);
+        // It's checked the same way as at startup, bar what only works then.
+        assert!(add("name = \"treasury\"\nkind = \"fees\"\n").await.is_err());
+        assert!(add("kind = \"wallet\"\n").await.is_err());
+        let bootstrapped = format!(
+            "kind = \"wallet\"\naddress = \"{}\"\nbootstrap = true\n",
+            testing::address(&testing::key_address(1))
+        );
+        assert!(add(&bootstrapped).await.is_err());
+
+        handle.remove_index("treasury").await.unwrap();
+        assert!(handle.remove_index("treasury").await.is_err());
+        token.cancel();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_wallet_seeing_a_block_twice_counts_it_once() {
+        let mine = testing::key_address(1);
+        let received = Tx::new().pay(testing::output(&mine, 5_000_000));
+        let first = testing::block(10, None, std::slice::from_ref(&received));
+        let spent = Tx::new()
+            .spend(&received.output_ref(0))
+            .pay(testing::output(&mine, 3_000_000));
+        let second = testing::block(20, Some(&first.0), &[spent]);
+
+        let mut index = wallet(&[1]);
+        replay(&mut index, &[&first]).await;
+        replay(&mut index, &[&first]).await;
+        assert_eq!(index.total_balance(), Value::Coin(5_000_000));
+        assert_eq!(index.utxos.len(), 1);
+
+        // Nor does seeing an output again bring it back once it's been spent.
+        replay(&mut index, &[&second, &first, &second]).await;
+        assert_eq!(index.total_balance(), Value::Coin(3_000_000));
+        assert_eq!(index.utxos.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_wallets_balance_can_be_looked_up_at_any_slot_it_remembers() {
+        let mine = testing::key_address(1);
+        let first = Tx::new().pay(testing::output(&mine, 5_000_000));
+        let second = Tx::new().pay(testing::output(&mine, 2_000_000));
+        let spend = Tx::new()
+            .spend(&first.output_ref(0))
+            .pay(testing::output(&mine, 3_000_000))
+            .pay(testing::output(&testing::key_address(2), 2_000_000));
+        let blocks = testing::block(10, None, std::slice::from_ref(&first));
+        let blocks = [
+            blocks.clone(),
+            testing::block(20, Some(&blocks.0), &[second]),
+        ];
+        let third = testing::block(30, Some(&blocks[1].0), &[spend]);
+        let later = testing::block(50, Some(&third.0), &[]);
+
+        let mut index = wallet(&[1]).with_history(15);
+        replay(&mut index, &[&blocks[0], &blocks[1], &third]).await;
+        // Only the last 15 slots are kept, which goes back to slot 15.
+        assert_eq!(index.balance_at(14), None);
+        assert_eq!(index.balance_at(15), Some(Value::Coin(5_000_000)));
+        assert_eq!(index.balance_at(20), Some(Value::Coin(7_000_000)));
+        assert_eq!(index.balance_at(29), Some(Value::Coin(7_000_000)));
+        assert_eq!(index.balance_at(30), Some(Value::Coin(5_000_000)));
+
+        // By slot 50, the spend is more than 15 slots back, so it's forgotten,
+        // and there's no telling what the wallet held before it.
+        replay(&mut index, &[&later]).await;
+        assert_eq!(index.utxos.len(), 2);
+        assert_eq!(index.balance_at(29), None);
+        assert_eq!(index.balance_at(40), Some(Value::Coin(5_000_000)));
+    }
+
+    #[tokio::test]
+    async fn a_wallet_starts_over_with_nothing_left_to_publish() {
+        let received = Tx::new().pay(testing::output(&testing::key_address(1), 5_000_000));
+        let block = testing::block(10, None, &[received]);
+        let mut index = wallet(&[1]);
+        replay(&mut index, &[&block]).await;
+
+        index.reset().await.unwrap();
+        assert_eq!(index.total_balance(), Value::Coin(0));
+        assert!(index.drain_events().is_empty());
+    }
 }
@@ -0,0 +1,406 @@
+use std::{fmt::Write as _, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use tokio::net::TcpListener;
+
+use crate::{
+    acropolis::{
+        core::{Context, Module},
+        indexer::{BLOCK_DURATION_BUCKETS, ChainIndexerHandle},
+    },
+    http::{self, Handler, Request, Response},
+};
+
+/// Builds an index from the TOML of one `[[indexes]]` entry and adds it to the running indexer,
+/// giving back the name it went in under.
+pub type AddIndex = Arc<dyn Fn(String) -> BoxFuture<'static, Result<String>> + Send + Sync>;
+
+/// Serves the indexer's health in the Prometheus text format, on `/metrics`,
+/// and lets an operator pause and resume it with `POST /control/pause` and `/control/resume`.
+/// `POST /control/add` adds an index, with `AddIndex`, and `/control/remove/{name}` removes one.
+/// It also answers the `/healthz` and `/readyz` probes, the same as the query server.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    indexer: ChainIndexerHandle,
+    ready_lag: u64,
+    control_token: Option<String>,
+    add_index: Option<AddIndex>,
+}
+
+impl MetricsServer {
+    /// The control endpoints are refused unless there's a `control_token`,
+    /// and then they need it as a bearer token.
+    pub fn new(
+        addr: SocketAddr,
+        indexer: ChainIndexerHandle,
+        ready_lag: u64,
+        control_token: Option<String>,
+    ) -> Self {
+        Self {
+            addr,
+            indexer,
+            ready_lag,
+            control_token,
+            add_index: None,
+        }
+    }
+
+    /// Lets `POST /control/add` add indexes, with `add`. Without it, there's no such endpoint.
+    pub fn with_add_index(mut self, add: AddIndex) -> Self {
+        self.add_index = Some(add);
+        self
+    }
+}
+
+#[async_trait]
+impl Module for MetricsServer {
+    fn name(&self) -> String {
+        "metrics".into()
+    }
+
+    async fn init(&mut self, context: Arc<Context>) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        let indexer = self.indexer.clone();
+        let ready_lag = self.ready_lag;
+        let control_token = self.control_token.clone();
+        let add_index = self.add_index.clone();
+        let handler: Handler = Arc::new(move |request: Request| {
+            let (indexer, control_token) = (indexer.clone(), control_token.clone());
+            let add_index = add_index.clone();
+            Box::pin(async move {
+                let control = Control {
+                    token: control_token.as_deref(),
+                    add_index: add_index.as_ref(),
+                };
+                respond(&indexer, ready_lag, control, &request).await
+            })
+        });
+        let shutdown = context.shutdown_token();
+        context.run(async move {
+            http::serve(listener, shutdown, handler).await;
+            Ok(())
+        });
+        Ok(())
+    }
+}
+
+// What the control endpoints need.
+#[derive(Clone, Copy)]
+struct Control<'a> {
+    token: Option<&'a str>,
+    add_index: Option<&'a AddIndex>,
+}
+
+async fn respond(
+    indexer: &ChainIndexerHandle,
+    ready_lag: u64,
+    control: Control<'_>,
+    request: &Request,
+) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response::ok("text/plain; version=0.0.4", render(indexer)),
+        ("GET", "/healthz") => http::healthz(indexer),
+        ("GET", "/readyz") => http::readyz(indexer, ready_lag),
+        ("POST", path) if path.starts_with("/control/") => {
+            if let Err(refused) = authorize(request, control.token) {
+                return refused;
+            }
+            control_request(indexer, control, request).await
+        }
+        _ => Response::not_found(),
+    }
+}
+
+// A control request which has been authorized.
+async fn control_request(
+    indexer: &ChainIndexerHandle,
+    control: Control<'_>,
+    request: &Request,
+) -> Response {
+    match request.path.as_str() {
+        "/control/pause" => {
+            indexer.pause();
+            Response::ok("text/plain", "pausing\n".into())
+        }
+        "/control/resume" => {
+            indexer.resume();
+            Response::ok("text/plain", "resuming\n".into())
+        }
+        "/control/add" => {
+            let Some(add_index) = control.add_index else {
+                return Response::not_found();
+            };
+            match add_index(request.body.clone()).await {
+                Ok(name) => Response::ok("text/plain", format!("added {name}\n")),
+                Err(e) => Response::bad_request(format!("{e:#}\n")),
+            }
+        }
+        path => {
+            let Some(name) = path.strip_prefix("/control/remove/") else {
+                return Response::not_found();
+            };
+            match indexer.remove_index(name).await {
+                Ok(()) => Response::ok("text/plain", format!("removed {name}\n")),
+                Err(e) => Response::error(404, &format!("{e:#}\n")),
+            }
+        }
+    }
+}
+
+// The metrics port is usually open to whatever scrapes it, which shouldn't get to stop the
+// indexer, so control takes a token, and without one configured, it's off.
+fn authorize(request: &Request, control_token: Option<&str>) -> Result<(), Response> {
+    let Some(expected) = control_token else {
+        return Err(Response::error(
+            403,
+            "control is off; start with --control-token-file to turn it on\n",
+        ));
+    };
+    let given = request
+        .headers
+        .get("authorization")
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if given.is_some_and(|given| same_secret(given.as_bytes(), expected.as_bytes())) {
+        Ok(())
+    } else {
+        Err(Response::error(401, "a valid bearer token is needed\n"))
+    }
+}
+
+// Compares every byte, whatever it finds, so how long it takes says nothing about the token.
+fn same_secret(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (g, e)| diff | (g ^ e))
+            == 0
+}
+
+fn render(indexer: &ChainIndexerHandle) -> String {
+    let counters = indexer.counters();
+    let mut out = String::new();
+    metric(
+        &mut out,
+        "acropolis_blocks_processed_total",
+        "counter",
+        "Blocks received from the chain.",
+    );
+    let _ = writeln!(
+        out,
+        "acropolis_blocks_processed_total {}",
+        counters.blocks_processed
+    );
+    metric(
+        &mut out,
+        "acropolis_rollbacks_total",
+        "counter",
+        "Rollbacks received from the chain.",
+    );
+    let _ = writeln!(out, "acropolis_rollbacks_total {}", counters.rollbacks);
+    metric(
+        &mut out,
+        "acropolis_index_processed_tx_total",
+        "counter",
+        "Txs handled by each index.",
+    );
+    for (index, txs) in &counters.processed_txs {
+        let index = label(index);
+        let _ = writeln!(
+            out,
+            "acropolis_index_processed_tx_total{{index=\"{index}\"}} {txs}"
+        );
+    }
+    metric(
+        &mut out,
+        "acropolis_index_block_duration_seconds",
+        "histogram",
+        "How long each index takes over a block.",
+    );
+    for (index, histogram) in &counters.block_durations {
+        let index = label(index);
+        let bounds = BLOCK_DURATION_BUCKETS.iter().map(|b| b.to_string());
+        for (le, count) in bounds.chain(["+Inf".into()]).zip(histogram.buckets) {
+            let _ = writeln!(
+                out,
+                "acropolis_index_block_duration_seconds_bucket{{index=\"{index}\",le=\"{le}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "acropolis_index_block_duration_seconds_sum{{index=\"{index}\"}} {}",
+            histogram.sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "acropolis_index_block_duration_seconds_count{{index=\"{index}\"}} {}",
+            histogram.buckets[BLOCK_DURATION_BUCKETS.len()]
+        );
+    }
+    metric(
+        &mut out,
+        "acropolis_index_lag_slots",
+        "gauge",
+        "How many slots each index is behind the tip.",
+    );
+    for sync in indexer.sync_status() {
+        let _ = writeln!(
+            out,
+            "acropolis_index_lag_slots{{index=\"{}\"}} {}",
+            label(&sync.index),
+            sync.lag_slots
+        );
+    }
+    metric(
+        &mut out,
+        "acropolis_block_buffer_depth",
+        "gauge",
+        "Blocks fetched from the chain but not yet handled.",
+    );
+    let _ = writeln!(
+        out,
+        "acropolis_block_buffer_depth {}",
+        indexer.buffered_blocks()
+    );
+    metric(
+        &mut out,
+        "acropolis_paused",
+        "gauge",
+        "1 while the indexer is paused, 0 otherwise.",
+    );
+    let _ = writeln!(out, "acropolis_paused {}", indexer.is_paused() as u8);
+    out
+}
+
+// A label value as Prometheus reads it, between double quotes, where a backslash,
+// a double quote or a newline has to be escaped.
+fn label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::acropolis::indexer::{ChainIndexer, InMemoryCursorStore};
+
+    fn post(path: &str, authorization: Option<&str>) -> Request {
+        let headers = authorization
+            .map(|a| ("authorization".to_string(), a.to_string()))
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        Request {
+            method: "POST".into(),
+            path: path.into(),
+            #[cfg(feature = "http")]
+            query: String::new(),
+            headers,
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(label("pools"), "pools");
+        assert_eq!(label("a\\b \"c\"\nd"), r#"a\\b \"c\"\nd"#);
+    }
+
+    #[tokio::test]
+    async fn control_needs_the_token() {
+        let indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![])).handle();
+        let status = async |token, request| {
+            let control = Control {
+                token,
+                add_index: None,
+            };
+            respond(&indexer, 0, control, &request).await.status
+        };
+
+        // Without a token set up, there's no getting in.
+        assert_eq!(status(None, post("/control/pause", None)).await, 403);
+        assert_eq!(
+            status(None, post("/control/pause", Some("Bearer anything"))).await,
+            403
+        );
+
+        let token = Some("s3cret");
+        assert_eq!(status(token, post("/control/pause", None)).await, 401);
+        assert_eq!(
+            status(token, post("/control/pause", Some("Bearer s3cre"))).await,
+            401
+        );
+        assert_eq!(
+            status(token, post("/control/pause", Some("s3cret"))).await,
+            401
+        );
+        assert_eq!(
+            status(token, post("/control/pause", Some("Bearer s3cret"))).await,
+            200
+        );
+        assert_eq!(
+            status(token, post("/control/resume", Some("Bearer s3cret"))).await,
+            200
+        );
+        assert_eq!(status(None, post("/control/add", None)).await, 403);
+        // The metrics themselves don't need it.
+        let scrape = Request {
+            method: "GET".into(),
+            ..post("/metrics", None)
+        };
+        assert_eq!(status(None, scrape).await, 200);
+    }
+
+    #[tokio::test]
+    async fn indexes_are_added_from_their_config_and_removed_by_name() {
+        let indexer = ChainIndexer::new(InMemoryCursorStore::new(vec![])).handle();
+        let add_index: AddIndex = Arc::new(|config: String| {
+            Box::pin(async move {
+                match config.trim() {
+                    "kind = \"mints\"" => Ok("mints".to_string()),
+                    _ => anyhow::bail!("not an index"),
+                }
+            })
+        });
+        let respond = async |add_index, mut request: Request, body: &str| {
+            body.clone_into(&mut request.body);
+            let control = Control {
+                token: Some("s3cret"),
+                add_index,
+            };
+            respond(&indexer, 0, control, &request).await
+        };
+        let post = |path| post(path, Some("Bearer s3cret"));
+
+        let added = respond(Some(&add_index), post("/control/add"), "kind = \"mints\"\n").await;
+        assert_eq!((added.status, added.body.as_str()), (200, "added mints\n"));
+        let refused = respond(Some(&add_index), post("/control/add"), "kind = 7").await;
+        assert_eq!(refused.status, 400);
+        // Without a way to build indexes, there's nowhere to add them.
+        let missing = respond(None, post("/control/add"), "kind = \"mints\"").await;
+        assert_eq!(missing.status, 404);
+
+        let removed = respond(None, post("/control/remove/mints"), "").await;
+        assert_eq!(removed.status, 404);
+        assert_eq!(
+            respond(None, post("/control/nothing"), "").await.status,
+            404
+        );
+    }
+}
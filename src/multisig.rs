@@ -0,0 +1,31 @@
+use plutus_parser::AsPlutus;
+use serde::{Deserialize, Serialize};
+
+use crate::sundaev3::Integer;
+
+/// The native-script-like authorization SundaeSwap uses for order owners and fee managers.
+#[derive(AsPlutus, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MultisigScript {
+    Signature {
+        key_hash: Vec<u8>,
+    },
+    AllOf {
+        scripts: Vec<MultisigScript>,
+    },
+    AnyOf {
+        scripts: Vec<MultisigScript>,
+    },
+    AtLeast {
+        required: Integer,
+        scripts: Vec<MultisigScript>,
+    },
+    Before {
+        time: Integer,
+    },
+    After {
+        time: Integer,
+    },
+    Script {
+        script_hash: Vec<u8>,
+    },
+}
@@ -0,0 +1,191 @@
+// Follows a local node over its unix socket, with the node-to-client mini-protocols.
+use std::{collections::VecDeque, path::PathBuf};
+
+use anyhow::{Context as _, Result, anyhow};
+use async_trait::async_trait;
+use pallas_addresses::Address;
+use pallas_network::{
+    facades::NodeClient,
+    miniprotocols::{
+        Point,
+        chainsync::{NextResponse, Tip},
+        localstate::queries_v16::{self, TransactionOutput},
+    },
+};
+use pallas_primitives::{Fragment, conway::Value};
+use pallas_traverse::{MultiEraBlock, OutputRef};
+use tokio::sync::watch;
+
+use crate::acropolis::{
+    core::{AcropolisMessage, BlockHash, BlockInfo},
+    indexer::{ChainSource, Intersect},
+};
+
+// How many blocks we remember, for working out where a rollback starts. Comfortably more
+// than the 2160 a node can roll back.
+const REMEMBERED_BLOCKS: usize = 4320;
+
+/// Reads blocks straight from a local node with node-to-client chain-sync, rather than from
+/// the message bus. The node's tip comes from local-state-query when we connect, and from
+/// chain-sync after that, so the indexer can tell how far behind it is. Blocks are requested
+/// one at a time, even in a batch: pallas's chain-sync client won't send a request while one
+/// is outstanding, so `next_batch` is left to its default.
+pub struct N2cChainSource {
+    path: PathBuf,
+    magic: u64,
+    client: Option<NodeClient>,
+    // The blocks we've handed out since we last intersected, oldest first.
+    recent: VecDeque<BlockInfo>,
+    // The slot of the newest block we've forgotten, if there are too many to remember.
+    forgotten: Option<u64>,
+    tip: watch::Sender<Point>,
+}
+
+impl N2cChainSource {
+    pub fn new(path: impl Into<PathBuf>, magic: u64) -> Self {
+        Self {
+            path: path.into(),
+            magic,
+            client: None,
+            recent: VecDeque::new(),
+            forgotten: None,
+            tip: watch::Sender::new(Point::Origin),
+        }
+    }
+
+    async fn connect(&mut self) -> Result<&mut NodeClient> {
+        if let Some(client) = self.client.take() {
+            client.abort().await;
+        }
+        let mut client = NodeClient::connect(&self.path, self.magic)
+            .await
+            .with_context(|| format!("could not connect to {}", self.path.display()))?;
+        let query = client.statequery();
+        query.acquire(None).await?;
+        let tip = queries_v16::get_chain_point(query).await?;
+        query.send_release().await?;
+        self.tip.send_replace(tip);
+        Ok(self.client.insert(client))
+    }
+
+    async fn client(&mut self) -> Result<&mut NodeClient> {
+        if self.client.is_none() {
+            self.connect().await?;
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    /// Every UTXO held by `addresses` as of the node's tip, along with the tip itself.
+    /// They're read from a single ledger state, so they're exactly what was unspent at that
+    /// point, and syncing from it picks up with whatever's happened since.
+    pub async fn utxos_at(
+        &mut self,
+        addresses: &[Address],
+    ) -> Result<(Point, Vec<(OutputRef, Address, Value)>)> {
+        let query = self.client().await?.statequery();
+        query.acquire(None).await?;
+        let point = queries_v16::get_chain_point(query).await?;
+        let era = queries_v16::get_current_era(query).await?;
+        let addrs = addresses.iter().map(|a| a.to_vec().into()).collect();
+        let found = queries_v16::get_utxo_by_address(query, era, addrs).await?;
+        query.send_release().await?;
+        let mut utxos = vec![];
+        for (utxo, output) in found.utxo.iter() {
+            let (address, amount) = match output {
+                TransactionOutput::Current(o) => (&o.address, &o.amount),
+                TransactionOutput::Legacy(o) => (&o.address, &o.amount),
+            };
+            let address = Address::from_bytes(address)?;
+            // The query has its own copy of the value type, with the same encoding.
+            let value = amount
+                .encode_fragment()
+                .and_then(|cbor| Value::decode_fragment(&cbor))
+                .map_err(|e| anyhow!("malformed value at {address}: {e}"))?;
+            let index = u64::from(utxo.index);
+            utxos.push((OutputRef::new(utxo.transaction_id, index), address, value));
+        }
+        Ok((point, utxos))
+    }
+
+    fn set_tip(&self, Tip(point, _): Tip) {
+        self.tip.send_replace(point);
+    }
+
+    // The first block a rollback to `point` throws away, if we handed any out after it.
+    fn discard_after(&mut self, point: &Point) -> Result<Option<BlockInfo>> {
+        let keep = match point {
+            Point::Origin => 0,
+            // By hash, since a Byron epoch boundary block shares its slot with the next block.
+            Point::Specific(slot, hash) => {
+                match self.recent.iter().position(|b| b.hash.to_vec() == *hash) {
+                    Some(i) => i + 1,
+                    None => self.recent.partition_point(|b| b.slot <= *slot),
+                }
+            }
+        };
+        if self.forgotten.is_some_and(|f| point.slot_or_default() < f) {
+            anyhow::bail!("the node rolled back to {point:?}, before any block we remember");
+        }
+        let first_discarded = self.recent.get(keep).cloned();
+        self.recent.truncate(keep);
+        Ok(first_discarded)
+    }
+}
+
+#[async_trait]
+impl ChainSource for N2cChainSource {
+    async fn sync_from(&mut self, points: &[Point]) -> Result<Point> {
+        self.recent.clear();
+        self.forgotten = None;
+        let client = self.connect().await?;
+        let (found, _) = client.chainsync().find_intersect(points.to_vec()).await?;
+        found.with_context(|| format!("none of {points:?} are on the chain"))
+    }
+
+    async fn next(&mut self) -> Result<AcropolisMessage> {
+        loop {
+            let client = self.client.as_mut().context("not syncing yet")?;
+            match client.chainsync().request_or_await_next().await? {
+                NextResponse::RollForward(content, tip) => {
+                    self.set_tip(tip);
+                    let block = MultiEraBlock::decode(&content.0)?;
+                    let hash = BlockHash::try_from(block.hash().to_vec())
+                        .map_err(|_| anyhow::anyhow!("malformed block hash"))?;
+                    let info = BlockInfo::new(block.slot(), hash, block.number());
+                    self.recent.push_back(info.clone());
+                    if self.recent.len() > REMEMBERED_BLOCKS {
+                        self.forgotten = self.recent.pop_front().map(|b| b.slot);
+                    }
+                    return Ok(AcropolisMessage::NewBlock(info, content.0));
+                }
+                NextResponse::RollBackward(point, tip) => {
+                    self.set_tip(tip);
+                    // The node always starts by rolling back to wherever we intersected,
+                    // which discards nothing.
+                    if let Some(first_discarded) = self.discard_after(&point)? {
+                        return Ok(AcropolisMessage::Rollback(first_discarded));
+                    }
+                }
+                NextResponse::Await => return Ok(AcropolisMessage::AwaitingBlocks),
+            }
+        }
+    }
+
+    async fn find_intersect(&mut self, point: &Point) -> Result<Intersect> {
+        let (found, _) = self
+            .client()
+            .await?
+            .chainsync()
+            .find_intersect(vec![point.clone()])
+            .await?;
+        // The node doesn't say what the closest point it does have is.
+        Ok(match found {
+            Some(_) => Intersect::Found,
+            None => Intersect::NotFound { closest: None },
+        })
+    }
+
+    fn tip(&self) -> Option<watch::Receiver<Point>> {
+        Some(self.tip.subscribe())
+    }
+}
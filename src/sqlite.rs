@@ -0,0 +1,204 @@
+// Cursors kept in a SQLite database, so an index picks up where it left off after a restart.
+use std::{collections::BTreeMap, path::Path, sync::Mutex};
+
+use anyhow::{Context, Result, bail};
+use pallas_network::miniprotocols::Point;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+
+use crate::acropolis::indexer::CursorStore;
+
+// The point stored as a slot and hash, in the columns from `column` on.
+fn read_point(row: &Row, column: usize) -> rusqlite::Result<Point> {
+    Ok(match row.get::<_, Option<i64>>(column)? {
+        Some(slot) => Point::Specific(slot as u64, row.get(column + 1)?),
+        None => Point::Origin,
+    })
+}
+
+/// Keeps each index's cursor in a SQLite database, one row per index,
+/// so syncing resumes from the last committed point after a restart.
+pub struct SqliteCursorStore {
+    db: Mutex<Connection>,
+}
+
+impl SqliteCursorStore {
+    /// Opens the database at `path`, creating it (and its table) if it isn't there yet.
+    pub fn open(path: &Path) -> Result<Self> {
+        let db =
+            Connection::open(path).with_context(|| format!("could not open {}", path.display()))?;
+        // The origin is stored with a null slot and hash.
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS cursors (
+                name TEXT PRIMARY KEY,
+                slot INTEGER,
+                hash BLOB
+            )",
+        )?;
+        // With a write-ahead log, another connection can read while this one commits.
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        db.busy_timeout(std::time::Duration::from_secs(5))?;
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    fn db(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // Nothing panics while holding the lock, short of a bug in SQLite itself.
+        self.db.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl CursorStore for SqliteCursorStore {
+    async fn get(&self, name: &str) -> Result<Option<Point>> {
+        let point = self
+            .db()
+            .query_row(
+                "SELECT slot, hash FROM cursors WHERE name = ?1",
+                [name],
+                |row| read_point(row, 0),
+            )
+            .optional()?;
+        Ok(point)
+    }
+
+    async fn latest(&self) -> Result<BTreeMap<String, Point>> {
+        let db = self.db();
+        let mut statement = db.prepare("SELECT name, slot, hash FROM cursors")?;
+        let points = statement
+            .query_map([], |row| Ok((row.get(0)?, read_point(row, 1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(points)
+    }
+
+    async fn commit_batch(&mut self, updates: &[(String, Point)]) -> Result<()> {
+        let mut db = self.db();
+        // Dropped without being committed, it rolls back.
+        let tx = db.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        for (name, point) in updates {
+            let (slot, hash) = match point {
+                Point::Origin => (None, None),
+                Point::Specific(slot, hash) => {
+                    let Ok(slot) = i64::try_from(*slot) else {
+                        bail!("slot {slot} is too big to store");
+                    };
+                    (Some(slot), Some(hash))
+                }
+            };
+            tx.execute(
+                "INSERT INTO cursors (name, slot, hash) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE SET slot = excluded.slot, hash = excluded.hash",
+                params![name, slot, hash],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A database file of its own for each test, removed once it's done.
+    struct TempDb(std::path::PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "acropolis-indexer-{name}-{}.sqlite",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            // And the write-ahead log, left over if a connection wasn't closed cleanly.
+            for suffix in ["-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cursors_survive_reopening() {
+        let path = TempDb::new("reopen");
+        let mut store = SqliteCursorStore::open(&path.0).unwrap();
+        assert_eq!(store.get("pools").await.unwrap(), None);
+        store
+            .commit_batch(&[
+                ("pools".into(), Point::Specific(100, vec![1; 32])),
+                ("wallet".into(), Point::Origin),
+            ])
+            .await
+            .unwrap();
+        store
+            .commit_batch(&[("pools".into(), Point::Specific(200, vec![2; 32]))])
+            .await
+            .unwrap();
+        drop(store);
+
+        let store = SqliteCursorStore::open(&path.0).unwrap();
+        assert_eq!(
+            store.get("pools").await.unwrap(),
+            Some(Point::Specific(200, vec![2; 32]))
+        );
+        assert_eq!(store.get("wallet").await.unwrap(), Some(Point::Origin));
+        assert_eq!(store.get("orders").await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn another_connection_can_read_while_cursors_are_committed() {
+        let path = TempDb::new("latest");
+        let mut writer = SqliteCursorStore::open(&path.0).unwrap();
+        let reader = SqliteCursorStore::open(&path.0).unwrap();
+        let writing = tokio::spawn(async move {
+            for slot in 1..=200 {
+                let point = Point::Specific(slot, vec![1; 32]);
+                let batch = [("pools".into(), point.clone()), ("wallet".into(), point)];
+                writer.commit_batch(&batch).await.unwrap();
+            }
+        });
+        let mut last = 0;
+        while last < 200 {
+            let latest = reader.latest().await.unwrap();
+            let slots: Vec<_> = latest.values().map(|p| p.slot_or_default()).collect();
+            // Both or neither of a batch, and never going backwards.
+            assert!(slots.is_empty() || slots == [slots[0]; 2]);
+            let slot = slots.first().copied().unwrap_or(0);
+            assert!(slot >= last);
+            last = slot;
+            tokio::task::yield_now().await;
+        }
+        writing.await.unwrap();
+        assert_eq!(
+            reader.latest().await.unwrap().keys().collect::<Vec<_>>(),
+            ["pools", "wallet"]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_batch_commits_nothing() {
+        let path = TempDb::new("batch");
+        let mut store = SqliteCursorStore::open(&path.0).unwrap();
+        store
+            .commit_batch(&[("pools".into(), Point::Specific(100, vec![1; 32]))])
+            .await
+            .unwrap();
+        let result = store
+            .commit_batch(&[
+                ("pools".into(), Point::Specific(200, vec![2; 32])),
+                ("wallet".into(), Point::Specific(u64::MAX, vec![3; 32])),
+            ])
+            .await;
+        assert!(result.is_err());
+        assert_eq!(
+            store.get("pools").await.unwrap(),
+            Some(Point::Specific(100, vec![1; 32]))
+        );
+        assert_eq!(store.get("wallet").await.unwrap(), None);
+    }
+}
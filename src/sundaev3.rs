@@ -1,13 +1,961 @@
-use pallas_primitives::BigInt;
-use plutus_parser::AsPlutus;
+use std::{fmt, ops::Deref};
 
-pub type Ident = Vec<u8>;
+use pallas_primitives::{BigInt, Fragment as _};
+use plutus_parser::{AsPlutus, DecodeError, PlutusData};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-pub type AssetClass = (Vec<u8>, Vec<u8>);
+use crate::multisig::MultisigScript;
 
-#[derive(AsPlutus, Clone)]
+/// A byte string that has to be exactly `N` bytes long, like a 28-byte key hash or a 32-byte tx id.
+/// (`[u8; N]` itself can't be given an `AsPlutus` impl outside `plutus_parser`: the orphan rule.)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FixedBytes<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> AsPlutus for FixedBytes<N> {
+    fn from_plutus(data: PlutusData) -> Result<Self, DecodeError> {
+        Vec::<u8>::from_plutus(data)?
+            .as_slice()
+            .try_into()
+            .map_err(|e: WrongLength| DecodeError::Custom(e.to_string()))
+    }
+
+    fn to_plutus(self) -> PlutusData {
+        self.0.to_vec().to_plutus()
+    }
+}
+
+impl<const N: usize> TryFrom<&[u8]> for FixedBytes<N> {
+    type Error = WrongLength;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, WrongLength> {
+        bytes.try_into().map(Self).map_err(|_| WrongLength {
+            expected: N,
+            found: bytes.len(),
+        })
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for FixedBytes<N> {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// As a list of bytes, the way a `Vec<u8>` would be, since serde only covers arrays up to 32.
+impl<const N: usize> Serialize for FixedBytes<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0)
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for FixedBytes<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        bytes
+            .as_slice()
+            .try_into()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Bytes which were meant to be a `FixedBytes`, but weren't the right length for it.
+#[derive(Debug)]
+pub struct WrongLength {
+    pub expected: usize,
+    pub found: usize,
+}
+
+impl fmt::Display for WrongLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {} bytes, found {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for WrongLength {}
+
+/// Implements `AsPlutus` for a newtype by encoding it exactly as its one field,
+/// rather than wrapped in a constructor the way `#[derive(AsPlutus)]` would.
+/// ```ignore
+/// struct Ident(FixedBytes<28>);
+/// plutus_newtype!(Ident);
+/// ```
+macro_rules! plutus_newtype {
+    ($name:ident) => {
+        impl AsPlutus for $name {
+            fn from_plutus(data: PlutusData) -> Result<Self, DecodeError> {
+                AsPlutus::from_plutus(data).map(Self)
+            }
+
+            fn to_plutus(self) -> PlutusData {
+                self.0.to_plutus()
+            }
+        }
+    };
+}
+
+/// Which pool a datum belongs to: the hash the pool was minted with, which its NFT is named for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Ident(pub FixedBytes<28>);
+
+plutus_newtype!(Ident);
+
+/// The hash of a minting policy's script.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PolicyId(pub FixedBytes<28>);
+
+plutus_newtype!(PolicyId);
+
+// Both are 28-byte hashes, which is how they're written and parsed everywhere else too.
+macro_rules! hash_newtype {
+    ($name:ident) => {
+        impl From<[u8; 28]> for $name {
+            fn from(bytes: [u8; 28]) -> Self {
+                Self(FixedBytes(bytes))
+            }
+        }
+
+        impl TryFrom<&[u8]> for $name {
+            type Error = WrongLength;
+
+            fn try_from(bytes: &[u8]) -> Result<Self, WrongLength> {
+                bytes.try_into().map(Self)
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                self.0.as_ref()
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = anyhow::Error;
+
+            fn from_str(s: &str) -> anyhow::Result<Self> {
+                Ok(Self::try_from(hex::decode(s)?.as_slice())?)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", hex::encode(self))
+            }
+        }
+    };
+}
+
+hash_newtype!(Ident);
+hash_newtype!(PolicyId);
+
+/// The name of a token under its policy. Unlike the policy, it isn't a fixed length: anything
+/// up to 32 bytes, down to none at all.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u8>", into = "Vec<u8>")]
+pub struct AssetName(Vec<u8>);
+
+impl AssetName {
+    pub const MAX_LEN: usize = 32;
+}
+
+impl TryFrom<Vec<u8>> for AssetName {
+    type Error = WrongLength;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, WrongLength> {
+        match bytes.len() {
+            0..=Self::MAX_LEN => Ok(Self(bytes)),
+            found => Err(WrongLength {
+                expected: Self::MAX_LEN,
+                found,
+            }),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for AssetName {
+    type Error = WrongLength;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, WrongLength> {
+        bytes.to_vec().try_into()
+    }
+}
+
+impl From<AssetName> for Vec<u8> {
+    fn from(name: AssetName) -> Vec<u8> {
+        name.0
+    }
+}
+
+impl AsRef<[u8]> for AssetName {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsPlutus for AssetName {
+    fn from_plutus(data: PlutusData) -> Result<Self, DecodeError> {
+        Vec::<u8>::from_plutus(data)?
+            .try_into()
+            .map_err(|e: WrongLength| DecodeError::Custom(format!("asset name {e}")))
+    }
+
+    fn to_plutus(self) -> PlutusData {
+        self.0.to_plutus()
+    }
+}
+
+/// A kind of token, identified by its minting policy and name.
+/// ADA is the odd one out, with no policy and an empty name.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct AssetClass {
+    pub policy: Option<PolicyId>,
+    pub name: AssetName,
+}
+
+impl AssetClass {
+    pub fn ada() -> Self {
+        Self {
+            policy: None,
+            name: AssetName::default(),
+        }
+    }
+
+    pub fn new(policy: impl Into<PolicyId>, name: AssetName) -> Self {
+        Self {
+            policy: Some(policy.into()),
+            name,
+        }
+    }
+
+    pub fn is_ada(&self) -> bool {
+        self.policy.is_none()
+    }
+}
+
+// Formatted as `policy.name` in hex, the way most explorers show it.
+impl fmt::Display for AssetClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.policy {
+            None => write!(f, "lovelace"),
+            Some(policy) => write!(f, "{policy}.{}", hex::encode(&self.name)),
+        }
+    }
+}
+
+// Parses what Display writes: `lovelace`, or `policy.name` in hex.
+impl std::str::FromStr for AssetClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "lovelace" {
+            return Ok(Self::ada());
+        }
+        let Some((policy, name)) = s.split_once('.') else {
+            anyhow::bail!("expected lovelace or policy.name, found {s}");
+        };
+        let name = AssetName::try_from(hex::decode(name)?)?;
+        Ok(Self::new(policy.parse::<PolicyId>()?, name))
+    }
+}
+
+// On-chain, an asset class is just a (policy, name) pair, and ADA's are both empty.
+impl AsPlutus for AssetClass {
+    fn from_plutus(data: PlutusData) -> Result<Self, DecodeError> {
+        let (policy, name) = <(Vec<u8>, AssetName)>::from_plutus(data)?;
+        if policy.is_empty() && name.0.is_empty() {
+            return Ok(Self::ada());
+        }
+        let policy = PolicyId::try_from(policy.as_slice())
+            .map_err(|e| DecodeError::Custom(format!("policy {e}")))?;
+        Ok(Self::new(policy, name))
+    }
+
+    fn to_plutus(self) -> PlutusData {
+        let policy = self.policy.map_or(vec![], |p| p.as_ref().to_vec());
+        (policy, self.name).to_plutus()
+    }
+}
+
+/// A Plutus integer. pallas's `BigInt` can't be compared, and being foreign it can't be given
+/// `PartialEq` here either (the orphan rule), so the datums hold it in this instead. Two are equal
+/// if they're encoded the same way, so a small number as a bignum isn't equal to it as an `Int`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Integer(pub BigInt);
+
+impl PartialEq for Integer {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (BigInt::Int(a), BigInt::Int(b)) => a == b,
+            (BigInt::BigUInt(a), BigInt::BigUInt(b)) => a == b,
+            (BigInt::BigNInt(a), BigInt::BigNInt(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Integer {}
+
+impl Deref for Integer {
+    type Target = BigInt;
+
+    fn deref(&self) -> &BigInt {
+        &self.0
+    }
+}
+
+impl From<i64> for Integer {
+    fn from(n: i64) -> Self {
+        Self(BigInt::Int(n.into()))
+    }
+}
+
+plutus_newtype!(Integer);
+
+pub type SingletonValue = (Vec<u8>, Vec<u8>, Integer);
+
+#[derive(AsPlutus, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PoolDatum {
     pub ident: Ident,
     pub assets: (AssetClass, AssetClass),
-    pub circulating_lp: BigInt,
+    pub circulating_lp: Integer,
+    /// The fee, in hundredths of a percent, on swaps of the pool's first asset for its second.
+    pub bid_fees_per_10_thousand: Integer,
+    /// The fee, in hundredths of a percent, on swaps the other way.
+    pub ask_fees_per_10_thousand: Integer,
+    /// Who can change the fees, if anyone.
+    pub fee_manager: Option<MultisigScript>,
+    /// When the pool opens for trading, as a POSIX time in milliseconds.
+    pub market_open: Integer,
+    /// The lovelace on the pool's UTXO set aside for protocol fees, which isn't part of its reserves.
+    pub protocol_fees: Integer,
+}
+
+/// The redeemer a pool's UTXO is spent with, which says what the tx is doing to the pool.
+/// Only which one it is matters to us, so the scoop's fields are left undecoded.
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub enum PoolRedeemer {
+    /// A batch of orders (swaps, deposits, withdrawals and the rest) run against the pool.
+    PoolScoop {
+        signatory_index: Integer,
+        scoop_index: Integer,
+        input_order: Data,
+    },
+    /// The fee manager or treasury changing the pool's fees, or collecting protocol fees.
+    Manage,
+}
+
+impl PoolRedeemer {
+    pub fn action(&self) -> &'static str {
+        match self {
+            PoolRedeemer::PoolScoop { .. } => "scoop",
+            PoolRedeemer::Manage => "manage",
+        }
+    }
+}
+
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub struct OrderDatum {
+    pub pool_ident: Option<Ident>,
+    pub owner: MultisigScript,
+    pub max_protocol_fee: Integer,
+    pub destination: Destination,
+    pub details: Order,
+    pub extension: Data,
+}
+
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub enum Order {
+    Strategy {
+        signer: StrategyAuthorization,
+    },
+    Swap {
+        offer: SingletonValue,
+        min_received: SingletonValue,
+    },
+    Deposit {
+        assets: (SingletonValue, SingletonValue),
+    },
+    Withdrawal {
+        amount: SingletonValue,
+    },
+    Donation {
+        assets: (SingletonValue, SingletonValue),
+    },
+    Record {
+        policy: AssetClass,
+    },
+}
+
+impl Order {
+    /// What sort of order it is, by its constructor.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Order::Strategy { .. } => "strategy",
+            Order::Swap { .. } => "swap",
+            Order::Deposit { .. } => "deposit",
+            Order::Withdrawal { .. } => "withdrawal",
+            Order::Donation { .. } => "donation",
+            Order::Record { .. } => "record",
+        }
+    }
+}
+
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub enum StrategyAuthorization {
+    Signature { signer: Vec<u8> },
+    Script { script: Vec<u8> },
+}
+
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub enum Destination {
+    Fixed { address: Address, datum: Datum },
+    // Pay the output back to the order's own address.
+    SelfDestination,
+}
+
+// The plutus (not the ledger) representation of an address.
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub struct Address {
+    pub payment_credential: Credential,
+    pub stake_credential: Option<Referenced>,
+}
+
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub enum Credential {
+    VerificationKey(Vec<u8>),
+    Script(Vec<u8>),
+}
+
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub enum Referenced {
+    Inline(Credential),
+    Pointer {
+        slot_number: Integer,
+        transaction_index: Integer,
+        certificate_index: Integer,
+    },
+}
+
+#[derive(AsPlutus, Clone, Debug, PartialEq)]
+pub enum Datum {
+    None,
+    Hash(Vec<u8>),
+    Inline(Data),
+}
+
+/// Arbitrary plutus data, left undecoded.
+#[derive(Clone, Debug)]
+pub struct Data(pub PlutusData);
+
+// Plutus data can't be compared either, so two are equal if they encode to the same CBOR.
+impl PartialEq for Data {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.0.encode_fragment(), other.0.encode_fragment()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl AsPlutus for Data {
+    fn from_plutus(data: PlutusData) -> Result<Self, DecodeError> {
+        Ok(Self(data))
+    }
+
+    fn to_plutus(self) -> PlutusData {
+        self.0
+    }
+}
+
+/// Checks that `value` comes back out of its Plutus encoding as it went in.
+#[cfg(test)]
+pub fn assert_plutus_roundtrip<T: AsPlutus + Clone + PartialEq + fmt::Debug>(value: T) {
+    let encoded = value.clone().to_plutus();
+    let cbor = encoded.encode_fragment().expect("could not encode to CBOR");
+    match T::from_plutus(encoded) {
+        Ok(decoded) => assert_eq!(decoded, value, "the round trip changed the value"),
+        Err(e) => panic!(
+            "{value:?} doesn't decode from its own encoding, {}: {e}",
+            hex::encode(&cbor)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use plutus_parser::{KeyValuePairs, MaybeIndefArray, create_constr, parse_constr};
+    use proptest::{collection::vec, prelude::*};
+
+    use super::*;
+
+    // The constructor `value` encodes as.
+    fn variant(value: impl AsPlutus) -> u64 {
+        parse_constr(value.to_plutus()).unwrap().0
+    }
+
+    #[test]
+    fn enum_variants_are_numbered_in_order() {
+        assert_eq!(variant(Datum::None), 0);
+        assert_eq!(variant(Datum::Hash(vec![1; 32])), 1);
+        assert_eq!(variant(Datum::Inline(Data(42i64.to_plutus()))), 2);
+        assert_plutus_roundtrip(Datum::None);
+        assert_plutus_roundtrip(Datum::Hash(vec![1; 32]));
+        assert_plutus_roundtrip(Datum::Inline(Data(42i64.to_plutus())));
+
+        let hash = create_constr(1, vec![vec![2u8; 28].to_plutus()]);
+        assert!(matches!(Datum::from_plutus(hash), Ok(Datum::Hash(h)) if h == [2; 28]));
+        let unknown = create_constr(3, vec![]);
+        assert!(matches!(
+            Datum::from_plutus(unknown),
+            Err(DecodeError::UnexpectedVariant { variant: 3 })
+        ));
+        // A fieldless variant with a field is still malformed.
+        let extra = create_constr(0, vec![0i64.to_plutus()]);
+        assert!(Datum::from_plutus(extra).is_err());
+    }
+
+    #[derive(AsPlutus, Clone, Debug, PartialEq)]
+    enum Pinned {
+        Zero,
+        #[variant = 4]
+        Four,
+        #[variant = 2]
+        Two(Integer),
+    }
+
+    #[test]
+    fn enum_variants_can_be_pinned() {
+        assert_eq!(variant(Pinned::Zero), 0);
+        assert_eq!(variant(Pinned::Four), 4);
+        assert_eq!(variant(Pinned::Two(7.into())), 2);
+        assert_plutus_roundtrip(Pinned::Four);
+        assert_plutus_roundtrip(Pinned::Two(7.into()));
+        assert!(matches!(
+            Pinned::from_plutus(create_constr(1, vec![])),
+            Err(DecodeError::UnexpectedVariant { variant: 1 })
+        ));
+    }
+
+    // Shaped like a datum with an optional fee, which is what `Option` is usually for on-chain.
+    #[derive(AsPlutus, Clone, Debug, PartialEq)]
+    struct WithFee {
+        amount: Integer,
+        fee: Option<Integer>,
+    }
+
+    fn fields(value: impl AsPlutus) -> Vec<PlutusData> {
+        parse_constr(value.to_plutus()).unwrap().1
+    }
+
+    #[test]
+    fn optional_fields_round_trip() {
+        let some = WithFee {
+            amount: 100.into(),
+            fee: Some(3.into()),
+        };
+        let fee = fields(some.clone()).remove(1);
+        assert_eq!(parse_constr(fee.clone()).unwrap().0, 0);
+        assert!(matches!(
+            Option::<BigInt>::from_plutus(fee),
+            Ok(Some(BigInt::Int(fee))) if i128::from(fee) == 3
+        ));
+        let none = WithFee {
+            amount: 100.into(),
+            fee: None,
+        };
+        let fee = fields(none.clone()).remove(1);
+        assert_eq!(parse_constr(fee.clone()).unwrap(), (1, vec![]));
+        assert!(matches!(Option::<BigInt>::from_plutus(fee), Ok(None)));
+        assert_plutus_roundtrip(some);
+        assert_plutus_roundtrip(none);
+    }
+
+    fn cbor(value: impl AsPlutus) -> Vec<u8> {
+        value.to_plutus().encode_fragment().unwrap()
+    }
+
+    #[test]
+    fn hashes_are_exactly_28_bytes() {
+        assert_plutus_roundtrip(FixedBytes([5; 28]));
+        assert_plutus_roundtrip(Ident::from([6; 28]));
+        assert_plutus_roundtrip(PolicyId::from([7; 28]));
+        // A newtype encodes as its field alone, with no constructor around it.
+        assert_eq!(cbor(Ident::from([6; 28])), cbor(vec![6u8; 28]));
+        for len in [0, 27, 29, 32] {
+            let bytes = vec![1u8; len].to_plutus();
+            assert!(matches!(
+                Ident::from_plutus(bytes.clone()),
+                Err(DecodeError::Custom(e)) if e == format!("expected 28 bytes, found {len}")
+            ));
+            assert!(PolicyId::from_plutus(bytes).is_err());
+        }
+        let policy = PolicyId::from([7; 28]);
+        assert_eq!(policy.to_string().parse::<PolicyId>().unwrap(), policy);
+        assert!("0707".parse::<PolicyId>().is_err());
+    }
+
+    #[test]
+    fn asset_names_are_up_to_32_bytes_and_ada_has_no_policy() {
+        assert_plutus_roundtrip(AssetName::default());
+        assert_plutus_roundtrip(AssetName::try_from(vec![1; 32]).unwrap());
+        assert!(AssetName::try_from(vec![1; 33]).is_err());
+        assert!(AssetName::from_plutus(vec![1u8; 33].to_plutus()).is_err());
+
+        // On-chain, ADA's policy and name are both empty.
+        assert_eq!(
+            cbor(AssetClass::ada()),
+            cbor((Vec::<u8>::new(), Vec::<u8>::new()))
+        );
+        assert_plutus_roundtrip(AssetClass::ada());
+        assert_plutus_roundtrip(asset(1));
+        let named_ada = (Vec::<u8>::new(), b"ADA".to_vec()).to_plutus();
+        assert!(AssetClass::from_plutus(named_ada).is_err());
+        let short_policy = (vec![1u8; 27], b"TOKEN".to_vec()).to_plutus();
+        assert!(AssetClass::from_plutus(short_policy).is_err());
+
+        for class in [AssetClass::ada(), asset(1)] {
+            assert_eq!(class.to_string().parse::<AssetClass>().unwrap(), class);
+        }
+        assert_eq!(AssetClass::ada().to_string(), "lovelace");
+    }
+
+    fn pool(fee_manager: Option<MultisigScript>) -> PoolDatum {
+        let int = Integer::from;
+        PoolDatum {
+            ident: Ident::from([1; 28]),
+            assets: (
+                AssetClass::ada(),
+                AssetClass::new([2; 28], b"TOKEN".as_slice().try_into().unwrap()),
+            ),
+            circulating_lp: int(1_000),
+            bid_fees_per_10_thousand: int(30),
+            ask_fees_per_10_thousand: int(50),
+            fee_manager,
+            market_open: int(0),
+            protocol_fees: int(2_000_000),
+        }
+    }
+
+    #[test]
+    fn pool_fee_manager_round_trips_either_way() {
+        let manager = MultisigScript::Signature {
+            key_hash: vec![3; 28],
+        };
+        let decoded = PoolDatum::from_plutus(pool(Some(manager)).to_plutus()).unwrap();
+        assert!(matches!(
+            decoded.fee_manager,
+            Some(MultisigScript::Signature { key_hash }) if key_hash == [3; 28]
+        ));
+        let decoded = PoolDatum::from_plutus(pool(None).to_plutus()).unwrap();
+        assert!(decoded.fee_manager.is_none());
+    }
+
+    fn asset(n: u8) -> AssetClass {
+        AssetClass::new([n; 28], vec![n].try_into().unwrap())
+    }
+
+    #[test]
+    fn lists_round_trip() {
+        let empty: Vec<(AssetClass, Integer)> = vec![];
+        let encoded = empty.clone().to_plutus();
+        assert!(
+            matches!(&encoded, PlutusData::Array(MaybeIndefArray::Def(items)) if items.is_empty())
+        );
+        assert!(
+            Vec::<(AssetClass, Integer)>::from_plutus(encoded)
+                .unwrap()
+                .is_empty()
+        );
+        assert_plutus_roundtrip(empty);
+
+        let balances: Vec<(AssetClass, Integer)> = vec![(asset(1), 5.into()), (asset(2), 6.into())];
+        let encoded = balances.clone().to_plutus();
+        assert!(
+            matches!(&encoded, PlutusData::Array(MaybeIndefArray::Indef(items)) if items.len() == 2)
+        );
+        assert_plutus_roundtrip(balances);
+
+        let not_a_list = BigInt::Int(1.into()).to_plutus();
+        assert!(matches!(
+            Vec::<BigInt>::from_plutus(not_a_list),
+            Err(DecodeError::UnexpectedType { .. })
+        ));
+    }
+
+    #[test]
+    fn maps_round_trip() {
+        let amounts = BTreeMap::from([
+            (asset(3), Integer::from(30)),
+            (asset(1), Integer::from(10)),
+            (asset(2), Integer::from(20)),
+        ]);
+        let encoded = amounts.clone().to_plutus();
+        let PlutusData::Map(KeyValuePairs::Def(pairs)) = &encoded else {
+            panic!("a map should encode as a definite plutus map");
+        };
+        assert_eq!(pairs.len(), 3);
+        let decoded = BTreeMap::<AssetClass, Integer>::from_plutus(encoded).unwrap();
+        assert_eq!(
+            decoded.keys().collect::<Vec<_>>(),
+            [&asset(1), &asset(2), &asset(3)]
+        );
+        assert_eq!(decoded[&asset(2)], 20.into());
+        assert_plutus_roundtrip(amounts);
+
+        let not_a_map = Vec::<BigInt>::new().to_plutus();
+        assert!(matches!(
+            BTreeMap::<AssetClass, BigInt>::from_plutus(not_a_map),
+            Err(DecodeError::UnexpectedType { .. })
+        ));
+    }
+
+    // A datum whose fields are laid out on-chain as (fee, owner, spare), where `spare` is
+    // padding, and which also carries a note that's never on-chain at all. The derive can only
+    // read fields in declaration order, so layouts like this get an impl by hand.
+    struct Awkward {
+        owner: Vec<u8>,
+        fee: BigInt,
+        note: String,
+    }
+
+    impl AsPlutus for Awkward {
+        fn from_plutus(data: PlutusData) -> Result<Self, DecodeError> {
+            let (variant, fields) = parse_constr(data)?;
+            if variant != 0 {
+                return Err(DecodeError::UnexpectedVariant { variant });
+            }
+            let [fee, owner, _spare] = plutus_parser::parse_variant(variant, fields)?;
+            Ok(Self {
+                owner: AsPlutus::from_plutus(owner)?,
+                fee: AsPlutus::from_plutus(fee)?,
+                note: Default::default(),
+            })
+        }
+
+        fn to_plutus(self) -> PlutusData {
+            let spare = create_constr(0, vec![]);
+            create_constr(0, vec![self.fee.to_plutus(), self.owner.to_plutus(), spare])
+        }
+    }
+
+    #[test]
+    fn hand_written_impls_can_reorder_and_skip_fields() {
+        let awkward = Awkward {
+            owner: vec![4; 28],
+            fee: BigInt::Int(25.into()),
+            note: "not on-chain".into(),
+        };
+        let fields = fields(awkward);
+        assert_eq!(fields.len(), 3);
+        assert!(
+            matches!(BigInt::from_plutus(fields[0].clone()), Ok(BigInt::Int(n)) if i128::from(n) == 25)
+        );
+        assert_eq!(Vec::<u8>::from_plutus(fields[1].clone()).unwrap(), [4; 28]);
+
+        let decoded = Awkward::from_plutus(create_constr(0, fields)).unwrap();
+        assert_eq!(decoded.owner, [4; 28]);
+        assert!(matches!(decoded.fee, BigInt::Int(n) if i128::from(n) == 25));
+        assert_eq!(decoded.note, "");
+        assert!(Awkward::from_plutus(create_constr(0, vec![])).is_err());
+    }
+
+    // Every datum and redeemer type, generated and shrunk by proptest.
+    fn bytes(len: usize) -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), len)
+    }
+
+    fn ident() -> impl Strategy<Value = Ident> {
+        any::<[u8; 28]>().prop_map(Ident::from)
+    }
+
+    fn int() -> impl Strategy<Value = Integer> {
+        prop_oneof![
+            any::<i64>().prop_map(Integer::from),
+            // As bignum bytes, which the encoding keeps even where an Int would do.
+            vec(any::<u8>(), 0..=20).prop_map(|b| Integer(BigInt::BigUInt(b.into()))),
+            vec(any::<u8>(), 0..=20).prop_map(|b| Integer(BigInt::BigNInt(b.into()))),
+        ]
+    }
+
+    fn asset_class() -> impl Strategy<Value = AssetClass> {
+        prop_oneof![
+            1 => Just(AssetClass::ada()),
+            4 => (any::<[u8; 28]>(), vec(any::<u8>(), 0..=32))
+                .prop_map(|(policy, name)| AssetClass::new(policy, name.try_into().unwrap())),
+        ]
+    }
+
+    fn singleton() -> impl Strategy<Value = SingletonValue> {
+        (asset_class(), int()).prop_map(|(asset, n)| {
+            let policy = asset.policy.map_or(vec![], |p| p.as_ref().to_vec());
+            (policy, asset.name.into(), n)
+        })
+    }
+
+    fn multisig() -> impl Strategy<Value = MultisigScript> {
+        let leaf = prop_oneof![
+            bytes(28).prop_map(|key_hash| MultisigScript::Signature { key_hash }),
+            int().prop_map(|time| MultisigScript::Before { time }),
+            int().prop_map(|time| MultisigScript::After { time }),
+            bytes(28).prop_map(|script_hash| MultisigScript::Script { script_hash }),
+        ];
+        leaf.prop_recursive(3, 16, 4, |inner| {
+            let scripts = || vec(inner.clone(), 0..4);
+            prop_oneof![
+                scripts().prop_map(|scripts| MultisigScript::AllOf { scripts }),
+                scripts().prop_map(|scripts| MultisigScript::AnyOf { scripts }),
+                (int(), scripts())
+                    .prop_map(|(required, scripts)| MultisigScript::AtLeast { required, scripts }),
+            ]
+        })
+    }
+
+    fn credential() -> impl Strategy<Value = Credential> {
+        prop_oneof![
+            bytes(28).prop_map(Credential::VerificationKey),
+            bytes(28).prop_map(Credential::Script),
+        ]
+    }
+
+    fn address() -> impl Strategy<Value = Address> {
+        let stake_credential = prop_oneof![
+            Just(None),
+            credential().prop_map(|c| Some(Referenced::Inline(c))),
+            (int(), int(), int()).prop_map(
+                |(slot_number, transaction_index, certificate_index)| {
+                    Some(Referenced::Pointer {
+                        slot_number,
+                        transaction_index,
+                        certificate_index,
+                    })
+                }
+            ),
+        ];
+        (credential(), stake_credential).prop_map(|(payment_credential, stake_credential)| {
+            Address {
+                payment_credential,
+                stake_credential,
+            }
+        })
+    }
+
+    fn data() -> impl Strategy<Value = Data> {
+        (int(), bytes(4)).prop_map(|d| Data(d.to_plutus()))
+    }
+
+    fn datum() -> impl Strategy<Value = Datum> {
+        prop_oneof![
+            Just(Datum::None),
+            bytes(32).prop_map(Datum::Hash),
+            data().prop_map(Datum::Inline),
+        ]
+    }
+
+    fn destination() -> impl Strategy<Value = Destination> {
+        prop_oneof![
+            (address(), datum()).prop_map(|(address, datum)| Destination::Fixed { address, datum }),
+            Just(Destination::SelfDestination),
+        ]
+    }
+
+    fn order() -> impl Strategy<Value = Order> {
+        prop_oneof![
+            bytes(28).prop_map(|signer| Order::Strategy {
+                signer: StrategyAuthorization::Signature { signer },
+            }),
+            bytes(28).prop_map(|script| Order::Strategy {
+                signer: StrategyAuthorization::Script { script },
+            }),
+            (singleton(), singleton()).prop_map(|(offer, min_received)| Order::Swap {
+                offer,
+                min_received
+            }),
+            (singleton(), singleton()).prop_map(|assets| Order::Deposit { assets }),
+            singleton().prop_map(|amount| Order::Withdrawal { amount }),
+            (singleton(), singleton()).prop_map(|assets| Order::Donation { assets }),
+            asset_class().prop_map(|policy| Order::Record { policy }),
+        ]
+    }
+
+    fn order_datum() -> impl Strategy<Value = OrderDatum> {
+        let pool_ident = proptest::option::of(ident());
+        (
+            pool_ident,
+            multisig(),
+            int(),
+            destination(),
+            order(),
+            data(),
+        )
+            .prop_map(
+                |(pool_ident, owner, max_protocol_fee, destination, details, extension)| {
+                    OrderDatum {
+                        pool_ident,
+                        owner,
+                        max_protocol_fee,
+                        destination,
+                        details,
+                        extension,
+                    }
+                },
+            )
+    }
+
+    fn pool_datum() -> impl Strategy<Value = PoolDatum> {
+        let fees = (int(), int(), int(), int(), int());
+        let fee_manager = proptest::option::of(multisig());
+        (ident(), asset_class(), asset_class(), fees, fee_manager).prop_map(
+            |(ident, a, b, (circulating_lp, bid, ask, market_open, protocol_fees), fee_manager)| {
+                PoolDatum {
+                    ident,
+                    assets: (a, b),
+                    circulating_lp,
+                    bid_fees_per_10_thousand: bid,
+                    ask_fees_per_10_thousand: ask,
+                    fee_manager,
+                    market_open,
+                    protocol_fees,
+                }
+            },
+        )
+    }
+
+    fn pool_redeemer() -> impl Strategy<Value = PoolRedeemer> {
+        prop_oneof![
+            (int(), int(), data()).prop_map(|(signatory_index, scoop_index, input_order)| {
+                PoolRedeemer::PoolScoop {
+                    signatory_index,
+                    scoop_index,
+                    input_order,
+                }
+            }),
+            Just(PoolRedeemer::Manage),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn pool_datums_round_trip(datum in pool_datum()) {
+            assert_plutus_roundtrip(datum);
+        }
+
+        #[test]
+        fn pool_redeemers_round_trip(redeemer in pool_redeemer()) {
+            assert_plutus_roundtrip(redeemer);
+        }
+
+        #[test]
+        fn order_datums_round_trip(datum in order_datum()) {
+            assert_plutus_roundtrip(datum);
+        }
+
+        #[test]
+        fn multisig_scripts_round_trip(script in multisig()) {
+            assert_plutus_roundtrip(script);
+        }
+    }
 }
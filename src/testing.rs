@@ -0,0 +1,528 @@
+// Blocks and txs built from scratch, and an indexer run over them, for the tests.
+use std::time::Duration;
+
+use anyhow::Result;
+use pallas_addresses::{
+    Address,
+    byron::{AddrType, AddressPayload, ByronAddress, SpendingData},
+};
+use pallas_codec::{
+    minicbor,
+    utils::{CborWrap, EmptyMap, TagWrap},
+};
+use pallas_crypto::hash::{Hash, Hasher};
+use pallas_primitives::{
+    Fragment, KeyValuePairs, MaybeIndefArray, NonEmptyKeyValuePairs, NonEmptySet, PlutusData,
+    PositiveCoin, Set, TransactionInput,
+    alonzo::AuxiliaryData,
+    babbage::{Header, HeaderBody, OperationalCert},
+    byron,
+    conway::{
+        Block, Certificate, DatumOption, ExUnits, Metadata, PostAlonzoTransactionOutput,
+        PseudoTransactionOutput, Redeemer, RedeemerTag, Redeemers, TransactionBody, Value, VrfCert,
+        WitnessSet,
+    },
+};
+use pallas_traverse::{MultiEraBlock, OutputRef};
+
+use crate::acropolis::{
+    core::{BlockHash, BlockInfo, CancellationToken, Process},
+    indexer::{
+        ChainEvent, ChainIndexer, ChainIndexerHandle, ChainSource, CursorStore, MockChainSource,
+    },
+};
+
+// Conway's era tag, which every block's CBOR starts with.
+const CONWAY: u16 = 7;
+// The tags Byron's epoch boundary blocks and its other blocks go by.
+const BYRON_BOUNDARY: u16 = 0;
+const BYRON: u16 = 1;
+const MAINNET_MAGIC: u32 = 764824073;
+
+/// A Shelley address paying to a key hash made of `n`s.
+pub fn key_address(n: u8) -> Vec<u8> {
+    let mut address = vec![0x61];
+    address.extend([n; 28]);
+    address
+}
+
+/// A Shelley address paying to a key hash made of `n`s, and staked with one made of `stake`s.
+pub fn base_address(n: u8, stake: u8) -> Vec<u8> {
+    let mut address = vec![0x01];
+    address.extend([n; 28]);
+    address.extend([stake; 28]);
+    address
+}
+
+/// The reward account for a stake key hash made of `stake`s.
+pub fn reward_account(stake: u8) -> Vec<u8> {
+    let mut account = vec![0xe1];
+    account.extend([stake; 28]);
+    account
+}
+
+/// A Shelley address paying to the script with this hash.
+pub fn script_address(hash: &Hash<28>) -> Vec<u8> {
+    let mut address = vec![0x71];
+    address.extend(hash.as_ref());
+    address
+}
+
+pub fn address(bytes: &[u8]) -> Address {
+    Address::from_bytes(bytes).unwrap()
+}
+
+/// An output paying `lovelace` to `address`.
+pub fn output(address: &[u8], lovelace: u64) -> PostAlonzoTransactionOutput {
+    PostAlonzoTransactionOutput {
+        address: address.to_vec().into(),
+        value: Value::Coin(lovelace),
+        datum_option: None,
+        script_ref: None,
+    }
+}
+
+/// An output paying `lovelace` and some native assets to `address`.
+pub fn output_with_assets(
+    address: &[u8],
+    lovelace: u64,
+    assets: &[(Hash<28>, &[u8], u64)],
+) -> PostAlonzoTransactionOutput {
+    let mut policies: Vec<(Hash<28>, Vec<(_, PositiveCoin)>)> = vec![];
+    for (policy, name, quantity) in assets {
+        let asset = (name.to_vec().into(), (*quantity).try_into().unwrap());
+        match policies.iter_mut().find(|(p, _)| p == policy) {
+            Some((_, names)) => names.push(asset),
+            None => policies.push((*policy, vec![asset])),
+        }
+    }
+    let multiasset = policies
+        .into_iter()
+        .map(|(policy, names)| (policy, NonEmptyKeyValuePairs::from_vec(names).unwrap()))
+        .collect();
+    PostAlonzoTransactionOutput {
+        value: Value::Multiasset(
+            lovelace,
+            NonEmptyKeyValuePairs::from_vec(multiasset).unwrap(),
+        ),
+        ..output(address, lovelace)
+    }
+}
+
+/// `output`, with `datum` inline.
+pub fn with_datum(
+    output: PostAlonzoTransactionOutput,
+    datum: PlutusData,
+) -> PostAlonzoTransactionOutput {
+    PostAlonzoTransactionOutput {
+        datum_option: Some(DatumOption::Data(CborWrap(datum))),
+        ..output
+    }
+}
+
+/// A Conway tx, built up a piece at a time.
+#[derive(Clone)]
+pub struct Tx {
+    body: TransactionBody,
+    witnesses: WitnessSet,
+    metadata: Option<Metadata>,
+    valid: bool,
+}
+
+impl Tx {
+    pub fn new() -> Self {
+        Self {
+            body: TransactionBody {
+                inputs: Set::from(vec![]),
+                outputs: vec![],
+                fee: 0,
+                ttl: None,
+                certificates: None,
+                withdrawals: None,
+                auxiliary_data_hash: None,
+                validity_interval_start: None,
+                mint: None,
+                script_data_hash: None,
+                collateral: None,
+                required_signers: None,
+                network_id: None,
+                collateral_return: None,
+                total_collateral: None,
+                reference_inputs: None,
+                voting_procedures: None,
+                proposal_procedures: None,
+                treasury_value: None,
+                donation: None,
+            },
+            witnesses: WitnessSet {
+                vkeywitness: None,
+                native_script: None,
+                bootstrap_witness: None,
+                plutus_v1_script: None,
+                plutus_data: None,
+                redeemer: None,
+                plutus_v2_script: None,
+                plutus_v3_script: None,
+            },
+            metadata: None,
+            valid: true,
+        }
+    }
+
+    pub fn spend(mut self, utxo: &OutputRef) -> Self {
+        let mut inputs = self.body.inputs.to_vec();
+        inputs.push(input(utxo));
+        self.body.inputs = Set::from(inputs);
+        self
+    }
+
+    pub fn pay(mut self, output: PostAlonzoTransactionOutput) -> Self {
+        let output = PseudoTransactionOutput::PostAlonzo(output);
+        self.body.outputs.push(output);
+        self
+    }
+
+    /// Put `utxo` up as collateral, which the tx forfeits if its scripts fail.
+    pub fn collateral(mut self, utxo: &OutputRef) -> Self {
+        let mut collateral = self.body.collateral.map(|c| c.to_vec()).unwrap_or_default();
+        collateral.push(input(utxo));
+        self.body.collateral = NonEmptySet::from_vec(collateral);
+        self
+    }
+
+    /// What's left of the collateral, paid back if the tx's scripts fail.
+    pub fn collateral_return(mut self, output: PostAlonzoTransactionOutput) -> Self {
+        self.body.collateral_return = Some(PseudoTransactionOutput::PostAlonzo(output));
+        self
+    }
+
+    /// A redeemer holding `data`, for whatever `tag` and `index` point at.
+    pub fn redeemer(mut self, tag: RedeemerTag, index: u32, data: PlutusData) -> Self {
+        let mut redeemers = match self.witnesses.redeemer.take() {
+            Some(Redeemers::List(redeemers)) => redeemers.to_vec(),
+            _ => vec![],
+        };
+        redeemers.push(Redeemer {
+            tag,
+            index,
+            data,
+            ex_units: ExUnits { mem: 0, steps: 0 },
+        });
+        self.witnesses.redeemer = Some(Redeemers::List(MaybeIndefArray::Def(redeemers)));
+        self
+    }
+
+    /// Mint `quantity` of `policy`'s `name`, or burn it if `quantity` is negative.
+    pub fn mint(mut self, policy: Hash<28>, name: &[u8], quantity: i64) -> Self {
+        let mut policies: Vec<(Hash<28>, Vec<_>)> = self
+            .body
+            .mint
+            .take()
+            .map(|m| {
+                m.to_vec()
+                    .into_iter()
+                    .map(|(p, names)| (p, names.to_vec()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let asset = (name.to_vec().into(), quantity.try_into().unwrap());
+        match policies.iter_mut().find(|(p, _)| *p == policy) {
+            Some((_, names)) => names.push(asset),
+            None => policies.push((policy, vec![asset])),
+        }
+        let mint = policies
+            .into_iter()
+            .map(|(policy, names)| (policy, NonEmptyKeyValuePairs::from_vec(names).unwrap()))
+            .collect();
+        self.body.mint = NonEmptyKeyValuePairs::from_vec(mint);
+        self
+    }
+
+    pub fn certificate(mut self, certificate: Certificate) -> Self {
+        let mut certificates = self
+            .body
+            .certificates
+            .map(|c| c.to_vec())
+            .unwrap_or_default();
+        certificates.push(certificate);
+        self.body.certificates = NonEmptySet::from_vec(certificates);
+        self
+    }
+
+    /// Withdraw `amount` of rewards from `reward_account`.
+    pub fn withdraw(mut self, reward_account: &[u8], amount: u64) -> Self {
+        let mut withdrawals = self
+            .body
+            .withdrawals
+            .map(|w| w.to_vec())
+            .unwrap_or_default();
+        withdrawals.push((reward_account.to_vec().into(), amount));
+        self.body.withdrawals = NonEmptyKeyValuePairs::from_vec(withdrawals);
+        self
+    }
+
+    /// Mark the tx's scripts as having failed, so the block lists it as invalid.
+    pub fn failing(mut self) -> Self {
+        self.valid = false;
+        self
+    }
+
+    pub fn fee(mut self, fee: u64) -> Self {
+        self.body.fee = fee;
+        self
+    }
+
+    pub fn hash(&self) -> Hash<32> {
+        Hasher::<256>::hash(&self.body.encode_fragment().unwrap())
+    }
+
+    /// The output this tx creates at `index`.
+    pub fn output_ref(&self, index: u64) -> OutputRef {
+        OutputRef::new(self.hash(), index)
+    }
+}
+
+fn input(utxo: &OutputRef) -> TransactionInput {
+    TransactionInput {
+        transaction_id: *utxo.hash(),
+        index: utxo.index(),
+    }
+}
+
+/// A block at `slot` on top of `parent`, holding `txs`, as the chain would send it.
+pub fn block(slot: u64, parent: Option<&BlockInfo>, txs: &[Tx]) -> (BlockInfo, Vec<u8>) {
+    build_block(slot, parent, txs, 0)
+}
+
+/// Like `block`, but from another issuer, so it's a different block even with the same txs,
+/// for building forks.
+pub fn fork_block(slot: u64, parent: Option<&BlockInfo>, txs: &[Tx]) -> (BlockInfo, Vec<u8>) {
+    build_block(slot, parent, txs, 1)
+}
+
+fn build_block(
+    slot: u64,
+    parent: Option<&BlockInfo>,
+    txs: &[Tx],
+    issuer: u8,
+) -> (BlockInfo, Vec<u8>) {
+    let height = parent.map_or(0, |p| p.height + 1);
+    let header_body = HeaderBody {
+        block_number: height,
+        slot,
+        prev_hash: parent.map(|p| Hash::new(p.hash.to_vec().try_into().unwrap())),
+        issuer_vkey: vec![issuer; 32].into(),
+        vrf_vkey: vec![0; 32].into(),
+        vrf_result: VrfCert(vec![].into(), vec![].into()),
+        block_body_size: 0,
+        block_body_hash: Hash::new([0; 32]),
+        operational_cert: OperationalCert {
+            operational_cert_hot_vkey: vec![].into(),
+            operational_cert_sequence_number: 0,
+            operational_cert_kes_period: 0,
+            operational_cert_sigma: vec![].into(),
+        },
+        protocol_version: (9, 0),
+    };
+    let auxiliary_data = txs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, tx)| Some((i as u32, AuxiliaryData::Shelley(tx.metadata.clone()?))))
+        .collect::<Vec<_>>();
+    let invalid = txs
+        .iter()
+        .enumerate()
+        .filter(|(_, tx)| !tx.valid)
+        .map(|(i, _)| i as u32)
+        .collect::<Vec<_>>();
+    let block = Block {
+        header: Header {
+            header_body,
+            body_signature: vec![].into(),
+        },
+        transaction_bodies: MaybeIndefArray::Def(txs.iter().map(|t| t.body.clone()).collect()),
+        transaction_witness_sets: MaybeIndefArray::Def(
+            txs.iter().map(|t| t.witnesses.clone()).collect(),
+        ),
+        auxiliary_data_set: KeyValuePairs::Def(auxiliary_data),
+        invalid_transactions: (!invalid.is_empty()).then_some(MaybeIndefArray::Def(invalid)),
+    };
+    let cbor = (CONWAY, block).encode_fragment().unwrap();
+    let hash = MultiEraBlock::decode(&cbor).unwrap().hash().to_vec();
+    let info = BlockInfo::new(slot, BlockHash::try_from(hash).unwrap(), height);
+    (info, cbor)
+}
+
+/// A Byron address paying to a public key made of `n`s, as the bytes it's carried in.
+pub fn byron_address(n: u8) -> Vec<u8> {
+    let spending = SpendingData::PubKey(vec![n; 64].into());
+    let payload = AddressPayload::new(AddrType::PubKey, spending, vec![].into());
+    ByronAddress::from_decoded(payload).to_vec()
+}
+
+/// A Byron tx, which only moves lovelace: it spends `inputs` and pays each of `outputs`.
+pub fn byron_tx(inputs: &[OutputRef], outputs: &[(Vec<u8>, u64)]) -> byron::Tx {
+    let inputs = inputs
+        .iter()
+        .map(|i| byron::TxIn::Variant0(CborWrap((*i.hash(), i.index() as u32))))
+        .collect();
+    let outputs = outputs
+        .iter()
+        .map(|(address, amount)| {
+            let address = ByronAddress::from_bytes(address).unwrap();
+            let address = byron::Address {
+                payload: address.payload,
+                crc: address.crc,
+            };
+            byron::TxOut {
+                address,
+                amount: *amount,
+            }
+        })
+        .collect();
+    byron::Tx {
+        inputs: MaybeIndefArray::Def(inputs),
+        outputs: MaybeIndefArray::Def(outputs),
+        attributes: EmptyMap,
+    }
+}
+
+/// The output a Byron tx creates at `index`.
+pub fn byron_output_ref(tx: &byron::Tx, index: u64) -> OutputRef {
+    OutputRef::new(Hasher::<256>::hash(&tx.encode_fragment().unwrap()), index)
+}
+
+/// The epoch boundary block opening `epoch`, on top of `parent`, as the chain would send it.
+/// It shares its slot with the first block of the epoch.
+pub fn epoch_boundary_block(epoch: u64, parent: &BlockInfo) -> (BlockInfo, Vec<u8>) {
+    let block = byron::EbBlock {
+        header: byron::EbbHead {
+            protocol_magic: MAINNET_MAGIC,
+            prev_block: Hash::new(parent.hash.to_vec().try_into().unwrap()),
+            body_proof: Hash::new([0; 32]),
+            consensus_data: byron::EbbCons {
+                epoch_id: epoch,
+                difficulty: MaybeIndefArray::Def(vec![parent.height]),
+            },
+            extra_data: (EmptyMap,),
+        },
+        body: MaybeIndefArray::Def(vec![]),
+        extra: MaybeIndefArray::Def(vec![]),
+    };
+    byron_encoded(BYRON_BOUNDARY, block)
+}
+
+/// A Byron block at `slot` of `epoch`, on top of `parent`, holding `txs`, as the chain would
+/// send it.
+pub fn byron_block(
+    epoch: u64,
+    slot: u64,
+    parent: &BlockInfo,
+    txs: &[byron::Tx],
+) -> (BlockInfo, Vec<u8>) {
+    let zero = Hash::new([0; 32]);
+    let header = byron::BlockHead {
+        protocol_magic: MAINNET_MAGIC,
+        prev_block: Hash::new(parent.hash.to_vec().try_into().unwrap()),
+        body_proof: byron::BlockProof {
+            tx_proof: (txs.len() as u32, zero, zero),
+            ssc_proof: byron::SscProof::Variant3(zero),
+            dlg_proof: zero,
+            upd_proof: zero,
+        },
+        consensus_data: byron::BlockCons(
+            byron::SlotId { epoch, slot },
+            vec![].into(),
+            MaybeIndefArray::Def(vec![parent.height + 1]),
+            byron::BlockSig::Signature(vec![].into()),
+        ),
+        extra_data: byron::BlockHeadEx {
+            block_version: (0, 0, 0),
+            software_version: ("testing".into(), 0),
+            attributes: Some(EmptyMap),
+            extra_proof: zero,
+        },
+    };
+    let payload = txs
+        .iter()
+        .map(|tx| byron::TxPayload {
+            transaction: tx.clone(),
+            witness: MaybeIndefArray::Def(vec![]),
+        })
+        .collect();
+    let body = byron::BlockBody {
+        tx_payload: MaybeIndefArray::Def(payload),
+        ssc_payload: byron::Ssc::Variant3(TagWrap(MaybeIndefArray::Def(vec![]))),
+        dlg_payload: MaybeIndefArray::Def(vec![]),
+        // An empty array, for no update proposal; there's no other way to build one.
+        upd_payload: byron::Up {
+            proposal: minicbor::decode(&[0x80]).unwrap(),
+            votes: MaybeIndefArray::Def(vec![]),
+        },
+    };
+    let block = byron::Block {
+        header,
+        body,
+        extra: MaybeIndefArray::Def(vec![EmptyMap]),
+    };
+    byron_encoded(BYRON, block)
+}
+
+fn byron_encoded<B: minicbor::Encode<()>>(era: u16, block: B) -> (BlockInfo, Vec<u8>) {
+    let cbor = minicbor::to_vec((era, block)).unwrap();
+    let decoded = MultiEraBlock::decode(&cbor).unwrap();
+    let hash = BlockHash::try_from(decoded.hash().to_vec()).unwrap();
+    let info = BlockInfo::new(decoded.slot(), hash, decoded.number());
+    (info, cbor)
+}
+
+/// Empty blocks at each of `slots`, each on top of the one before.
+pub fn chain(slots: &[u64]) -> Vec<(BlockInfo, Vec<u8>)> {
+    let mut blocks: Vec<(BlockInfo, Vec<u8>)> = vec![];
+    for slot in slots {
+        let next = block(*slot, blocks.last().map(|(info, _)| info), &[]);
+        blocks.push(next);
+    }
+    blocks
+}
+
+/// The blocks, rolled forward one after another.
+pub fn roll_forward(blocks: &[(BlockInfo, Vec<u8>)]) -> Vec<ChainEvent> {
+    blocks
+        .iter()
+        .map(|(info, cbor)| ChainEvent::RollForward(info.clone(), cbor.clone()))
+        .collect()
+}
+
+/// Runs `indexer` over `events` until it's caught up with them (or has stopped by itself),
+/// then shuts it down, which commits whatever it's handled.
+pub async fn sync<CS: CursorStore>(
+    indexer: ChainIndexer<CS>,
+    events: Vec<ChainEvent>,
+) -> Result<ChainIndexerHandle> {
+    sync_from_source(indexer, MockChainSource::new(events)).await
+}
+
+/// `sync`, following `source`.
+pub async fn sync_from_source<CS: CursorStore>(
+    mut indexer: ChainIndexer<CS>,
+    source: impl ChainSource,
+) -> Result<ChainIndexerHandle> {
+    indexer.set_chain_source(source);
+    let handle = indexer.handle();
+    let token = CancellationToken::new();
+    let mut process = Process::create();
+    process.register(indexer);
+    let running = tokio::spawn(process.run_until(token.clone()));
+    let caught_up = async {
+        while !handle.is_at_tip() && !running.is_finished() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), caught_up)
+        .await
+        .expect("the indexer never caught up");
+    token.cancel();
+    running.await??;
+    Ok(handle)
+}
@@ -0,0 +1,347 @@
+// WebSockets (RFC 6455) for pushing index events to a client. The handshake is ours, and
+// tungstenite takes the connection over from there: text frames out, and whatever the client
+// sends in, of which only pings and closes get an answer.
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use futures::{SinkExt as _, StreamExt as _};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufReader},
+    net::TcpStream,
+    sync::{broadcast, mpsc},
+};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        Error, Message,
+        error::ProtocolError,
+        protocol::{CloseFrame, Role, WebSocketConfig},
+    },
+};
+
+use crate::acropolis::{core::CancellationToken, indexer::IndexEvent};
+
+// How many frames a client can fall behind by before we drop it.
+const CLIENT_BUFFER: usize = 256;
+// The biggest message we'll take from a client, in however many frames. They've no reason to
+// send anything but control frames, which are at most 125 bytes.
+const MAX_CLIENT_MESSAGE: usize = 4096;
+// How long a client can leave a frame unread before we give up on it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const POLICY_VIOLATION: u16 = 1008;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// The `Sec-WebSocket-Accept` answer to a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let digest = cryptoxide::hashing::sha1(format!("{}{HANDSHAKE_GUID}", key.trim()).as_bytes());
+    base64(&digest)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Streams `index`'s events to a client which has just been switched over to WebSockets,
+/// one JSON text frame per event, starting with `snapshot` if there is one.
+/// `events` should be subscribed before the snapshot is taken, so nothing falls between them.
+/// The connection is closed if the client falls more than `CLIENT_BUFFER` events behind,
+/// breaks the protocol, or once `shutdown` is cancelled. It's dropped without a close frame
+/// if the client stops reading for `WRITE_TIMEOUT`, or we shut down while it isn't reading.
+pub async fn stream_events(
+    stream: BufReader<TcpStream>,
+    index: String,
+    events: broadcast::Receiver<(String, IndexEvent)>,
+    snapshot: Option<serde_json::Value>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let config = WebSocketConfig::default()
+        .max_frame_size(Some(MAX_CLIENT_MESSAGE))
+        .max_message_size(Some(MAX_CLIENT_MESSAGE));
+    let socket = WebSocketStream::from_raw_socket(stream, Role::Server, Some(config)).await;
+    let (frames, mut outgoing) = mpsc::channel(CLIENT_BUFFER);
+    let mut forwarder = tokio::spawn(forward(events, index, frames));
+    let result = async {
+        let mut output = Output {
+            socket,
+            shutdown: &shutdown,
+        };
+        if let Some(snapshot) = snapshot {
+            output.send(snapshot.to_string()).await?;
+        }
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => {
+                    return output.close(GOING_AWAY, "shutting down").await;
+                }
+                frame = outgoing.recv() => match frame {
+                    Some(frame) => output.send(frame).await?,
+                    None => {
+                        let (code, reason) = (&mut forwarder).await?;
+                        return output.close(code, reason).await;
+                    }
+                },
+                // Pings are answered as they're read, and fragments put back together.
+                message = output.socket.next() => match message {
+                    Some(Ok(Message::Close(_))) => return output.answer_close().await,
+                    Some(Ok(_)) => {}
+                    // A client which hangs up without closing isn't there to tell.
+                    None | Some(Err(Error::Protocol(ProtocolError::ResetWithoutClosingHandshake))) => {
+                        return Ok(());
+                    }
+                    Some(Err(Error::Protocol(e))) => {
+                        return output.close(PROTOCOL_ERROR, &e.to_string()).await;
+                    }
+                    Some(Err(Error::Capacity(e))) => {
+                        return output.close(MESSAGE_TOO_BIG, &e.to_string()).await;
+                    }
+                    Some(Err(Error::Utf8(_))) => {
+                        return output.close(INVALID_DATA, "text frames have to be UTF-8").await;
+                    }
+                    Some(Err(e)) => return Err(e.into()),
+                },
+            }
+        }
+    }
+    .await;
+    forwarder.abort();
+    result
+}
+
+// Passes `index`'s events on to the connection until the client falls behind or goes away,
+// or the indexer stops, then says how to close the connection.
+async fn forward(
+    mut events: broadcast::Receiver<(String, IndexEvent)>,
+    index: String,
+    frames: mpsc::Sender<String>,
+) -> (u16, &'static str) {
+    loop {
+        let event = tokio::select! {
+            _ = frames.closed() => return (NORMAL_CLOSURE, ""),
+            event = events.recv() => event,
+        };
+        match event {
+            Ok((name, event)) if name == index => {
+                if frames.try_send(event.to_json(&index).to_string()).is_err() {
+                    return (POLICY_VIOLATION, "fell too far behind");
+                }
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                return (POLICY_VIOLATION, "fell too far behind");
+            }
+            Err(broadcast::error::RecvError::Closed) => return (GOING_AWAY, "the indexer stopped"),
+        }
+    }
+}
+
+// The client's end of the connection, which gives up on a write the client isn't reading,
+// rather than wait on it and miss a shutdown.
+struct Output<'a, S> {
+    socket: WebSocketStream<S>,
+    shutdown: &'a CancellationToken,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Output<'_, S> {
+    // There's no stopping a frame halfway through, so a write that's given up on leaves
+    // nothing to do but drop the connection.
+    async fn send(&mut self, text: String) -> Result<()> {
+        tokio::select! {
+            _ = self.shutdown.cancelled() => bail!("shut down while the client wasn't reading"),
+            sent = tokio::time::timeout(WRITE_TIMEOUT, self.socket.send(Message::text(text))) => {
+                match sent {
+                    Ok(sent) => Ok(sent?),
+                    Err(_) => bail!("the client stopped reading"),
+                }
+            }
+        }
+    }
+
+    // Closing is often what we do on shutdown, so only the timeout can cut it short.
+    async fn close(&mut self, code: u16, reason: &str) -> Result<()> {
+        let frame = CloseFrame {
+            code: code.into(),
+            reason: reason.into(),
+        };
+        match tokio::time::timeout(WRITE_TIMEOUT, self.socket.close(Some(frame))).await {
+            Ok(closed) => Ok(closed?),
+            Err(_) => bail!("the client stopped reading"),
+        }
+    }
+
+    // tungstenite answers a close with a close itself, so there's only the answer to send.
+    async fn answer_close(&mut self) -> Result<()> {
+        match tokio::time::timeout(WRITE_TIMEOUT, self.socket.flush()).await {
+            Ok(flushed) => Ok(flushed?),
+            Err(_) => bail!("the client stopped reading"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt as _, net::TcpListener};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+    use super::*;
+
+    const PING: u8 = 0x9;
+
+    // Streams events to a client over loopback, and returns the client's end and the stream's
+    // task, with the sender its events come from.
+    async fn connect(
+        snapshot: Option<serde_json::Value>,
+        shutdown: CancellationToken,
+    ) -> (
+        TcpStream,
+        tokio::task::JoinHandle<Result<()>>,
+        broadcast::Sender<(String, IndexEvent)>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (events, receiver) = broadcast::channel(1);
+        let streaming = tokio::spawn(stream_events(
+            BufReader::new(server),
+            "pools".into(),
+            receiver,
+            snapshot,
+            shutdown,
+        ));
+        (client, streaming, events)
+    }
+
+    // A frame from a client, masked as a client's have to be, starting with `first`: the FIN
+    // and reserved bits and the opcode.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        const MASK: [u8; 4] = [1, 2, 3, 4];
+        let mut frame = vec![first];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+        }
+        frame.extend(MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        frame
+    }
+
+    // What the server sends the client next.
+    async fn receive(client: &mut WebSocketStream<TcpStream>) -> Message {
+        tokio::time::timeout(Duration::from_secs(1), client.next())
+            .await
+            .expect("the server sent nothing")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_frame_which_breaks_the_protocol_is_closed_on() {
+        let cases = [
+            ("an unmasked ping", vec![0x80 | PING, 0]),
+            ("a reserved bit", client_frame(0x80 | 0x40 | PING, b"")),
+            ("a long ping", client_frame(0x80 | PING, &[0; 126])),
+            ("a fragmented ping", client_frame(PING, b"")),
+            ("a continuation of nothing", client_frame(0x80, b"lo")),
+        ];
+        for (frame, bytes) in cases {
+            let (mut client, streaming, _events) = connect(None, CancellationToken::new()).await;
+            client.write_all(&bytes).await.unwrap();
+            let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            let Message::Close(Some(close)) = receive(&mut client).await else {
+                panic!("{frame} wasn't closed on");
+            };
+            assert_eq!(close.code, CloseCode::Protocol, "{frame}");
+            streaming.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn a_message_can_come_in_fragments() {
+        let (mut client, streaming, _events) = connect(None, CancellationToken::new()).await;
+        // A text message in two, with a ping between its fragments, and another after it.
+        for frame in [
+            client_frame(0x1, b"hel"),
+            client_frame(0x80 | PING, b"between"),
+            client_frame(0x80, b"lo"),
+            client_frame(0x80 | PING, b"after"),
+        ] {
+            client.write_all(&frame).await.unwrap();
+        }
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        for ping in ["between", "after"] {
+            assert_eq!(receive(&mut client).await, Message::Pong(ping.into()));
+        }
+        let bye = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+        client.close(Some(bye.clone())).await.unwrap();
+        let Message::Close(Some(close)) = receive(&mut client).await else {
+            panic!("the close wasn't answered");
+        };
+        assert_eq!(close, bye);
+        streaming.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_message_too_big_is_closed_on() {
+        let (mut client, streaming, _events) = connect(None, CancellationToken::new()).await;
+        // Each fragment's small, but they add up to more than we'll take.
+        client
+            .write_all(&client_frame(0x1, &[b'x'; 120]))
+            .await
+            .unwrap();
+        for _ in 0..MAX_CLIENT_MESSAGE / 120 {
+            client
+                .write_all(&client_frame(0x0, &[b'x'; 120]))
+                .await
+                .unwrap();
+        }
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let Message::Close(Some(close)) = receive(&mut client).await else {
+            panic!("the message wasn't refused");
+        };
+        assert_eq!(close.code, CloseCode::Size);
+        streaming.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_client_which_isnt_reading_doesnt_hold_up_shutdown() {
+        // Far more than the socket can buffer, so the write blocks on the client.
+        let snapshot = serde_json::Value::String("x".repeat(16 << 20));
+        let shutdown = CancellationToken::new();
+        let (_client, streaming, _events) = connect(Some(snapshot), shutdown.clone()).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!streaming.is_finished());
+        shutdown.cancel();
+        let stopped = tokio::time::timeout(Duration::from_secs(1), streaming)
+            .await
+            .expect("shutdown waited on the client");
+        assert!(stopped.unwrap().is_err());
+    }
+}